@@ -0,0 +1,105 @@
+/*
+    Optional token authentication for the HTTP API
+
+    When `httpd.auth_token` is set every request below /api/v1/ needs to carry
+    the token either as "Authorization: Bearer <token>" or, for websockets where
+    browsers can not set headers, as URL encoded "access_token" query parameter.
+    The OpenAPI document stays open so the Swagger UI can still be loaded.
+*/
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{http::header, web, HttpResponse};
+use log::warn;
+use std::collections::HashMap;
+
+use crate::{config::ConfigBases, get_config_or_panic, CONFIG};
+
+const PROTECTED_PREFIX: &str = "/api/v1/";
+const OPENAPI_PATH: &str = "/api/v1/openapi.json";
+
+/// Only the API is protected, /health, the UI files, the OpenAPI document and Prometheus stay open
+fn is_protected_path(path: &str) -> bool {
+    path.starts_with(PROTECTED_PREFIX) && path != OPENAPI_PATH
+}
+
+/// Get the token from the Authorization header or the access_token query parameter
+fn provided_token(auth_header: Option<&str>, query: &str) -> Option<String> {
+    if let Some(value) = auth_header {
+        if let Some(token) = value.strip_prefix("Bearer ") {
+            return Some(token.trim().to_string());
+        }
+    }
+
+    web::Query::<HashMap<String, String>>::from_query(query).ok()
+        .and_then(|mut params| params.remove("access_token"))
+}
+
+/// Compare in constant time so the token can not be guessed by timing responses
+fn token_matches(expected: &str, given: &str) -> bool {
+    if expected.len() != given.len() {
+        return false;
+    }
+
+    expected.bytes()
+        .zip(given.bytes())
+        .fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+pub async fn require_token(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let config = get_config_or_panic!("httpd", ConfigBases::Httpd);
+
+    let expected = match config.auth_token {
+        Some(token) if !token.is_empty() && is_protected_path(req.path()) => token,
+        _ => return next.call(req).await.map(ServiceResponse::map_into_left_body),
+    };
+
+    let auth_header = req.headers().get(header::AUTHORIZATION).and_then(|v| v.to_str().ok());
+    let authorized = provided_token(auth_header, req.query_string())
+        .map(|token| token_matches(&expected, &token))
+        .unwrap_or(false);
+
+    if !authorized {
+        warn!("Rejected unauthorized API request to {}", req.path());
+        let response = HttpResponse::Unauthorized()
+            .insert_header((header::WWW_AUTHENTICATE, "Bearer"))
+            .body("Unauthorized");
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+
+    next.call(req).await.map(ServiceResponse::map_into_left_body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_protected_paths() {
+        assert!(is_protected_path("/api/v1/modbus"));
+        assert!(is_protected_path("/api/v1/ws/live"));
+        assert!(!is_protected_path("/health"));
+        assert!(!is_protected_path("/ui/index.html"));
+        assert!(!is_protected_path("/api/v1/openapi.json"));
+    }
+
+    #[test]
+    fn test_provided_token() {
+        assert_eq!(provided_token(Some("Bearer secret"), ""), Some("secret".to_string()));
+        assert_eq!(provided_token(None, "foo=bar&access_token=secret"), Some("secret".to_string()));
+        assert_eq!(provided_token(None, "access_token=a%2Bb%3D%26c"), Some("a+b=&c".to_string()));
+        assert_eq!(provided_token(Some("Basic abc"), ""), None);
+        assert_eq!(provided_token(None, ""), None);
+    }
+
+    #[test]
+    fn test_token_matches() {
+        assert!(token_matches("secret", "secret"));
+        assert!(!token_matches("secret", "secreT"));
+        assert!(!token_matches("secret", "secret2"));
+    }
+}
@@ -0,0 +1,149 @@
+//! Rotating backups of the config file
+//!
+//! Every save copies the current file to backups/e2m-<timestamp>-<counter>.<format> below the base
+//! path before replacing it. Only the newest storage.config_backups files are kept, a restore
+//! copies one of them back and reloads the configuration.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::Local;
+use serde::Serialize;
+#[cfg(feature = "api")]
+use utoipa::ToSchema;
+
+use super::format::{ConfigFormat, CONFIG_FORMATS};
+
+/// Directory below the base path holding the backups
+pub const BACKUP_DIR: &str = "backups";
+
+/// A stored backup of the configuration
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "api", derive(ToSchema))]
+pub struct ConfigBackup {
+    pub name: String,
+    pub size: u64,
+    /// Unix timestamp the backup was written
+    pub created: u64,
+}
+
+/// Only names created by us are accepted, this keeps restores inside the backup directory
+pub fn is_backup_name(name: &str) -> bool {
+    name.starts_with("e2m-")
+        && backup_format(name).is_some()
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+        && !name.contains("..")
+}
+
+/// Format of a backup, taken from its extension
+pub fn backup_format(name: &str) -> Option<ConfigFormat> {
+    CONFIG_FORMATS.into_iter().find(|f| name.ends_with(&format!(".{}", f.extension())))
+}
+
+/// Path of a backup, None for names not created by us
+pub fn backup_path(base: &Path, name: &str) -> Option<PathBuf> {
+    is_backup_name(name).then(|| base.join(BACKUP_DIR).join(name))
+}
+
+/// Copy the current config file into the backup directory and drop the oldest backups beyond keep.
+/// Returns the name of the new backup, None if there is no config yet or backups are disabled.
+pub fn create_backup(base: &Path, format: ConfigFormat, keep: usize) -> io::Result<Option<String>> {
+    if keep == 0 {
+        return Ok(None);
+    }
+
+    let config_path = base.join(format.file_name());
+    if !config_path.exists() {
+        return Ok(None);
+    }
+
+    let dir = base.join(BACKUP_DIR);
+    fs::create_dir_all(&dir)?;
+
+    /* Milliseconds keep the names sortable, a counter orders saves within the same one */
+    let prefix = format!("e2m-{}-", Local::now().format("%Y%m%d-%H%M%S-%3f"));
+    let counter = list_backups(base)?.iter()
+        .filter_map(|b| b.name.strip_prefix(&prefix)?.split('.').next()?.parse::<u32>().ok())
+        .max()
+        .map_or(0, |c| c + 1);
+    let name = format!("{prefix}{counter:03}.{}", format.extension());
+    fs::copy(&config_path, dir.join(&name))?;
+
+    for old in list_backups(base)?.into_iter().skip(keep) {
+        fs::remove_file(dir.join(&old.name))?;
+    }
+
+    Ok(Some(name))
+}
+
+/// All backups, the newest first
+pub fn list_backups(base: &Path) -> io::Result<Vec<ConfigBackup>> {
+    let dir = base.join(BACKUP_DIR);
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut backups = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !is_backup_name(&name) {
+            continue;
+        }
+
+        let metadata = entry.metadata()?;
+        let created = metadata.modified().ok()
+            .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        backups.push(ConfigBackup { name, size: metadata.len(), created });
+    }
+
+    backups.sort_by(|a, b| b.name.cmp(&a.name));
+    Ok(backups)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backup_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(create_backup(dir.path(), ConfigFormat::Yaml, 3).unwrap(), None);
+
+        let mut names = Vec::new();
+        for i in 0..5 {
+            fs::write(dir.path().join("e2m.yaml"), format!("version: {i}\n")).unwrap();
+            names.push(create_backup(dir.path(), ConfigFormat::Yaml, 3).unwrap().unwrap());
+        }
+
+        let backups = list_backups(dir.path()).unwrap();
+        let listed: Vec<String> = backups.iter().map(|b| b.name.clone()).collect();
+        assert_eq!(listed, vec![names[4].clone(), names[3].clone(), names[2].clone()]);
+        assert_eq!(fs::read_to_string(backup_path(dir.path(), &names[4]).unwrap()).unwrap(), "version: 4\n");
+
+        /* Disabled backups leave the existing ones alone */
+        assert_eq!(create_backup(dir.path(), ConfigFormat::Yaml, 0).unwrap(), None);
+        assert_eq!(list_backups(dir.path()).unwrap().len(), 3);
+
+        /* Backups keep the format of the config file */
+        fs::write(dir.path().join("e2m.toml"), "version = 5\n").unwrap();
+        let name = create_backup(dir.path(), ConfigFormat::Toml, 3).unwrap().unwrap();
+        assert!(name.ends_with(".toml"));
+        assert_eq!(backup_format(&name), Some(ConfigFormat::Toml));
+        assert_eq!(list_backups(dir.path()).unwrap()[0].name, name);
+    }
+
+    #[test]
+    fn test_backup_names() {
+        assert!(is_backup_name("e2m-20261016-120000-123-000.yaml"));
+        assert!(is_backup_name("e2m-20261016-120000-123-000.json"));
+        assert!(!is_backup_name("e2m-20261016-120000-123-000.yml"));
+        assert!(!is_backup_name("../e2m.yaml"));
+        assert!(!is_backup_name("e2m-../../etc.yaml"));
+        assert!(!is_backup_name("e2m-1/x.yaml"));
+        assert!(backup_path(Path::new("/tmp"), "e2m.yaml").is_none());
+    }
+}
@@ -0,0 +1,228 @@
+//! ${ENV_VAR} interpolation for string values of the config file
+//!
+//! Passwords, tokens and keys can be written as `pass: ${E2M_MQTT_PASS}` and are taken from
+//! the environment when the config is loaded. The places are remembered so saving the config
+//! writes the placeholders again instead of the resolved secrets. List entries are found again
+//! by their `name`, so deleting or reordering entries through the API keeps every placeholder.
+
+use log::warn;
+use serde_yml::Value;
+
+/// Step from a value to one of its children
+#[derive(Clone, Debug, PartialEq)]
+enum PathSegment {
+    Key(String),
+    Index(usize),
+    /// Entry of a list identified by its `name` key
+    Named(String),
+}
+
+/// A string of the config which contained at least one ${ENV_VAR}
+#[derive(Clone, Debug, PartialEq)]
+pub struct EnvSecret {
+    path: Vec<PathSegment>,
+    /// Text as written in the config file
+    template: String,
+    /// Text after replacing the variables
+    resolved: String,
+}
+
+impl EnvSecret {
+    /// Text after replacing the variables, never to be shown
+    pub fn resolved(&self) -> &str {
+        &self.resolved
+    }
+}
+
+/// Replace ${NAME} by lookup(NAME), unknown variables are kept as written
+fn interpolate(template: &str, lookup: &dyn Fn(&str) -> Option<String>) -> String {
+    let mut result = String::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find("${") {
+        result.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let Some(end) = after.find('}') else {
+            result.push_str(&rest[start..]);
+            return result;
+        };
+
+        let name = &after[..end];
+        let valid = !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        match lookup(name).filter(|_| valid) {
+            Some(v) => result.push_str(&v),
+            None => {
+                if valid {
+                    warn!("Environment variable {name} used in config is not set");
+                }
+                result.push_str(&rest[start..start + 3 + end]);
+            }
+        }
+        rest = &after[end + 1..];
+    }
+
+    result.push_str(rest);
+    result
+}
+
+fn entry_name(value: &Value) -> Option<String> {
+    value.as_mapping()?.get("name")?.as_str().map(|s| s.to_string())
+}
+
+fn resolve_value(value: &mut Value, path: &mut Vec<PathSegment>, lookup: &dyn Fn(&str) -> Option<String>, secrets: &mut Vec<EnvSecret>) {
+    match value {
+        Value::String(s) if s.contains("${") => {
+            let resolved = interpolate(s, lookup);
+            if resolved != *s {
+                secrets.push(EnvSecret { path: path.clone(), template: s.clone(), resolved: resolved.clone() });
+                *s = resolved;
+            }
+        },
+        Value::Sequence(seq) => {
+            for (i, v) in seq.iter_mut().enumerate() {
+                match entry_name(v) {
+                    Some(name) => path.push(PathSegment::Named(name)),
+                    None => path.push(PathSegment::Index(i)),
+                }
+                resolve_value(v, path, lookup, secrets);
+                path.pop();
+            }
+        },
+        Value::Mapping(map) => {
+            for (k, v) in map.iter_mut() {
+                let Value::String(key) = k else {
+                    continue;
+                };
+                path.push(PathSegment::Key(key.clone()));
+                resolve_value(v, path, lookup, secrets);
+                path.pop();
+            }
+        },
+        Value::Tagged(tagged) => resolve_value(&mut tagged.value, path, lookup, secrets),
+        _ => {},
+    }
+}
+
+/// Resolve all ${ENV_VAR} in string values using lookup, returns what was replaced
+pub fn resolve_with(value: &mut Value, lookup: &dyn Fn(&str) -> Option<String>) -> Vec<EnvSecret> {
+    let mut secrets = Vec::new();
+    resolve_value(value, &mut Vec::new(), lookup, &mut secrets);
+    secrets
+}
+
+/// Resolve all ${ENV_VAR} in string values from the process environment
+pub fn resolve_env(value: &mut Value) -> Vec<EnvSecret> {
+    resolve_with(value, &|name| std::env::var(name).ok())
+}
+
+fn lookup_mut<'a>(value: &'a mut Value, path: &[PathSegment]) -> Option<&'a mut Value> {
+    let mut current = value;
+    for segment in path {
+        if let Value::Tagged(tagged) = current {
+            current = &mut tagged.value;
+        }
+        current = match segment {
+            PathSegment::Key(key) => current.as_mapping_mut()?.get_mut(key.as_str())?,
+            PathSegment::Index(i) => current.as_sequence_mut()?.get_mut(*i)?,
+            PathSegment::Named(name) => current.as_sequence_mut()?
+                .iter_mut()
+                .find(|v| entry_name(v).as_deref() == Some(name.as_str()))?,
+        };
+    }
+    Some(current)
+}
+
+/// Replace every string equal to the resolved secret by its template
+fn restore_by_value(value: &mut Value, secret: &EnvSecret) {
+    match value {
+        Value::String(s) if *s == secret.resolved => *s = secret.template.clone(),
+        Value::Sequence(seq) => seq.iter_mut().for_each(|v| restore_by_value(v, secret)),
+        Value::Mapping(map) => map.iter_mut().for_each(|(_, v)| restore_by_value(v, secret)),
+        Value::Tagged(tagged) => restore_by_value(&mut tagged.value, secret),
+        _ => {},
+    }
+}
+
+/// Put the placeholders back before writing the config. Values changed since loading
+/// (e.g. a new password set in the UI) are written as they are. When the remembered place
+/// does not hold the secret anymore, e.g. an unnamed entry moved, it is searched by value
+/// so the plaintext never ends up in the file.
+pub fn restore_templates(value: &mut Value, secrets: &[EnvSecret]) {
+    for secret in secrets {
+        if let Some(Value::String(s)) = lookup_mut(value, &secret.path) {
+            if *s == secret.resolved {
+                *s = secret.template.clone();
+                continue;
+            }
+        }
+        restore_by_value(value, secret);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "E2M_MQTT_PASS" => Some("secret".to_string()),
+            "E2M_TOKEN" => Some("abc".to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_interpolate() {
+        assert_eq!(interpolate("${E2M_MQTT_PASS}", &lookup), "secret");
+        assert_eq!(interpolate("Bearer ${E2M_TOKEN}!", &lookup), "Bearer abc!");
+        assert_eq!(interpolate("${E2M_TOKEN}${E2M_TOKEN}", &lookup), "abcabc");
+        assert_eq!(interpolate("${E2M_UNSET}", &lookup), "${E2M_UNSET}");
+        assert_eq!(interpolate("${not valid}", &lookup), "${not valid}");
+        assert_eq!(interpolate("open ${E2M_TOKEN", &lookup), "open ${E2M_TOKEN");
+        assert_eq!(interpolate("plain", &lookup), "plain");
+    }
+
+    #[test]
+    fn test_resolve_and_restore() {
+        let yaml = "mqtt:\n  pass: ${E2M_MQTT_PASS}\n  user: e2m\ntibber:\n- name: home\n  token: ${E2M_TOKEN}\n- name: other\n  token: ${E2M_TOKEN}\n";
+        let mut value: Value = serde_yml::from_str(yaml).unwrap();
+        let secrets = resolve_with(&mut value, &lookup);
+        assert_eq!(secrets.len(), 3);
+        assert_eq!(value["mqtt"]["pass"].as_str(), Some("secret"));
+        assert_eq!(value["tibber"][1]["token"].as_str(), Some("abc"));
+
+        /* A token replaced in the meantime is kept */
+        value["tibber"][1]["token"] = Value::String("new".to_string());
+        restore_templates(&mut value, &secrets);
+        assert_eq!(value["mqtt"]["pass"].as_str(), Some("${E2M_MQTT_PASS}"));
+        assert_eq!(value["mqtt"]["user"].as_str(), Some("e2m"));
+        assert_eq!(value["tibber"][0]["token"].as_str(), Some("${E2M_TOKEN}"));
+        assert_eq!(value["tibber"][1]["token"].as_str(), Some("new"));
+    }
+
+    #[test]
+    fn test_restore_after_deleting_and_reordering() {
+        let yaml = "tibber:\n- name: home\n  token: ${E2M_MQTT_PASS}\n- name: other\n  token: ${E2M_TOKEN}\n\
+                    list:\n- ${E2M_MQTT_PASS}\n- ${E2M_TOKEN}\n";
+        let mut value: Value = serde_yml::from_str(yaml).unwrap();
+        let secrets = resolve_with(&mut value, &lookup);
+        assert_eq!(secrets.len(), 4);
+
+        /* The first entries are deleted, the remaining ones move to index 0 */
+        value["tibber"].as_sequence_mut().unwrap().remove(0);
+        value["list"].as_sequence_mut().unwrap().remove(0);
+        restore_templates(&mut value, &secrets);
+        assert_eq!(value["tibber"][0]["name"].as_str(), Some("other"));
+        assert_eq!(value["tibber"][0]["token"].as_str(), Some("${E2M_TOKEN}"));
+        assert_eq!(value["list"][0].as_str(), Some("${E2M_TOKEN}"));
+
+        /* Reordered named entries keep their own placeholder */
+        let mut value: Value = serde_yml::from_str(yaml).unwrap();
+        let secrets = resolve_with(&mut value, &lookup);
+        value["tibber"].as_sequence_mut().unwrap().reverse();
+        restore_templates(&mut value, &secrets);
+        assert_eq!(value["tibber"][0]["token"].as_str(), Some("${E2M_TOKEN}"));
+        assert_eq!(value["tibber"][1]["token"].as_str(), Some("${E2M_MQTT_PASS}"));
+        assert!(!serde_yml::to_string(&value).unwrap().contains("secret"));
+    }
+}
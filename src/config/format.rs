@@ -0,0 +1,125 @@
+//! File formats of the configuration
+//!
+//! The configuration is read from e2m.yaml, e2m.toml or e2m.json below the base path and
+//! written back in the format it was read in. All formats are converted to a YAML value, so
+//! the ${ENV_VAR} handling works the same for each of them.
+
+use std::path::Path;
+
+use log::warn;
+use serde::de::DeserializeOwned;
+use serde_yml::Value;
+
+use super::parse_error_message;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Yaml,
+    Toml,
+    Json,
+}
+
+/// Formats in the order they are looked for
+pub const CONFIG_FORMATS: [ConfigFormat; 3] = [ConfigFormat::Yaml, ConfigFormat::Toml, ConfigFormat::Json];
+
+/// TOML has no null, unset options are left out instead
+fn remove_nulls(value: &mut Value) {
+    match value {
+        Value::Mapping(map) => {
+            map.retain(|_, v| !v.is_null());
+            map.values_mut().for_each(remove_nulls);
+        },
+        Value::Sequence(seq) => seq.iter_mut().for_each(remove_nulls),
+        Value::Tagged(tagged) => remove_nulls(&mut tagged.value),
+        _ => {},
+    }
+}
+
+impl ConfigFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ConfigFormat::Yaml => "yaml",
+            ConfigFormat::Toml => "toml",
+            ConfigFormat::Json => "json",
+        }
+    }
+
+    /// Name of the config file in this format
+    pub fn file_name(&self) -> String {
+        format!("e2m.{}", self.extension())
+    }
+
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        CONFIG_FORMATS.into_iter().find(|f| f.extension() == extension)
+    }
+
+    /// Format of the config file below base, YAML if there is none yet
+    pub fn detect(base: &Path) -> Self {
+        let found: Vec<ConfigFormat> = CONFIG_FORMATS.into_iter()
+            .filter(|f| base.join(f.file_name()).exists())
+            .collect();
+
+        if found.len() > 1 {
+            warn!("Several config files found in {}, using {}", base.display(), found[0].file_name());
+        }
+        found.first().copied().unwrap_or(ConfigFormat::Yaml)
+    }
+
+    /// Parse the text into T, errors name the position in the file
+    pub fn parse<T: DeserializeOwned>(&self, contents: &str) -> Result<T, String> {
+        match self {
+            ConfigFormat::Yaml => serde_yml::from_str(contents).map_err(|e| parse_error_message(contents, &e)),
+            ConfigFormat::Toml => toml::from_str(contents).map_err(|e| e.to_string().trim_end().to_string()),
+            ConfigFormat::Json => serde_json::from_str(contents).map_err(|e| e.to_string()),
+        }
+    }
+
+    /// Parse the text into a YAML value whatever the format is
+    pub fn parse_value(&self, contents: &str) -> Result<Value, String> {
+        match self {
+            ConfigFormat::Yaml => self.parse(contents),
+            ConfigFormat::Toml => serde_yml::to_value(self.parse::<toml::Value>(contents)?).map_err(|e| e.to_string()),
+            ConfigFormat::Json => serde_yml::to_value(self.parse::<serde_json::Value>(contents)?).map_err(|e| e.to_string()),
+        }
+    }
+
+    /// Write a YAML value as text of this format
+    pub fn serialize(&self, value: &Value) -> Result<String, String> {
+        match self {
+            ConfigFormat::Yaml => serde_yml::to_string(value).map_err(|e| e.to_string()),
+            ConfigFormat::Toml => {
+                let mut value = value.clone();
+                remove_nulls(&mut value);
+                toml::to_string_pretty(&value).map_err(|e| e.to_string())
+            },
+            ConfigFormat::Json => serde_json::to_string_pretty(value).map(|s| s + "\n").map_err(|e| e.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(ConfigFormat::detect(dir.path()), ConfigFormat::Yaml);
+
+        std::fs::write(dir.path().join("e2m.json"), "{}").unwrap();
+        assert_eq!(ConfigFormat::detect(dir.path()), ConfigFormat::Json);
+        std::fs::write(dir.path().join("e2m.toml"), "").unwrap();
+        assert_eq!(ConfigFormat::detect(dir.path()), ConfigFormat::Toml);
+
+        assert_eq!(ConfigFormat::from_extension("toml"), Some(ConfigFormat::Toml));
+        assert_eq!(ConfigFormat::from_extension("yml"), None);
+    }
+
+    #[test]
+    fn test_parse_errors_name_the_position() {
+        let e = ConfigFormat::Json.parse::<serde_json::Value>("{\n  \"mqtt\": [\n}").unwrap_err();
+        assert!(e.contains("line 3"), "{e}");
+        let e = ConfigFormat::Toml.parse::<toml::Value>("[mqtt]\nhost = \n").unwrap_err();
+        assert!(e.contains("line 2"), "{e}");
+    }
+}
@@ -4,16 +4,24 @@ use serde::{Deserialize, Serialize};
 use serde_yml;
 #[cfg(feature = "api")]
 use utoipa::ToSchema;
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::fs::{self, File};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::io::prelude::*;
 use std::sync::RwLock;
 
+pub mod backup;
 pub mod defaults;
+pub mod env;
+pub mod format;
+pub mod validate;
+
+use format::ConfigFormat;
 
 fn httpd_enabled_default() -> bool { return true }
 fn httpd_port_default() -> u16 { return 8240 }
+fn httpd_bind_address_default() -> String { "0.0.0.0".to_string() }
 
 #[derive(Deserialize, Serialize, Clone)]
 #[cfg_attr(feature = "api", derive(ToSchema))]
@@ -21,19 +29,60 @@ pub struct HttpdConfig {
     #[serde(default="httpd_enabled_default")]
     pub enabled: bool,
     #[serde(default="httpd_port_default")]
-    pub port: u16
+    pub port: u16,
+    /// Interface to listen on, e.g. 127.0.0.1 behind a reverse proxy
+    #[serde(default="httpd_bind_address_default")]
+    pub bind_address: String,
+    /// Bearer token required for all /api/v1/ requests except the OpenAPI document, no authentication if unset.
+    /// The web UI asks for it once and keeps it in the browser's local storage
+    #[serde(default)]
+    pub auth_token: Option<String>,
+}
+
+impl HttpdConfig {
+    /// Address and port the webserver listens on
+    pub fn socket_addr(&self) -> Result<std::net::SocketAddr, String> {
+        let ip: std::net::IpAddr = self.bind_address.parse()
+            .map_err(|_| format!("httpd bind_address \"{}\" is not an IP address", self.bind_address))?;
+        Ok(std::net::SocketAddr::new(ip, self.port))
+    }
 }
 
 fn mqtt_client_name_default() -> String { return "energy2mqtt".to_string() }
 fn mqtt_client_user_default() -> String { return "energy2mqtt".to_string() }
 fn mqtt_client_pass_default() -> String { return "energy2mqtt".to_string() }
 fn mqtt_discovery_version_default() -> u32 { 1 }
+fn mqtt_topic_prefix_default() -> String { "energy2mqtt".to_string() }
+fn mqtt_discovery_prefix_default() -> String { "homeassistant".to_string() }
+fn mqtt_qos_default() -> u8 { 1 }
+fn mqtt_availability_factor_default() -> f64 { 3.0 }
+fn mqtt_offline_buffer_size_default() -> usize { 1000 }
+fn mqtt_reconnect_delay_default() -> u64 { 1 }
+fn mqtt_reconnect_max_delay_default() -> u64 { 60 }
 
 /// Current discovery format version
 /// Version 1: Flat topic structure (homeassistant/sensor/e2m_proto_device_sensor/config)
 /// Version 2: Hierarchical topics + availability (homeassistant/sensor/e2m_proto_device/sensor/config)
 pub const MQTT_DISCOVERY_VERSION_CURRENT: u32 = 2;
 
+/// TLS settings for a broker connection, all paths point to PEM files
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[cfg_attr(feature = "api", derive(ToSchema))]
+pub struct MqttTlsConfig {
+    #[serde(default)]
+    pub tls_enabled: bool,
+    /// CA used to verify the broker, the system roots are used if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ca_cert_path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_cert: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_key: Option<String>,
+    /// Accept any broker certificate, only meant for self-signed test setups
+    #[serde(default)]
+    pub insecure_skip_verify: bool,
+}
+
 #[derive(Deserialize, Serialize, Clone)]
 #[cfg_attr(feature = "api", derive(ToSchema))]
 pub struct MqttConfig {
@@ -47,6 +96,145 @@ pub struct MqttConfig {
     /// Discovery format version - used to trigger cleanup when format changes
     #[serde(default="mqtt_discovery_version_default")]
     pub discovery_version: u32,
+    /// Prefix of all topics of the bridge (metering, raw, status, mgt, input, set and cmds topics),
+    /// change it to run several instances on one broker
+    #[serde(default="mqtt_topic_prefix_default")]
+    pub topic_prefix: String,
+    /// Discovery prefix as configured in Home Assistant's MQTT integration
+    #[serde(default="mqtt_discovery_prefix_default")]
+    pub discovery_prefix: String,
+    /// Topics of the meter values per protocol (e.g. SML: "home/energy/{name}/state"), "default" applies
+    /// to all other protocols. Supports {prefix}, {proto}, {name}, {tenant} and {id}, unset is {prefix}/devs/{proto}/{name}.
+    /// Only the state topics follow the templates, the availability and error topics of a meter stay
+    /// {prefix}/devs/{proto}/{name}/availability and {prefix}/devs/{proto}/{name}/error without tenant
+    #[serde(default)]
+    pub topic_templates: BTreeMap<String, String>,
+    /// QoS used for metering publishes (0, 1 or 2)
+    #[serde(default="mqtt_qos_default")]
+    pub qos: u8,
+    /// Retain the meter values on the device topics, Home Assistant gets the last values right after a restart
+    #[serde(default)]
+    pub retain: bool,
+    /// QoS and retain of the metering publishes per protocol (e.g. SML: {qos: 0}), "default" applies
+    /// to all other protocols. Unset values use qos and retain above.
+    #[serde(default)]
+    pub publish_overrides: BTreeMap<String, MqttPublishConfig>,
+    /// Metering messages kept while the broker is unreachable, the oldest are dropped first (0 disables)
+    #[serde(default="mqtt_offline_buffer_size_default")]
+    pub offline_buffer_size: usize,
+    /// A meter is reported offline after missing its learned interval by this factor
+    #[serde(default="mqtt_availability_factor_default")]
+    pub availability_factor: f64,
+    /// Seconds to wait before reconnecting after a connection error, doubled with every failed attempt.
+    /// Used for all MQTT connections including the Victron brokers.
+    #[serde(default="mqtt_reconnect_delay_default")]
+    pub reconnect_delay: u64,
+    /// Upper limit of the reconnect delay in seconds
+    #[serde(default="mqtt_reconnect_max_delay_default")]
+    pub reconnect_max_delay: u64,
+    /// Messages per second accepted on an input topic (e.g. oms_input: 20), the rest is dropped
+    #[serde(default)]
+    pub input_rate_limits: BTreeMap<String, u32>,
+    /// Counters per meter id (e.g. modbus-grid: [energy_import]) published as ever increasing totals,
+    /// a counter dropping to less than half of its last value is taken as reset and continued
+    #[serde(default)]
+    pub energy_accumulators: BTreeMap<String, Vec<String>>,
+    /// Counters per meter id (e.g. modbus-grid: [energy_import]) whose consumption in the current
+    /// hour and day is published to the device topic + /hourly and /daily, following message_formats
+    #[serde(default)]
+    pub rollups: BTreeMap<String, Vec<String>>,
+    /// Change-only publishing per meter id (e.g. modbus-grid: {fields: {power: {absolute: 5}}}),
+    /// "default" applies to all other meters
+    #[serde(default)]
+    pub deadbands: BTreeMap<String, MqttDeadbandConfig>,
+    /// Metering messages per meter id or protocol (e.g. SML: flat), "default" applies to all
+    /// other meters. Unset is both, the envelope to {prefix}/raw and the values to the device topic.
+    /// Meters publishing only the envelope are not announced to Home Assistant.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub message_formats: BTreeMap<String, MqttMessageFormat>,
+    #[serde(flatten)]
+    pub tls: MqttTlsConfig,
+    /// Additional brokers every publish is mirrored to, e.g. a cloud broker next to the local one
+    #[serde(default)]
+    pub brokers: Vec<MqttBrokerConfig>,
+}
+
+/// Publish options of the metering data of a protocol
+#[derive(Deserialize, Serialize, Clone, Default, Debug, PartialEq)]
+#[cfg_attr(feature = "api", derive(ToSchema))]
+pub struct MqttPublishConfig {
+    #[serde(default)]
+    pub qos: Option<u8>,
+    #[serde(default)]
+    pub retain: Option<bool>,
+}
+
+/// Messages published for a reading
+#[derive(Deserialize, Serialize, Clone, Copy, Default, Debug, PartialEq)]
+#[cfg_attr(feature = "api", derive(ToSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum MqttMessageFormat {
+    /// Only the values as flat map to the device topic
+    Flat,
+    /// Only the whole reading including its metadata to {prefix}/raw
+    Envelope,
+    #[default]
+    Both,
+}
+
+impl MqttMessageFormat {
+    pub fn publishes_flat(&self) -> bool {
+        matches!(self, MqttMessageFormat::Flat | MqttMessageFormat::Both)
+    }
+
+    pub fn publishes_envelope(&self) -> bool {
+        matches!(self, MqttMessageFormat::Envelope | MqttMessageFormat::Both)
+    }
+}
+
+/// Minimal change of a value before it is published again, either limit is enough
+#[derive(Deserialize, Serialize, Clone, Default, Debug, PartialEq)]
+#[cfg_attr(feature = "api", derive(ToSchema))]
+pub struct MqttDeadband {
+    #[serde(default)]
+    pub absolute: Option<f64>,
+    /// Percent of the value published last
+    #[serde(default)]
+    pub percent: Option<f64>,
+}
+
+fn mqtt_deadband_max_interval_default() -> u64 { 300 }
+
+/// Change-only publishing of a meter, fields without deadband are published on every change
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "api", derive(ToSchema))]
+pub struct MqttDeadbandConfig {
+    #[serde(default)]
+    pub fields: BTreeMap<String, MqttDeadband>,
+    /// Seconds after which the values are published even without change (0 disables)
+    #[serde(default="mqtt_deadband_max_interval_default")]
+    pub max_interval: u64,
+}
+
+/// An additional broker, the main broker above is always used for subscriptions
+#[derive(Deserialize, Serialize, Clone)]
+#[cfg_attr(feature = "api", derive(ToSchema))]
+pub struct MqttBrokerConfig {
+    /// Name used in logs and the health status
+    pub name: String,
+    pub host: String,
+    pub port: u16,
+    #[serde(default)]
+    pub user: String,
+    #[serde(default)]
+    pub pass: String,
+    #[serde(default="mqtt_client_name_default")]
+    pub client_name: String,
+    /// Subscribe to command and input topics (OMS, SML, ...) on this broker as well
+    #[serde(default)]
+    pub input: bool,
+    #[serde(flatten)]
+    pub tls: MqttTlsConfig,
 }
 
 fn db_dbtype_default() -> String {return "sqlite".to_string() }
@@ -62,6 +250,7 @@ pub struct DatabaseConfig {
 }
 
 fn discovered_devices_path_default() -> String { "discovered_devices.yaml".to_string() }
+fn definitions_path_default() -> String { "".to_string() }
 
 #[derive(Deserialize, Serialize, Clone)]
 #[cfg_attr(feature = "api", derive(ToSchema))]
@@ -69,14 +258,28 @@ pub struct StorageConfig {
     /// Path to the discovered devices file
     #[serde(default="discovered_devices_path_default")]
     pub discovered_devices_path: String,
+    /// Directory with additional meter definitions in its modbus/ and sml/ subdirectories,
+    /// relative to the config directory like the discovered devices file
+    #[serde(default="definitions_path_default")]
+    pub definitions_path: String,
+    /// Backups of e2m.yaml kept in the backups/ directory next to it, 0 disables them
+    #[serde(default="config_backups_default")]
+    pub config_backups: usize,
 }
 
+fn config_backups_default() -> usize { 10 }
+
 fn storage_default() -> StorageConfig {
     StorageConfig {
         discovered_devices_path: discovered_devices_path_default(),
+        definitions_path: definitions_path_default(),
+        config_backups: config_backups_default(),
     }
 }
 
+fn modbus_device_batch_reads_default() -> bool { true }
+fn modbus_enabled_default() -> bool { true }
+
 #[derive(Deserialize, Serialize, Clone)]
 #[cfg_attr(feature = "api", derive(ToSchema))]
 pub struct ModbusDeviceConfig {
@@ -84,7 +287,35 @@ pub struct ModbusDeviceConfig {
     pub meter: String,
     pub slave_id: u8,
     pub read_interval: u32,
+    /// Cron expression (e.g. "* * * * *" for every full minute, "0 6 * * 1-5" at 6:00 on
+    /// workdays with 0 and 7 as Sunday) to read on wall-clock boundaries, read_interval is used
+    /// if not set. Only Modbus devices can be read on a schedule.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cron: Option<String>,
     pub defaults: Option<Vec<String>>, /* Name of a default configuration */
+    /// Tenant the readings belong to (e.g. an apartment), part of the topic and the HA area
+    #[serde(default)]
+    pub tenant: Option<String>,
+    /// Read contiguous registers with a single request, disable for devices which refuse that
+    #[serde(default="modbus_device_batch_reads_default")]
+    pub batch_reads: bool,
+    /// Pause between two read requests for slow (e.g. RS-485) devices, 0 reads back-to-back
+    #[serde(default)]
+    pub inter_register_delay_ms: u64,
+    /// Time a single request may take until the complete response arrived, overrides the hub
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_timeout_ms: Option<u64>,
+    /// Id used for the metering data instead of the name, keeps the id when renaming the device
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unique_id: Option<String>,
+    /// Numbering of the register addresses in the meter definition: 0 for addresses as sent on
+    /// the wire (protocol addresses), 1 for datasheets counting from 1 (register 1 is sent as 0).
+    /// Set to 1 if all values look shifted by one register.
+    #[serde(default)]
+    pub address_base: u8,
+    /// Disabled devices are not read but kept in the config
+    #[serde(default="modbus_enabled_default")]
+    pub enabled: bool,
 }
 
 #[derive(Deserialize, Serialize, Clone, PartialEq)]
@@ -98,6 +329,7 @@ pub enum ModbusProtoConfig {
 fn modbus_hubs_devices_default() -> Vec<ModbusDeviceConfig> { return Vec::new() }
 fn modbus_hub_connection_timeout_default() -> u64 { 10 }
 fn modbus_hub_read_timeout_default() -> u64 { 5 }
+fn modbus_hub_max_parallel_reads_default() -> u32 { 1 }
 
 #[derive(Deserialize, Serialize, Clone)]
 #[cfg_attr(feature = "api", derive(ToSchema))]
@@ -109,8 +341,29 @@ pub struct ModbusHubConfig
     pub proto: ModbusProtoConfig,
     #[serde(default="modbus_hub_connection_timeout_default")]
     pub connection_timeout: u64,  // Connection timeout in seconds
+    /// Idle seconds until TCP keepalive probes are sent, a dead gateway is noticed before the
+    /// next read then. The OS default (usually off) is kept if not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tcp_keepalive_secs: Option<u64>,
+    /// Seconds between unanswered keepalive probes, tcp_keepalive_secs if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tcp_keepalive_interval_secs: Option<u64>,
     #[serde(default="modbus_hub_read_timeout_default")]
     pub read_timeout: u64,        // Read/write timeout in seconds
+    /// Time a single request may take until the complete response arrived, read_timeout if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_timeout_ms: Option<u64>,
+    /// Devices of a TCP hub read at the same time, each on its own persistent connection, so the
+    /// gateway has to accept that many clients. RTU and RTU over TCP always read one device after another.
+    #[serde(default="modbus_hub_max_parallel_reads_default")]
+    pub max_parallel_reads: u32,
+    /// Delay of up to this many seconds for the first reads of the hub and its devices, taken from
+    /// their names, spreads the reads of many devices over their interval. 0 reads all of them on the same tick.
+    #[serde(default)]
+    pub max_read_jitter: u32,
+    /// Disabled hubs and all their devices are not read but kept in the config
+    #[serde(default="modbus_enabled_default")]
+    pub enabled: bool,
     #[serde(default="modbus_hubs_devices_default")]
     pub devices: Vec<ModbusDeviceConfig>
 }
@@ -148,6 +401,133 @@ pub struct Callbacks {
 pub struct TibberConfig {
     pub name: String,
     pub account_token: String,
+    /// Seconds between two API requests, Tibber asks for at least a minute
+    #[serde(default = "tibber_poll_interval_default")]
+    pub poll_interval: u64,
+    /// Tenant the readings belong to (e.g. an apartment), part of the topic and the HA area
+    #[serde(default)]
+    pub tenant: Option<String>,
+}
+
+fn tibber_poll_interval_default() -> u64 { 300 }
+
+/// Value taken from the JSON payload of a generic MQTT input
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "api", derive(ToSchema))]
+pub struct MqttInputField {
+    /// Key of the value in the published metering data
+    pub name: String,
+    /// JSON pointer (/ENERGY/Power) or simple JSONPath ($.ENERGY.Power, $.values[0])
+    pub path: String,
+    #[serde(default)]
+    pub unit: Option<String>,
+    /// Home Assistant device class, e.g. power or energy
+    #[serde(default)]
+    pub device_class: Option<String>,
+    /// Home Assistant state class, measurement if unset
+    #[serde(default)]
+    pub state_class: Option<String>,
+    /// Numeric values are multiplied by this factor
+    #[serde(default)]
+    pub factor: Option<f64>,
+}
+
+impl MqttInputField {
+    /// The path as JSON pointer, None if it is neither a pointer nor a supported JSONPath
+    pub fn pointer(&self) -> Option<String> {
+        if self.path.starts_with('/') {
+            return Some(self.path.clone());
+        }
+
+        let rest = self.path.strip_prefix('$')?;
+        let mut pointer = String::new();
+        for part in rest.split('.').skip(1) {
+            /* Array indices like values[0][1] */
+            let (key, indices) = part.split_once('[').map_or((part, ""), |(k, i)| (k, i));
+            if !key.is_empty() {
+                pointer.push('/');
+                pointer.push_str(&key.replace('~', "~0").replace('/', "~1"));
+            }
+            if indices.is_empty() {
+                if key.is_empty() {
+                    return None;
+                }
+                continue;
+            }
+            for index in indices.split('[') {
+                let index = index.strip_suffix(']')?;
+                if index.parse::<usize>().is_err() {
+                    return None;
+                }
+                pointer.push('/');
+                pointer.push_str(index);
+            }
+        }
+
+        match rest.is_empty() || rest.starts_with('.') {
+            true => Some(pointer),
+            false => None,
+        }
+    }
+}
+
+fn mqtt_input_protocol_default() -> String { "mqtt_input".to_string() }
+
+/// Meter fed by JSON another device publishes to an MQTT topic of the broker
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "api", derive(ToSchema))]
+pub struct MqttInputConfig {
+    pub name: String,
+    /// Topic to subscribe to, used as given without the energy2mqtt prefix
+    pub topic: String,
+    /// Protocol label used for the state topic and the Home Assistant discovery
+    #[serde(default = "mqtt_input_protocol_default")]
+    pub protocol: String,
+    #[serde(default)]
+    pub manufacturer: Option<String>,
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default)]
+    pub fields: Vec<MqttInputField>,
+    /// Tenant the readings belong to (e.g. an apartment), part of the topic and the HA area
+    #[serde(default)]
+    pub tenant: Option<String>,
+}
+
+fn oms_deduplicate_default() -> bool { true }
+
+/// Replacement of a decoded OMS record for meters not following the VIF table
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "api", derive(ToSchema))]
+pub struct OmsVifOverride {
+    /// VIF bytes of the record as hex, including the FB/FD extension (e.g. FF or FD3A)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vif: Option<String>,
+    /// Offset of the record's VIF in the decrypted payload, the position in unknown_at_<position>_<vif> names
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub position: Option<usize>,
+    /// Field name used instead of the decoded one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
+    /// Factor applied to the raw value instead of the one of the VIF
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scaler: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unit: Option<String>,
+}
+
+impl OmsVifOverride {
+    /// True if the override applies to the record at position with the given VIF bytes,
+    /// overrides with neither vif nor position never do
+    pub fn matches(&self, position: usize, vif: &[u8]) -> bool {
+        if self.vif.is_none() && self.position.is_none() {
+            return false;
+        }
+
+        let vif_hex: String = vif.iter().map(|b| format!("{b:02X}")).collect();
+        let vif_matches = self.vif.as_ref().is_none_or(|v| v.replace(' ', "").eq_ignore_ascii_case(&vif_hex));
+        vif_matches && self.position.is_none_or(|p| p == position)
+    }
 }
 
 #[derive(Deserialize, Serialize, Clone)]
@@ -156,6 +536,80 @@ pub struct OmsConfig {
     pub name: String,
     pub id: String,
     pub key: String,
+    /// Tenant the readings belong to (e.g. an apartment), part of the topic and the HA area
+    #[serde(default)]
+    pub tenant: Option<String>,
+    /// Drop telegrams heard by several receivers, disable to publish every reception
+    #[serde(default="oms_deduplicate_default")]
+    pub deduplicate: bool,
+    /// Also publish the decrypted payload and the telegram details under proto, for debugging
+    #[serde(default)]
+    pub include_raw: bool,
+    /// Expressions per field evaluated on the decoded values, e.g. volume_l: "volume * 1000"
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub transforms: BTreeMap<String, String>,
+    /// Corrections of records decoded wrongly or as unknown_at_*, first match wins
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub vif_overrides: Vec<OmsVifOverride>,
+}
+
+/// Settings of a single SML meter, meters without entry are published as decoded
+#[derive(Deserialize, Serialize, Clone)]
+#[cfg_attr(feature = "api", derive(ToSchema))]
+pub struct SmlMeterConfig {
+    /// Server id as hex string like in the topic (e.g. 0a01454d480000abcdef)
+    pub server_id: String,
+    /// Name shown in Home Assistant and used in the topics instead of SML-<server id>
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Tenant the readings belong to (e.g. an apartment), part of the topic and the HA area
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    /// Publish known OBIS codes under their field name (e.g. total_energy_consumed) instead of the OBIS code
+    #[serde(default)]
+    pub field_names: bool,
+    /// With field_names, also publish the OBIS codes without known meaning, for debugging
+    #[serde(default)]
+    pub include_raw: bool,
+    /// Expressions per field evaluated on the decoded values, e.g. energy_kwh: "energy_import / 1000"
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub transforms: BTreeMap<String, String>,
+    /// Only these OBIS codes (e.g. 1-0:1.8.0) are published, all if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub obis_allowlist: Vec<String>,
+    /// OBIS codes never published
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub obis_blocklist: Vec<String>,
+}
+
+impl SmlMeterConfig {
+    /// True if values of the OBIS code are published, the lists may contain codes with or without .255
+    pub fn obis_allowed(&self, code: &str) -> bool {
+        let short = |c: &str| c.strip_suffix(".255").unwrap_or(c).to_string();
+        let code = short(code);
+        let listed = |list: &Vec<String>| list.iter().any(|c| short(c) == code);
+
+        (self.obis_allowlist.is_empty() || listed(&self.obis_allowlist)) && !listed(&self.obis_blocklist)
+    }
+}
+
+/// IEC 62056-21 meter pushing data blocks without identification line (Mode D)
+#[derive(Deserialize, Serialize, Clone)]
+#[cfg_attr(feature = "api", derive(ToSchema))]
+pub struct Iec62056MeterConfig {
+    pub name: String,
+    /// Serial number as sent in 0-0:C.1.0, 0-0:96.1.0 or 1-0:0.0.0, needed with several meters
+    #[serde(default)]
+    pub serial: Option<String>,
+    /// FLAG manufacturer code (e.g. ESY)
+    #[serde(default)]
+    pub manufacturer: Option<String>,
+    /// Tenant the readings belong to (e.g. an apartment), part of the topic and the HA area
+    #[serde(default)]
+    pub tenant: Option<String>,
+    /// Publish known OBIS codes under the field name of their medium (e.g. gas_volume) instead of the OBIS code
+    #[serde(default)]
+    pub field_names: bool,
 }
 
 /// Configuration for a single Victron cluster
@@ -214,7 +668,7 @@ pub struct VictronClustersConfig {
     #[serde(default = "victron_cluster_phase_default")]
     pub phase_details: VictronClusterConfig,
 
-    /// Temperature and tank sensors
+    /// Tank, temperature and digital input sensors
     #[serde(default = "victron_cluster_env_default")]
     pub environment: VictronClusterConfig,
 
@@ -273,17 +727,33 @@ pub struct VictronConfig {
     pub broker_host: String,
     #[serde(default = "victron_broker_port_default")]
     pub broker_port: u16,
+    /// Seconds between two read cycles, also the interval the readings are published in
     #[serde(default = "victron_update_interval_default")]
     pub update_interval: u64,
+    /// Delay between two read requests sent to the GX device in milliseconds.
+    /// Lower values refresh large installations faster but put more load on the GX,
+    /// all read requests of one cycle are squeezed into update_interval if needed.
+    #[serde(default = "victron_read_delay_ms_default")]
+    pub read_delay_ms: u64,
+    #[serde(flatten)]
+    pub tls: MqttTlsConfig,
     #[serde(default = "victron_enabled_default")]
     pub enabled: bool,
     /// Export clusters - control which data is exported to Home Assistant
     #[serde(default = "victron_clusters_default")]
     pub clusters: VictronClustersConfig,
+    /// Tenant the readings belong to (e.g. an apartment), part of the topic and the HA area
+    #[serde(default)]
+    pub tenant: Option<String>,
+    /// Factor per field (key as published, e.g. grid_l1_energy_forward) the value is multiplied with,
+    /// e.g. 1000 to publish kWh as Wh
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub scale: BTreeMap<String, f64>,
 }
 
 fn victron_broker_port_default() -> u16 { 1883 }
 fn victron_update_interval_default() -> u64 { 10 }
+fn victron_read_delay_ms_default() -> u64 { 100 }
 fn victron_enabled_default() -> bool { true }
 
 #[derive(Deserialize, Serialize, Clone, PartialEq, Debug)]
@@ -380,6 +850,9 @@ pub struct KnxMeterConfig {
     pub switch_ga: Option<String>,
     /// Global switch state feedback address
     pub switch_state_ga: Option<String>,
+    /// Tenant the readings belong to (e.g. an apartment), part of the topic and the HA area
+    #[serde(default)]
+    pub tenant: Option<String>,
 }
 
 fn knx_switch_enabled_default() -> bool { true }
@@ -473,14 +946,100 @@ pub struct ZennerDatahubConfig {
     pub base_topic: String,
 }
 
-fn httpd_default() -> HttpdConfig { return  HttpdConfig{ enabled: httpd_enabled_default(), port: httpd_port_default() }}
+#[derive(Deserialize, Serialize, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "api", derive(ToSchema))]
+pub enum FileExportFormat {
+    JsonLines,
+    Csv,
+}
+
+fn file_export_path_default() -> String { "config/metering.jsonl".to_string() }
+fn file_export_format_default() -> FileExportFormat { FileExportFormat::JsonLines }
+fn file_export_columns_default() -> Vec<String> {
+    ["metered_time", "protocol", "meter_name"].iter().map(|c| c.to_string()).collect()
+}
+fn file_export_max_size_default() -> u64 { 10 * 1024 * 1024 }
+fn file_export_max_files_default() -> u32 { 5 }
+
+/// Local export of all metering data independent of MQTT
+#[derive(Deserialize, Serialize, Clone)]
+#[cfg_attr(feature = "api", derive(ToSchema))]
+pub struct FileExportConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default="file_export_path_default")]
+    pub path: String,
+    #[serde(default="file_export_format_default")]
+    pub format: FileExportFormat,
+    /// CSV columns, either a field of the metering data (id, meter_name, tenant, protocol,
+    /// metered_time, transmission_time) or the name of a metered value
+    #[serde(default="file_export_columns_default")]
+    pub columns: Vec<String>,
+    /// The file is rotated once it would grow beyond this size in bytes
+    #[serde(default="file_export_max_size_default")]
+    pub max_size: u64,
+    /// Number of rotated files kept next to the current one (path.1, path.2, ...)
+    #[serde(default="file_export_max_files_default")]
+    pub max_files: u32,
+}
+
+fn file_export_default() -> FileExportConfig {
+    FileExportConfig {
+        enabled: false,
+        path: file_export_path_default(),
+        format: file_export_format_default(),
+        columns: file_export_columns_default(),
+        max_size: file_export_max_size_default(),
+        max_files: file_export_max_files_default(),
+    }
+}
+
+fn logging_path_default() -> String { "config/e2m.log".to_string() }
+fn logging_level_default() -> String { "info".to_string() }
+fn logging_max_size_default() -> u64 { 10 * 1024 * 1024 }
+fn logging_max_files_default() -> usize { 5 }
+
+/// Logging to a file in addition to stderr, applied on startup
+#[derive(Deserialize, Serialize, Clone)]
+#[cfg_attr(feature = "api", derive(ToSchema))]
+pub struct LoggingConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Base name of the log files, e2m.log is written as e2m_rCURRENT.log and rotated to e2m_r00000.log, ...
+    #[serde(default="logging_path_default")]
+    pub path: String,
+    /// Level of the file log (error, warn, info, debug, trace), stderr keeps using E2M_LOG_LEVEL
+    #[serde(default="logging_level_default")]
+    pub level: String,
+    /// The file is rotated once it grows beyond this size in bytes
+    #[serde(default="logging_max_size_default")]
+    pub max_size: u64,
+    /// Number of rotated files kept next to the current one
+    #[serde(default="logging_max_files_default")]
+    pub max_files: usize,
+}
+
+fn logging_default() -> LoggingConfig {
+    LoggingConfig {
+        enabled: false,
+        path: logging_path_default(),
+        level: logging_level_default(),
+        max_size: logging_max_size_default(),
+        max_files: logging_max_files_default(),
+    }
+}
+
+fn httpd_default() -> HttpdConfig { return  HttpdConfig{ enabled: httpd_enabled_default(), port: httpd_port_default(), bind_address: httpd_bind_address_default(), auth_token: None }}
 fn db_default() -> DatabaseConfig { return DatabaseConfig { dbtype: db_dbtype_default(), uri: db_uri_default() }}
 fn modbus_default() -> ModbusConfig { return ModbusConfig { hubs: Vec::new() }}
 fn tibber_default() -> Vec<TibberConfig> { return Vec::new(); }
 fn oms_default() -> Vec<OmsConfig> { return Vec::new(); }
+fn iec62056_default() -> Vec<Iec62056MeterConfig> { return Vec::new(); }
+fn sml_default() -> Vec<SmlMeterConfig> { return Vec::new(); }
 fn victron_default() -> Vec<VictronConfig> { return Vec::new(); }
 fn knx_default() -> Vec<KnxAdapterConfig> { return Vec::new(); }
 fn zridh_default() -> Vec<ZennerDatahubConfig> { return Vec::new(); }
+fn mqtt_input_default() -> Vec<MqttInputConfig> { return Vec::new(); }
 
 #[derive(Deserialize, Serialize, Clone)]
 #[cfg_attr(feature = "api", derive(ToSchema))]
@@ -498,12 +1057,22 @@ pub struct Config {
     pub tibber: Vec<TibberConfig>,
     #[serde(default="oms_default")]
     pub oms: Vec<OmsConfig>,
+    #[serde(default="iec62056_default")]
+    pub iec62056: Vec<Iec62056MeterConfig>,
+    #[serde(default="sml_default")]
+    pub sml: Vec<SmlMeterConfig>,
     #[serde(default="victron_default")]
     pub victron: Vec<VictronConfig>,
     #[serde(default="knx_default")]
     pub knx: Vec<KnxAdapterConfig>,
     #[serde(default="zridh_default")]
     pub zenner_datahub: Vec<ZennerDatahubConfig>,
+    #[serde(default="mqtt_input_default")]
+    pub mqtt_input: Vec<MqttInputConfig>,
+    #[serde(default="file_export_default")]
+    pub file_export: FileExportConfig,
+    #[serde(default="logging_default")]
+    pub logging: LoggingConfig,
 }
 
 pub struct ConfigHolder {
@@ -512,17 +1081,51 @@ pub struct ConfigHolder {
     pub dirty: bool,
     pub lock: RwLock<bool>,
     pub base_path: String,
+    /// Values taken from ${ENV_VAR} placeholders, written back as placeholders on save
+    pub env_secrets: Vec<env::EnvSecret>,
+    /// Format of the config file, saves keep it
+    pub format: ConfigFormat,
+    /// Sections ignored because of validation errors, saves write them back unchanged
+    pub invalid_sections: BTreeMap<String, serde_yml::Value>,
 }
 
+/* Only short lived copies of single sections, boxing would just complicate every match */
+#[allow(clippy::large_enum_variant)]
 pub enum ConfigBases {
     Httpd(HttpdConfig),
     Mqtt(MqttConfig),
     Modbus(ModbusConfig),
     Tibber(Vec<TibberConfig>),
     Oms(Vec<OmsConfig>),
+    Iec62056(Vec<Iec62056MeterConfig>),
     Victron(Vec<VictronConfig>),
     Knx(Vec<KnxAdapterConfig>),
     ZRIDH(Vec<ZennerDatahubConfig>),
+    MqttInput(Vec<MqttInputConfig>),
+    Database(DatabaseConfig),
+    FileExport(FileExportConfig),
+}
+
+/// Parse the config file and resolve ${ENV_VAR} placeholders in its strings.
+/// The text is parsed directly first so errors still point at their line.
+fn parse_config(contents: &str, format: ConfigFormat) -> Result<(Config, Vec<env::EnvSecret>), String> {
+    let config = format.parse::<Config>(contents)?;
+    let mut value = format.parse_value(contents)?;
+    let env_secrets = env::resolve_env(&mut value);
+    if env_secrets.is_empty() {
+        return Ok((config, env_secrets));
+    }
+    Ok((serde_yml::from_value(value).map_err(|e| e.to_string())?, env_secrets))
+}
+
+/// Describe a YAML error, serde_yml already names the position so only the offending line is added
+fn parse_error_message(contents: &str, e: &serde_yml::Error) -> String {
+    let line = e.location()
+        .and_then(|location| contents.lines().nth(location.line().saturating_sub(1)));
+    match line {
+        Some(line) => format!("{}: `{}`", e, line.trim_end()),
+        None => e.to_string(),
+    }
 }
 
 /// Status of the configuration
@@ -534,19 +1137,50 @@ pub enum ConfigStatus {
 }
 
 impl ConfigHolder {
+    /// Directory of the user provided meter definitions
+    pub fn get_definitions_path(&self) -> PathBuf {
+        Path::new(&self.base_path).join(&self.config.storage.definitions_path)
+    }
+
+    /// Directory holding the config file, tests use their own so they never touch config/
+    fn default_base_path() -> String {
+        if cfg!(test) {
+            format!("{}/", std::env::temp_dir().join("e2m-test").display())
+        } else {
+            "config/".to_string()
+        }
+    }
+
     /// Try to load config, returning status and optional holder
     pub fn try_load() -> (ConfigStatus, Option<Self>) {
-        let bpath = "config/".to_string();
+        let bpath = Self::default_base_path();
 
-        // Load config from config/e2m.yaml only
-        let file = match File::open("config/e2m.yaml") {
+        // Load config from config/e2m.yaml, e2m.toml or e2m.json
+        let format = ConfigFormat::detect(Path::new(&bpath));
+        let path = Path::new(&bpath).join(format.file_name());
+        let file = match File::open(&path) {
             Ok(f) => {
-                info!("Config file config/e2m.yaml loaded");
+                info!("Config file {} loaded", path.display());
                 f
             },
-            Err(_) => {
-                info!("No config file found at config/e2m.yaml");
-                return (ConfigStatus::Missing, None);
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                /* First start, write a template the setup wizard or the user can fill in */
+                info!("No config file found at {}, creating a template", path.display());
+                if let Err(e) = Self::create_initial_config(Self::template_mqtt_config(), &bpath) {
+                    error!("Config template could not be written: {}", e);
+                    return (ConfigStatus::Missing, None);
+                }
+                match File::open(&path) {
+                    Ok(f) => f,
+                    Err(e) => {
+                        error!("Config template could not be opened: {}", e);
+                        return (ConfigStatus::Missing, None);
+                    }
+                }
+            },
+            Err(e) => {
+                error!("Config file {} could not be opened: {}", path.display(), e);
+                return (ConfigStatus::Invalid(format!("Unable to open config file: {}", e)), None);
             }
         };
 
@@ -557,8 +1191,13 @@ impl ConfigHolder {
             return (ConfigStatus::Invalid(format!("Unable to read config file: {}", e)), None);
         }
 
-        match serde_yml::from_str::<Config>(&contents) {
-            Ok(c) => {
+        match parse_config(&contents, format) {
+            Ok((mut c, env_secrets)) => {
+                let (errors, invalid_sections) = c.remove_invalid_sections();
+                for e in errors {
+                    error!("Config error, ignoring section {}: {}", e.section, e);
+                }
+
                 let (s, _) = tokio::sync::broadcast::channel(100);
                 (ConfigStatus::Valid, Some(ConfigHolder {
                     config: c,
@@ -566,19 +1205,57 @@ impl ConfigHolder {
                     dirty: false,
                     lock: RwLock::new(true),
                     base_path: bpath,
+                    env_secrets,
+                    format,
+                    invalid_sections,
                 }))
             },
-            Err(e) => {
-                error!("Config could not be parsed: {}", e);
-                (ConfigStatus::Invalid(format!("Unable to parse config file: {}", e)), None)
+            Err(message) => {
+                error!("Config could not be parsed: {}", message);
+                (ConfigStatus::Invalid(format!("Unable to parse config file: {}", message)), None)
             }
         }
     }
 
+    /// MQTT settings of a freshly created config, the empty host keeps the setup wizard active
+    pub fn template_mqtt_config() -> MqttConfig {
+        MqttConfig {
+            host: "".to_string(),
+            port: 1883,
+            user: "".to_string(),
+            pass: "".to_string(),
+            ha_enabled: true,
+            client_name: mqtt_client_name_default(),
+            discovery_version: MQTT_DISCOVERY_VERSION_CURRENT,
+            topic_prefix: mqtt_topic_prefix_default(),
+            discovery_prefix: mqtt_discovery_prefix_default(),
+            topic_templates: BTreeMap::new(),
+            qos: mqtt_qos_default(),
+            retain: false,
+            publish_overrides: BTreeMap::new(),
+            offline_buffer_size: mqtt_offline_buffer_size_default(),
+            availability_factor: mqtt_availability_factor_default(),
+            reconnect_delay: mqtt_reconnect_delay_default(),
+            reconnect_max_delay: mqtt_reconnect_max_delay_default(),
+            input_rate_limits: BTreeMap::new(),
+            energy_accumulators: BTreeMap::new(),
+            rollups: BTreeMap::new(),
+            deadbands: BTreeMap::new(),
+            message_formats: BTreeMap::new(),
+            tls: MqttTlsConfig::default(),
+            brokers: Vec::new(),
+        }
+    }
+
     pub fn load() -> Self {
         let (_status, holder) = Self::try_load();
         match holder {
-            Some(h) => h,
+            Some(h) => {
+                if !h.is_configured() && !cfg!(feature = "api") {
+                    panic!("No MQTT broker configured AND api is disabled, please set mqtt.host in {}{}", h.base_path, h.format.file_name())
+                }
+                h
+            },
             None => {
                 if cfg!(feature = "api") {
                     info!("Loading the config failed, using default config");
@@ -591,23 +1268,20 @@ impl ConfigHolder {
                 info!("Creating default config holder for setup wizard");
                 let default_config = Config {
                     httpd: httpd_default(),
-                    mqtt: MqttConfig {
-                        host: "".to_string(),
-                        port: 1883,
-                        user: "".to_string(),
-                        pass: "".to_string(),
-                        ha_enabled: true,
-                        client_name: mqtt_client_name_default(),
-                        discovery_version: MQTT_DISCOVERY_VERSION_CURRENT,
-                    },
+                    mqtt: Self::template_mqtt_config(),
                     db: db_default(),
                     storage: storage_default(),
                     modbus: modbus_default(),
                     tibber: tibber_default(),
                     oms: oms_default(),
+                    iec62056: iec62056_default(),
+                    sml: sml_default(),
                     victron: victron_default(),
                     knx: knx_default(),
                     zenner_datahub: zridh_default(),
+                    mqtt_input: mqtt_input_default(),
+                    file_export: file_export_default(),
+                    logging: logging_default(),
                 };
                 let (s, _) = tokio::sync::broadcast::channel(100);
                 ConfigHolder {
@@ -615,7 +1289,10 @@ impl ConfigHolder {
                     callbacks: Callbacks { sender: s },
                     dirty: false,
                     lock: RwLock::new(true),
-                    base_path: "config/".to_string(),
+                    base_path: Self::default_base_path(),
+                    env_secrets: Vec::new(),
+                    format: ConfigFormat::Yaml,
+                    invalid_sections: BTreeMap::new(),
                 }
             }
         }
@@ -643,22 +1320,31 @@ impl ConfigHolder {
             modbus: modbus_default(),
             tibber: tibber_default(),
             oms: oms_default(),
+            iec62056: iec62056_default(),
+            sml: sml_default(),
             victron: victron_default(),
             knx: knx_default(),
             zenner_datahub: zridh_default(),
+            mqtt_input: mqtt_input_default(),
+            file_export: file_export_default(),
+            logging: logging_default(),
         };
 
-        let yaml = serde_yml::to_string(&config)
-            .map_err(|e| format!("Failed to serialize config: {}", e))?;
-
         // Ensure config directory exists
         let dir_path = base_path.trim_end_matches('/');
         fs::create_dir_all(dir_path)
             .map_err(|e| format!("Failed to create config directory: {}", e))?;
 
-        let config_path = format!("{}/e2m.yaml", dir_path);
+        /* An existing file keeps its format */
+        let format = ConfigFormat::detect(Path::new(dir_path));
+        let text = serde_yml::to_value(&config)
+            .map_err(|e| e.to_string())
+            .and_then(|value| format.serialize(&value))
+            .map_err(|e| format!("Failed to serialize config: {}", e))?;
+
+        let config_path = format!("{}/{}", dir_path, format.file_name());
 
-        fs::write(&config_path, yaml.as_bytes())
+        fs::write(&config_path, text.as_bytes())
             .map_err(|e| format!("Failed to write config file: {}", e))?;
 
         info!("Initial config created at {}", config_path);
@@ -673,14 +1359,13 @@ impl ConfigHolder {
         }
 
         let base = Path::new(&self.base_path);
-        let config_path = base.join("e2m.yaml");
-        let backup_path = base.join("backup.yaml");
+        let config_path = base.join(self.format.file_name());
 
-        match fs::copy(&config_path, &backup_path) {
-            Ok(_) => {}
-            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
-                // First save, no existing config to backup - proceed anyway
-                debug!("No existing config to backup, proceeding with first save");
+        match backup::create_backup(base, self.format, self.config.storage.config_backups) {
+            Ok(Some(name)) => { debug!("Previous config saved as {name}"); }
+            Ok(None) => {
+                // First save or backups disabled - proceed anyway
+                debug!("No config backup written");
             }
             Err(e) => {
                 error!("Backing up config failed: {e}, not replacing it");
@@ -688,7 +1373,22 @@ impl ConfigHolder {
             }
         }
 
-        let x = serde_yml::to_string(&self.config).unwrap();
+        /* Secrets from the environment never end up in the file */
+        let mut value = serde_yml::to_value(&self.config).unwrap();
+        /* Sections disabled because of errors keep what the user wrote */
+        if let serde_yml::Value::Mapping(map) = &mut value {
+            for (key, original) in self.invalid_sections.iter() {
+                map.insert(serde_yml::Value::from(key.as_str()), original.clone());
+            }
+        }
+        env::restore_templates(&mut value, &self.env_secrets);
+        let x = match self.format.serialize(&value) {
+            Ok(x) => x,
+            Err(e) => {
+                error!("Config could not be written as {}: {e}", self.format.extension());
+                return;
+            }
+        };
         match fs::write(&config_path, x.as_bytes()) {
             Ok(_) => { info!("New Config written"); self.dirty = false; }
             Err(e) => { error!("Error writing config {e:?}"); }
@@ -729,6 +1429,10 @@ impl ConfigHolder {
                 self.config.oms = oms_configs;
                 base = "oms";
             },
+            ConfigBases::Iec62056(iec62056_configs) => {
+                self.config.iec62056 = iec62056_configs;
+                base = "iec62056";
+            },
             ConfigBases::Victron(victron_configs) => {
                 self.config.victron = victron_configs;
                 base = "victron";
@@ -741,13 +1445,96 @@ impl ConfigHolder {
                 self.config.zenner_datahub = zridh_config;
                 base = "zridh";
             }
+            ConfigBases::MqttInput(input_config) => {
+                self.config.mqtt_input = input_config;
+                base = "mqtt_input";
+            }
+            ConfigBases::Database(db_config) => {
+                self.config.db = db_config;
+                base = "db";
+            }
+            ConfigBases::FileExport(export_config) => {
+                self.config.file_export = export_config;
+                base = "export";
+            }
         }
 
         self.dirty = true;
+        /* A replaced section is valid again and saved from memory */
+        self.invalid_sections.remove(validate::section_key(base));
 
         let _ = self.callbacks.sender.send(ConfigChange { operation: operation, base: base.to_string()});
     }
 
+    /// Re-read the config file from disk and notify every base whose section changed
+    /// Returns the names of the changed bases, unsaved changes are an error unless they are discarded
+    pub fn reload(&mut self, discard_changes: bool) -> Result<Vec<String>, String> {
+        if self.dirty && !discard_changes {
+            return Err("The config has unsaved changes, save or discard them first".to_string());
+        }
+        let config_path = Path::new(&self.base_path).join(self.format.file_name());
+        let contents = fs::read_to_string(&config_path)
+            .map_err(|e| format!("Unable to read config file: {e}"))?;
+        let (new_config, env_secrets) = parse_config(&contents, self.format)
+            .map_err(|e| format!("Unable to parse config file: {e}"))?;
+
+        let errors = new_config.validate();
+        if !errors.is_empty() {
+            let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+            return Err(format!("Config is invalid: {}", messages.join(", ")));
+        }
+
+        let changed = changed_bases(&self.config, &new_config);
+
+        self.config = new_config;
+        self.env_secrets = env_secrets;
+        self.invalid_sections.clear();
+        /* Memory now matches the file on disk */
+        self.dirty = false;
+
+        for base in changed.iter() {
+            info!("Config section {base} changed on disk");
+            let _ = self.callbacks.sender.send(ConfigChange { operation: ConfigOperation::CHANGE, base: base.clone() });
+        }
+
+        Ok(changed)
+    }
+
+    /// Replace the config file by one of its backups and reload it, unsaved changes are lost.
+    /// The current file is backed up first so a restore can be undone. A backup written in
+    /// another format replaces the config file of the current one.
+    pub fn restore_backup(&mut self, name: &str) -> Result<Vec<String>, String> {
+        let base = PathBuf::from(&self.base_path);
+        let path = backup::backup_path(&base, name)
+            .ok_or_else(|| format!("Invalid backup name {name}"))?;
+        let format = backup::backup_format(name)
+            .ok_or_else(|| format!("Invalid backup name {name}"))?;
+        let contents = fs::read_to_string(&path)
+            .map_err(|e| format!("Unable to read backup {name}: {e}"))?;
+
+        /* Never replace the config by something we can not load again */
+        let (restored, _) = parse_config(&contents, format)
+            .map_err(|e| format!("Unable to parse backup {name}: {e}"))?;
+        let errors = restored.validate();
+        if !errors.is_empty() {
+            let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+            return Err(format!("Backup {name} is invalid: {}", messages.join(", ")));
+        }
+
+        backup::create_backup(&base, self.format, self.config.storage.config_backups)
+            .map_err(|e| format!("Backing up the current config failed: {e}"))?;
+        fs::write(base.join(format.file_name()), contents)
+            .map_err(|e| format!("Unable to write config file: {e}"))?;
+        if format != self.format {
+            fs::remove_file(base.join(self.format.file_name()))
+                .map_err(|e| format!("Unable to remove the old config file: {e}"))?;
+            self.format = format;
+        }
+
+        info!("Config restored from backup {name}");
+        self.reload(true)
+    }
+
     pub fn get_copy(&self, base: &str) -> Result<ConfigBases, Box<dyn Error>> {
         /* Lock against modifications during copy */
         let _lock = self.lock.read().unwrap();
@@ -758,9 +1545,13 @@ impl ConfigHolder {
             "modbus" => { return Ok(ConfigBases::Modbus(self.config.modbus.clone())) },
             "tibber" => { return Ok(ConfigBases::Tibber(self.config.tibber.clone())) },
             "oms" => { return Ok(ConfigBases::Oms(self.config.oms.clone())) },
+            "iec62056" => { return Ok(ConfigBases::Iec62056(self.config.iec62056.clone())) },
             "victron" => { return Ok(ConfigBases::Victron(self.config.victron.clone())) },
             "knx" => { return Ok(ConfigBases::Knx(self.config.knx.clone())) },
             "zridh" => { return Ok(ConfigBases::ZRIDH(self.config.zenner_datahub.clone())) },
+            "mqtt_input" => { return Ok(ConfigBases::MqttInput(self.config.mqtt_input.clone())) },
+            "db" => { return Ok(ConfigBases::Database(self.config.db.clone())) },
+            "export" => { return Ok(ConfigBases::FileExport(self.config.file_export.clone())) },
             _ => { Err("Type not known")? }
         }
     }
@@ -772,6 +1563,33 @@ impl ConfigHolder {
     }
 }
 
+/// Compare two configs section by section and return the bases which differ
+fn changed_bases(old: &Config, new: &Config) -> Vec<String> {
+    fn differs<T: Serialize>(a: &T, b: &T) -> bool {
+        serde_json::to_value(a).ok() != serde_json::to_value(b).ok()
+    }
+
+    let sections = [
+        ("httpd", differs(&old.httpd, &new.httpd)),
+        ("mqtt", differs(&old.mqtt, &new.mqtt)),
+        ("db", differs(&old.db, &new.db)),
+        ("modbus", differs(&old.modbus, &new.modbus)),
+        ("tibber", differs(&old.tibber, &new.tibber)),
+        ("oms", differs(&old.oms, &new.oms)),
+        ("iec62056", differs(&old.iec62056, &new.iec62056)),
+        ("victron", differs(&old.victron, &new.victron)),
+        ("knx", differs(&old.knx, &new.knx)),
+        ("zridh", differs(&old.zenner_datahub, &new.zenner_datahub)),
+        ("mqtt_input", differs(&old.mqtt_input, &new.mqtt_input)),
+        ("export", differs(&old.file_export, &new.file_export)),
+    ];
+
+    sections.iter()
+        .filter(|(_, changed)| *changed)
+        .map(|(base, _)| base.to_string())
+        .collect()
+}
+
 lazy_static! {
     pub static ref CONFIG: RwLock<ConfigHolder> = RwLock::new(ConfigHolder::load());
 }
@@ -791,3 +1609,299 @@ macro_rules! get_config_or_panic {
         }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_writes_into_base_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let config: Config = serde_yml::from_str("mqtt:\n  host: localhost\n  port: 1883\n  user: e2m\n  pass: e2m\n  ha_enabled: true\n").unwrap();
+        let (s, _) = tokio::sync::broadcast::channel(1);
+        let mut holder = ConfigHolder {
+            config,
+            callbacks: Callbacks { sender: s },
+            dirty: true,
+            lock: RwLock::new(true),
+            base_path: dir.path().to_string_lossy().to_string(),
+            env_secrets: Vec::new(),
+            format: ConfigFormat::Yaml,
+            invalid_sections: BTreeMap::new(),
+        };
+
+        holder.save();
+        assert!(dir.path().join("e2m.yaml").exists());
+        assert!(!holder.is_dirty());
+
+        /* A second save must backup the existing file */
+        holder.dirty = true;
+        holder.save();
+        assert_eq!(backup::list_backups(dir.path()).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_invalid_sections_are_saved_unchanged() {
+        let dir = tempfile::tempdir().unwrap();
+        let yaml = "mqtt:\n  host: localhost\n  port: 1883\n  user: e2m\n  pass: e2m\n  ha_enabled: true\n\
+                    victron:\n- name: gx\n  broker_host: ''\n";
+        let mut config: Config = serde_yml::from_str(yaml).unwrap();
+        let (errors, invalid_sections) = config.remove_invalid_sections();
+        assert_eq!(errors.len(), 1);
+        assert!(config.victron.is_empty());
+
+        let (s, _) = tokio::sync::broadcast::channel(10);
+        let mut holder = ConfigHolder {
+            config,
+            callbacks: Callbacks { sender: s },
+            dirty: true,
+            lock: RwLock::new(true),
+            base_path: dir.path().to_string_lossy().to_string(),
+            env_secrets: Vec::new(),
+            format: ConfigFormat::Yaml,
+            invalid_sections,
+        };
+
+        holder.save();
+        let written: Config = serde_yml::from_str(&fs::read_to_string(dir.path().join("e2m.yaml")).unwrap()).unwrap();
+        assert_eq!(written.victron.len(), 1);
+        assert_eq!(written.victron[0].name, "gx");
+
+        /* Replacing the section through the API saves the new content */
+        holder.update_config(ConfigOperation::CHANGE, ConfigBases::Victron(Vec::new()));
+        holder.save();
+        let written: Config = serde_yml::from_str(&fs::read_to_string(dir.path().join("e2m.yaml")).unwrap()).unwrap();
+        assert!(written.victron.is_empty());
+    }
+
+    #[test]
+    fn test_env_secrets_are_not_saved() {
+        let dir = tempfile::tempdir().unwrap();
+        std::env::set_var("E2M_TEST_SAVE_MQTT_PASS", "from-env");
+        let yaml = "mqtt:\n  host: localhost\n  port: 1883\n  user: e2m\n  pass: ${E2M_TEST_SAVE_MQTT_PASS}\n  ha_enabled: true\n";
+        fs::write(dir.path().join("e2m.yaml"), yaml).unwrap();
+
+        let (config, env_secrets) = parse_config(yaml, ConfigFormat::Yaml).unwrap();
+        assert_eq!(config.mqtt.pass, "from-env");
+        let (s, _) = tokio::sync::broadcast::channel(1);
+        let mut holder = ConfigHolder {
+            config,
+            callbacks: Callbacks { sender: s },
+            dirty: true,
+            lock: RwLock::new(true),
+            base_path: dir.path().to_string_lossy().to_string(),
+            env_secrets,
+            format: ConfigFormat::Yaml,
+            invalid_sections: BTreeMap::new(),
+        };
+
+        holder.save();
+        let written = fs::read_to_string(dir.path().join("e2m.yaml")).unwrap();
+        assert!(written.contains("${E2M_TEST_SAVE_MQTT_PASS}"));
+        assert!(!written.contains("from-env"));
+
+        holder.reload(false).unwrap();
+        assert_eq!(holder.config.mqtt.pass, "from-env");
+    }
+
+    #[test]
+    fn test_reload_reports_changed_bases() {
+        let dir = tempfile::tempdir().unwrap();
+        let yaml = "mqtt:\n  host: localhost\n  port: 1883\n  user: e2m\n  pass: e2m\n  ha_enabled: true\n";
+        fs::write(dir.path().join("e2m.yaml"), yaml).unwrap();
+
+        let (s, mut receiver) = tokio::sync::broadcast::channel(10);
+        let mut holder = ConfigHolder {
+            config: serde_yml::from_str(yaml).unwrap(),
+            callbacks: Callbacks { sender: s },
+            dirty: false,
+            lock: RwLock::new(true),
+            base_path: dir.path().to_string_lossy().to_string(),
+            env_secrets: Vec::new(),
+            format: ConfigFormat::Yaml,
+            invalid_sections: BTreeMap::new(),
+        };
+
+        /* Nothing changed on disk */
+        assert!(holder.reload(false).unwrap().is_empty());
+
+        /* Unsaved changes are only thrown away on request */
+        holder.dirty = true;
+        assert!(holder.reload(false).is_err());
+        assert!(holder.reload(true).unwrap().is_empty());
+        assert!(!holder.is_dirty());
+
+        fs::write(dir.path().join("e2m.yaml"), yaml.replace("localhost", "broker") + "tibber:\n- name: home\n  account_token: abc\n").unwrap();
+        assert_eq!(holder.reload(false).unwrap(), vec!["mqtt".to_string(), "tibber".to_string()]);
+        assert_eq!(holder.config.mqtt.host, "broker");
+        assert_eq!(receiver.try_recv().unwrap().base, "mqtt");
+        assert_eq!(receiver.try_recv().unwrap().base, "tibber");
+    }
+
+    #[test]
+    fn test_parse_error_message() {
+        let contents = "mqtt:\n  host: localhost\n  port: [1883\n";
+        let Err(e) = serde_yml::from_str::<Config>(contents) else { panic!("broken yaml parsed") };
+        let message = parse_error_message(contents, &e);
+        assert!(message.contains("line 3"));
+        assert!(message.ends_with(": `  port: [1883`"));
+    }
+
+    #[test]
+    fn test_restore_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let yaml = "mqtt:\n  host: localhost\n  port: 1883\n  user: e2m\n  pass: e2m\n  ha_enabled: true\n";
+        fs::write(dir.path().join("e2m.yaml"), yaml).unwrap();
+        let first = backup::create_backup(dir.path(), ConfigFormat::Yaml, 10).unwrap().unwrap();
+
+        let (s, _receiver) = tokio::sync::broadcast::channel(10);
+        let mut holder = ConfigHolder {
+            config: serde_yml::from_str(yaml).unwrap(),
+            callbacks: Callbacks { sender: s },
+            dirty: false,
+            lock: RwLock::new(true),
+            base_path: dir.path().to_string_lossy().to_string(),
+            env_secrets: Vec::new(),
+            format: ConfigFormat::Yaml,
+            invalid_sections: BTreeMap::new(),
+        };
+
+        fs::write(dir.path().join("e2m.yaml"), yaml.replace("localhost", "broker")).unwrap();
+        holder.reload(false).unwrap();
+        assert_eq!(holder.config.mqtt.host, "broker");
+
+        assert_eq!(holder.restore_backup(&first).unwrap(), vec!["mqtt".to_string()]);
+        assert_eq!(holder.config.mqtt.host, "localhost");
+        /* The replaced config is kept as well */
+        assert_eq!(backup::list_backups(dir.path()).unwrap().len(), 2);
+
+        assert!(holder.restore_backup("../e2m.yaml").is_err());
+        assert!(holder.restore_backup("e2m-19700101-000000-000.yaml").is_err());
+    }
+
+    #[test]
+    fn test_config_formats_round_trip() {
+        std::env::set_var("E2M_TEST_FORMAT_MQTT_PASS", "from-env");
+        let yaml = "mqtt:\n  host: localhost\n  port: 1883\n  user: e2m\n  pass: ${E2M_TEST_FORMAT_MQTT_PASS}\n  ha_enabled: true\n\
+                    modbus:\n  hubs:\n  - name: hub\n    host: 10.0.0.1\n    port: 502\n    proto: TCP\n    devices:\n\
+                    \x20   - name: meter\n      meter: sdm72\n      slave_id: 1\n      read_interval: 60\n\
+                    sml:\n- server_id: 0a01\n  name: main\n  obis_allowlist: [1-0:1.8.0]\n\
+                    tibber:\n- name: home\n  account_token: abc\n";
+        let (config, env_secrets) = parse_config(yaml, ConfigFormat::Yaml).unwrap();
+        let expected = serde_yml::to_value(&config).unwrap();
+
+        for format in [ConfigFormat::Toml, ConfigFormat::Json] {
+            let dir = tempfile::tempdir().unwrap();
+            let (s, _) = tokio::sync::broadcast::channel(10);
+            let mut holder = ConfigHolder {
+                config: config.clone(),
+                callbacks: Callbacks { sender: s },
+                dirty: true,
+                lock: RwLock::new(true),
+                base_path: dir.path().to_string_lossy().to_string(),
+                env_secrets: env_secrets.clone(),
+                format,
+                invalid_sections: BTreeMap::new(),
+            };
+
+            holder.save();
+            assert!(!dir.path().join("e2m.yaml").exists());
+            assert_eq!(ConfigFormat::detect(dir.path()), format);
+            let written = fs::read_to_string(dir.path().join(format.file_name())).unwrap();
+            assert!(written.contains("${E2M_TEST_FORMAT_MQTT_PASS}"), "{written}");
+
+            assert!(holder.reload(false).unwrap().is_empty());
+            assert_eq!(holder.config.mqtt.pass, "from-env");
+            assert_eq!(serde_yml::to_value(&holder.config).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_restore_backup_of_other_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml = "[mqtt]\nhost = \"localhost\"\nport = 1883\nuser = \"e2m\"\npass = \"e2m\"\nha_enabled = true\n";
+        fs::write(dir.path().join("e2m.toml"), toml).unwrap();
+        let first = backup::create_backup(dir.path(), ConfigFormat::Toml, 10).unwrap().unwrap();
+        fs::remove_file(dir.path().join("e2m.toml")).unwrap();
+
+        let yaml = "mqtt:\n  host: broker\n  port: 1883\n  user: e2m\n  pass: e2m\n  ha_enabled: true\n";
+        fs::write(dir.path().join("e2m.yaml"), yaml).unwrap();
+        let (s, _receiver) = tokio::sync::broadcast::channel(10);
+        let mut holder = ConfigHolder {
+            config: serde_yml::from_str(yaml).unwrap(),
+            callbacks: Callbacks { sender: s },
+            dirty: false,
+            lock: RwLock::new(true),
+            base_path: dir.path().to_string_lossy().to_string(),
+            env_secrets: Vec::new(),
+            format: ConfigFormat::Yaml,
+            invalid_sections: BTreeMap::new(),
+        };
+
+        assert_eq!(holder.restore_backup(&first).unwrap(), vec!["mqtt".to_string()]);
+        assert_eq!(holder.config.mqtt.host, "localhost");
+        assert_eq!(holder.format, ConfigFormat::Toml);
+        assert!(!dir.path().join("e2m.yaml").exists());
+        assert!(backup::list_backups(dir.path()).unwrap().iter().any(|b| b.name.ends_with(".yaml")));
+    }
+
+    #[test]
+    fn test_httpd_bind_address() {
+        let config: HttpdConfig = serde_yml::from_str("port: 8080\n").unwrap();
+        assert_eq!(config.socket_addr().unwrap(), "0.0.0.0:8080".parse().unwrap());
+
+        let config: HttpdConfig = serde_yml::from_str("port: 8080\nbind_address: '::1'\n").unwrap();
+        assert_eq!(config.socket_addr().unwrap(), "[::1]:8080".parse().unwrap());
+
+        let config: HttpdConfig = serde_yml::from_str("bind_address: localhost\n").unwrap();
+        assert_eq!(config.socket_addr().unwrap_err(), "httpd bind_address \"localhost\" is not an IP address");
+    }
+
+    #[test]
+    fn test_modbus_device_read_defaults() {
+        let yaml = "name: meter\nmeter: sdm72\nslave_id: 1\nread_interval: 60\n";
+        let device: ModbusDeviceConfig = serde_yml::from_str(yaml).unwrap();
+        assert!(device.batch_reads);
+        assert_eq!(device.inter_register_delay_ms, 0);
+        assert_eq!(device.response_timeout_ms, None);
+        assert!(device.enabled);
+
+        let device: ModbusDeviceConfig = serde_yml::from_str(&format!("{yaml}inter_register_delay_ms: 50\nenabled: false\n")).unwrap();
+        assert_eq!(device.inter_register_delay_ms, 50);
+        assert!(!device.enabled);
+        /* Disabled devices stay in the config */
+        assert!(serde_yml::to_string(&device).unwrap().contains("enabled: false"));
+    }
+
+    #[test]
+    fn test_sml_obis_filter() {
+        let meter: SmlMeterConfig = serde_yml::from_str("server_id: 0a01\n").unwrap();
+        assert!(meter.obis_allowed("1-0:1.8.0"));
+
+        let meter: SmlMeterConfig = serde_yml::from_str(
+            "server_id: 0a01\nobis_allowlist: [1-0:1.8.0.255, 1-0:16.7.0]\nobis_blocklist: [1-0:16.7.0]\n").unwrap();
+        assert!(meter.obis_allowed("1-0:1.8.0"));
+        assert!(meter.obis_allowed("1-0:1.8.0.255"));
+        assert!(!meter.obis_allowed("1-0:16.7.0"));
+        assert!(!meter.obis_allowed("1-0:2.8.0"));
+
+        let meter: SmlMeterConfig = serde_yml::from_str("server_id: 0a01\nobis_blocklist: [1-0:96.50.1.1]\n").unwrap();
+        assert!(meter.obis_allowed("1-0:1.8.0"));
+        assert!(!meter.obis_allowed("1-0:96.50.1.1"));
+    }
+
+    #[test]
+    fn test_additional_brokers() {
+        let yaml = "host: localhost\nport: 1883\nuser: e2m\npass: e2m\nha_enabled: true\nbrokers:\n\
+                    - name: cloud\n  host: cloud.example.org\n  port: 8883\n  tls_enabled: true\n  input: true\n\
+                    - name: backup\n  host: 10.0.0.2\n  port: 1883\n";
+        let config: MqttConfig = serde_yml::from_str(yaml).unwrap();
+
+        assert_eq!(config.brokers.len(), 2);
+        assert!(config.brokers[0].input);
+        assert!(config.brokers[0].tls.tls_enabled);
+        assert_eq!(config.brokers[1].client_name, "energy2mqtt");
+        assert!(!config.brokers[1].input);
+        assert!(serde_yml::from_str::<MqttConfig>("host: a\nport: 1\nuser: b\npass: c\nha_enabled: false\n").unwrap().brokers.is_empty());
+    }
+}
@@ -0,0 +1,444 @@
+/*
+    Validation of the configuration
+
+    Every check returns a list of errors instead of failing on the first one so the
+    API and the log can show all problems of a section at once.
+*/
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+
+use serde::Serialize;
+
+use super::{
+    Config, KnxAdapterConfig, ModbusConfig, MqttInputConfig, OmsConfig, SmlMeterConfig, TibberConfig, VictronConfig,
+    ZennerDatahubConfig, knx_default, modbus_default, mqtt_input_default, oms_default, sml_default, tibber_default,
+    victron_default, zridh_default,
+};
+
+/// A single problem found in the configuration
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ValidationError {
+    /// Config base the problem belongs to, e.g. modbus or knx
+    pub section: String,
+    /// Path to the offending entry inside the section
+    pub field: String,
+    pub message: String,
+}
+
+impl ValidationError {
+    fn new(section: &str, field: String, message: &str) -> Self {
+        ValidationError {
+            section: section.to_string(),
+            field,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}: {}", self.section, self.field, self.message)
+    }
+}
+
+/// Group addresses use the three level format main/middle/sub (0-31/0-7/0-255)
+pub fn is_valid_group_address(ga: &str) -> bool {
+    let parts: Vec<&str> = ga.split('/').collect();
+    if parts.len() != 3 {
+        return false;
+    }
+
+    match (parts[0].parse::<u8>(), parts[1].parse::<u8>(), parts[2].parse::<u8>()) {
+        (Ok(main), Ok(middle), Ok(_)) => main <= 31 && middle <= 7,
+        _ => false,
+    }
+}
+
+/// Report every name which is used more than once
+fn check_unique_names<'a>(section: &str, prefix: &str, names: impl Iterator<Item = &'a String>, errors: &mut Vec<ValidationError>) {
+    let mut seen = HashSet::new();
+    for name in names {
+        if name.is_empty() {
+            errors.push(ValidationError::new(section, format!("{prefix}name"), "name must not be empty"));
+        } else if !seen.insert(name) {
+            errors.push(ValidationError::new(section, format!("{prefix}{name}"), "name is used more than once"));
+        }
+    }
+}
+
+fn check_group_address(section: &str, field: String, ga: &Option<String>, errors: &mut Vec<ValidationError>) {
+    if let Some(ga) = ga {
+        if !is_valid_group_address(ga) {
+            errors.push(ValidationError::new(section, field, "group addresses must match a/b/c format"));
+        }
+    }
+}
+
+pub fn validate_modbus(config: &ModbusConfig) -> Vec<ValidationError> {
+    let mut errors = Vec::new();
+    check_unique_names("modbus", "", config.hubs.iter().map(|h| &h.name), &mut errors);
+
+    for hub in config.hubs.iter() {
+        if hub.host.is_empty() {
+            errors.push(ValidationError::new("modbus", format!("{}.host", hub.name), "host must not be empty"));
+        }
+
+        check_unique_names("modbus", &format!("{}/", hub.name), hub.devices.iter().map(|d| &d.name), &mut errors);
+        for device in hub.devices.iter() {
+            if device.read_interval < 1 {
+                errors.push(ValidationError::new("modbus", format!("{}/{}.read_interval", hub.name, device.name), "read_interval must be >= 1"));
+            }
+            if device.meter.is_empty() {
+                errors.push(ValidationError::new("modbus", format!("{}/{}.meter", hub.name, device.name), "meter must not be empty"));
+            }
+            if let Some(cron) = &device.cron {
+                if let Err(e) = crate::schedule::parse_cron(cron) {
+                    errors.push(ValidationError::new("modbus", format!("{}/{}.cron", hub.name, device.name), &e));
+                }
+            }
+            if device.address_base > 1 {
+                errors.push(ValidationError::new("modbus", format!("{}/{}.address_base", hub.name, device.name), "address_base must be 0 or 1"));
+            }
+        }
+    }
+
+    errors
+}
+
+pub fn validate_knx(config: &[KnxAdapterConfig]) -> Vec<ValidationError> {
+    let mut errors = Vec::new();
+    check_unique_names("knx", "", config.iter().map(|a| &a.name), &mut errors);
+
+    for adapter in config.iter() {
+        if adapter.host.is_empty() {
+            errors.push(ValidationError::new("knx", format!("{}.host", adapter.name), "host must not be empty"));
+        }
+
+        check_unique_names("knx", &format!("{}/", adapter.name), adapter.meters.iter().map(|m| &m.name), &mut errors);
+        for meter in adapter.meters.iter() {
+            let prefix = format!("{}/{}", adapter.name, meter.name);
+            if meter.read_interval < 1 {
+                errors.push(ValidationError::new("knx", format!("{prefix}.read_interval"), "read_interval must be >= 1"));
+            }
+
+            let addresses = [
+                ("voltage_ga", &meter.voltage_ga),
+                ("current_ga", &meter.current_ga),
+                ("power_ga", &meter.power_ga),
+                ("energy_ga", &meter.energy_ga),
+                ("total_energy_ga", &meter.total_energy_ga),
+                ("total_power_ga", &meter.total_power_ga),
+                ("total_current_ga", &meter.total_current_ga),
+                ("switch_ga", &meter.switch_ga),
+                ("switch_state_ga", &meter.switch_state_ga),
+            ];
+            for (field, ga) in addresses {
+                check_group_address("knx", format!("{prefix}.{field}"), ga, &mut errors);
+            }
+
+            for phase in meter.phases.iter() {
+                let addresses = [
+                    ("voltage_ga", &phase.voltage_ga),
+                    ("current_ga", &phase.current_ga),
+                    ("power_ga", &phase.power_ga),
+                    ("energy_ga", &phase.energy_ga),
+                    ("switch_ga", &phase.switch_ga),
+                    ("switch_state_ga", &phase.switch_state_ga),
+                ];
+                for (field, ga) in addresses {
+                    check_group_address("knx", format!("{prefix}/{}.{field}", phase.name), ga, &mut errors);
+                }
+            }
+        }
+
+        check_unique_names("knx", &format!("{}/", adapter.name), adapter.switches.iter().map(|s| &s.name), &mut errors);
+        for switch in adapter.switches.iter() {
+            let prefix = format!("{}/{}", adapter.name, switch.name);
+            check_group_address("knx", format!("{prefix}.group_address"), &Some(switch.group_address.clone()), &mut errors);
+            check_group_address("knx", format!("{prefix}.state_address"), &switch.state_address, &mut errors);
+        }
+
+        for poll in adapter.poll_groups.iter() {
+            let prefix = format!("{}/{}", adapter.name, poll.name);
+            check_group_address("knx", format!("{prefix}.group_address"), &Some(poll.group_address.clone()), &mut errors);
+            if poll.poll_interval < 1 {
+                errors.push(ValidationError::new("knx", format!("{prefix}.poll_interval"), "poll_interval must be >= 1"));
+            }
+        }
+    }
+
+    errors
+}
+
+pub fn validate_victron(config: &[VictronConfig]) -> Vec<ValidationError> {
+    let mut errors = Vec::new();
+    check_unique_names("victron", "", config.iter().map(|v| &v.name), &mut errors);
+
+    for victron in config.iter() {
+        if victron.broker_host.is_empty() {
+            errors.push(ValidationError::new("victron", format!("{}.broker_host", victron.name), "broker_host must not be empty"));
+        }
+        if victron.update_interval < 1 {
+            errors.push(ValidationError::new("victron", format!("{}.update_interval", victron.name), "update_interval must be >= 1"));
+        }
+    }
+
+    errors
+}
+
+pub fn validate_oms(config: &[OmsConfig]) -> Vec<ValidationError> {
+    let mut errors = Vec::new();
+    check_unique_names("oms", "", config.iter().map(|o| &o.name), &mut errors);
+
+    for oms in config.iter() {
+        if oms.id.is_empty() {
+            errors.push(ValidationError::new("oms", format!("{}.id", oms.name), "id must not be empty"));
+        }
+        /* An empty key is fine for unencrypted meters, otherwise it has to be AES-128 in hex */
+        if !oms.key.is_empty() && (oms.key.len() != 32 || !oms.key.chars().all(|c| c.is_ascii_hexdigit())) {
+            errors.push(ValidationError::new("oms", format!("{}.key", oms.name), "key must be 32 hex characters"));
+        }
+        for (i, o) in oms.vif_overrides.iter().enumerate() {
+            if o.vif.is_none() && o.position.is_none() {
+                errors.push(ValidationError::new("oms", format!("{}.vif_overrides[{i}]", oms.name), "vif or position must be set"));
+            }
+            if o.vif.as_ref().map(|v| v.replace(' ', "")).is_some_and(|v| v.is_empty() || v.len() % 2 != 0 || !v.chars().all(|c| c.is_ascii_hexdigit())) {
+                errors.push(ValidationError::new("oms", format!("{}.vif_overrides[{i}].vif", oms.name), "vif must be hex bytes like FF or FD3A"));
+            }
+        }
+    }
+
+    errors
+}
+
+pub fn validate_sml(config: &[SmlMeterConfig]) -> Vec<ValidationError> {
+    let mut errors = Vec::new();
+    /* Meters without a name are published under SML-<server id> */
+    check_unique_names("sml", "", config.iter().filter_map(|m| m.name.as_ref()), &mut errors);
+
+    for meter in config.iter() {
+        if meter.server_id.is_empty() {
+            errors.push(ValidationError::new("sml", "server_id".to_string(), "server_id must not be empty"));
+        }
+        if let Some(name) = &meter.name {
+            if name.contains(['/', '+', '#']) {
+                errors.push(ValidationError::new("sml", format!("{}.name", meter.server_id), "name must be a single topic level"));
+            }
+        }
+    }
+
+    errors
+}
+
+pub fn validate_tibber(config: &[TibberConfig]) -> Vec<ValidationError> {
+    let mut errors = Vec::new();
+    check_unique_names("tibber", "", config.iter().map(|t| &t.name), &mut errors);
+
+    for tibber in config.iter() {
+        if tibber.account_token.is_empty() {
+            errors.push(ValidationError::new("tibber", format!("{}.account_token", tibber.name), "account_token must not be empty"));
+        }
+    }
+
+    errors
+}
+
+pub fn validate_zridh(config: &[ZennerDatahubConfig]) -> Vec<ValidationError> {
+    let mut errors = Vec::new();
+    check_unique_names("zridh", "", config.iter().map(|z| &z.name), &mut errors);
+
+    for zridh in config.iter() {
+        if zridh.broker_host.is_empty() {
+            errors.push(ValidationError::new("zridh", format!("{}.broker_host", zridh.name), "broker_host must not be empty"));
+        }
+        if zridh.update_interval < 1 {
+            errors.push(ValidationError::new("zridh", format!("{}.update_interval", zridh.name), "update_interval must be >= 1"));
+        }
+    }
+
+    errors
+}
+
+pub fn validate_mqtt_input(config: &[MqttInputConfig]) -> Vec<ValidationError> {
+    let mut errors = Vec::new();
+    check_unique_names("mqtt_input", "", config.iter().map(|m| &m.name), &mut errors);
+
+    for input in config.iter() {
+        if input.topic.is_empty() || input.topic.contains(['+', '#']) {
+            errors.push(ValidationError::new("mqtt_input", format!("{}.topic", input.name), "topic must be set and must not contain wildcards"));
+        }
+        if input.protocol.is_empty() || input.protocol.contains(['/', '+', '#']) {
+            errors.push(ValidationError::new("mqtt_input", format!("{}.protocol", input.name), "protocol must be a single topic level"));
+        }
+        check_unique_names("mqtt_input", &format!("{}.", input.name), input.fields.iter().map(|f| &f.name), &mut errors);
+        for field in input.fields.iter() {
+            if field.pointer().is_none() {
+                errors.push(ValidationError::new("mqtt_input", format!("{}.{}.path", input.name, field.name), "path must be a JSON pointer or a JSONPath like $.a.b[0]"));
+            }
+        }
+    }
+
+    errors
+}
+
+impl Config {
+    /// Validate all sections and return every problem found
+    pub fn validate(&self) -> Vec<ValidationError> {
+        let mut errors = validate_modbus(&self.modbus);
+        errors.extend(validate_knx(&self.knx));
+        errors.extend(validate_victron(&self.victron));
+        errors.extend(validate_oms(&self.oms));
+        errors.extend(validate_sml(&self.sml));
+        errors.extend(validate_tibber(&self.tibber));
+        errors.extend(validate_zridh(&self.zenner_datahub));
+        errors.extend(validate_mqtt_input(&self.mqtt_input));
+        errors
+    }
+
+    /// Replace every section with validation errors by its default so broken
+    /// parts do not reach the managers. Returns the errors found and the original
+    /// content of the removed sections keyed by their config key, saves write
+    /// those back unchanged so the user does not lose them.
+    pub fn remove_invalid_sections(&mut self) -> (Vec<ValidationError>, BTreeMap<String, serde_yml::Value>) {
+        let errors = self.validate();
+        let sections: HashSet<&str> = errors.iter().map(|e| e.section.as_str()).collect();
+        let mut removed = BTreeMap::new();
+
+        for section in sections {
+            let original = match section {
+                "modbus" => serde_yml::to_value(std::mem::replace(&mut self.modbus, modbus_default())),
+                "knx" => serde_yml::to_value(std::mem::replace(&mut self.knx, knx_default())),
+                "victron" => serde_yml::to_value(std::mem::replace(&mut self.victron, victron_default())),
+                "oms" => serde_yml::to_value(std::mem::replace(&mut self.oms, oms_default())),
+                "sml" => serde_yml::to_value(std::mem::replace(&mut self.sml, sml_default())),
+                "tibber" => serde_yml::to_value(std::mem::replace(&mut self.tibber, tibber_default())),
+                "zridh" => serde_yml::to_value(std::mem::replace(&mut self.zenner_datahub, zridh_default())),
+                "mqtt_input" => serde_yml::to_value(std::mem::replace(&mut self.mqtt_input, mqtt_input_default())),
+                _ => continue,
+            };
+            if let Ok(original) = original {
+                removed.insert(section_key(section).to_string(), original);
+            }
+        }
+
+        (errors, removed)
+    }
+}
+
+/// Key of a config base inside the config file
+pub fn section_key(base: &str) -> &str {
+    match base {
+        "zridh" => "zenner_datahub",
+        "export" => "file_export",
+        _ => base,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_group_address_format() {
+        assert!(is_valid_group_address("1/2/3"));
+        assert!(is_valid_group_address("31/7/255"));
+        assert!(!is_valid_group_address("32/0/0"));
+        assert!(!is_valid_group_address("1/8/0"));
+        assert!(!is_valid_group_address("1/2"));
+        assert!(!is_valid_group_address("a/b/c"));
+    }
+
+    #[test]
+    fn test_modbus_cron_is_validated() {
+        let mut config: ModbusConfig = serde_yml::from_str(
+            "hubs:\n- name: hub\n  host: 10.0.0.1\n  port: 502\n  proto: TCP\n  devices:\n\
+             \x20 - name: meter\n    meter: sdm72\n    slave_id: 1\n    read_interval: 60\n    cron: '*/15 * * * *'\n"
+        ).unwrap();
+        assert!(validate_modbus(&config).is_empty());
+
+        config.hubs[0].devices[0].cron = Some("every quarter".to_string());
+        let errors = validate_modbus(&config);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].field, "hub/meter.cron");
+
+        config.hubs[0].devices[0].cron = None;
+        config.hubs[0].devices[0].address_base = 2;
+        let errors = validate_modbus(&config);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].field, "hub/meter.address_base");
+    }
+
+    #[test]
+    fn test_invalid_sections_are_removed() {
+        let mut config: Config = serde_yml::from_str(
+            "mqtt:\n  host: localhost\n  port: 1883\n  user: e2m\n  pass: e2m\n  ha_enabled: true\n\
+             modbus:\n  hubs:\n  - name: hub\n    host: 10.0.0.1\n    port: 502\n    proto: TCP\n    devices:\n\
+             \x20   - name: meter\n      meter: sdm72\n      slave_id: 1\n      read_interval: 0\n\
+             victron:\n- name: gx\n  broker_host: ''\n\
+             tibber:\n- name: home\n  account_token: abc\n"
+        ).unwrap();
+
+        let (errors, removed) = config.remove_invalid_sections();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].field, "hub/meter.read_interval");
+        assert_eq!(errors[1].section, "victron");
+
+        assert!(config.modbus.hubs.is_empty());
+        assert!(config.victron.is_empty());
+        assert_eq!(config.tibber.len(), 1);
+
+        /* The original sections are kept for saving */
+        assert_eq!(removed.keys().collect::<Vec<_>>(), vec!["modbus", "victron"]);
+        assert_eq!(removed["victron"][0]["name"], serde_yml::Value::from("gx"));
+    }
+
+    #[test]
+    fn test_knx_switch_state_ga_is_validated() {
+        let config: Vec<KnxAdapterConfig> = serde_yml::from_str(
+            "- name: knx\n  host: 10.0.0.2\n  meters:\n  - name: heater\n    switch_ga: 1/2/3\n    switch_state_ga: 1/2\n"
+        ).unwrap();
+
+        let errors = validate_knx(&config);
+        let fields: Vec<&str> = errors.iter().map(|e| e.field.as_str()).collect();
+        assert_eq!(fields, vec!["knx/heater.switch_state_ga"]);
+    }
+
+    #[test]
+    fn test_duplicate_names() {
+        let config: Vec<TibberConfig> = serde_yml::from_str(
+            "- name: home\n  account_token: abc\n- name: home\n  account_token: def\n"
+        ).unwrap();
+
+        let errors = validate_tibber(&config);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].to_string(), "tibber: home: name is used more than once");
+    }
+
+    #[test]
+    fn test_validate_sml() {
+        let config: Vec<SmlMeterConfig> = serde_yml::from_str(
+            "- server_id: 0a01\n  name: main\n\
+             - server_id: 0a02\n  name: main\n\
+             - server_id: 0a03\n  name: flat/1\n\
+             - server_id: 0a04\n"
+        ).unwrap();
+
+        let errors = validate_sml(&config);
+        let fields: Vec<&str> = errors.iter().map(|e| e.field.as_str()).collect();
+        assert_eq!(fields, vec!["main", "0a03.name"]);
+    }
+
+    #[test]
+    fn test_validate_mqtt_input() {
+        let config: Vec<MqttInputConfig> = serde_yml::from_str(
+            "- name: plug\n  topic: tele/plug/SENSOR\n  fields:\n  - name: power\n    path: $.ENERGY.Power\n\
+             - name: all\n  topic: tele/+/SENSOR\n  protocol: a/b\n  fields:\n  - name: power\n    path: ENERGY.Power\n"
+        ).unwrap();
+        assert_eq!(config[0].protocol, "mqtt_input");
+
+        let errors = validate_mqtt_input(&config);
+        let fields: Vec<&str> = errors.iter().map(|e| e.field.as_str()).collect();
+        assert_eq!(fields, vec!["all.topic", "all.protocol", "all.power.path"]);
+    }
+}
@@ -0,0 +1,262 @@
+//! SQLite persistence of the known devices
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+
+use crate::models::{Device, DeviceStatus, DeviceType};
+
+/// Device table stored in the SQLite database from `DatabaseConfig.uri`
+pub struct DeviceDb {
+    conn: Mutex<Connection>,
+}
+
+fn row_to_device(row: &Row) -> rusqlite::Result<Device> {
+    let device_type: String = row.get(2)?;
+    let status: String = row.get(3)?;
+    let last_seen: String = row.get(5)?;
+    let parameters: String = row.get(6)?;
+
+    Ok(Device {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        device_type: DeviceType::from_str(&device_type).unwrap_or(DeviceType::Sensor),
+        status: DeviceStatus::from_str(&status).unwrap_or(DeviceStatus::Unknown),
+        device_protocol: row.get(4)?,
+        last_seen: DateTime::parse_from_rfc3339(&last_seen)
+            .map(|d| d.with_timezone(&Utc))
+            .unwrap_or_default(),
+        parameters: serde_json::from_str::<HashMap<String, String>>(&parameters).unwrap_or_default(),
+    })
+}
+
+impl DeviceDb {
+    /// Open (or create) the database and make sure the devices table exists
+    pub fn open(uri: &str) -> rusqlite::Result<Self> {
+        Self::init(Connection::open(uri)?)
+    }
+
+    /// In memory database, used for tests
+    pub fn open_in_memory() -> rusqlite::Result<Self> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(conn: Connection) -> rusqlite::Result<Self> {
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS devices (
+                id TEXT PRIMARY KEY,
+                name TEXT NOT NULL,
+                device_type TEXT NOT NULL,
+                status TEXT NOT NULL,
+                device_protocol TEXT NOT NULL,
+                last_seen TEXT NOT NULL,
+                parameters TEXT NOT NULL
+            )",
+            [],
+        )?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS energy_accumulators (
+                meter_id TEXT NOT NULL,
+                field TEXT NOT NULL,
+                last_raw REAL NOT NULL,
+                offset REAL NOT NULL,
+                PRIMARY KEY (meter_id, field)
+            )",
+            [],
+        )?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS period_rollups (
+                meter_id TEXT NOT NULL,
+                field TEXT NOT NULL,
+                period TEXT NOT NULL,
+                period_start INTEGER NOT NULL,
+                start_value REAL NOT NULL,
+                last_value REAL,
+                PRIMARY KEY (meter_id, field, period)
+            )",
+            [],
+        )?;
+        /* Tables created before the last value was kept lack its column */
+        let has_last_value: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM pragma_table_info('period_rollups') WHERE name = 'last_value'", [], |row| row.get(0))?;
+        if has_last_value == 0 {
+            conn.execute("ALTER TABLE period_rollups ADD COLUMN last_value REAL", [])?;
+        }
+
+        Ok(DeviceDb { conn: Mutex::new(conn) })
+    }
+
+    /// Insert a device, an existing device with the same id is replaced
+    pub fn insert_device(&self, device: &Device) -> rusqlite::Result<()> {
+        let parameters = serde_json::to_string(&device.parameters).unwrap_or_else(|_| "{}".to_string());
+
+        self.conn.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO devices (id, name, device_type, status, device_protocol, last_seen, parameters)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                device.id,
+                device.name,
+                device.device_type.to_string(),
+                device.status.to_string(),
+                device.device_protocol,
+                device.last_seen.to_rfc3339(),
+                parameters,
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn get_device(&self, id: &str) -> rusqlite::Result<Option<Device>> {
+        self.conn.lock().unwrap().query_row(
+            "SELECT id, name, device_type, status, device_protocol, last_seen, parameters FROM devices WHERE id = ?1",
+            params![id],
+            row_to_device,
+        ).optional()
+    }
+
+    pub fn list_devices(&self) -> rusqlite::Result<Vec<Device>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, name, device_type, status, device_protocol, last_seen, parameters FROM devices ORDER BY name",
+        )?;
+
+        let devices = stmt.query_map([], row_to_device)?.collect::<rusqlite::Result<Vec<Device>>>();
+        devices
+    }
+
+    /// Update the status of a device, going online also refreshes last_seen.
+    /// Returns false if the device is unknown.
+    pub fn update_status(&self, id: &str, status: DeviceStatus) -> rusqlite::Result<bool> {
+        let conn = self.conn.lock().unwrap();
+
+        let changed = if status == DeviceStatus::Online {
+            conn.execute(
+                "UPDATE devices SET status = ?1, last_seen = ?2 WHERE id = ?3",
+                params![status.to_string(), Utc::now().to_rfc3339(), id],
+            )?
+        } else {
+            conn.execute(
+                "UPDATE devices SET status = ?1 WHERE id = ?2",
+                params![status.to_string(), id],
+            )?
+        };
+
+        Ok(changed > 0)
+    }
+
+    /// Last raw value and offset of an energy accumulator
+    pub fn get_accumulator(&self, meter_id: &str, field: &str) -> rusqlite::Result<Option<(f64, f64)>> {
+        self.conn.lock().unwrap().query_row(
+            "SELECT last_raw, offset FROM energy_accumulators WHERE meter_id = ?1 AND field = ?2",
+            params![meter_id, field],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        ).optional()
+    }
+
+    pub fn set_accumulator(&self, meter_id: &str, field: &str, last_raw: f64, offset: f64) -> rusqlite::Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO energy_accumulators (meter_id, field, last_raw, offset) VALUES (?1, ?2, ?3, ?4)",
+            params![meter_id, field, last_raw, offset],
+        )?;
+
+        Ok(())
+    }
+
+    /// Start time, counter value at the start and last counter value of the current period of a rollup
+    pub fn get_rollup(&self, meter_id: &str, field: &str, period: &str) -> rusqlite::Result<Option<(u64, f64, f64)>> {
+        /* Rows stored before the last value was kept start with it */
+        self.conn.lock().unwrap().query_row(
+            "SELECT period_start, start_value, COALESCE(last_value, start_value) FROM period_rollups
+             WHERE meter_id = ?1 AND field = ?2 AND period = ?3",
+            params![meter_id, field, period],
+            |row| Ok((row.get::<_, i64>(0)? as u64, row.get(1)?, row.get(2)?)),
+        ).optional()
+    }
+
+    pub fn set_rollup(&self, meter_id: &str, field: &str, period: &str, period_start: u64, start_value: f64, last_value: f64) -> rusqlite::Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO period_rollups (meter_id, field, period, period_start, start_value, last_value)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![meter_id, field, period, period_start as i64, start_value, last_value],
+        )?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_and_get() {
+        let db = DeviceDb::open_in_memory().unwrap();
+        let mut device = Device::new("meter".to_string(), DeviceType::ModbusTcp, "ModbusTCP".to_string());
+        device.set_parameter("slave_id".to_string(), "1".to_string());
+
+        db.insert_device(&device).unwrap();
+
+        let loaded = db.get_device(&device.id).unwrap().unwrap();
+        assert_eq!(loaded.name, "meter");
+        assert_eq!(loaded.device_type, DeviceType::ModbusTcp);
+        assert_eq!(loaded.status, DeviceStatus::Offline);
+        assert_eq!(loaded.get_parameter("slave_id"), Some(&"1".to_string()));
+
+        assert!(db.get_device("unknown").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_list_and_update_status() {
+        let db = DeviceDb::open_in_memory().unwrap();
+        let a = Device::new("b_meter".to_string(), DeviceType::Sensor, "SML".to_string());
+        let b = Device::new("a_meter".to_string(), DeviceType::Sensor, "OMS".to_string());
+        db.insert_device(&a).unwrap();
+        db.insert_device(&b).unwrap();
+
+        let devices = db.list_devices().unwrap();
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].name, "a_meter");
+
+        assert!(db.update_status(&a.id, DeviceStatus::Online).unwrap());
+        assert_eq!(db.get_device(&a.id).unwrap().unwrap().status, DeviceStatus::Online);
+        assert!(!db.update_status("unknown", DeviceStatus::Online).unwrap());
+    }
+
+    #[test]
+    fn test_accumulator() {
+        let db = DeviceDb::open_in_memory().unwrap();
+        assert_eq!(db.get_accumulator("modbus-grid", "energy").unwrap(), None);
+
+        db.set_accumulator("modbus-grid", "energy", 12.5, 100.0).unwrap();
+        db.set_accumulator("modbus-grid", "energy", 13.0, 100.0).unwrap();
+        assert_eq!(db.get_accumulator("modbus-grid", "energy").unwrap(), Some((13.0, 100.0)));
+        assert_eq!(db.get_accumulator("modbus-grid", "power").unwrap(), None);
+    }
+
+    #[test]
+    fn test_rollup() {
+        let db = DeviceDb::open_in_memory().unwrap();
+        assert_eq!(db.get_rollup("modbus-grid", "energy", "daily").unwrap(), None);
+
+        db.set_rollup("modbus-grid", "energy", "daily", 86400, 12.5, 14.0).unwrap();
+        db.set_rollup("modbus-grid", "energy", "hourly", 90000, 13.0, 14.0).unwrap();
+        assert_eq!(db.get_rollup("modbus-grid", "energy", "daily").unwrap(), Some((86400, 12.5, 14.0)));
+        assert_eq!(db.get_rollup("modbus-grid", "energy", "hourly").unwrap(), Some((90000, 13.0, 14.0)));
+    }
+
+    #[test]
+    fn test_rollup_table_without_last_value() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute("CREATE TABLE period_rollups (meter_id TEXT NOT NULL, field TEXT NOT NULL, period TEXT NOT NULL, \
+            period_start INTEGER NOT NULL, start_value REAL NOT NULL, PRIMARY KEY (meter_id, field, period))", []).unwrap();
+        conn.execute("INSERT INTO period_rollups VALUES ('modbus-grid', 'energy', 'daily', 86400, 12.5)", []).unwrap();
+
+        let db = DeviceDb::init(conn).unwrap();
+        assert_eq!(db.get_rollup("modbus-grid", "energy", "daily").unwrap(), Some((86400, 12.5, 12.5)));
+        db.set_rollup("modbus-grid", "energy", "daily", 86400, 12.5, 14.0).unwrap();
+        assert_eq!(db.get_rollup("modbus-grid", "energy", "daily").unwrap(), Some((86400, 12.5, 14.0)));
+    }
+}
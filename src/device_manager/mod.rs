@@ -1,24 +1,92 @@
+use crate::config::{ConfigBases, DatabaseConfig};
+use crate::models::{Device, DeviceStatus};
 use crate::mqtt::Transmission;
+use crate::{get_config_or_panic, CONFIG};
+use log::{error, info};
+use std::sync::Arc;
 use tokio::sync::mpsc::Sender;
 use tokio::sync::broadcast;
 
+pub mod db;
+
+use db::DeviceDb;
+
 /// DeviceManager handles the storage and configuration
 pub struct DeviceManager {
     sender: Sender<Transmission>,
     broadcast: tokio::sync::broadcast::Sender<String>,
     #[allow(dead_code)]
-    rx_first: tokio::sync::broadcast::Receiver<String>
+    rx_first: tokio::sync::broadcast::Receiver<String>,
+    db: Option<Arc<DeviceDb>>,
+}
+
+fn open_db(config: &DatabaseConfig) -> Option<Arc<DeviceDb>> {
+    if config.dbtype != "sqlite" {
+        error!("Database type {} is not supported, devices will not be persisted", config.dbtype);
+        return None;
+    }
+
+    match DeviceDb::open(&config.uri) {
+        Ok(db) => {
+            info!("Device database opened at {}", config.uri);
+            Some(Arc::new(db))
+        },
+        Err(e) => {
+            error!("Unable to open device database {}: {}", config.uri, e);
+            None
+        }
+    }
 }
 
 impl DeviceManager {
-    /// Create a new DeviceManager with the specified SQLite database path
+    /// Create a new DeviceManager with the SQLite database path from the config
     pub fn new(meter_data_sender: Sender<Transmission>) -> Self {
+        let db_config = get_config_or_panic!("db", ConfigBases::Database);
+        Self::with_db(meter_data_sender, open_db(&db_config))
+    }
+
+    /// Create a new DeviceManager using the given database, None disables persistence
+    pub fn with_db(meter_data_sender: Sender<Transmission>, db: Option<Arc<DeviceDb>>) -> Self {
         let (broadcast_tx, rx) = broadcast::channel(16);
-        return DeviceManager {
+        DeviceManager {
             sender: meter_data_sender.clone(),
             broadcast: broadcast_tx,
             rx_first: rx,
-        };
+            db,
+        }
+    }
+
+    /// Shared handle to the device database, e.g. for metering managers
+    pub fn get_db(&self) -> Option<Arc<DeviceDb>> {
+        self.db.clone()
+    }
+
+    pub fn insert_device(&self, device: &Device) -> Result<(), String> {
+        match &self.db {
+            Some(db) => db.insert_device(device).map_err(|e| e.to_string()),
+            None => Err("No device database available".to_string()),
+        }
+    }
+
+    pub fn get_device(&self, id: &str) -> Result<Option<Device>, String> {
+        match &self.db {
+            Some(db) => db.get_device(id).map_err(|e| e.to_string()),
+            None => Err("No device database available".to_string()),
+        }
+    }
+
+    pub fn list_devices(&self) -> Result<Vec<Device>, String> {
+        match &self.db {
+            Some(db) => db.list_devices().map_err(|e| e.to_string()),
+            None => Err("No device database available".to_string()),
+        }
+    }
+
+    pub fn update_status(&self, id: &str, status: DeviceStatus) -> Result<bool, String> {
+        match &self.db {
+            Some(db) => db.update_status(id, status).map_err(|e| e.to_string()),
+            None => Err("No device database available".to_string()),
+        }
     }
 
     pub fn get_sender_instance(&self) -> Sender<Transmission> {
@@ -0,0 +1,267 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use log::{debug, error, info, warn};
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+
+use crate::config::{ConfigBases, ConfigChange, FileExportConfig, FileExportFormat};
+use crate::{get_config_or_panic, MeteringData, CONFIG};
+
+/// Escape a single CSV field if needed
+fn csv_escape(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        return format!("\"{}\"", field.replace('"', "\"\""));
+    }
+    field.to_string()
+}
+
+/// Get a column of the metering data, either a field of the data itself or a metered value
+fn get_column(data: &MeteringData, column: &str) -> String {
+    match column {
+        "id" => data.id.clone(),
+        "meter_name" => data.meter_name.clone(),
+        "tenant" => data.tenant.clone(),
+        "protocol" => data.protocol.to_string(),
+        "metered_time" => data.metered_time.to_string(),
+        "transmission_time" => data.transmission_time.to_string(),
+        _ => match data.metered_values.get(column) {
+            Some(serde_json::Value::String(s)) => s.clone(),
+            Some(serde_json::Value::Null) | None => String::new(),
+            Some(v) => v.to_string(),
+        },
+    }
+}
+
+pub fn format_csv_header(columns: &[String]) -> String {
+    columns.iter().map(|c| csv_escape(c)).collect::<Vec<String>>().join(",")
+}
+
+pub fn format_csv_row(data: &MeteringData, columns: &[String]) -> String {
+    columns.iter()
+        .map(|c| csv_escape(&get_column(data, c)))
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+/// Appends lines to a file and rotates it once it grows beyond the configured size
+pub struct FileSink {
+    config: FileExportConfig,
+    file: Option<File>,
+    size: u64,
+}
+
+impl FileSink {
+    pub fn new(config: FileExportConfig) -> Self {
+        FileSink { config, file: None, size: 0 }
+    }
+
+    fn open(&mut self) -> io::Result<()> {
+        let path = Path::new(&self.config.path);
+        if let Some(dir) = path.parent() {
+            if !dir.as_os_str().is_empty() {
+                fs::create_dir_all(dir)?;
+            }
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        self.size = file.metadata()?.len();
+        self.file = Some(file);
+
+        /* A new CSV file starts with its header */
+        if self.size == 0 && self.config.format == FileExportFormat::Csv {
+            let header = format_csv_header(&self.config.columns);
+            self.append(&header)?;
+        }
+
+        Ok(())
+    }
+
+    /// Move path to path.1, path.1 to path.2 and so on, dropping the oldest file
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file = None;
+
+        let path = &self.config.path;
+        if self.config.max_files == 0 {
+            fs::remove_file(path)?;
+        } else {
+            for i in (1..self.config.max_files).rev() {
+                let from = format!("{path}.{i}");
+                if Path::new(&from).exists() {
+                    fs::rename(&from, format!("{path}.{}", i + 1))?;
+                }
+            }
+            fs::rename(path, format!("{path}.1"))?;
+        }
+
+        debug!("Rotated export file {}", path);
+        self.open()
+    }
+
+    fn append(&mut self, line: &str) -> io::Result<()> {
+        if let Some(file) = self.file.as_mut() {
+            file.write_all(line.as_bytes())?;
+            file.write_all(b"\n")?;
+            self.size += line.len() as u64 + 1;
+        }
+        Ok(())
+    }
+
+    pub fn write(&mut self, data: &MeteringData) -> io::Result<()> {
+        if self.file.is_none() {
+            self.open()?;
+        }
+
+        let line = match self.config.format {
+            FileExportFormat::JsonLines => serde_json::to_string(data)?,
+            FileExportFormat::Csv => format_csv_row(data, &self.config.columns),
+        };
+
+        if self.size > 0 && self.size + line.len() as u64 + 1 > self.config.max_size {
+            self.rotate()?;
+        }
+
+        self.append(&line)
+    }
+}
+
+/// Writes all metering data to a local file, consuming the broadcast channel so MQTT is never blocked
+pub struct FileExportManager {
+    receiver: Receiver<String>,
+    config_change: Receiver<ConfigChange>,
+    config: FileExportConfig,
+}
+
+impl FileExportManager {
+    pub fn new(receiver: Receiver<String>) -> Self {
+        return FileExportManager {
+            receiver,
+            config_change: CONFIG.read().unwrap().get_change_receiver(),
+            config: get_config_or_panic!("export", ConfigBases::FileExport),
+        };
+    }
+
+    pub async fn start_thread(&mut self) -> ! {
+        loop {
+            if !self.config.enabled {
+                info!("File export disabled, waiting for a config change to wake me up");
+                loop {
+                    let change = self.config_change.recv().await.unwrap();
+                    if change.base == "export" {
+                        break;
+                    }
+                }
+                self.config = get_config_or_panic!("export", ConfigBases::FileExport);
+                continue;
+            }
+
+            info!("Exporting metering data to {}", self.config.path);
+            let mut sink = FileSink::new(self.config.clone());
+
+            /* Skip whatever was sent while we were not exporting */
+            self.receiver = self.receiver.resubscribe();
+
+            loop {
+                tokio::select! {
+                    msg = self.receiver.recv() => {
+                        match msg {
+                            Ok(text) => {
+                                /* The broadcast also carries other messages, we only care for metering data */
+                                let data: MeteringData = match serde_json::from_str(&text) {
+                                    Ok(d) => d,
+                                    Err(_) => continue,
+                                };
+
+                                if let Err(e) = sink.write(&data) {
+                                    error!("Unable to write metering data to {}: {}", self.config.path, e);
+                                }
+                            },
+                            Err(RecvError::Lagged(count)) => {
+                                warn!("File export is too slow, {} metering messages were not exported", count);
+                            },
+                            Err(RecvError::Closed) => {
+                                error!("Metering broadcast closed, file export stops");
+                                std::future::pending::<()>().await;
+                            },
+                        }
+                    },
+                    change = self.config_change.recv() => {
+                        if let Ok(change) = change {
+                            if change.base == "export" {
+                                break;
+                            }
+                        }
+                    },
+                }
+            }
+
+            info!("File export configuration changed, restarting");
+            self.config = get_config_or_panic!("export", ConfigBases::FileExport);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metering(name: &str, power: f64) -> MeteringData {
+        let mut data = MeteringData::new().unwrap();
+        data.meter_name = name.to_string();
+        data.metered_time = 1700000000;
+        data.metered_values.insert("power".to_string(), serde_json::Value::from(power));
+        data
+    }
+
+    fn export_config(path: &Path, format: FileExportFormat, max_size: u64) -> FileExportConfig {
+        FileExportConfig {
+            enabled: true,
+            path: path.to_string_lossy().to_string(),
+            format,
+            columns: vec!["metered_time".to_string(), "meter_name".to_string(), "power".to_string()],
+            max_size,
+            max_files: 2,
+        }
+    }
+
+    #[test]
+    fn test_format_csv_row() {
+        let columns = vec!["meter_name".to_string(), "power".to_string(), "missing".to_string()];
+        assert_eq!(format_csv_row(&metering("grid, main", 1.5), &columns), "\"grid, main\",1.5,");
+        assert_eq!(format_csv_header(&columns), "meter_name,power,missing");
+    }
+
+    #[test]
+    fn test_csv_export_writes_header_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("export/metering.csv");
+
+        let mut sink = FileSink::new(export_config(&path, FileExportFormat::Csv, 1024));
+        sink.write(&metering("grid", 1.0)).unwrap();
+        sink.write(&metering("grid", 2.0)).unwrap();
+
+        let content = fs::read_to_string(&path).unwrap();
+        assert_eq!(content, "metered_time,meter_name,power\n1700000000,grid,1.0\n1700000000,grid,2.0\n");
+    }
+
+    #[test]
+    fn test_jsonl_export_rotates_by_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("metering.jsonl");
+
+        let mut sink = FileSink::new(export_config(&path, FileExportFormat::JsonLines, 100));
+        for i in 0..6 {
+            sink.write(&metering("grid", i as f64)).unwrap();
+        }
+
+        let rotated = format!("{}.1", path.display());
+        let oldest = format!("{}.2", path.display());
+        assert!(Path::new(&rotated).exists());
+        assert!(Path::new(&oldest).exists());
+        assert!(!Path::new(&format!("{}.3", path.display())).exists());
+
+        /* Every line is a complete MeteringData document */
+        let current = fs::read_to_string(&path).unwrap();
+        let last: MeteringData = serde_json::from_str(current.lines().last().unwrap()).unwrap();
+        assert_eq!(last.metered_values["power"], 5.0);
+    }
+}
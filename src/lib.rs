@@ -1,7 +1,7 @@
 //! Device Manager library for managing IoT devices
 //!
 //! This library provides functionality for storing, retrieving, and configuring
-//! IoT devices with a SQLite-based persistence layer.
+//! IoT devices with a SQLite-based persistence layer (see `device_manager::db`).
 
 /// Application version - set via BUILD_VERSION env var at compile time,
 /// falls back to "local" if not set
@@ -24,16 +24,27 @@ pub mod metering_oms;
 pub mod metering_62056;
 #[cfg(feature = "sml")]
 pub mod metering_sml;
+#[cfg(feature = "tibber")]
+pub mod metering_tibber;
 #[cfg(feature = "victron")]
 pub mod metering_victron;
 #[cfg(feature = "zenner-datahub")]
 pub mod metering_zennerdatahub;
 #[cfg(feature = "knx")]
 pub mod metering_knx;
+#[cfg(feature = "mqtt-input")]
+pub mod metering_mqtt_input;
+pub mod logging;
 pub mod obis_utils;
+pub mod prometheus;
+pub mod schedule;
+pub mod simulation;
 pub mod storage;
 pub mod task_monitor;
+#[cfg(any(feature = "sml", feature = "oms"))]
+pub mod transform;
 pub mod discovered_devices;
+pub mod file_export;
 
 // Re-export common types for easier access
 pub use models::{Device, DeviceType, DeviceStatus};
@@ -44,6 +55,7 @@ pub use config::CONFIG;
 pub use storage::StoredData;
 pub use task_monitor::{TaskMonitor, TaskInfo, TaskStatus};
 pub use discovered_devices::{init_discovered_devices, get_discovered_devices, DiscoveredDevice, DiscoveredDeviceUpdate};
+pub use file_export::FileExportManager;
 
 #[cfg(feature = "api")]
 pub use api::ApiManager;
@@ -53,17 +65,93 @@ pub use metering_oms::OmsManager;
 pub use metering_62056::Iec62056Manager;
 #[cfg(feature = "sml")]
 pub use metering_sml::SmlManager;
+#[cfg(feature = "tibber")]
+pub use metering_tibber::TibberManager;
 #[cfg(feature = "victron")]
 pub use metering_victron::VictronManager;
 #[cfg(feature = "zenner-datahub")]
 pub use metering_zennerdatahub::ZennerDatahubManager;
 #[cfg(feature = "knx")]
 pub use metering_knx::KnxManager;
+#[cfg(feature = "mqtt-input")]
+pub use metering_mqtt_input::MqttInputManager;
 
+use std::{collections::{HashMap, HashSet}, sync::Mutex};
+use lazy_static::lazy_static;
+use log::warn;
+
+/// Current unix timestamp in seconds, used for all metering and health timestamps
 pub fn get_unix_ts() -> u64 {
     return std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_secs();
 }
 
+/// Stable id of a meter, built from the protocol and the sanitized meter name
 pub fn get_id(protocol: String, meter_name: &String) -> String {
-    return format!("{}-{}-{:?}", protocol, meter_name, get_unix_ts());
+    return get_id_with_unique_id(protocol, meter_name, None);
+}
+
+lazy_static! {
+    /* Names (or unique ids) each id was built from, names sanitized to the same id are different meters sharing topics */
+    static ref ID_SOURCES: Mutex<HashMap<String, HashSet<String>>> = Mutex::new(HashMap::new());
+}
+
+/// Like get_id, but a unique id from the configuration takes precedence over the meter name
+pub fn get_id_with_unique_id(protocol: String, meter_name: &String, unique_id: Option<&String>) -> String {
+    let source = unique_id.unwrap_or(meter_name);
+    let name: String = source
+        .trim()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' { c } else { '_' })
+        .collect();
+    let id = format!("{}-{}", protocol, name);
+    record_id_source(&id, source);
+    id
+}
+
+/// Remember the name an id was built from, returns false (and warns once) if another name already got the same id
+fn record_id_source(id: &str, source: &str) -> bool {
+    let mut sources = ID_SOURCES.lock().unwrap();
+    let names = sources.entry(id.to_string()).or_default();
+    if names.contains(source) {
+        return names.len() == 1;
+    }
+
+    if !names.is_empty() {
+        let mut others: Vec<&String> = names.iter().collect();
+        others.sort();
+        warn!("Meter '{}' gets the id {} like {:?}, their values and topics collide, use distinct names or a unique_id", source, id, others);
+    }
+    names.insert(source.to_string());
+    names.len() == 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_id_is_stable() {
+        let name = "Grid Meter/1".to_string();
+        let first = get_id("modbus".to_string(), &name);
+        assert_eq!(first, get_id("modbus".to_string(), &name));
+        assert_eq!(first, "modbus-Grid_Meter_1");
+
+        /* The same name with another protocol is another meter */
+        assert_ne!(first, get_id("knx".to_string(), &name));
+
+        let unique = "heatpump-01".to_string();
+        assert_eq!(get_id_with_unique_id("modbus".to_string(), &name, Some(&unique)), "modbus-heatpump-01");
+    }
+
+    #[test]
+    fn test_get_id_collisions() {
+        assert!(record_id_source("collide-a_b", "a/b"));
+        assert!(record_id_source("collide-a_b", "a/b"));
+
+        /* Another name sanitized to the same id */
+        let other = "a_b".to_string();
+        assert_eq!(get_id("collide".to_string(), &other), "collide-a_b");
+        assert!(!record_id_source("collide-a_b", "a_b"));
+        assert!(!record_id_source("collide-a_b", "a/b"));
+    }
 }
\ No newline at end of file
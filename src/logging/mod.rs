@@ -0,0 +1,130 @@
+//! Logging to stderr and optionally to a rotating file
+//!
+//! Stderr is filtered by E2M_LOG_LEVEL (env_logger syntax, default "info"). The file log
+//! is enabled from the logging section once the configuration is loaded, so everything
+//! logged while loading it only shows up on stderr. For a path like config/e2m.log the
+//! current file is config/e2m_rCURRENT.log, rotated files are config/e2m_r00000.log and so on.
+
+use std::str::FromStr;
+use std::sync::RwLock;
+use flexi_logger::{Cleanup, Criterion, DeferredNow, FileSpec, Naming};
+use flexi_logger::writers::{FileLogWriter, LogWriter};
+use lazy_static::lazy_static;
+use log::{LevelFilter, Log, Metadata, Record};
+
+use crate::config::LoggingConfig;
+
+lazy_static! {
+    static ref FILE_LOG: RwLock<Option<(LevelFilter, FileLogWriter)>> = RwLock::new(None);
+}
+
+struct E2mLogger {
+    stderr: env_logger::Logger,
+}
+
+impl Log for E2mLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        if self.stderr.enabled(metadata) {
+            return true;
+        }
+        matches!(FILE_LOG.read().unwrap().as_ref(), Some((level, _)) if metadata.level() <= *level)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.stderr.matches(record) {
+            self.stderr.log(record);
+        }
+
+        if let Some((level, writer)) = FILE_LOG.read().unwrap().as_ref() {
+            if record.level() <= *level {
+                let _ = writer.write(&mut DeferredNow::new(), record);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        self.stderr.flush();
+        if let Some((_, writer)) = FILE_LOG.read().unwrap().as_ref() {
+            writer.flush().ok();
+        }
+    }
+}
+
+/// Install the logger, stderr only until the file log is enabled
+pub fn init() {
+    let default_filter = std::env::var("E2M_LOG_LEVEL").unwrap_or("info".to_string());
+    let stderr = env_logger::Builder::from_env(env_logger::Env::new().default_filter_or(default_filter)).build();
+    let max_level = stderr.filter();
+
+    if log::set_boxed_logger(Box::new(E2mLogger { stderr })).is_ok() {
+        log::set_max_level(max_level);
+    }
+}
+
+fn build_file_writer(config: &LoggingConfig) -> Result<(LevelFilter, FileLogWriter), String> {
+    let level = LevelFilter::from_str(&config.level)
+        .map_err(|_| format!("invalid log level \"{}\"", config.level))?;
+
+    let writer = FileLogWriter::builder(FileSpec::try_from(&config.path).map_err(|e| e.to_string())?)
+        .format(flexi_logger::detailed_format)
+        .rotate(Criterion::Size(config.max_size), Naming::Numbers, Cleanup::KeepLogFiles(config.max_files))
+        .append()
+        .try_build()
+        .map_err(|e| e.to_string())?;
+
+    Ok((level, writer))
+}
+
+/// Additionally write the log to the configured file
+pub fn enable_file_logging(config: &LoggingConfig) -> Result<(), String> {
+    let (level, writer) = build_file_writer(config)?;
+
+    if level > log::max_level() {
+        log::set_max_level(level);
+    }
+    *FILE_LOG.write().unwrap() = Some((level, writer));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn logging_config(path: &std::path::Path, level: &str) -> LoggingConfig {
+        LoggingConfig {
+            enabled: true,
+            path: path.to_string_lossy().to_string(),
+            level: level.to_string(),
+            max_size: 1024,
+            max_files: 2,
+        }
+    }
+
+    #[test]
+    fn test_file_writer() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("e2m.log");
+
+        let (level, writer) = build_file_writer(&logging_config(&path, "debug")).unwrap();
+        assert_eq!(level, LevelFilter::Debug);
+
+        let record = Record::builder()
+            .args(format_args!("meter read"))
+            .level(log::Level::Info)
+            .target("energy2mqtt")
+            .build();
+        writer.write(&mut DeferredNow::new(), &record).unwrap();
+        writer.flush().unwrap();
+
+        let content = std::fs::read_to_string(dir.path().join("e2m_rCURRENT.log")).unwrap();
+        assert!(content.contains("INFO"));
+        assert!(content.contains("meter read"));
+    }
+
+    #[test]
+    fn test_invalid_level() {
+        let dir = tempfile::tempdir().unwrap();
+        let err = build_file_writer(&logging_config(&dir.path().join("e2m.log"), "loud")).err().unwrap();
+        assert_eq!(err, "invalid log level \"loud\"");
+    }
+}
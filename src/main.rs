@@ -1,9 +1,9 @@
 
 
-use energy2mqtt::{CONFIG, DeviceManager, init_discovered_devices, get_discovered_devices, mqtt::{MqttManager, internal_commands::CommandHandler, publish_uptime}};
+use energy2mqtt::{CONFIG, DeviceManager, FileExportManager, logging, simulation, init_discovered_devices, get_discovered_devices, mqtt::{MqttManager, internal_commands::CommandHandler, publish_offline, publish_uptime}};
 use tokio::task::JoinHandle;
 use std::{env, path::PathBuf, time::Duration};
-use log::info;
+use log::{error, info};
 
 #[cfg(feature = "api")]
 use energy2mqtt::ApiManager;
@@ -13,21 +13,49 @@ use energy2mqtt::OmsManager;
 use energy2mqtt::Iec62056Manager;
 #[cfg(feature = "sml")]
 use energy2mqtt::SmlManager;
+#[cfg(feature = "tibber")]
+use energy2mqtt::TibberManager;
 #[cfg(feature = "victron")]
 use energy2mqtt::VictronManager;
 #[cfg(feature = "zenner-datahub")]
 use energy2mqtt::ZennerDatahubManager;
 #[cfg(feature = "knx")]
 use energy2mqtt::KnxManager;
+#[cfg(feature = "mqtt-input")]
+use energy2mqtt::MqttInputManager;
 #[cfg(feature = "modbus")]
 use energy2mqtt::ModbusManger;
 
 
+/// Resolves on SIGINT or, on unix, SIGTERM which is what container runtimes send on stop
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {},
+                    _ = sigterm.recv() => {},
+                }
+            }
+            Err(e) => {
+                error!("Unable to register SIGTERM handler: {e}");
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
     // Initialize logging
-    let default_filter =  std::env::var("E2M_LOG_LEVEL").unwrap_or("info".to_string());
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or(default_filter));
+    logging::init();
 
     env::set_var("RUST_BACKTRACE", "1");
 
@@ -37,6 +65,21 @@ async fn main() -> std::io::Result<()> {
         let base_path = &config.base_path;
         PathBuf::from(base_path).join(&config.config.storage.discovered_devices_path)
     };
+    let logging_config = CONFIG.read().unwrap().config.logging.clone();
+    if logging_config.enabled {
+        match logging::enable_file_logging(&logging_config) {
+            Ok(()) => info!("Logging to {}", logging_config.path),
+            Err(e) => error!("Unable to log to {}: {}", logging_config.path, e),
+        }
+    }
+
+    /* Better stop right away than running without the webserver */
+    let httpd_config = CONFIG.read().unwrap().config.httpd.clone();
+    if let (true, Err(e)) = (httpd_config.enabled, httpd_config.socket_addr()) {
+        error!("{e}, please fix the config");
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, e));
+    }
+
     init_discovered_devices(discovered_devices_path);
     info!("Discovered devices store initialized");
 
@@ -45,6 +88,7 @@ async fn main() -> std::io::Result<()> {
     
     // Initialize device manager
     let device_manager = DeviceManager::new(tx);
+    mqtt.set_device_db(device_manager.get_db());
     
     let mut threads: Vec<JoinHandle<()>> = Vec::new();
 
@@ -53,6 +97,12 @@ async fn main() -> std::io::Result<()> {
         mqtt.start_thread(bsender).await;
     }));
 
+    /* Local file export consumes the broadcast so MQTT is never blocked by disk I/O */
+    let mut file_export = FileExportManager::new(device_manager.get_broadcast_receiver());
+    threads.push(tokio::spawn(async move {
+        file_export.start_thread().await;
+    }));
+
     // Start Modbus if needed
     let mr_sender = device_manager.get_sender_instance();
     let mut modbus = ModbusManger::new(mr_sender);
@@ -89,6 +139,16 @@ async fn main() -> std::io::Result<()> {
         }));
     }
 
+    // Start Tibber manager
+    #[cfg(feature = "tibber")]
+    {
+        let mr_sender = device_manager.get_sender_instance();
+        let mut tibber = TibberManager::new(mr_sender);
+        threads.push(tokio::spawn(async move {
+            tibber.start_thread().await;
+        }));
+    }
+
     #[cfg(feature = "victron")]
     {
         // Start Victron managers for each configured instance
@@ -128,12 +188,25 @@ async fn main() -> std::io::Result<()> {
         }));
     }
 
-    #[cfg(feature = "api")] {
+    #[cfg(feature = "mqtt-input")]
+    {
+        // Start the generic MQTT input
+        let mr_sender = device_manager.get_sender_instance();
+        let mut mqtt_input = MqttInputManager::new(mr_sender);
+        threads.push(tokio::spawn(async move {
+            mqtt_input.start_thread().await;
+        }));
+    }
+
+    #[cfg(feature = "api")]
+    if CONFIG.read().unwrap().config.httpd.enabled {
         /* Run our api gateway now */
-        let api = ApiManager::new();
+        let api = ApiManager::new(device_manager.get_broadcast_sender(), device_manager.get_sender_instance());
         threads.push(tokio::spawn(async move {
             let _ = api.start_thread().await;
         }));
+    } else {
+        info!("Webserver disabled, the API and web interface are not available");
     }
 
     /* Make sure to handle the dirty flag of the configuration and discovered devices */
@@ -174,6 +247,9 @@ async fn main() -> std::io::Result<()> {
         }
     }));
 
+    /* Kept for the shutdown to tell MQTT that we are going down */
+    let shutdown_sender = device_manager.get_sender_instance();
+
     /* Last but not least start our command handling */
     let mr_sender = device_manager.get_sender_instance();
     let command = CommandHandler::new(mr_sender);
@@ -182,9 +258,49 @@ async fn main() -> std::io::Result<()> {
     }));
 
 
+    /* Replay captured telegrams instead of waiting for live data: --simulate <file> */
+    let args: Vec<String> = env::args().collect();
+    if let Some(path) = args.iter().position(|a| a == "--simulate").and_then(|i| args.get(i + 1)).cloned() {
+        threads.push(tokio::spawn(async move {
+            simulation::replay(&path, Duration::from_secs(1)).await;
+            /* Keep running so everything gets published */
+            std::future::pending::<()>().await;
+        }));
+    }
+
     info!("All modules started, now waiting for a signal to exit");
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+
     loop {
-        tokio::time::sleep(Duration::from_secs(10)).await;
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(10)) => {},
+            _ = &mut shutdown => {
+                info!("Received shutdown signal, going down");
+                publish_offline(&shutdown_sender).await;
+
+                let mut c = CONFIG.write().unwrap();
+                if c.is_dirty() {
+                    c.save();
+                }
+                drop(c);
+
+                if let Some(store) = get_discovered_devices() {
+                    if let Err(e) = store.save_if_dirty() {
+                        error!("Failed to save discovered devices: {}", e);
+                    }
+                }
+
+                /* Give the MQTT thread a moment to send what is still queued */
+                tokio::time::sleep(Duration::from_secs(2)).await;
+
+                for task in threads.iter_mut() {
+                    task.abort();
+                }
+                break;
+            }
+        }
+
         let mut kill_all_tasks = false;
         for task in threads.iter() {
             if task.is_finished() {
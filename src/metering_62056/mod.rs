@@ -1,10 +1,10 @@
 use lazy_static::lazy_static;
 use std::sync::Mutex;
-use crate::{models::DeviceProtocol, mqtt::{SubscribeData, Transmission}, MeteringData};
+use crate::{config::{ConfigBases, Iec62056MeterConfig}, get_config_or_panic, get_id, models::DeviceProtocol, mqtt::{home_assistant::build_metering_discovery, SubscribeData, Transmission}, obis_utils, MeteringData, CONFIG};
 use log::{debug, error, info, warn};
 use tokio::sync::mpsc::Sender;
 use thiserror::Error;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 pub mod utils;
 pub mod structs;
@@ -13,6 +13,8 @@ pub mod meter_definitions;
 
 pub struct Iec62056Manager {
     sender: Sender<Transmission>,
+    /* Meters we already sent a Home Assistant discovery for */
+    discovered: HashSet<String>,
 }
 
 lazy_static! {
@@ -21,7 +23,7 @@ lazy_static! {
 
 impl Iec62056Manager {
     pub fn new(sender: Sender<Transmission>) -> Self {
-        Self { sender }
+        Self { sender, discovered: HashSet::new() }
     }
 
     pub async fn start_thread(&mut self) {
@@ -40,8 +42,16 @@ impl Iec62056Manager {
         while let Some((_topic, message)) = receiver.recv().await {
             debug!("Received IEC 62056-21 message: {}", message);
             
-            match parse_iec62056_telegram(&message) {
+            let meters = get_config_or_panic!("iec62056", ConfigBases::Iec62056);
+            match parse_iec62056_telegram(&message, &meters) {
                 Ok(metering_data) => {
+                    if !self.discovered.contains(&metering_data.meter_name) {
+                        let proto = metering_data.metered_values.get("proto");
+                        let manu = proto.and_then(|p| p.get("manufacturer")).and_then(|v| v.as_str()).map(|v| v.to_string());
+                        let model = proto.and_then(|p| p.get("identification")).and_then(|v| v.as_str()).map(|v| v.to_string());
+                        let _ = self.sender.send(Transmission::AutoDiscovery2(build_metering_discovery(&metering_data, manu, model))).await;
+                        self.discovered.insert(metering_data.meter_name.clone());
+                    }
                     let _ = self.sender.send(Transmission::Metering(metering_data)).await;
                 }
                 Err(e) => {
@@ -62,7 +72,7 @@ pub enum Iec62056ParseError {
     InvalidObisCode,
     #[error("Checksum verification failed")]
     ChecksumFailed,
-    #[error("Device not configured")]
+    #[error("No identification line and no configured meter matches the data block")]
     DeviceNotConfigured,
     #[error("Missing identification line")]
     MissingIdentification,
@@ -88,42 +98,80 @@ pub enum ProtocolMode {
     ModeD,
 }
 
-fn parse_iec62056_telegram(telegram: &str) -> Result<MeteringData, Iec62056ParseError> {
-    let lines: Vec<&str> = telegram.lines().collect();
-    
-    if lines.is_empty() {
-        return Err(Iec62056ParseError::InvalidFormat);
+/// OBIS codes carrying the serial number, they identify the configured meter of a Mode D data block
+const SERIAL_CODES: [&str; 4] = ["0-0:C.1.0", "0-0:96.1.0", "1-0:0.0.0", "1-0:96.1.0"];
+
+/// The configured meter with the serial number sent in the data block
+fn find_meter_by_serial<'a>(values: &serde_json::Map<String, serde_json::Value>, meters: &'a [Iec62056MeterConfig]) -> Option<&'a Iec62056MeterConfig> {
+    let serials: Vec<String> = SERIAL_CODES.iter()
+        .filter_map(|code| values.get(*code))
+        .map(|v| match v {
+            serde_json::Value::String(s) => s.clone(),
+            v => v.to_string(),
+        })
+        .collect();
+
+    meters.iter().find(|m| m.serial.as_ref().is_some_and(|s| serials.contains(s)))
+}
+
+/// Find the configured meter a data block without identification line belongs to, a single
+/// meter without serial number takes every data block
+fn find_configured_meter<'a>(values: &serde_json::Map<String, serde_json::Value>, meters: &'a [Iec62056MeterConfig]) -> Option<&'a Iec62056MeterConfig> {
+    find_meter_by_serial(values, meters)
+        .or(match meters {
+            [single] if single.serial.is_none() => Some(single),
+            _ => None,
+        })
+}
+
+/// Move the values (and units) of OBIS codes with a field name of their medium (e.g. 7-0:3.0.0) to that name (gas_volume)
+fn use_field_names(values: &mut serde_json::Map<String, serde_json::Value>) {
+    let codes: Vec<String> = values.keys().filter(|k| !k.ends_with("_unit")).cloned().collect();
+    for code in codes {
+        let Some(field) = obis_utils::get_obis_medium(&code)
+            .and_then(|medium| obis_parser::get_obis_mapping_for_medium(medium).get(code.as_str()).copied()) else {
+            continue;
+        };
+
+        if let Some(value) = values.remove(&code) {
+            values.insert(field.to_string(), value);
+        }
+        if let Some(unit) = values.remove(&format!("{code}_unit")) {
+            values.insert(format!("{field}_unit"), unit);
+        }
     }
+}
 
-    // Parse identification line (must start with '/')
-    let identification_line = lines.first()
-        .ok_or(Iec62056ParseError::MissingIdentification)?;
-    
-    if !identification_line.starts_with('/') {
-        return Err(Iec62056ParseError::MissingIdentification);
+fn parse_iec62056_telegram(telegram: &str, meters: &[Iec62056MeterConfig]) -> Result<MeteringData, Iec62056ParseError> {
+    if !utils::verify_block_check(telegram) {
+        return Err(Iec62056ParseError::ChecksumFailed);
     }
 
-    let device_info = utils::parse_identification_line(identification_line)?;
+    /* The data block may be framed by STX ... ETX and the block check character */
+    let lines: Vec<&str> = telegram.lines()
+        .map(|l| l.trim_start_matches('\x02'))
+        .filter(|l| !l.trim().is_empty())
+        .collect();
+
+    if lines.is_empty() {
+        return Err(Iec62056ParseError::InvalidFormat);
+    }
+
+    // Read-outs start with the identification line, Mode D meters push the data block only
+    let device_info = match lines[0].starts_with('/') {
+        true => Some(utils::parse_identification_line(lines[0])?),
+        false => None,
+    };
     debug!("Parsed device info: {:?}", device_info);
 
     // Create metering data object
     let mut mr = MeteringData::new().unwrap();
     mr.protocol = DeviceProtocol::IEC62056;
-    mr.meter_name = device_info.full_id.clone();
-
-    let mut protocol_map = serde_json::Map::new();
-    protocol_map.insert("type".to_string(), "iec62056".into());
-    protocol_map.insert("manufacturer".to_string(), device_info.manufacturer.clone().into());
-    protocol_map.insert("identification".to_string(), device_info.identification.clone().into());
-    protocol_map.insert("mode".to_string(), device_info.mode.clone().into());
 
     // Parse data lines (OBIS codes and values)
+    let data_lines = if device_info.is_some() { &lines[1..] } else { &lines[..] };
     let mut has_data = false;
-    for line in &lines[1..] {
-        if line.trim().is_empty() {
-            continue;
-        }
-        
+    for line in data_lines {
         // Check for end of telegram
         if line.starts_with('!') {
             debug!("End of telegram found");
@@ -134,7 +182,11 @@ fn parse_iec62056_telegram(telegram: &str) -> Result<MeteringData, Iec62056Parse
         match obis_parser::parse_obis_line(line) {
             Ok(obis_data) => {
                 let code_clone = obis_data.code.clone();
-                mr.metered_values.insert(obis_data.code, obis_data.value.into());
+                let value = match obis_data.number {
+                    Some(number) => number.into(),
+                    None => obis_data.value.into(),
+                };
+                mr.metered_values.insert(obis_data.code, value);
                 if let Some(unit) = obis_data.unit {
                     mr.metered_values.insert(format!("{}_unit", code_clone), unit.into());
                 }
@@ -150,6 +202,37 @@ fn parse_iec62056_telegram(telegram: &str) -> Result<MeteringData, Iec62056Parse
         warn!("No valid OBIS data found in telegram");
     }
 
+    let mut protocol_map = serde_json::Map::new();
+    protocol_map.insert("type".to_string(), "iec62056".into());
+
+    let meter = match device_info {
+        Some(device_info) => {
+            /* A configured meter with the same serial number gives the meter its name */
+            let meter = find_meter_by_serial(&mr.metered_values, meters);
+            mr.meter_name = meter.map_or(device_info.full_id.clone(), |m| m.name.clone());
+            mr.tenant = meter.and_then(|m| m.tenant.clone()).unwrap_or_default();
+            protocol_map.insert("manufacturer".to_string(), device_info.manufacturer.into());
+            protocol_map.insert("identification".to_string(), device_info.identification.into());
+            protocol_map.insert("mode".to_string(), device_info.mode.into());
+            meter
+        }
+        None => {
+            let meter = find_configured_meter(&mr.metered_values, meters)
+                .ok_or(Iec62056ParseError::DeviceNotConfigured)?;
+            mr.meter_name = meter.name.clone();
+            mr.tenant = meter.tenant.clone().unwrap_or_default();
+            protocol_map.insert("manufacturer".to_string(), meter.manufacturer.clone().unwrap_or_default().into());
+            protocol_map.insert("identification".to_string(), meter.serial.clone().unwrap_or_default().into());
+            protocol_map.insert("mode".to_string(), "D".into());
+            Some(meter)
+        }
+    };
+
+    if meter.is_some_and(|m| m.field_names) {
+        use_field_names(&mut mr.metered_values);
+    }
+
+    mr.id = get_id("iec62056".to_string(), &mr.meter_name);
     mr.metered_values.insert("proto".to_string(), protocol_map.into());
     Ok(mr)
 }
@@ -166,16 +249,92 @@ mod tests {
 1-0:15.7.0(001.234*kW)
 !";
         
-        let result = parse_iec62056_telegram(telegram);
+        let result = parse_iec62056_telegram(telegram, &[]);
         assert!(result.is_ok());
         let metering_data = result.unwrap();
         assert_eq!(metering_data.protocol, DeviceProtocol::IEC62056);
+        assert_eq!(metering_data.metered_values["1-0:1.8.1"], 123.456);
+        assert_eq!(metering_data.metered_values["1-0:1.8.1_unit"], "kWh");
+        assert_eq!(metering_data.metered_values["1-0:15.7.0"], 1.234);
+    }
+
+    fn meter(name: &str, serial: Option<&str>) -> Iec62056MeterConfig {
+        Iec62056MeterConfig { name: name.to_string(), serial: serial.map(|s| s.to_string()), manufacturer: Some("ESY".to_string()), tenant: None, field_names: false }
+    }
+
+    /// Frame a data block like a Mode D meter: STX, data, ETX and the block check character
+    fn mode_d_frame(data: &str) -> String {
+        let bcc = data.bytes().chain([0x03]).fold(0u8, |a, b| a ^ b);
+        format!("\x02{data}\x03{}", bcc as char)
+    }
+
+    #[test]
+    fn test_identified_meter_uses_configured_name() {
+        let telegram = "/ESY5Q3DA1004 V3.04\r\n0-0:C.1.0(1ESY1160123456)\r\n1-0:1.8.0(001234.5678*kWh)\r\n!\r\n";
+        let anonymous = parse_iec62056_telegram(telegram, &[]).unwrap().meter_name;
+        assert!(anonymous.starts_with("ESY"));
+
+        /* Only the serial number identifies the meter, a meter without serial does not match */
+        assert_eq!(parse_iec62056_telegram(telegram, &[meter("grid", None)]).unwrap().meter_name, anonymous);
+        assert_eq!(parse_iec62056_telegram(telegram, &[meter("grid", Some("1ESY1160123456"))]).unwrap().meter_name, "grid");
+
+        /* Tenant and id of the configured meter end up in discovery and topics */
+        let mut flat = meter("grid", Some("1ESY1160123456"));
+        flat.tenant = Some("flat1".to_string());
+        let data = parse_iec62056_telegram(telegram, &[flat]).unwrap();
+        assert_eq!(data.tenant, "flat1");
+        assert_eq!(data.id, "iec62056-grid");
+    }
+
+    #[test]
+    fn test_parse_mode_d_data_block() {
+        let frame = mode_d_frame("0-0:C.1.0(1ESY1160123456)\r\n1-0:1.8.0(001234.5678*kWh)\r\n!\r\n");
+
+        /* Without identification line the meter has to be configured */
+        assert!(matches!(parse_iec62056_telegram(&frame, &[]), Err(Iec62056ParseError::DeviceNotConfigured)));
+
+        let data = parse_iec62056_telegram(&frame, &[meter("grid", None)]).unwrap();
+        assert_eq!(data.meter_name, "grid");
+        assert_eq!(data.metered_values["1-0:1.8.0"], 1234.5678);
+        assert_eq!(data.metered_values["proto"]["mode"], "D");
+        assert_eq!(data.metered_values["proto"]["manufacturer"], "ESY");
+
+        /* Several meters are told apart by their serial number */
+        let meters = [meter("heatpump", Some("1ESY1160999999")), meter("grid", Some("1ESY1160123456"))];
+        assert_eq!(parse_iec62056_telegram(&frame, &meters).unwrap().meter_name, "grid");
+        assert!(parse_iec62056_telegram(&frame, &meters[..1]).is_err());
+
+        /* Broken block check character */
+        let mut broken = frame.clone();
+        broken.pop();
+        broken.push('\x7f');
+        assert!(matches!(parse_iec62056_telegram(&broken, &[meter("grid", None)]), Err(Iec62056ParseError::ChecksumFailed)));
+    }
+
+    #[test]
+    fn test_field_names() {
+        let telegram = "/ESY5Q3DA1004 V3.04\r\n0-0:C.1.0(1ESY1160123456)\r\n7-0:3.0.0(00123.456*m3)\r\n1-0:1.8.1(000123.456*kWh)\r\n!\r\n";
+
+        /* Without the option values stay under their OBIS code */
+        let data = parse_iec62056_telegram(telegram, &[meter("gas", Some("1ESY1160123456"))]).unwrap();
+        assert_eq!(data.metered_values["7-0:3.0.0"], 123.456);
+
+        let mut gas = meter("gas", Some("1ESY1160123456"));
+        gas.field_names = true;
+        let data = parse_iec62056_telegram(telegram, &[gas]).unwrap();
+        assert_eq!(data.metered_values["gas_volume"], 123.456);
+        assert_eq!(data.metered_values["gas_volume_unit"], "m3");
+        assert!(!data.metered_values.contains_key("7-0:3.0.0"));
+        assert!(!data.metered_values.contains_key("7-0:3.0.0_unit"));
+        /* Codes without field name and the serial number are kept */
+        assert_eq!(data.metered_values["1-0:1.8.1"], 123.456);
+        assert_eq!(data.metered_values["0-0:C.1.0"], "1ESY1160123456");
     }
 
     #[test]
     fn test_parse_invalid_telegram() {
         let telegram = "invalid telegram format";
-        let result = parse_iec62056_telegram(telegram);
+        let result = parse_iec62056_telegram(telegram, &[]);
         assert!(result.is_err());
     }
 }
\ No newline at end of file
@@ -30,6 +30,7 @@ pub fn parse_obis_line(line: &str) -> Result<ObisData, Iec62056ParseError> {
     
     // Parse value and unit
     let unit = obis_utils::extract_unit(value_content);
+    let number = obis_utils::parse_numeric_value(value_content);
     let value = value_content.to_string();
     
     debug!("Parsed OBIS line - Code: {}, Value: {}, Unit: {:?}", 
@@ -39,6 +40,7 @@ pub fn parse_obis_line(line: &str) -> Result<ObisData, Iec62056ParseError> {
         code: obis_code,
         value,
         unit,
+        number,
     })
 }
 
@@ -54,6 +56,10 @@ pub fn get_ebz_obis_mapping() -> std::collections::HashMap<&'static str, &'stati
     obis_utils::get_ebz_obis_mapping()
 }
 
+pub fn get_obis_mapping_for_medium(medium: u8) -> std::collections::HashMap<&'static str, &'static str> {
+    obis_utils::get_obis_mapping_for_medium(medium)
+}
+
 pub fn validate_obis_code(code: &str) -> bool {
     obis_utils::validate_obis_code(code)
 }
@@ -71,6 +77,7 @@ mod tests {
         assert_eq!(obis_data.code, "1-0:1.8.1");
         assert_eq!(obis_data.value, "000123.456*kWh");
         assert_eq!(obis_data.unit, Some("kWh".to_string()));
+        assert_eq!(obis_data.number, Some(123.456));
     }
 
     #[test]
@@ -67,6 +67,22 @@ pub fn verify_checksum(telegram: &str, provided_checksum: &str) -> bool {
     calculated == provided_checksum
 }
 
+/// Verify the block check character following ETX, an XOR over everything after STX up to
+/// and including ETX. Telegrams without such framing have nothing to verify.
+pub fn verify_block_check(telegram: &str) -> bool {
+    let bytes = telegram.as_bytes();
+    let stx = bytes.iter().position(|b| *b == 0x02);
+    let etx = bytes.iter().rposition(|b| *b == 0x03);
+
+    match (stx, etx) {
+        (Some(stx), Some(etx)) if stx < etx => match bytes.get(etx + 1) {
+            Some(bcc) => bytes[stx + 1..=etx].iter().fold(0, |a, b| a ^ b) == *bcc,
+            None => true,
+        },
+        _ => true,
+    }
+}
+
 pub fn get_meter_type_from_manufacturer(manufacturer: &str) -> super::structs::MeterType {
     match manufacturer.to_uppercase().as_str() {
         "ESY" | "EAS" => super::structs::MeterType::EasyMeter,
@@ -3,7 +3,7 @@
 //! This module implements KNX bus communication via KNX/IP UDP tunneling
 //! using the knx_rust library.
 
-use crate::config::{ConfigBases, ConfigChange, ConfigOperation, KnxAdapterConfig, KnxDatapointType, KnxMeterConfig};
+use crate::config::{ConfigBases, ConfigChange, ConfigOperation, KnxAdapterConfig, KnxDatapointType, KnxMeterConfig, KnxSwitchConfig};
 use crate::models::DeviceProtocol;
 use crate::mqtt::home_assistant::{get_command_topic, get_state_topic, HaComponent2, HaSensor};
 use crate::mqtt::{publish_protocol_count, SubscribeData, Transmission};
@@ -15,7 +15,7 @@ use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio::sync::mpsc::Sender;
-use tokio::sync::RwLock;
+use tokio::sync::{Notify, RwLock};
 
 pub mod group_address;
 pub mod knxd_client;
@@ -27,10 +27,15 @@ use knxd_client::{KnxClient, KnxError};
 #[derive(Debug, Clone)]
 struct SwitchConfig {
     group_address: GroupAddress,
+    /// Address the switch reports its state on, the group address if there is no feedback address
+    state_address: GroupAddress,
     meter_name: String,
     switch_name: String,
 }
 
+/// Time the actuator gets to answer the state read after a switch command
+const SWITCH_FEEDBACK_WAIT: Duration = Duration::from_secs(1);
+
 /// A cached value from the KNX bus
 #[derive(Debug, Clone)]
 struct CachedValue {
@@ -276,6 +281,7 @@ async fn run_adapter_connection(
     let response_wait_secs = config.response_wait.unwrap_or(3);
 
     let client = Arc::new(client);
+    let switched = Notify::new();
 
     info!(
         "KNX adapter '{}': Starting poll cycle (interval={}s, wait={}s)",
@@ -303,15 +309,18 @@ async fn run_adapter_connection(
             response_wait_secs,
             sender,
             stats.clone(),
+            &switched,
         ) => {
             warn!("KNX adapter '{}': Poll cycle task ended: {:?}", config.name, result);
             result
         }
         result = switch_control_task(
             client.clone(),
+            cache.clone(),
             &config.name,
             switch_map.clone(),
             sender,
+            &switched,
         ) => {
             warn!("KNX adapter '{}': Switch control task ended: {:?}", config.name, result);
             result
@@ -379,6 +388,7 @@ async fn poll_cycle_task(
     response_wait_secs: u64,
     sender: &Sender<Transmission>,
     stats: SharedStats,
+    switched: &Notify,
 ) -> Result<(), KnxError> {
     let mut last_poll = Instant::now() - Duration::from_secs(poll_interval_secs);
     let mut discovered_meters: HashSet<String> = HashSet::new();
@@ -430,71 +440,93 @@ async fn poll_cycle_task(
             info!("KNX adapter '{}': Waiting {}s for responses", config.name, response_wait_secs);
             tokio::time::sleep(Duration::from_secs(response_wait_secs)).await;
 
-            let cache_snapshot = {
-                let cache_guard = cache.read().await;
-                cache_guard.clone()
-            };
-
-            let mut cycle_cache_hits: u64 = 0;
-            let mut cycle_cache_misses: u64 = 0;
-            let mut cycle_meters_published: u64 = 0;
-
-            for meter in &config.meters {
-                if !meter.enabled {
-                    continue;
-                }
+            stats.write().await.inc("poll_cycles_completed");
+            let (hits, misses, published) = publish_cached_values(config, &cache, sender, &stats, &mut discovered_meters).await;
 
-                // Send Home Assistant discovery if not yet done for this meter
-                let meter_key = sanitize_id(&format!("{}_{}", config.name, meter.name));
-                if !discovered_meters.contains(&meter_key) {
-                    info!(
-                        "KNX adapter '{}': Sending HA discovery for meter '{}'",
-                        config.name, meter.name
-                    );
-                    let disc = build_ha_discovery(&config.name, meter);
-                    let _ = sender.send(Transmission::AutoDiscovery2(disc)).await;
-                    discovered_meters.insert(meter_key);
+            info!(
+                "KNX adapter '{}': Poll cycle complete (hits={}, misses={}, published={})",
+                config.name, hits, misses, published
+            );
+            last_poll = Instant::now();
+        }
 
-                    // Give Home Assistant time to process discovery
-                    tokio::time::sleep(Duration::from_millis(500)).await;
-                }
+        let sleep_duration = Duration::from_secs(poll_interval_secs)
+            .saturating_sub(Instant::now().duration_since(last_poll))
+            .max(Duration::from_secs(1));
 
-                let (hits, misses, published) = publish_meter_values(&config.name, meter, &cache_snapshot, sender).await;
-                cycle_cache_hits += hits;
-                cycle_cache_misses += misses;
-                if published {
-                    cycle_meters_published += 1;
-                }
+        tokio::select! {
+            _ = tokio::time::sleep(sleep_duration) => {}
+            _ = switched.notified() => {
+                /* Show the new switch state right away instead of with the next poll cycle */
+                tokio::time::sleep(SWITCH_FEEDBACK_WAIT).await;
+                debug!("KNX adapter '{}': Publishing switch feedback", config.name);
+                publish_cached_values(config, &cache, sender, &stats, &mut discovered_meters).await;
             }
+        }
+    }
+}
 
-            // Update stats
-            {
-                let mut stats_guard = stats.write().await;
-                stats_guard.add("cache_hits", cycle_cache_hits);
-                stats_guard.add("cache_misses", cycle_cache_misses);
-                stats_guard.inc("poll_cycles_completed");
-                for _ in 0..cycle_meters_published {
-                    stats_guard.inc("meters_published");
-                }
-            }
+/// Publish the cached values of all meters and the adapter
+/// Returns (cache_hits, cache_misses, meters_published) tuple
+async fn publish_cached_values(
+    config: &KnxAdapterConfig,
+    cache: &GroupAddressCache,
+    sender: &Sender<Transmission>,
+    stats: &SharedStats,
+    discovered_meters: &mut HashSet<String>,
+) -> (u64, u64, u64) {
+    let cache_snapshot = {
+        let cache_guard = cache.read().await;
+        cache_guard.clone()
+    };
+
+    let mut cycle_cache_hits: u64 = 0;
+    let mut cycle_cache_misses: u64 = 0;
+    let mut cycle_meters_published: u64 = 0;
 
-            // Publish adapter stats
-            let stats_snapshot = stats.read().await.clone();
-            publish_adapter_stats(&config.name, &stats_snapshot, sender).await;
+    for meter in &config.meters {
+        if !meter.enabled {
+            continue;
+        }
 
+        // Send Home Assistant discovery if not yet done for this meter
+        let meter_key = sanitize_id(&format!("{}_{}", config.name, meter.name));
+        if !discovered_meters.contains(&meter_key) {
             info!(
-                "KNX adapter '{}': Poll cycle complete (hits={}, misses={}, published={})",
-                config.name, cycle_cache_hits, cycle_cache_misses, cycle_meters_published
+                "KNX adapter '{}': Sending HA discovery for meter '{}'",
+                config.name, meter.name
             );
-            last_poll = Instant::now();
+            let disc = build_ha_discovery(&config.name, meter);
+            let _ = sender.send(Transmission::AutoDiscovery2(disc)).await;
+            discovered_meters.insert(meter_key);
+
+            // Give Home Assistant time to process discovery
+            tokio::time::sleep(Duration::from_millis(500)).await;
         }
 
-        let sleep_duration = Duration::from_secs(poll_interval_secs)
-            .saturating_sub(Instant::now().duration_since(last_poll))
-            .max(Duration::from_secs(1));
+        let (hits, misses, published) = publish_meter_values(&config.name, meter, &cache_snapshot, sender).await;
+        cycle_cache_hits += hits;
+        cycle_cache_misses += misses;
+        if published {
+            cycle_meters_published += 1;
+        }
+    }
 
-        tokio::time::sleep(sleep_duration).await;
+    // Update stats
+    {
+        let mut stats_guard = stats.write().await;
+        stats_guard.add("cache_hits", cycle_cache_hits);
+        stats_guard.add("cache_misses", cycle_cache_misses);
+        for _ in 0..cycle_meters_published {
+            stats_guard.inc("meters_published");
+        }
     }
+
+    // Publish adapter stats
+    let stats_snapshot = stats.read().await.clone();
+    publish_adapter_stats(config, &stats_snapshot, &cache_snapshot, sender).await;
+
+    (cycle_cache_hits, cycle_cache_misses, cycle_meters_published)
 }
 
 /// Publish all values for a meter in a single MQTT message
@@ -515,6 +547,7 @@ async fn publish_meter_values(
     meter_data.state_topic_base = "KNX".to_string();
     meter_data.meter_name = device_id.clone();
     meter_data.id = get_id("knx".to_string(), &device_id);
+    meter_data.tenant = meter.tenant.clone().unwrap_or_default();
     meter_data.transmission_time = get_unix_ts();
     meter_data.metered_time = meter_data.transmission_time;
 
@@ -810,12 +843,14 @@ async fn publish_meter_values(
     (cache_hits, cache_misses, published)
 }
 
-/// Publish adapter statistics to MQTT
+/// Publish adapter statistics and the state of adapter level switches to MQTT
 async fn publish_adapter_stats(
-    adapter_name: &str,
+    config: &KnxAdapterConfig,
     stats: &KnxAdapterStats,
+    cache: &HashMap<u16, CachedValue>,
     sender: &Sender<Transmission>,
 ) {
+    let adapter_name = &config.name;
     let device_id = sanitize_id(adapter_name);
 
     let mut stats_data = MeteringData::new().unwrap();
@@ -835,6 +870,16 @@ async fn publish_adapter_stats(
     stats_data.metered_values.insert("poll_cycles_completed".to_string(), serde_json::Value::from(stats.poll_cycles_completed));
     stats_data.metered_values.insert("meters_published".to_string(), serde_json::Value::from(stats.meters_published));
 
+    for switch in config.switches.iter().filter(|s| s.enabled) {
+        if let Some(ga) = switch_state_address(switch) {
+            if let Some(cached) = cache.get(&ga.to_u16()) {
+                if let Some(value) = parse_dpt_value(&KnxDatapointType::Switch, &cached.data) {
+                    stats_data.metered_values.insert(get_adapter_switch_key(switch), value);
+                }
+            }
+        }
+    }
+
     debug!("KNX {}: Publishing adapter stats", adapter_name);
     let _ = sender.send(Transmission::Metering(stats_data)).await;
 }
@@ -927,6 +972,13 @@ fn build_poll_addresses(config: &KnxAdapterConfig) -> Vec<(GroupAddress, KnxData
         }
     }
 
+    // Adapter level switches
+    for switch in config.switches.iter().filter(|s| s.enabled) {
+        if let Some(ga) = switch_state_address(switch) {
+            addresses.push((ga, KnxDatapointType::Switch));
+        }
+    }
+
     addresses
 }
 
@@ -1018,6 +1070,13 @@ fn build_group_address_set(config: &KnxAdapterConfig) -> GroupAddressSet {
         }
     }
 
+    // Adapter level switches
+    for switch in config.switches.iter().filter(|s| s.enabled) {
+        if let Some(ga) = switch_state_address(switch) {
+            set.insert(ga.to_u16());
+        }
+    }
+
     set
 }
 
@@ -1039,6 +1098,7 @@ fn build_switch_map(config: &KnxAdapterConfig) -> HashMap<String, SwitchConfig>
                 let topic = get_command_topic(&proto, &device_id, &"switch".to_string());
                 map.insert(topic, SwitchConfig {
                     group_address: ga,
+                    state_address: parse_state_address(meter.switch_state_ga.as_ref(), ga),
                     meter_name: meter.name.clone(),
                     switch_name: "switch".to_string(),
                 });
@@ -1054,6 +1114,7 @@ fn build_switch_map(config: &KnxAdapterConfig) -> HashMap<String, SwitchConfig>
                     let topic = get_command_topic(&proto, &device_id, &switch_name);
                     map.insert(topic, SwitchConfig {
                         group_address: ga,
+                        state_address: parse_state_address(phase.switch_state_ga.as_ref(), ga),
                         meter_name: meter.name.clone(),
                         switch_name,
                     });
@@ -1062,15 +1123,73 @@ fn build_switch_map(config: &KnxAdapterConfig) -> HashMap<String, SwitchConfig>
         }
     }
 
+    // Adapter level switches live on the adapter device
+    let adapter_id = sanitize_id(&config.name);
+    for switch in config.switches.iter().filter(|s| s.enabled) {
+        match GroupAddress::from_str(&switch.group_address) {
+            Ok(ga) => {
+                let switch_name = get_adapter_switch_key(switch);
+                let topic = get_command_topic(&"KNX".to_string(), &adapter_id, &switch_name);
+                map.insert(topic, SwitchConfig {
+                    group_address: ga,
+                    state_address: switch_state_address(switch).unwrap_or(ga),
+                    meter_name: config.name.clone(),
+                    switch_name,
+                });
+            }
+            Err(e) => {
+                warn!(
+                    "KNX adapter '{}': Invalid group address '{}' for switch '{}': {:?}",
+                    config.name, switch.group_address, switch.name, e
+                );
+            }
+        }
+    }
+
     map
 }
 
+/// Feedback address of a switch, the control address if none is configured. Validation
+/// rejects invalid ones, should one get through anyway it is logged.
+fn parse_state_address(state_ga: Option<&String>, group_address: GroupAddress) -> GroupAddress {
+    let Some(state_ga) = state_ga else {
+        return group_address;
+    };
+
+    GroupAddress::from_str(state_ga).unwrap_or_else(|e| {
+        warn!("Invalid switch_state_ga {state_ga} ({e}), the state of {group_address} is taken from the switch itself");
+        group_address
+    })
+}
+
+/// DPT 1.001 value of a switch command, HA sends ON/OFF
+fn switch_payload(payload: &str) -> Option<Vec<u8>> {
+    match payload.trim().to_uppercase().as_str() {
+        "ON" | "1" | "TRUE" => Some(vec![0x01u8]),
+        "OFF" | "0" | "FALSE" => Some(vec![0x00u8]),
+        _ => None,
+    }
+}
+
+/// Key of an adapter level switch in the state and command topics
+pub(crate) fn get_adapter_switch_key(switch: &KnxSwitchConfig) -> String {
+    format!("switch_{}", sanitize_id(&switch.name))
+}
+
+/// Address to read the state of an adapter level switch from (prefer state_address, fallback to group_address)
+fn switch_state_address(switch: &KnxSwitchConfig) -> Option<GroupAddress> {
+    let ga_str = switch.state_address.as_ref().unwrap_or(&switch.group_address);
+    GroupAddress::from_str(ga_str).ok()
+}
+
 /// Switch control task - handles MQTT commands and writes to KNX bus
 async fn switch_control_task(
     client: Arc<KnxClient>,
+    cache: GroupAddressCache,
     adapter_name: &str,
     switch_map: HashMap<String, SwitchConfig>,
     sender: &Sender<Transmission>,
+    switched: &Notify,
 ) -> Result<(), KnxError> {
     if switch_map.is_empty() {
         info!("KNX adapter '{}': No switches configured, switch control disabled", adapter_name);
@@ -1109,17 +1228,13 @@ async fn switch_control_task(
                 debug!("KNX adapter '{}': Received switch command: {} = {}", adapter_name, topic, payload);
 
                 if let Some(switch_config) = switch_map.get(&topic) {
-                    let value = match payload.trim().to_uppercase().as_str() {
-                        "ON" | "1" | "TRUE" => Some(vec![0x01u8]),
-                        "OFF" | "0" | "FALSE" => Some(vec![0x00u8]),
-                        _ => {
-                            warn!(
-                                "KNX adapter '{}': Invalid switch payload for {}: {}",
-                                adapter_name, switch_config.switch_name, payload
-                            );
-                            None
-                        }
-                    };
+                    let value = switch_payload(&payload);
+                    if value.is_none() {
+                        warn!(
+                            "KNX adapter '{}': Invalid switch payload for {}: {}",
+                            adapter_name, switch_config.switch_name, payload
+                        );
+                    }
 
                     if let Some(data) = value {
                         match client.send_write(switch_config.group_address, &data).await {
@@ -1132,6 +1247,23 @@ async fn switch_control_task(
                                     switch_config.meter_name,
                                     switch_config.switch_name
                                 );
+
+                                if switch_config.state_address == switch_config.group_address {
+                                    // Our own telegrams are not reported back by the tunnel, the written value is the state
+                                    cache.write().await.insert(
+                                        switch_config.group_address.to_u16(),
+                                        CachedValue {
+                                            data,
+                                            timestamp: Instant::now(),
+                                        },
+                                    );
+                                } else if let Err(e) = client.send_read_request(switch_config.state_address).await {
+                                    warn!(
+                                        "KNX adapter '{}': Failed to read state of switch {}: {:?}",
+                                        adapter_name, switch_config.switch_name, e
+                                    );
+                                }
+                                switched.notify_one();
                             }
                             Err(KnxError::ConnectionClosed) | Err(KnxError::SequenceOverflow) => {
                                 error!(
@@ -1220,7 +1352,7 @@ fn parse_dpt_value(dpt: &KnxDatapointType, data: &[u8]) -> Option<serde_json::Va
 }
 
 /// Sanitize a name for use as identifier (remove spaces and special chars)
-fn sanitize_id(name: &str) -> String {
+pub(crate) fn sanitize_id(name: &str) -> String {
     name.chars()
         .map(|c| if c.is_alphanumeric() || c == '_' { c } else { '_' })
         .collect::<String>()
@@ -1335,6 +1467,20 @@ fn build_adapter_ha_discovery(config: &KnxAdapterConfig, with_stats: bool) -> Ha
         disc.add_cmp("meters_published".to_string(), cmp);
     }
 
+    // Adapter level switches
+    for switch in config.switches.iter().filter(|s| s.enabled && s.expose_to_ha) {
+        let switch_key = get_adapter_switch_key(switch);
+        let cmd_topic = get_command_topic(&proto, &device_id, &switch_key);
+        let cmp = HaComponent2::new()
+            .name(switch.name.clone())
+            .platform("switch".to_string())
+            .del_information("state_class")
+            .add_information("command_topic", serde_json::Value::from(cmd_topic))
+            .add_information("payload_on", serde_json::Value::from("ON"))
+            .add_information("payload_off", serde_json::Value::from("OFF"));
+        disc.add_cmp(switch_key, cmp);
+    }
+
     disc
 }
 
@@ -1352,7 +1498,8 @@ fn build_ha_discovery(adapter_name: &str, meter: &KnxMeterConfig) -> HaSensor {
         device_id.clone(),
         Some(manufacturer),
         Some(model),
-    );
+    )
+    .meter_ids(meter.tenant.clone().unwrap_or_default(), get_id("knx".to_string(), &device_id));
 
     // Set the friendly name (original name from config, not sanitized)
     disc = disc.device_name(meter.name.clone());
@@ -1593,6 +1740,66 @@ mod tests {
         assert!((v - 1000.0).abs() < 0.01);
     }
 
+    #[test]
+    fn test_adapter_switches_in_switch_map() {
+        let config: KnxAdapterConfig = serde_yml::from_str(
+            "name: Main Gateway\n\
+             host: 127.0.0.1\n\
+             switches:\n\
+             - name: Heat Pump\n\
+             \x20 group_address: 1/2/3\n\
+             \x20 state_address: 1/2/4\n\
+             - name: Disabled\n\
+             \x20 enabled: false\n\
+             \x20 group_address: 1/2/5\n"
+        ).unwrap();
+
+        let map = build_switch_map(&config);
+        assert_eq!(map.len(), 1);
+        let topic = get_command_topic(&"KNX".to_string(), &"main_gateway".to_string(), &"switch_heat_pump".to_string());
+        assert_eq!(map.get(&topic).unwrap().group_address, GroupAddress::from_str("1/2/3").unwrap());
+        assert_eq!(map.get(&topic).unwrap().state_address, GroupAddress::from_str("1/2/4").unwrap());
+
+        let polls = build_poll_addresses(&config);
+        assert_eq!(polls.len(), 1);
+        assert_eq!(polls[0].0, GroupAddress::from_str("1/2/4").unwrap());
+    }
+
+    #[test]
+    fn test_switch_payload_and_discovery() {
+        assert_eq!(switch_payload("ON"), Some(vec![0x01]));
+        assert_eq!(switch_payload(" off\n"), Some(vec![0x00]));
+        assert_eq!(switch_payload("1"), Some(vec![0x01]));
+        assert_eq!(switch_payload("toggle"), None);
+        /* The written value is reported as the state of the switch */
+        assert_eq!(parse_dpt_value(&KnxDatapointType::Switch, &switch_payload("ON").unwrap()), Some(serde_json::Value::from("ON")));
+
+        let config: KnxAdapterConfig = serde_yml::from_str(
+            "name: gw\n\
+             host: 127.0.0.1\n\
+             switches:\n\
+             - name: Pump\n\
+             \x20 group_address: 1/2/3\n\
+             - name: Hidden\n\
+             \x20 group_address: 1/2/5\n\
+             \x20 expose_to_ha: false\n"
+        ).unwrap();
+
+        /* Switches not exposed to HA can still be switched via MQTT */
+        let map = build_switch_map(&config);
+        assert_eq!(map.len(), 2);
+        let pump = map.get(&get_command_topic(&"KNX".to_string(), &"gw".to_string(), &"switch_pump".to_string())).unwrap();
+        assert_eq!(pump.state_address, pump.group_address);
+
+        let switches: Vec<_> = build_adapter_ha_discovery(&config, false).get_entity_discoveries()
+            .into_iter()
+            .filter(|d| d.topic.contains("/switch/"))
+            .collect();
+        assert_eq!(switches.len(), 1);
+        assert_eq!(switches[0].payload["command_topic"], "energy2mqtt/cmds/KNX/gw/switch_pump");
+        assert_eq!(switches[0].payload["payload_on"], "ON");
+    }
+
     #[test]
     fn test_parse_dpt_value_insufficient_data() {
         let data = [0x00, 0x01];
@@ -1,7 +1,7 @@
 
 use serde_json::Value;
 use tokio::sync::mpsc::Sender;
-use crate::{metering_modbus::registers::{self, Register}, mqtt::{SubscribeData, Transmission, home_assistant::{HaComponent2, HaSensor}}};
+use crate::{metering_modbus::registers::{self, Register}, mqtt::{SubscribeData, Transmission, home_assistant::{entity_name, is_diagnostic_key, HaComponent2, HaSensor}}};
 
 pub async fn get_cmp_from_reg(reg: Register, discover: &mut HaSensor,
                         sender: &Sender<(String, String)>, hub_sender: &Sender<Transmission>,
@@ -10,7 +10,7 @@ pub async fn get_cmp_from_reg(reg: Register, discover: &mut HaSensor,
     let (platform, name, device_class,
         unit_of_measurement, state_class,
         value_template, options,
-        min, max, step) = match reg.clone() {
+        min, max, step, entity_category) = match reg.clone() {
         registers::Register::Template(register) => (
                 register.platform,
                 register.name,
@@ -21,7 +21,8 @@ pub async fn get_cmp_from_reg(reg: Register, discover: &mut HaSensor,
                 Vec::new(),
                 None,
                 None,
-                None
+                None,
+                register.entity_category
             ),
         registers::Register::Modbus(register) => (
                 register.platform,
@@ -33,14 +34,29 @@ pub async fn get_cmp_from_reg(reg: Register, discover: &mut HaSensor,
                 register.options,
                 register.min,
                 register.max,
-                register.step
+                register.step,
+                register.entity_category
             ),
     };
 
+    let (payload_on, payload_off) = match &reg {
+        registers::Register::Modbus(register) => (register.payload_on.clone(), register.payload_off.clone()),
+        registers::Register::Template(_) => (None, None),
+    };
+
     // Build component using the new HaComponent2 builder
+    /* The register name stays the key, so the unique_id does not change, HA shows the friendly name */
     let mut cmp = HaComponent2::new()
-        .name(name.clone())
-        .platform(platform);
+        .name(entity_name(&name))
+        .platform(platform.clone());
+
+    // NONE keeps a serial or version with the primary entities
+    match entity_category {
+        Some(cat) if cat.is_empty() || cat == "NONE" => {},
+        Some(cat) => cmp = cmp.entity_category(cat),
+        None if is_diagnostic_key(&name) => cmp = cmp.cat_diagnostic(),
+        None => {},
+    }
 
     // Only add device_class if it's not NONE
     if !device_class.is_empty() && device_class != "NONE" {
@@ -61,6 +77,12 @@ pub async fn get_cmp_from_reg(reg: Register, discover: &mut HaSensor,
         cmp = cmp.non_numeric();
     }
 
+    /* Binary states are published as 1/0, unless a value_template maps them to something else */
+    if (platform == "binary_sensor" || platform == "switch") && value_template.is_empty() {
+        cmp = cmp.add_information("payload_on", payload_on.unwrap_or_else(|| "1".to_string()).into())
+            .add_information("payload_off", payload_off.unwrap_or_else(|| "0".to_string()).into());
+    }
+
     if !value_template.is_empty() {
         cmp = cmp.add_information("value_template", value_template.into());
     }
@@ -88,14 +110,17 @@ pub async fn get_cmp_from_reg(reg: Register, discover: &mut HaSensor,
         if r.input_type == registers::ModbusRegisterType::Holding ||
             r.input_type == registers::ModbusRegisterType::Coil {
 
-            let topic= format!("energy2mqtt/cmds/modbus/{}/{}/{}", hub_name, device_name, name);
+            let topic= format!("{}/cmds/modbus/{}/{}/{}", crate::mqtt::get_topic_prefix(), hub_name, device_name, name);
 
             match r.platform.as_str() {
-                "number" | "switch" | "select" => {
+                "number" | "switch" | "select" | "button" => {
                     cmp = cmp.add_information("command_topic", Value::from(topic.clone()));
                     if !r.command_template.is_empty() {
                         cmp = cmp.add_information("command_template", r.command_template.clone().into());
                     }
+                    if r.platform == "button" {
+                        cmp = cmp.add_information("payload_press", r.payload_press.clone().unwrap_or_else(|| "1".to_string()).into());
+                    }
                 },
                 _ => { }
             }
@@ -106,4 +131,90 @@ pub async fn get_cmp_from_reg(reg: Register, discover: &mut HaSensor,
 
     /* Add our device */
     discover.add_cmp(name.clone(), cmp);
-}
\ No newline at end of file
+}
+
+/// Button reading the device right away instead of waiting for the next interval
+pub fn refresh_button(hub_name: &str, device_name: &str) -> HaComponent2 {
+    HaComponent2::new()
+        .name("Refresh".to_string())
+        .platform("button".to_string())
+        .non_numeric()
+        .entity_category("config".to_string())
+        .add_information("command_topic", Value::from(format!("{}/mgt/command", crate::mqtt::get_topic_prefix())))
+        .add_information("payload_press", Value::from(format!("read modbus {hub_name}/{device_name}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reg_from_yaml(yaml: &str) -> Register {
+        Register::Modbus(serde_yml::from_str(yaml).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_binary_sensor_and_button_platforms() {
+        let (sender, _receiver) = tokio::sync::mpsc::channel(10);
+        let (hub_sender, mut hub_receiver) = tokio::sync::mpsc::channel(10);
+        let mut discover = HaSensor::new("ModbusTCP".to_string(), "charger".to_string(), None, None);
+        let hub = "hub".to_string();
+        let dev = "charger".to_string();
+
+        let coil = reg_from_yaml("name: car_connected\ninput_type: Coil\nregister: 1\nlength: 1\nformat: Coil\nplatform: binary_sensor\n");
+        let reset = reg_from_yaml("name: reset_energy\ninput_type: Holding\nregister: 2\nlength: 1\nformat: UInt16\nplatform: button\npayload_press: \"42\"\n");
+
+        get_cmp_from_reg(coil, &mut discover, &sender, &hub_sender, &hub, &dev).await;
+        get_cmp_from_reg(reset, &mut discover, &sender, &hub_sender, &hub, &dev).await;
+
+        let discoveries = discover.get_entity_discoveries();
+        assert_eq!(discoveries.len(), 2);
+        assert_eq!(discoveries[0].payload["name"], "Car Connected");
+        assert!(discoveries[0].payload.get("entity_category").is_none());
+
+        let binary = &discoveries[0];
+        assert!(binary.topic.starts_with("homeassistant/binary_sensor/"));
+        assert_eq!(binary.payload["payload_on"], "1");
+        assert_eq!(binary.payload["payload_off"], "0");
+        assert!(binary.payload.get("command_topic").is_none());
+
+        let button = &discoveries[1];
+        assert!(button.topic.starts_with("homeassistant/button/"));
+        assert_eq!(button.payload["command_topic"], "energy2mqtt/cmds/modbus/hub/charger/reset_energy");
+        assert_eq!(button.payload["payload_press"], "42");
+
+        /* Both are readable/writable registers, the button needs its command topic subscribed */
+        let mut topics = Vec::new();
+        while let Ok(Transmission::Subscribe(sub)) = hub_receiver.try_recv() {
+            topics.push(sub.topic);
+        }
+        assert!(topics.contains(&"energy2mqtt/cmds/modbus/hub/charger/reset_energy".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_phase_names_and_entity_category() {
+        let (sender, _receiver) = tokio::sync::mpsc::channel(10);
+        let (hub_sender, _hub_receiver) = tokio::sync::mpsc::channel(10);
+        let mut discover = HaSensor::new("ModbusTCP".to_string(), "meter".to_string(), None, None);
+        let hub = "hub".to_string();
+        let dev = "meter".to_string();
+
+        let regs = [
+            "name: voltage_l1\ninput_type: Input\nregister: 1\nlength: 1\nformat: UInt16\n",
+            "name: serial_number\ninput_type: Input\nregister: 2\nlength: 4\nformat: String\n",
+            "name: version\ninput_type: Input\nregister: 6\nlength: 1\nformat: UInt16\nentity_category: NONE\n",
+            "name: limit\ninput_type: Input\nregister: 7\nlength: 1\nformat: UInt16\nentity_category: config\n",
+        ];
+        for reg in regs {
+            get_cmp_from_reg(reg_from_yaml(reg), &mut discover, &sender, &hub_sender, &hub, &dev).await;
+        }
+
+        let discoveries = discover.get_entity_discoveries();
+        let category = |i: usize| discoveries[i].payload.get("entity_category").cloned();
+        assert_eq!(discoveries[0].payload["name"], "Voltage L1");
+        assert_eq!(discoveries[0].payload["unique_id"], "e2m_modbustcp_meter_voltage_l1");
+        assert_eq!(category(0), None);
+        assert_eq!(category(1), Some(Value::from("diagnostic")));
+        assert_eq!(category(2), None);
+        assert_eq!(category(3), Some(Value::from("config")));
+    }
+}
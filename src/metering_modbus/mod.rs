@@ -1,12 +1,15 @@
 use std::collections::HashMap;
 use std::time::Duration;
+use tokio::time::Instant;
+use crate::schedule::{self, Schedule};
 use crate::config::defaults::Defaults;
 use crate::metering_modbus::registers::ModbusRegister;
-use crate::mqtt::SubscribeData;
-use crate::{config::{ConfigBases, ConfigChange, ConfigOperation, ModbusConfig, ModbusDeviceConfig, ModbusHubConfig, ModbusProtoConfig}, metering_modbus::registers::Register, models::DeviceProtocol, mqtt::{home_assistant::HaSensor, Transmission, publish_protocol_count}, task_monitor::TaskMonitor, CONFIG};
+use crate::mqtt::{internal_commands, SubscribeData};
+use crate::{config::{ConfigBases, ConfigChange, ConfigOperation, ModbusConfig, ModbusDeviceConfig, ModbusHubConfig, ModbusProtoConfig}, metering_modbus::registers::Register, models::DeviceProtocol, mqtt::{home_assistant::{error_component, HaSensor}, Transmission, publish_protocol_count}, task_monitor::TaskMonitor, CONFIG};
 use log::{debug, error, info, warn};
 use rmodbus::ModbusProto;
 use serde::Deserialize;
+use socket2::TcpKeepalive;
 use tokio::{net::TcpStream, sync::mpsc::Sender};
 pub mod registers;
 pub mod read_device_parms;
@@ -20,35 +23,88 @@ pub enum ModbusError {
     ConnectionFailed(String),
     ConnectionTimeout(u64),
     ConnectionClosed,
-    ReadTimeout(u64),
-    WriteTimeout(u64),
+    ResponseTimeout(u64),
     WriteFailed(String),
     ProtocolError(String),
     IoError(std::io::Error),
+    Exception(ModbusException),
 }
 
 impl std::fmt::Display for ModbusError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             ModbusError::ConnectionFailed(msg) => write!(f, "Connection failed: {}", msg),
-            ModbusError::ConnectionTimeout(secs) => write!(f, "Connection timeout after {}s", secs),
+            ModbusError::ConnectionTimeout(ms) => write!(f, "Connection timeout after {}ms", ms),
             ModbusError::ConnectionClosed => write!(f, "Connection closed by server"),
-            ModbusError::ReadTimeout(secs) => write!(f, "Read timeout after {}s", secs),
-            ModbusError::WriteTimeout(secs) => write!(f, "Write timeout after {}s", secs),
+            ModbusError::ResponseTimeout(ms) => write!(f, "No complete response within {}ms", ms),
             ModbusError::WriteFailed(msg) => write!(f, "Write failed: {}", msg),
             ModbusError::ProtocolError(msg) => write!(f, "Protocol error: {}", msg),
             ModbusError::IoError(e) => write!(f, "IO error: {}", e),
+            ModbusError::Exception(e) => write!(f, "Device responded with exception: {}", e),
         }
     }
 }
 
 impl std::error::Error for ModbusError {}
 
+/// Exception codes a device answers with instead of the requested data
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ModbusException {
+    IllegalFunction,
+    IllegalDataAddress,
+    IllegalDataValue,
+    SlaveDeviceFailure,
+    Acknowledge,
+    SlaveDeviceBusy,
+    NegativeAcknowledge,
+    MemoryParityError,
+    GatewayPathUnavailable,
+    GatewayTargetFailed,
+    Unknown(u8),
+}
+
+impl ModbusException {
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            0x01 => ModbusException::IllegalFunction,
+            0x02 => ModbusException::IllegalDataAddress,
+            0x03 => ModbusException::IllegalDataValue,
+            0x04 => ModbusException::SlaveDeviceFailure,
+            0x05 => ModbusException::Acknowledge,
+            0x06 => ModbusException::SlaveDeviceBusy,
+            0x07 => ModbusException::NegativeAcknowledge,
+            0x08 => ModbusException::MemoryParityError,
+            0x0A => ModbusException::GatewayPathUnavailable,
+            0x0B => ModbusException::GatewayTargetFailed,
+            c => ModbusException::Unknown(c),
+        }
+    }
+}
+
+impl std::fmt::Display for ModbusException {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ModbusException::IllegalFunction => write!(f, "illegal function"),
+            ModbusException::IllegalDataAddress => write!(f, "illegal data address"),
+            ModbusException::IllegalDataValue => write!(f, "illegal data value"),
+            ModbusException::SlaveDeviceFailure => write!(f, "slave device failure"),
+            ModbusException::Acknowledge => write!(f, "acknowledge, processing takes longer"),
+            ModbusException::SlaveDeviceBusy => write!(f, "slave device busy"),
+            ModbusException::NegativeAcknowledge => write!(f, "negative acknowledge"),
+            ModbusException::MemoryParityError => write!(f, "memory parity error"),
+            ModbusException::GatewayPathUnavailable => write!(f, "gateway path unavailable"),
+            ModbusException::GatewayTargetFailed => write!(f, "gateway target device failed to respond"),
+            ModbusException::Unknown(code) => write!(f, "unknown exception code {:#04x}", code),
+        }
+    }
+}
+
 /// Connection state for a Modbus hub - lives in task scope across read cycles
 pub struct HubConnectionState {
     stream: Option<TcpStream>,
     connection_timeout: Duration,
-    read_timeout: Duration,
+    keepalive: Option<TcpKeepalive>,
+    response_timeout: Duration,
     consecutive_failures: u32,
 }
 
@@ -57,7 +113,8 @@ impl HubConnectionState {
         Self {
             stream: None,
             connection_timeout: Duration::from_secs(config.connection_timeout),
-            read_timeout: Duration::from_secs(config.read_timeout),
+            keepalive: utils::tcp_keepalive(config),
+            response_timeout: utils::response_timeout(config),
             consecutive_failures: 0,
         }
     }
@@ -88,6 +145,9 @@ pub struct ModbusDevice {
     config: ModbusDeviceConfig,
     waits_till_read: u32,
     cur_waits: u32,
+    /* Cron schedule replacing the read_interval and the time it fires next */
+    schedule: Option<Schedule>,
+    next_read: Option<Instant>,
     registers: Vec<registers::Register>,
     default: Option<Vec<Defaults>>,
 }
@@ -112,6 +172,8 @@ impl ModbusManger
 {
     pub fn new(sender: Sender<Transmission>) -> Self {
         let config: ModbusConfig = crate::get_config_or_panic!("modbus", ConfigBases::Modbus);
+        let user_dir = CONFIG.read().unwrap().get_definitions_path().join("modbus");
+        registers::load_user_definitions(&user_dir);
 
         ModbusManger {
             sender: sender.clone(),
@@ -145,9 +207,9 @@ impl ModbusManger
             let mut device_count : u32 = 0;
             self.config = crate::get_config_or_panic!("modbus", ConfigBases::Modbus);
 
-            /* Read config of all modbus devices */
-            for config_hub  in self.config.hubs.iter() {
-                device_count += config_hub.devices.len() as u32;
+            /* Read config of all modbus devices, disabled hubs and devices are left alone */
+            for config_hub  in self.config.hubs.iter().filter(|h| h.enabled) {
+                device_count += config_hub.devices.iter().filter(|d| d.enabled).count() as u32;
 
                 let hub_sender = self.sender.clone();
                 /* Sender and Receiver for our Callbacks */
@@ -156,7 +218,7 @@ impl ModbusManger
                     config: config_hub.clone(),
                     devices: {
                         let mut devs: Vec<ModbusDevice> = Vec::new();
-                        for dev in config_hub.devices.iter() {
+                        for dev in config_hub.devices.iter().filter(|d| d.enabled) {
                             let (regs, manu, model) = registers::get_registers(&dev.meter);
                             let r = regs.clone();
                             let defaults = match &dev.defaults {
@@ -170,10 +232,21 @@ impl ModbusManger
                                 None => None,
                             };
 
+                            let schedule = match dev.cron.as_deref().map(schedule::parse_cron) {
+                                Some(Ok(s)) => Some(s),
+                                Some(Err(e)) => {
+                                    error!("Device {}: {}, using read_interval", dev.name, e);
+                                    None
+                                },
+                                None => None,
+                            };
+
                             let d = ModbusDevice {
                                 config: dev.clone(),
                                 waits_till_read: 1,
                                 cur_waits: 0,
+                                next_read: schedule.as_ref().and_then(next_cron_read),
+                                schedule,
                                 registers: regs,
                                 default: defaults,
                             };
@@ -185,11 +258,12 @@ impl ModbusManger
                                 dev.name.clone(),
                                 Some(manu),
                                 Some(model)
-                            );
+                            ).meter_ids(dev.tenant.clone().unwrap_or_default(),
+                                         crate::get_id_with_unique_id("modbus".to_string(), &dev.name, dev.unique_id.as_ref()));
 
                             /* Subscribe to our set topic for RAW transmission of data to registers */
                             let _ = hub_sender.send(Transmission::Subscribe(SubscribeData {
-                                                            topic: format!("energy2mqtt/set/modbus/{}/{}",
+                                                            topic: format!("{}/set/modbus/{}/{}", crate::mqtt::get_topic_prefix(),
                                                             &config_hub.name, &dev.name),
                                                             sender: sender.clone() })).await;
 
@@ -199,6 +273,12 @@ impl ModbusManger
                                                     &hub_sender, &config_hub.name, &dev.name).await;
                             }
 
+                            /* Reads out of cycle, e.g. by the refresh button */
+                            internal_commands::register_read_request("modbus", &config_hub.name, &dev.name, sender.clone());
+                            discover.add_cmp("refresh".to_string(), ha_config::refresh_button(&config_hub.name, &dev.name));
+                            discover.add_cmp("read_error".to_string(),
+                                             error_component(&DeviceProtocol::ModbusTCP.to_string(), &dev.name));
+
                             let _ = hub_sender.send(Transmission::AutoDiscovery2(discover)).await;
                         }
                         devs
@@ -207,14 +287,14 @@ impl ModbusManger
 
                 /* Find the sleeptime of this hub, do not use a too small value as it may halt the application  */
                 let mut hub_inveral_sec: u32 = 60;
-                for device in hub.devices.iter() {
+                for device in hub.devices.iter().filter(|d| d.schedule.is_none()) {
                     hub_inveral_sec = std::cmp::min(hub_inveral_sec, device.config.read_interval);
                 }
 
                 /*
                  * Now check again to round the read intervals
                  */
-                for device in hub.devices.iter_mut() {
+                for device in hub.devices.iter_mut().filter(|d| d.schedule.is_none()) {
                     /* Round up based on the hubs read interval */
                     device.waits_till_read = device.config.read_interval / hub_inveral_sec;
 
@@ -224,8 +304,16 @@ impl ModbusManger
                         warn!("Device {} will be read every {} seconds instead of {} seconds because of your config",
                                 device.config.name, new_sec, device.config.read_interval);
                     }
+
+                    /* Spread the first reads, later reads keep the interval */
+                    let max_jitter_ticks = config_hub.max_read_jitter / hub_inveral_sec;
+                    device.cur_waits = utils::jitter_waits(device.waits_till_read, max_jitter_ticks,
+                                                           utils::name_hash(&format!("{}/{}", config_hub.name, device.config.name)));
                 }
 
+                /* Shift the ticks of the hub within its interval so hubs on the same gateway do not read at once */
+                let hub_offset = Duration::from_secs(
+                    utils::spread_below(&config_hub.name, config_hub.max_read_jitter.min(hub_inveral_sec)) as u64);
 
                 let hub_name_for_task = config_hub.name.clone();
                 self.task_monitor.spawn(
@@ -241,21 +329,46 @@ impl ModbusManger
                             proto = ModbusProto::Rtu;
                         }
 
-                        // Create connection state that persists across read cycles
-                        let mut conn_state = HubConnectionState::new(&hub.config);
+                        // Create connection states that persist across read cycles, writes use the first one
+                        let mut conn_states: Vec<HubConnectionState> = (0..utils::parallel_reads(&hub.config))
+                            .map(|_| HubConnectionState::new(&hub.config))
+                            .collect();
+
+                        let mut next_tick = Instant::now() + hub_delay + hub_offset;
+                        let topic_prefix = crate::mqtt::get_topic_prefix();
 
                         loop {
+                            /* Wake up for the next tick of hub_inveral_sec or the next cron read, whatever is first */
+                            let wake_up = hub.devices.iter()
+                                .filter_map(|d| d.next_read)
+                                .fold(next_tick, std::cmp::min);
+
                             tokio::select! {
-                                /* Now sleep for one tick of hub_inveral_sec */
-                                _ =  tokio::time::sleep(hub_delay) => {
-                                    /* Increment wait counters for all devices */
+                                _ = tokio::time::sleep_until(wake_up) => {
+                                    let now = Instant::now();
+                                    if now >= next_tick {
+                                        /* Increment wait counters for all devices read by interval */
+                                        for device in hub.devices.iter_mut().filter(|d| d.schedule.is_none()) {
+                                            device.cur_waits += 1;
+                                        }
+                                        next_tick += hub_delay;
+                                        /* Reads took longer than a tick, do not try to catch up */
+                                        if next_tick < now {
+                                            next_tick = now + hub_delay;
+                                        }
+                                    }
+
+                                    /* Cron devices are due once their boundary is reached */
                                     for device in hub.devices.iter_mut() {
-                                        device.cur_waits += 1;
+                                        if device.next_read.is_some_and(|t| t <= now) {
+                                            device.cur_waits = device.waits_till_read;
+                                            device.next_read = device.schedule.as_ref().and_then(next_cron_read);
+                                        }
                                     }
                                 },
                                 /* We got a write command, we may miss a beat but that is ok */
                                 Some((topic, payload)) = write_receiver.recv() => {
-                                    if topic.starts_with("energy2mqtt/set/modbus") {
+                                    if topic.starts_with(&format!("{topic_prefix}/set/modbus/")) {
                                         /* Check which device we need to call out to */
                                         let command: ModbusMqttCommand = match serde_json::from_slice(payload.as_bytes()) {
                                             Ok(d) => d,
@@ -276,7 +389,7 @@ impl ModbusManger
                                                     if let Some(registers) = &command.registers {
                                                         /* We found our device */
                                                         set_device_parms::set(&socket_addr, &hub.config.name, registers,
-                                                                                device, proto, &mut conn_state).await;
+                                                                                device, proto, &mut conn_states[0]).await;
                                                         debug!("Hub {} Device {} will now be read because the configuration changed",
                                                                 hub.config.name, device.config.name);
                                                         device.cur_waits = device.waits_till_read + 10;
@@ -292,8 +405,14 @@ impl ModbusManger
                                                 }
                                             }
                                         }
-                                    } else if topic.starts_with("energy2mqtt/cmds/modbus") {
-                                        //"energy2mqtt/cmds/modbus/{}/{}/{}"
+                                    } else if let Some(name) = topic.strip_prefix(&internal_commands::read_request_topic())
+                                                                    .and_then(|t| t.strip_prefix(&format!("modbus/{}/", hub.config.name))) {
+                                        for device in hub.devices.iter_mut().filter(|d| d.config.name == name) {
+                                            info!("Hub {} Device {} read on demand", hub.config.name, device.config.name);
+                                            device.cur_waits = device.cur_waits.max(device.waits_till_read);
+                                        }
+                                    } else if topic.starts_with(&format!("{topic_prefix}/cmds/modbus/")) {
+                                        //"{prefix}/cmds/modbus/{}/{}/{}"
                                         /* Get the correct device to run */
                                         let (topic, register) = topic.rsplit_once('/').unwrap();
                                         let (_, name) = topic.rsplit_once('/').unwrap();
@@ -315,7 +434,7 @@ impl ModbusManger
                                                         info!("WRITING {} -> {} -> {:?}", r.register, payload, value);
 
                                                         /* Write our register */
-                                                        set_device_parms::write_register(device, proto, &mut conn_state, &reg, value).await;
+                                                        set_device_parms::write_register(device, proto, &mut conn_states[0], reg, value).await;
                                                         debug!("Hub {} Device {} will now be read because the configuration changed",
                                                                 hub.config.name, device.config.name);
                                                         device.cur_waits = device.waits_till_read + 10;
@@ -338,14 +457,14 @@ impl ModbusManger
                                 &hub.config.name,
                                 proto,
                                 &hub_sender,
-                                &mut conn_state,
+                                &mut conn_states,
                             ).await;
 
 
                             // Log connection health if there are failures
-                            if conn_state.consecutive_failures > 0 {
-                                warn!("Hub {}: {} consecutive failures",
-                                    hub.config.name, conn_state.consecutive_failures);
+                            let failures = conn_states.iter().map(|c| c.consecutive_failures).max().unwrap_or(0);
+                            if failures > 0 {
+                                warn!("Hub {}: {} consecutive failures", hub.config.name, failures);
                             }
                         }
                     }
@@ -403,6 +522,11 @@ impl ModbusManger
     }
 }
 
+/// Point in time a cron scheduled device has to be read next
+fn next_cron_read(schedule: &Schedule) -> Option<Instant> {
+    schedule::until_next(schedule).map(|d| Instant::now() + d)
+}
+
 fn change_register(command: &ModbusMqttCommand, device: &mut ModbusDevice) {
 
     if let Some(changes) = &command.changes {
@@ -449,7 +573,7 @@ fn change_register(command: &ModbusMqttCommand, device: &mut ModbusDevice) {
                 format: change.format.clone(),
                 endianess: change.endianess.clone(),
                 scaler: change.scaler,
-                precision: change.precision,
+                decimals: change.decimals,
                 scale_factor: change.scale_factor.clone(),
                 unit_of_measurement: change.unit_of_measurement.clone(),
                 device_class: change.device_class.clone(),
@@ -462,6 +586,12 @@ fn change_register(command: &ModbusMqttCommand, device: &mut ModbusDevice) {
                 min: None,
                 max: None,
                 step: None,
+                payload_on: change.payload_on.clone(),
+                payload_off: change.payload_off.clone(),
+                payload_press: change.payload_press.clone(),
+                valid_min: None,
+                valid_max: None,
+                entity_category: change.entity_category.clone(),
             }));
         }
     } else {
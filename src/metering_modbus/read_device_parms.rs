@@ -1,11 +1,13 @@
 
+use futures_util::future::join_all;
 use evalexpr::{ContextWithMutableVariables, DefaultNumericTypes, HashMapContext};
 use log::{debug, error, info, warn};
 use rmodbus::{client::ModbusRequest, guess_response_frame_len, ModbusProto};
-use crate::{config::ModbusHubConfig, metering_modbus::{HubConnectionState, ModbusDevice, ModbusError, ModbusHub, registers, set_device_parms::write_register, utils::{self, round_number}}, mqtt::{PublishData, Transmission}};
+use crate::{config::ModbusHubConfig, metering_modbus::{HubConnectionState, ModbusDevice, ModbusError, ModbusHub, registers, set_device_parms::write_register, utils::{self, round_number}}, mqtt::{MeterErrorData, PublishData, Transmission}};
 use serde::Serialize;
+use socket2::{SockRef, TcpKeepalive};
 use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream, sync::mpsc::Sender, time::timeout};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::time::Duration;
 use crate::{metering_modbus::registers::Register, models::DeviceProtocol, MeteringData};
 
@@ -14,16 +16,22 @@ use crate::{metering_modbus::registers::Register, models::DeviceProtocol, Meteri
 async fn connect_to_hub(
     socket_addr: &str,
     connection_timeout: Duration,
+    keepalive: Option<&TcpKeepalive>,
 ) -> Result<TcpStream, ModbusError> {
     match timeout(connection_timeout, TcpStream::connect(socket_addr)).await {
         Ok(Ok(stream)) => {
             let _ = stream.set_nodelay(true);
+            if let Some(keepalive) = keepalive {
+                if let Err(e) = SockRef::from(&stream).set_tcp_keepalive(keepalive) {
+                    warn!("Unable to set TCP keepalive for {}: {}", socket_addr, e);
+                }
+            }
             Ok(stream)
         }
         Ok(Err(e)) => Err(ModbusError::ConnectionFailed(format!(
             "Failed to connect to {}: {}", socket_addr, e
         ))),
-        Err(_) => Err(ModbusError::ConnectionTimeout(connection_timeout.as_secs())),
+        Err(_) => Err(ModbusError::ConnectionTimeout(connection_timeout.as_millis() as u64)),
     }
 }
 
@@ -36,25 +44,37 @@ pub async fn read_hub_devices(
     hub_name: &str,
     proto: ModbusProto,
     hub_sender: &Sender<Transmission>,
-    conn_state: &mut HubConnectionState,
+    conn_states: &mut [HubConnectionState],
 ) {
-    // Collect indices of devices that need to be read this cycle
-    let devices_to_read: Vec<usize> = devices.iter()
-        .enumerate()
-        .filter(|(_, d)| d.cur_waits >= d.waits_till_read)
-        .map(|(i, _)| i)
-        .collect();
-
-    if devices_to_read.is_empty() {
-        return;
+    /* Spread the devices due this cycle over the connections, each connection reads its share in order */
+    let mut groups: Vec<Vec<&mut ModbusDevice>> = conn_states.iter().map(|_| Vec::new()).collect();
+    for (i, device) in devices.iter_mut().filter(|d| d.cur_waits >= d.waits_till_read).enumerate() {
+        groups[i % conn_states.len()].push(device);
     }
 
+    let reads = conn_states.iter_mut()
+        .zip(groups)
+        .filter(|(_, group)| !group.is_empty())
+        .map(|(conn_state, group)| read_devices(socket_addr, group, hub_name, proto, hub_sender, conn_state));
+    join_all(reads).await;
+}
+
+async fn read_devices(
+    socket_addr: &str,
+    devices: Vec<&mut ModbusDevice>,
+    hub_name: &str,
+    proto: ModbusProto,
+    hub_sender: &Sender<Transmission>,
+    conn_state: &mut HubConnectionState,
+) {
+
     // Ensure we have a connection (reuse existing or establish new)
     if conn_state.stream.is_none() {
         match connect_to_hub_with_retry(
             socket_addr,
             hub_name,
-            conn_state.connection_timeout
+            conn_state.connection_timeout,
+            conn_state.keepalive.as_ref()
         ).await {
             Ok(s) => {
                 info!("Hub {}: Connection established", hub_name);
@@ -64,14 +84,16 @@ pub async fn read_hub_devices(
             Err(e) => {
                 error!("Hub {}: Failed to establish connection after retries: {:?}", hub_name, e);
                 conn_state.record_failure();
+                for device in &devices {
+                    report_error(hub_sender, &device.config.name, &e).await;
+                }
                 return;
             }
         }
     }
 
     // Read all devices using the persistent connection
-    for idx in devices_to_read {
-        let device = &mut devices[idx];
+    for device in devices {
         device.cur_waits = 0;
 
         if let Some(default_list) = &device.default {
@@ -124,7 +146,7 @@ pub async fn read_hub_devices(
             hub_name,
             proto,
             hub_sender,
-            conn_state.read_timeout
+            device.config.response_timeout_ms.map(Duration::from_millis).unwrap_or(conn_state.response_timeout)
         ).await {
             Ok(_) => {
                 debug!("Hub {} Device {} done reading", hub_name, device.config.name);
@@ -132,6 +154,7 @@ pub async fn read_hub_devices(
             }
             Err(e) => {
                 error!("Hub {} Device {} read failed: {:?}", hub_name, device.config.name, e);
+                report_error(hub_sender, &device.config.name, &e).await;
                 conn_state.record_failure();
 
                 // Close the broken connection
@@ -141,7 +164,8 @@ pub async fn read_hub_devices(
                 match connect_to_hub_with_retry(
                     socket_addr,
                     hub_name,
-                    conn_state.connection_timeout
+                    conn_state.connection_timeout,
+                    conn_state.keepalive.as_ref()
                 ).await {
                     Ok(new_stream) => {
                         conn_state.stream = Some(new_stream);
@@ -158,17 +182,27 @@ pub async fn read_hub_devices(
     // Connection is NOT dropped here - it persists for the next cycle
 }
 
+/// Show the failed read on the error topic of the device
+async fn report_error(hub_sender: &Sender<Transmission>, device_name: &str, error: &ModbusError) {
+    let _ = hub_sender.send(Transmission::MeterError(MeterErrorData {
+        protocol: DeviceProtocol::ModbusTCP.to_string(),
+        meter_name: device_name.to_string(),
+        error: error.to_string(),
+    })).await;
+}
+
 /// Connect to hub with retry logic
 pub async fn connect_to_hub_with_retry(
     socket_addr: &str,
     hub_name: &str,
     connection_timeout: Duration,
+    keepalive: Option<&TcpKeepalive>,
 ) -> Result<TcpStream, ModbusError> {
     const MAX_RETRIES: u32 = 3;
     let mut retries = 0;
 
     loop {
-        match connect_to_hub(socket_addr, connection_timeout).await {
+        match connect_to_hub(socket_addr, connection_timeout, keepalive).await {
             Ok(stream) => return Ok(stream),
             Err(e) if retries < MAX_RETRIES => {
                 warn!("Hub {}: Connection error, retrying ({}/{}): {:?}",
@@ -197,6 +231,148 @@ struct E2MRawData {
     registers: Vec<E2MRegister>,
 }
 
+/// Responses to earlier requests skipped while waiting for the answer to the current one
+const MAX_STALE_FRAMES: usize = 3;
+
+/// Drop bytes left in the socket, e.g. the rest of a broken frame or a late answer to a request that
+/// timed out. Otherwise they would be taken as the start of the next response.
+fn drain_stale_bytes(stream: &mut TcpStream) -> Result<usize, ModbusError> {
+    let mut buf = [0u8; 256];
+    let mut drained = 0;
+    loop {
+        match stream.try_read(&mut buf) {
+            Ok(0) => return Err(ModbusError::ConnectionClosed),
+            Ok(n) => drained += n,
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(drained),
+            Err(e) => return Err(ModbusError::IoError(e)),
+        }
+    }
+}
+
+async fn read_frame_bytes(stream: &mut TcpStream, buf: &mut [u8]) -> Result<(), ModbusError> {
+    match stream.read_exact(buf).await {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Err(ModbusError::ConnectionClosed),
+        Err(e) => Err(ModbusError::IoError(e)),
+    }
+}
+
+/// Send a request and receive the complete response frame
+pub async fn transact(
+    stream: &mut TcpStream,
+    request: &[u8],
+    proto: ModbusProto,
+    response_timeout: Duration,
+    start: u16,
+) -> Result<Vec<u8>, ModbusError> {
+    /* A device trickling out its answer byte by byte must not stall the hub, so the
+       deadline covers the whole exchange and not each single read */
+    match timeout(response_timeout, exchange(stream, request, proto, start)).await {
+        Ok(result) => result,
+        Err(_) => Err(ModbusError::ResponseTimeout(response_timeout.as_millis() as u64)),
+    }
+}
+
+async fn exchange(
+    stream: &mut TcpStream,
+    request: &[u8],
+    proto: ModbusProto,
+    start: u16,
+) -> Result<Vec<u8>, ModbusError> {
+    let drained = drain_stale_bytes(stream)?;
+    if drained > 0 {
+        warn!("Dropped {drained} stale bytes before requesting register {start}");
+    }
+
+    if let Err(e) = stream.write_all(request).await {
+        return Err(ModbusError::WriteFailed(format!(
+            "Failed to write request for register {}: {}", start, e
+        )));
+    }
+
+    for _ in 0..=MAX_STALE_FRAMES {
+        /* The header has to be complete, a partial one gives a wrong frame length */
+        let mut response = vec![0u8; utils::response_header_len(proto)];
+        read_frame_bytes(stream, &mut response).await?;
+
+        let len = guess_response_frame_len(&response, proto)
+            .map_err(|e| ModbusError::ProtocolError(format!(
+                "Failed to determine response length for register {}: {:?}", start, e
+            )))?;
+
+        if len as usize > response.len() {
+            // Read rest of response, it may arrive in several segments
+            let mut rest = vec![0u8; len as usize - response.len()];
+            read_frame_bytes(stream, &mut rest).await?;
+            response.extend(&rest);
+        }
+
+        match utils::check_response_frame(request, &response, proto) {
+            Ok(()) => return Ok(response),
+            Err(utils::FrameMismatch::Stale(e)) => {
+                debug!("Skipping stale response ({e}) while waiting for register {start}");
+            },
+            Err(utils::FrameMismatch::Broken(e)) => {
+                return Err(ModbusError::ProtocolError(format!("Invalid response for register {start}: {e}")));
+            },
+        }
+    }
+
+    Err(ModbusError::ProtocolError(format!("No matching response for register {start}")))
+}
+
+/// Read a block of registers, coils and discrete inputs are returned as one word per bit. Connection problems
+/// are returned as error, a response we can not use as error of the data
+async fn read_block(
+    stream: &mut TcpStream,
+    slave_id: u8,
+    proto: ModbusProto,
+    block: &utils::ReadBlock,
+    address_base: u8,
+    response_timeout: Duration,
+) -> Result<(Vec<u8>, Result<Vec<u16>, String>), ModbusError> {
+    let mut mreq = ModbusRequest::new(slave_id, proto);
+    mreq.tr_id = utils::next_transaction_id();
+    let mut request = Vec::new();
+    let start = utils::protocol_address(block.start, address_base);
+
+    match block.input_type {
+        registers::ModbusRegisterType::Holding => {
+            mreq.generate_get_holdings(start, block.length, &mut request).unwrap();
+        }
+        registers::ModbusRegisterType::Input => {
+            mreq.generate_get_inputs(start, block.length, &mut request).unwrap();
+        }
+        registers::ModbusRegisterType::Coil => {
+            mreq.generate_get_coils(start, block.length, &mut request).unwrap();
+        }
+        registers::ModbusRegisterType::DiscreteInput => {
+            mreq.generate_get_discretes(start, block.length, &mut request).unwrap();
+        }
+    }
+
+    /* The stale bytes of a broken frame are dropped before the next request, so only this block fails */
+    let response = match transact(stream, &request, proto, response_timeout, block.start).await {
+        Ok(response) => response,
+        Err(ModbusError::ProtocolError(e)) => return Ok((Vec::new(), Err(e))),
+        Err(e) => return Err(e),
+    };
+
+    if let Some(exception) = utils::get_exception(&response, proto) {
+        return Ok((response, Err(format!("device responded with {}", exception))));
+    }
+
+    let data = match block.input_type {
+        registers::ModbusRegisterType::Coil | registers::ModbusRegisterType::DiscreteInput => utils::parse_bits(&mreq, &response),
+        _ => {
+            let mut data = Vec::new();
+            mreq.parse_u16(&response, &mut data).map(|_| data).map_err(|e| format!("{:?}", e))
+        }
+    };
+
+    Ok((response, data))
+}
+
 /// Read registers from a single device using an existing connection
 pub async fn read_device_registers(
     stream: &mut TcpStream,
@@ -204,12 +380,13 @@ pub async fn read_device_registers(
     hub_name: &str,
     proto: ModbusProto,
     hub_sender: &Sender<Transmission>,
-    read_timeout: Duration,
+    response_timeout: Duration,
 ) -> Result<(), ModbusError> {
     let mut meter_data = MeteringData::new().unwrap();
     meter_data.meter_name = device.config.name.clone();
     meter_data.protocol = DeviceProtocol::ModbusTCP;
-    meter_data.id = crate::get_id("modbus".to_string(), &device.config.name);
+    meter_data.id = crate::get_id_with_unique_id("modbus".to_string(), &device.config.name, device.config.unique_id.as_ref());
+    meter_data.tenant = device.config.tenant.clone().unwrap_or_default();
     meter_data.transmission_time = crate::get_unix_ts();
     meter_data.metered_time = meter_data.transmission_time;
 
@@ -224,225 +401,57 @@ pub async fn read_device_registers(
         registers: Vec::new()
     };
 
-    for reg in &device.registers {
-        let reg = match reg {
-            Register::Template(_) => continue,
-            Register::Modbus(modbus_register) => modbus_register,
-        };
-
-        debug!("Hub {} Device {} Register {} start reading", hub_name, device.config.name, reg.name);
-
-        let mut mreq = ModbusRequest::new(device.config.slave_id, proto);
-        let mut request = Vec::new();
-
-        match reg.input_type {
-            registers::ModbusRegisterType::Holding => {
-                mreq.generate_get_holdings(reg.register, reg.length, &mut request).unwrap();
-            }
-            registers::ModbusRegisterType::Input => {
-                mreq.generate_get_inputs(reg.register, reg.length, &mut request).unwrap();
-            }
-            registers::ModbusRegisterType::Coil => {
-                mreq.generate_get_coils(reg.register, reg.length, &mut request).unwrap();
-            }
-        }
-
-        // Write request with timeout
-        match timeout(read_timeout, stream.write_all(&request)).await {
-            Ok(Ok(_)) => {}
-            Ok(Err(e)) => {
-                return Err(ModbusError::WriteFailed(format!(
-                    "Failed to write request for register {}: {}", reg.name, e
-                )));
-            }
-            Err(_) => {
-                return Err(ModbusError::WriteTimeout(read_timeout.as_secs()));
-            }
-        }
-
-        // Read response header with timeout
-        let mut buf = [0u8; 6];
-        let bytes_read = match timeout(read_timeout, stream.read(&mut buf)).await {
-            Ok(Ok(n)) => n,
-            Ok(Err(e)) => {
-                return Err(ModbusError::IoError(e));
-            }
-            Err(_) => {
-                return Err(ModbusError::ReadTimeout(read_timeout.as_secs()));
-            }
-        };
+    let modbus_regs: Vec<&registers::ModbusRegister> = device.registers.iter()
+        .filter_map(|r| match r {
+            Register::Modbus(modbus_register) => Some(modbus_register),
+            Register::Template(_) => None,
+        })
+        .collect();
 
-        if bytes_read == 0 {
-            return Err(ModbusError::ConnectionClosed);
+    /* Read contiguous registers with a single request, a block the device refuses is read register by register */
+    let mut words: Vec<Result<Vec<u16>, String>> = vec![Err("not read".to_string()); modbus_regs.len()];
+    let mut pending: VecDeque<utils::ReadBlock> = utils::plan_reads(&modbus_regs, device.config.batch_reads).into();
+    let mut first_request = true;
+    while let Some(block) = pending.pop_front() {
+        if !first_request && device.config.inter_register_delay_ms > 0 {
+            tokio::time::sleep(Duration::from_millis(device.config.inter_register_delay_ms)).await;
         }
+        first_request = false;
 
-        let mut response = Vec::new();
-        response.extend_from_slice(&buf[..bytes_read]);
-
-        let len = guess_response_frame_len(&buf, proto)
-            .map_err(|e| ModbusError::ProtocolError(format!(
-                "Failed to determine response length for register {}: {:?}", reg.name, e
-            )))?;
-
-        if len as usize > bytes_read {
-            let mut rest = vec![0u8; len as usize - bytes_read];
-
-            // Read rest of response with timeout
-            let rest_bytes = match timeout(read_timeout, stream.read(&mut rest)).await {
-                Ok(Ok(n)) => n,
-                Ok(Err(e)) => {
-                    return Err(ModbusError::IoError(e));
-                }
-                Err(_) => {
-                    return Err(ModbusError::ReadTimeout(read_timeout.as_secs()));
-                }
-            };
-
-            if rest_bytes == 0 {
-                return Err(ModbusError::ConnectionClosed);
-            }
+        debug!("Hub {} Device {} reading {} registers from {}", hub_name, device.config.name, block.length, block.start);
 
-            response.extend(&rest[..rest_bytes]);
-        }
+        let (response, data) = read_block(stream, device.config.slave_id, proto, &block, device.config.address_base, response_timeout).await?;
+        raw_data.registers.push(E2MRegister { address: block.start as i32, data: response });
 
-        // Process the response - use f64 to handle all numeric types
-        let parsed_value: Result<f64, String>;
-        let mut string_value: Option<String> = None;
-
-        raw_data.registers.push( E2MRegister { address: reg.register as i32, data: response.clone() });
-
-        match reg.format {
-            registers::ModbusRegisterFormat::Coil => {
-                let mut data = Vec::new();
-                match mreq.parse_bool(&response, &mut data) {
-                    Ok(()) => {
-                        parsed_value = Ok(match data[0] {
-                            true => 1,
-                            false => 0
-                        } as f64);
-                    }
-                    Err(e) => {
-                        parsed_value = Err(format!("{:?}", e));
-                    },
-                }
-            }
-            registers::ModbusRegisterFormat::Int32 => {
-                let mut data = Vec::new();
-                match mreq.parse_i16(&response, &mut data) {
-                    Err(e) => {
-                        parsed_value = Err(format!("{:?}", e));
-                    }
-                    Ok(()) => {
-                        if data.len() < 2 {
-                            error!("Register {} is malformed, length is less then INT32 type", reg.name);
-                            parsed_value = Err(format!("Register {} is malformed, length is less then INT32", reg.name))
-                        } else {
-                            let v = ((data[0] as i32) << 16) | (data[1] as u16 as i32);
-                            parsed_value = Ok(v as f64);
-                        }
-                    }
-                }
-            },
-            registers::ModbusRegisterFormat::Int16 => {
-                let mut data = Vec::new();
-                match mreq.parse_i16(&response, &mut data) {
-                    Err(e) => {
-                        parsed_value = Err(format!("{:?}", e));
-                    }
-                    Ok(()) => {
-                        parsed_value = Ok(data[0] as f64);
-                    }
-                }
-            },
-            registers::ModbusRegisterFormat::UInt32 => {
-                let mut data = Vec::new();
-                match mreq.parse_u16(&response, &mut data) {
-                    Err(e) => {
-                        parsed_value = Err(format!("{:?}", e));
-                    }
-                    Ok(()) => {
-                        if data.len() < 2 {
-                            error!("Register {} is malformed, length is less then UInt32 type", reg.name);
-                            parsed_value = Err(format!("Register {} is malformed, length is less then UInt32f", reg.name))
-                        } else {
-                            let v = ((data[0] as u32) << 16) | (data[1] as u32);
-                            parsed_value = Ok(v as f64);
-                        }
-                    }
-                }
-            },
-            registers::ModbusRegisterFormat::UInt16 => {
-                let mut data = Vec::new();
-                match mreq.parse_u16(&response, &mut data) {
-                    Err(e) => {
-                        parsed_value = Err(format!("{:?}", e));
-                    }
-                    Ok(()) => {
-                        parsed_value = Ok(data[0] as f64);
-                    }
-                }
-            },
-            registers::ModbusRegisterFormat::Float32 => {
-                let mut data = Vec::new();
-                match mreq.parse_u16(&response, &mut data) {
-                    Err(e) => {
-                        parsed_value = Err(format!("{:?}", e));
-                    }
-                    Ok(()) => {
-                        if data.len() < 2 {
-                            error!("Register {} is malformed, length is less then Float32 type", reg.name);
-                            parsed_value = Err(format!("Register {} is malformed, length is less then Float32", reg.name))
-                        } else {
-                            // IEEE 754 float32: combine two u16 registers (big-endian)
-                            let bits = ((data[0] as u32) << 16) | (data[1] as u32);
-                            let v = f32::from_bits(bits);
-                            parsed_value = Ok(v as f64);
-                        }
-                    }
+        match data {
+            Ok(data) => {
+                for &i in &block.registers {
+                    let offset = (modbus_regs[i].register - block.start) as usize;
+                    words[i] = data.get(offset..offset + modbus_regs[i].length as usize)
+                        .map(|w| w.to_vec())
+                        .ok_or_else(|| format!("Register {} is missing in the response", modbus_regs[i].name));
                 }
             },
-            registers::ModbusRegisterFormat::String => {
-                let mut data = Vec::new();
-                match mreq.parse_u16(&response, &mut data) {
-                    Err(e) => {
-                        parsed_value = Err(format!("{:?}", e));
-                    }
-                    Ok(()) => {
-                        // Convert u16 registers to string (2 chars per register, big-endian)
-                        let mut chars = Vec::new();
-                        for word in &data {
-                            let hi = ((*word >> 8) & 0xFF) as u8;
-                            let lo = (*word & 0xFF) as u8;
-                            if hi != 0 { chars.push(hi); }
-                            if lo != 0 { chars.push(lo); }
-                        }
-                        string_value = Some(String::from_utf8_lossy(&chars).trim_end_matches('\0').trim().to_string());
-                        parsed_value = Ok(0.0); // Placeholder, we use string_value
-                    }
-                }
-            },
-            registers::ModbusRegisterFormat::SunSSF => {
-                // SunSpec scale factor: int16 representing power of 10 exponent
-                let mut data = Vec::new();
-                match mreq.parse_i16(&response, &mut data) {
-                    Err(e) => {
-                        parsed_value = Err(format!("{:?}", e));
-                    }
-                    Ok(()) => {
-                        let sf = data[0];
-                        scale_factors.insert(reg.name.clone(), sf);
-                        debug!("Hub {} Device {}: Stored scale factor {} = {}",
-                               hub_name, device.config.name, reg.name, sf);
-                        parsed_value = Ok(sf as f64);
-                    }
-                }
+            Err(e) if block.registers.len() > 1 => {
+                warn!("Hub {} Device {}: Reading registers {} to {} failed ({}), reading them one by one",
+                      hub_name, device.config.name, block.start, block.start as u32 + block.length as u32 - 1, e);
+                pending.extend(block.registers.iter().map(|&i| utils::ReadBlock::single(modbus_regs[i], i)));
             },
+            Err(e) => words[block.registers[0]] = Err(e),
         }
+    }
 
-        if let Err(e) = &parsed_value {
-            error!("Error getting response for register {}: {}", reg.name, e);
-            continue;
-        }
+    /* Registers are processed in their configured order, scale factors have to come first */
+    for (reg, data) in modbus_regs.iter().zip(words) {
+        let parsed_value = data.and_then(|w| utils::decode_register(reg, &w));
+
+        let (raw_value, string_value) = match parsed_value {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Error getting response for register {}: {}", reg.name, e);
+                continue;
+            },
+        };
 
         // Handle string values separately
         if let Some(s) = string_value {
@@ -453,11 +462,13 @@ pub async fn read_device_registers(
 
         // For SunSSF, don't add to metered_values (they're internal scale factors)
         if reg.format == registers::ModbusRegisterFormat::SunSSF {
+            let sf = raw_value as i16;
+            scale_factors.insert(reg.name.clone(), sf);
+            debug!("Hub {} Device {}: Stored scale factor {} = {}",
+                   hub_name, device.config.name, reg.name, sf);
             continue;
         }
 
-        let raw_value = parsed_value.unwrap();
-
         // Apply scale factor: either from referenced SunSSF register or from static scaler
         let scaled_value = if let Some(ref sf_name) = reg.scale_factor {
             if let Some(&sf) = scale_factors.get(sf_name) {
@@ -466,42 +477,27 @@ pub async fn read_device_registers(
             } else {
                 warn!("Hub {} Device {}: Scale factor {} not found for register {}, using raw value",
                       hub_name, device.config.name, sf_name, reg.name);
-                raw_value * reg.scaler as f64
+                raw_value * reg.scaler
             }
         } else {
             // Use static scaler
-            raw_value * reg.scaler as f64
+            raw_value * reg.scaler
         };
 
-        {
-
-            let v = round_number(scaled_value, reg.precision);
-            let mut value = serde_json::Value::from(v);
-
-            let mut found = false;
-            for mapping in reg.mappings.iter() {
-                info!("mapping {:?} with {:?}", mapping.data, v);
-                if mapping.data == format!("{:?}", v) {
-                    value = mapping.mapping.clone();
-                    found = true;
-                    break;
-                }
-            }
-
-            if !found {
-                for mapping in reg.mappings.iter() {
-                    if mapping.data == "_" {
-                        value = mapping.mapping.clone();
-                        break;
-                    }
-                }
-            }
-
-            meter_data.metered_values.insert(reg.name.clone(), value);
-            let _ = context.set_value(reg.name.clone(), evalexpr::Value::Float(v as f64));
+        if !utils::is_plausible(scaled_value, reg.valid_min, reg.valid_max) {
+            warn!("Hub {} Device {}: Dropping implausible value {} for register {}",
+                  hub_name, device.config.name, scaled_value, reg.name);
+            continue;
         }
+
+        let v = round_number(scaled_value, reg.decimals);
+        meter_data.metered_values.insert(reg.name.clone(), utils::apply_mappings(v, &reg.mappings));
+        let _ = context.set_value(reg.name.clone(), evalexpr::Value::Float(v as f64));
     }
 
+    // Values of other devices on the hub from their last read, see utils::shared_value_name
+    utils::add_shared_values(hub_name, &mut context);
+
     // Calculate template registers if needed
     for reg in &device.registers {
         let reg = match reg {
@@ -509,24 +505,33 @@ pub async fn read_device_registers(
             Register::Modbus(_) => continue,
         };
 
+        /* Skip the field instead of publishing a made up value */
         let value = match evalexpr::eval_float_with_context(&reg.value, &context) {
             Ok(r) => r,
             Err(e) => {
-                error!("Failed to evaluate: {e:?}");
-                0.0
+                error!("Hub {} Device {}: Failed to evaluate template {}: {e:?}", hub_name, device.config.name, reg.name);
+                continue;
             },
         };
 
-        let value = round_number(value, reg.precision);
-        meter_data.metered_values.insert(reg.name.clone(), serde_json::Value::from(value));
+        if !utils::is_plausible(value, reg.valid_min, reg.valid_max) {
+            warn!("Hub {} Device {}: Dropping implausible value {} for template {}",
+                  hub_name, device.config.name, value, reg.name);
+            continue;
+        }
+
+        let value = round_number(value, reg.decimals);
+        meter_data.metered_values.insert(reg.name.clone(), utils::apply_mappings(value, &reg.mappings));
         let _ = context.set_value(reg.name.clone(), evalexpr::Value::Float(value as f64));
     }
 
+    utils::store_shared_values(hub_name, &device.config.name, &meter_data.metered_values);
+
     let _ = hub_sender.send(Transmission::Metering(meter_data)).await;
 
     /* Now publish the raw data. In that mode we work as transparent bridge for the data to flow */
     let p = PublishData {
-        topic: format!("energy2mqtt/raw/modbus/{}", hub_name),
+        topic: format!("{}/raw/modbus/{}", crate::mqtt::get_topic_prefix(), hub_name),
         payload: serde_json::to_string(&raw_data).unwrap_or("{}".to_string()),
         qos: 1,
         retain: false,
@@ -536,3 +541,41 @@ pub async fn read_device_registers(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_exchange_resyncs() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            /* Rest of an answer the client gave up on */
+            socket.write_all(&[0x00, 0x2A, 0x00]).await.unwrap();
+
+            let mut request = [0u8; 12];
+            socket.read_exact(&mut request).await.unwrap();
+            /* A late answer to an earlier request, then the one asked for */
+            let mut stale = vec![request[0], request[1].wrapping_sub(1), 0, 0, 0, 5, 1, 3, 2, 0, 1];
+            stale.extend([request[0], request[1], 0, 0, 0, 5, 1, 3, 2, 0, 42]);
+            socket.write_all(&stale).await.unwrap();
+        });
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let mut mreq = ModbusRequest::new(1, ModbusProto::TcpUdp);
+        mreq.tr_id = 0x0100;
+        let mut request = Vec::new();
+        mreq.generate_get_holdings(0, 1, &mut request).unwrap();
+
+        let response = transact(&mut stream, &request, ModbusProto::TcpUdp, Duration::from_secs(2), 0).await.unwrap();
+        let mut data = Vec::new();
+        mreq.parse_u16(&response, &mut data).unwrap();
+        assert_eq!(data, vec![42]);
+        server.await.unwrap();
+    }
+}
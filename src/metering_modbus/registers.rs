@@ -1,18 +1,29 @@
-use std::fs::File;
+use std::collections::HashSet;
+use std::fs::{self, File};
 use std::io::prelude::*;
+use std::path::{Path, PathBuf};
 use log::{error, info};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_yml;
+#[cfg(feature = "api")]
+use utoipa::ToSchema;
 
-#[derive(Clone, PartialEq, Deserialize)]
+use crate::CONFIG;
+
+#[derive(Clone, PartialEq, Debug, Deserialize)]
 pub enum ModbusRegisterType {
     Holding,
     Input,
-    Coil
+    Coil,
+    /// Read only bits (function code 02)
+    DiscreteInput,
 }
 #[derive(Clone, PartialEq, Deserialize)]
 pub enum ModbusRegisterFormat {
+    /// Signed two's complement values, SInt16/SInt32 are accepted as well
+    #[serde(alias = "SInt16")]
     Int16,
+    #[serde(alias = "SInt32")]
     Int32,
     UInt16,
     UInt32,
@@ -35,7 +46,7 @@ pub struct Mapping {
     pub mapping: serde_json::Value
 }
 
-fn default_scaler() -> f32 {
+fn default_scaler() -> f64 {
     1.0
 }
 fn default_none_str() -> String {
@@ -44,9 +55,6 @@ fn default_none_str() -> String {
 fn default_platform() -> String {
     "sensor".to_string()
 }
-fn default_precision() -> u32 {
-    3
-}
 fn default_endianess() -> Endianess {
     Endianess::Big
 }
@@ -61,9 +69,10 @@ pub struct ModbusRegister {
     #[serde(default="default_endianess")]
     pub endianess: Endianess,
     #[serde(default="default_scaler")]
-    pub scaler: f32,
-    #[serde(default="default_precision")]
-    pub precision: u32,
+    pub scaler: f64,
+    /// Decimal places the scaled value is rounded to, full precision if unset
+    #[serde(default, alias="precision")]
+    pub decimals: Option<u32>,
     /// Reference to a SunSpec scale factor register name (e.g., "A_SF")
     /// The value from that register will be used as 10^x multiplier
     #[serde(default)]
@@ -88,14 +97,37 @@ pub struct ModbusRegister {
     pub min: Option<u32>,
     pub max: Option<u32>,
     pub step: Option<i32>,
+
+    /// State payloads of binary_sensor and switch platforms (default "1"/"0")
+    #[serde(default)]
+    pub payload_on: Option<String>,
+    #[serde(default)]
+    pub payload_off: Option<String>,
+    /// Value written when a button platform is pressed (default "1")
+    #[serde(default)]
+    pub payload_press: Option<String>,
+
+    /// Plausibility bounds, scaled readings outside are dropped instead of published
+    #[serde(default)]
+    pub valid_min: Option<f64>,
+    #[serde(default)]
+    pub valid_max: Option<f64>,
+
+    /// HA entity category ("diagnostic" or "config"), serials and versions are diagnostic if unset
+    #[serde(default)]
+    pub entity_category: Option<String>,
 }
 
 #[derive(Deserialize, Clone)]
 pub struct TemplateRegister {
     pub name: String,
+    /// evalexpr expression, registers of the same device are used by their name and
+    /// values of other devices on the hub as "{device}.{field}" (e.g. grid.power + pv.power).
+    /// Other devices are only visible with their last read, and only on the same hub:
+    /// device names are unique per hub, so devices of other hubs can't be referenced.
     pub value: String,
-    #[serde(default="default_precision")]
-    pub precision: u32,
+    #[serde(default, alias="precision")]
+    pub decimals: Option<u32>,
     pub unit_of_measurement: String,
     pub device_class: String,
     pub state_class: String,
@@ -103,6 +135,16 @@ pub struct TemplateRegister {
     pub platform: String,
     #[serde(default)]
     pub value_template: String,
+    /// Map results to other values (e.g. a status code to "ok"/"fault") like for modbus registers
+    #[serde(default)]
+    pub mappings: Vec<Mapping>,
+    /// Plausibility bounds, results outside are dropped instead of published
+    #[serde(default)]
+    pub valid_min: Option<f64>,
+    #[serde(default)]
+    pub valid_max: Option<f64>,
+    #[serde(default)]
+    pub entity_category: Option<String>,
 }
 
 #[derive(Clone)]
@@ -120,16 +162,47 @@ pub struct ModbusRegisterFile {
     templates: Vec<TemplateRegister>
 }
 
+/// A definition which can be used as meter of a device
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "api", derive(ToSchema))]
+pub struct ModbusTemplate {
+    /// Value for the meter of a device
+    pub name: String,
+    pub manufacturer: String,
+    pub model: String,
+    pub registers: usize,
+    /// Provided in the definitions directory instead of shipped with energy2mqtt
+    pub user_provided: bool,
+}
+
+/// Parse and check a definition, JSON works as well as it is valid YAML
+fn parse_register_file(contents: &str) -> Result<ModbusRegisterFile, String> {
+    let whole_file: ModbusRegisterFile = serde_yml::from_str(contents).map_err(|e| e.to_string())?;
+
+    if whole_file.registers.is_empty() {
+        return Err("no registers defined".to_string());
+    }
+
+    let mut names = HashSet::new();
+    for name in whole_file.registers.iter().map(|r| &r.name).chain(whole_file.templates.iter().map(|t| &t.name)) {
+        if !names.insert(name) {
+            return Err(format!("register {name} is defined more than once"));
+        }
+    }
+
+    Ok(whole_file)
+}
+
 fn parse_registers(file: &mut File)  -> (Vec<Register>, String, String) {
     let mut regs = Vec::new();
 
     let mut contents = String::new();
     let _ = file.read_to_string(&mut contents);
 
-    let whole_file = match serde_yml::from_str(&contents) {
+    let whole_file = match parse_register_file(&contents) {
         Ok(d) => d,
         Err(e) => {
-            error!("Failed to parse: {e:?}");
+            error!("Failed to parse: {e}");
             ModbusRegisterFile{
                 registers: Vec::new(),
                 templates: Vec::new(),
@@ -138,10 +211,6 @@ fn parse_registers(file: &mut File)  -> (Vec<Register>, String, String) {
             }
         },
     };
-    
-    if whole_file.registers.len() == 0 {
-        error!("Loading the yaml description seems to be failing, because we got no registers");
-    }
 
     for reg in whole_file.registers {
         regs.push(Register::Modbus(reg));
@@ -154,19 +223,104 @@ fn parse_registers(file: &mut File)  -> (Vec<Register>, String, String) {
     return (regs, whole_file.manufacturer, whole_file.model);
 }
 
+/// Directory of the user provided Modbus definitions
+fn user_definitions_dir() -> PathBuf {
+    CONFIG.read().unwrap().get_definitions_path().join("modbus")
+}
+
+/// Parse all definitions below `dir`, named by their path relative to `dir` without extension
+fn scan_definitions(dir: &Path) -> Vec<(String, PathBuf, Result<ModbusRegisterFile, String>)> {
+    let mut definitions = Vec::new();
+    let mut dirs = vec![dir.to_path_buf()];
+
+    while let Some(current) = dirs.pop() {
+        let Ok(entries) = fs::read_dir(&current) else {
+            continue;
+        };
+
+        for path in entries.flatten().map(|e| e.path()) {
+            if path.is_dir() {
+                dirs.push(path);
+                continue;
+            }
+            if !path.extension().is_some_and(|e| e == "yaml" || e == "json") {
+                continue;
+            }
+
+            let model = path.strip_prefix(dir).unwrap_or(&path).with_extension("").to_string_lossy().to_string();
+            let definition = fs::read_to_string(&path).map_err(|e| e.to_string()).and_then(|c| parse_register_file(&c));
+            definitions.push((model, path, definition));
+        }
+    }
+
+    definitions
+}
+
+/// Check all user provided definitions below `dir` and log them, returns the usable models.
+/// Models are named by their path relative to `dir` without extension, e.g. "sunspec/inverter".
+pub fn load_user_definitions(dir: &Path) -> Vec<String> {
+    let mut models = Vec::new();
+
+    for (model, path, definition) in scan_definitions(dir) {
+        match definition {
+            Ok(def) => {
+                info!("Loaded Modbus definition {model} ({} {}, {} registers) from {}",
+                      def.manufacturer, def.model, def.registers.len() + def.templates.len(), path.display());
+                models.push(model);
+            },
+            Err(e) => error!("Ignoring invalid Modbus definition {}: {e}", path.display()),
+        }
+    }
+
+    models.sort();
+    models
+}
+
+/// Usable definitions of both directories, user provided ones replace built-in ones of the same name
+fn templates_in(builtin_dir: &Path, user_dir: &Path) -> Vec<ModbusTemplate> {
+    let mut templates: Vec<ModbusTemplate> = Vec::new();
+
+    for (dir, user_provided) in [(user_dir, true), (builtin_dir, false)] {
+        for (name, _, definition) in scan_definitions(dir) {
+            let Ok(def) = definition else {
+                continue;
+            };
+            if templates.iter().any(|t| t.name == name) {
+                continue;
+            }
+            templates.push(ModbusTemplate {
+                name,
+                manufacturer: def.manufacturer,
+                model: def.model,
+                registers: def.registers.len() + def.templates.len(),
+                user_provided,
+            });
+        }
+    }
+
+    templates.sort_by(|a, b| a.name.cmp(&b.name));
+    templates
+}
+
+/// All definitions get_registers knows
+pub fn list_templates() -> Vec<ModbusTemplate> {
+    templates_in(Path::new("defs/modbus"), &user_definitions_dir())
+}
+
 pub fn get_registers(model: &String) -> (Vec<Register>, String, String) {
     // Model can include subdirectory path, e.g., "sunspec/sunspec_inverter_3p"
     // Search order:
-    // 1. config/modbus/{model}.yaml (user override)
+    // 1. {definitions_path}/modbus/{model}.yaml or .json (user provided, config/ by default)
     // 2. defs/modbus/{model}.yaml (built-in)
-
+    let user_dir = user_definitions_dir();
     let search_paths = [
-        format!("config/modbus/{}.yaml", model),
-        format!("defs/modbus/{}.yaml", model),
+        user_dir.join(format!("{}.yaml", model)),
+        user_dir.join(format!("{}.json", model)),
+        PathBuf::from(format!("defs/modbus/{}.yaml", model)),
     ];
 
     let mut file = None;
-    let mut used_path = String::new();
+    let mut used_path = PathBuf::new();
 
     for path in &search_paths {
         if let Ok(f) = File::open(path) {
@@ -178,10 +332,10 @@ pub fn get_registers(model: &String) -> (Vec<Register>, String, String) {
 
     match file {
         Some(mut f) => {
-            if used_path.starts_with("config/") {
-                info!("Using user provided definition of {model} from {used_path}");
+            if used_path.starts_with(&user_dir) {
+                info!("Using user provided definition of {model} from {}", used_path.display());
             } else {
-                info!("Loading definition of {model} from {used_path}");
+                info!("Loading definition of {model} from {}", used_path.display());
             }
             parse_registers(&mut f)
         }
@@ -190,4 +344,68 @@ pub fn get_registers(model: &String) -> (Vec<Register>, String, String) {
             (Vec::new(), "".to_string(), "".to_string())
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin_definitions_are_valid() {
+        /* The built-in definitions have to pass the checks of user provided ones */
+        let models = load_user_definitions(Path::new("defs/modbus"));
+        let sunspec = |name: &str| format!("sunspec{}{name}", std::path::MAIN_SEPARATOR);
+        for model in ["dzg".to_string(), "ivy-EM1180xx".to_string(), "phoenix_charger".to_string(), "weishaupt".to_string(),
+                      sunspec("sunspec_inverter_1p"), sunspec("sunspec_inverter_3p")] {
+            assert!(models.contains(&model), "{model} is not valid");
+        }
+    }
+
+    #[test]
+    fn test_scaler_keeps_precision() {
+        let reg: ModbusRegister = serde_yml::from_str("{name: current, input_type: Input, register: 0, length: 1, format: UInt16, scaler: 0.1}").unwrap();
+        assert_eq!(1.0 * reg.scaler, 0.1);
+        assert_eq!(12345.0 * reg.scaler, 1234.5);
+    }
+
+    #[test]
+    fn test_user_definitions() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("vendor")).unwrap();
+        fs::write(dir.path().join("vendor/meter.json"), r#"{"manufacturer": "ACME", "model": "M1", "registers": [
+            {"name": "power", "input_type": "Input", "register": 0, "length": 2, "format": "Float32"}]}"#).unwrap();
+        fs::write(dir.path().join("twice.yaml"), "manufacturer: ACME\nmodel: M2\nregisters:\n\
+            - {name: power, input_type: Input, register: 0, length: 1, format: UInt16}\n\
+            - {name: power, input_type: Input, register: 1, length: 1, format: UInt16}\n").unwrap();
+        fs::write(dir.path().join("broken.yaml"), "manufacturer: [").unwrap();
+        fs::write(dir.path().join("notes.txt"), "not a definition").unwrap();
+
+        assert_eq!(load_user_definitions(dir.path()), vec![format!("vendor{}meter", std::path::MAIN_SEPARATOR)]);
+    }
+
+    #[test]
+    fn test_list_templates() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("dzg.yaml"), "manufacturer: ACME\nmodel: M1\nregisters:\n\
+            - {name: power, input_type: Input, register: 0, length: 1, format: UInt16}\n").unwrap();
+        fs::write(dir.path().join("broken.yaml"), "manufacturer: [").unwrap();
+
+        let templates = templates_in(Path::new("defs/modbus"), dir.path());
+        let names: Vec<&String> = templates.iter().map(|t| &t.name).collect();
+        let sunspec = |name: &str| format!("sunspec{}{name}", std::path::MAIN_SEPARATOR);
+        for name in ["dzg".to_string(), "ivy-EM1180xx".to_string(), "phoenix_charger".to_string(), "weishaupt".to_string(),
+                     sunspec("sunspec_inverter_1p"), sunspec("sunspec_inverter_3p")] {
+            assert!(names.contains(&&name), "{name} is missing");
+        }
+        /* Broken files are left out */
+        assert!(!names.contains(&&"broken".to_string()));
+        assert!(templates.windows(2).all(|w| w[0].name < w[1].name));
+
+        /* The user provided definition replaces the built-in one */
+        let dzg = templates.iter().find(|t| t.name == "dzg").unwrap();
+        assert_eq!(dzg.manufacturer, "ACME");
+        assert_eq!(dzg.registers, 1);
+        assert!(dzg.user_provided);
+        assert!(templates.iter().filter(|t| t.name != "dzg").all(|t| !t.user_provided && t.registers > 0));
+    }
+}
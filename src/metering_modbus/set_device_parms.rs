@@ -1,9 +1,9 @@
 use std::{collections::HashMap, time::Duration};
 use log::{debug, error, info};
-use rmodbus::{ModbusProto, client::ModbusRequest, guess_response_frame_len};
-use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream, time::timeout};
+use rmodbus::{ModbusProto, client::ModbusRequest};
+use tokio::net::TcpStream;
 
-use crate::metering_modbus::{HubConnectionState, ModbusDevice, ModbusError, read_device_parms, registers::{ModbusRegisterType, Register}};
+use crate::metering_modbus::{HubConnectionState, ModbusDevice, ModbusError, read_device_parms, registers::{ModbusRegisterType, Register}, utils};
 
 pub async fn set(
     socket_addr: &str,
@@ -18,7 +18,8 @@ pub async fn set(
         match read_device_parms::connect_to_hub_with_retry(
             socket_addr,
             hub_name,
-            conn_state.connection_timeout
+            conn_state.connection_timeout,
+            conn_state.keepalive.as_ref()
         ).await {
             Ok(s) => {
                 info!("Hub {}: Connection established", hub_name);
@@ -51,6 +52,7 @@ pub async fn set(
                 debug!("Got register to write ... {address}");
 
                 let mut mreq = ModbusRequest::new(device.config.slave_id, proto);
+                mreq.tr_id = utils::next_transaction_id();
                 let value_u16 = match value.len() {
                     1 => value[0] as u16,
                     2 => u16::from_be_bytes([value[0], value[1]]),
@@ -61,11 +63,12 @@ pub async fn set(
                 };
 
                 let mut request = Vec::new();
+                let wire_address = utils::protocol_address(address, device.config.address_base);
 
                 if let Err(e) = match r.input_type {
-                    ModbusRegisterType::Holding => mreq.generate_set_holding(address, value_u16, &mut request),
-                    ModbusRegisterType::Coil => mreq.generate_set_coil(address, value[0], &mut request),
-                    ModbusRegisterType::Input => {
+                    ModbusRegisterType::Holding => mreq.generate_set_holding(wire_address, value_u16, &mut request),
+                    ModbusRegisterType::Coil => mreq.generate_set_coil(wire_address, value[0], &mut request),
+                    ModbusRegisterType::Input | ModbusRegisterType::DiscreteInput => {
                         error!("Trying to set input register on hub {} device {} address {address}", hub_name, device.config.name);
                         continue;
                     }
@@ -74,7 +77,7 @@ pub async fn set(
                     continue;
                 }
 
-                match write_single_register(stream, request, proto).await {
+                match write_single_register(stream, request, proto, address).await {
                     Ok(_) => info!("Written register {} on Device {} of Hub {}", address, device.config.name, hub_name),
                     Err(e) => error!("Writing register {} on Device {} of Hub {} failed: {e:?}", address, device.config.name, hub_name),
                 }
@@ -90,6 +93,7 @@ pub async fn write_register(device: &ModbusDevice, proto: ModbusProto,  conn_sta
         let address = r.register;
 
         let mut mreq = ModbusRequest::new(device.config.slave_id, proto);
+        mreq.tr_id = utils::next_transaction_id();
         let value_u16 = match value.len() {
             1 => value[0] as u16,
             2 => u16::from_be_bytes([value[0], value[1]]),
@@ -100,11 +104,12 @@ pub async fn write_register(device: &ModbusDevice, proto: ModbusProto,  conn_sta
         };
 
         let mut request = Vec::new();
+        let wire_address = utils::protocol_address(address, device.config.address_base);
 
         if let Err(e) = match r.input_type {
-            ModbusRegisterType::Holding => mreq.generate_set_holding(address, value_u16, &mut request),
-            ModbusRegisterType::Coil => mreq.generate_set_coil(address, value[0], &mut request),
-            ModbusRegisterType::Input => {
+            ModbusRegisterType::Holding => mreq.generate_set_holding(wire_address, value_u16, &mut request),
+            ModbusRegisterType::Coil => mreq.generate_set_coil(wire_address, value[0], &mut request),
+            ModbusRegisterType::Input | ModbusRegisterType::DiscreteInput => {
                 error!("Trying to set input register on device {} address {address}", device.config.name);
                 return;
             }
@@ -121,74 +126,20 @@ pub async fn write_register(device: &ModbusDevice, proto: ModbusProto,  conn_sta
         /* Get our stream to write to */
         let stream = conn_state.stream.as_mut().unwrap();
 
-        match write_single_register(stream, request, proto).await {
+        match write_single_register(stream, request, proto, address).await {
             Ok(_) => info!("Written register {} on Device {}", address, device.config.name),
             Err(e) => error!("Writing register {} on Device {} failed: {e:?}", address, device.config.name),
         }
     }
 }
 
-async fn write_single_register(stream: &mut TcpStream, request: Vec<u8>, proto: ModbusProto) -> Result<(), ModbusError> {
-
+async fn write_single_register(stream: &mut TcpStream, request: Vec<u8>, proto: ModbusProto, address: u16) -> Result<(), ModbusError> {
     let modbus_timeout = Duration::from_millis(1000);
+    let response = read_device_parms::transact(stream, &request, proto, modbus_timeout, address).await?;
 
-    // Write request with timeout
-    match timeout(modbus_timeout, stream.write_all(&request)).await {
-        Ok(Ok(_)) => {}
-        Ok(Err(e)) => {
-            return Err(ModbusError::WriteFailed(format!(
-                "Failed to write request {request:?}, {e}"
-            )));
-        }
-        Err(_) => {
-            return Err(ModbusError::WriteTimeout(modbus_timeout.as_secs()));
-        }
-    }
-
-    // Read response header with timeout
-    let mut buf = [0u8; 6];
-    let bytes_read = match timeout(modbus_timeout, stream.read(&mut buf)).await {
-        Ok(Ok(n)) => n,
-        Ok(Err(e)) => {
-            return Err(ModbusError::IoError(e));
-        }
-        Err(_) => {
-            return Err(ModbusError::ReadTimeout(modbus_timeout.as_secs()));
-        }
-    };
-
-    if bytes_read == 0 {
-        return Err(ModbusError::ConnectionClosed);
-    }
-
-    let mut response = Vec::new();
-    response.extend_from_slice(&buf[..bytes_read]);
-
-    let len = guess_response_frame_len(&buf, proto)
-        .map_err(|e| ModbusError::ProtocolError(format!(
-            "Failed to determine response length for register{e:?}"
-        )))?;
-
-    if len as usize > bytes_read {
-        let mut rest = vec![0u8; len as usize - bytes_read];
-
-        // Read rest of response with timeout
-        let rest_bytes = match timeout(modbus_timeout, stream.read(&mut rest)).await {
-            Ok(Ok(n)) => n,
-            Ok(Err(e)) => {
-                return Err(ModbusError::IoError(e));
-            }
-            Err(_) => {
-                return Err(ModbusError::ReadTimeout(modbus_timeout.as_secs()));
-            }
-        };
-
-        if rest_bytes == 0 {
-            return Err(ModbusError::ConnectionClosed);
-        }
-
-        response.extend(&rest[..rest_bytes]);
+    if let Some(exception) = super::utils::get_exception(&response, proto) {
+        return Err(ModbusError::Exception(exception));
     }
 
     Ok(())
-}
\ No newline at end of file
+}
@@ -1,6 +1,24 @@
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::RwLock;
+use std::time::Duration;
+
+use evalexpr::{ContextWithMutableVariables, DefaultNumericTypes, HashMapContext};
+use lazy_static::lazy_static;
 use log::error;
 
-use crate::metering_modbus::registers::{self, Register, Endianess};
+use rmodbus::{client::ModbusRequest, ModbusProto};
+use socket2::TcpKeepalive;
+
+use crate::config::{ModbusHubConfig, ModbusProtoConfig};
+use crate::metering_modbus::ModbusException;
+use crate::metering_modbus::registers::{self, Register, Endianess, Mapping};
+
+lazy_static! {
+    /// Last known numeric values of all devices, keyed by hub and then by "device.field"
+    static ref SHARED_VALUES: RwLock<HashMap<String, HashMap<String, f64>>> = RwLock::new(HashMap::new());
+}
 
 macro_rules! handle_endianess {
     ($input:expr, $endianess:expr) => {
@@ -27,28 +45,28 @@ pub fn get_data_vec(register_enum: &Register, payload: &String) -> Vec<u8> {
             registers::ModbusRegisterFormat::Int16 => {
                 let d: Result<i16, _> = input.parse();
                 if let Ok(d) = d {
-                    let d = (d as f32 / reg.scaler) as i16;
+                    let d = (d as f64 / reg.scaler) as i16;
                     value = handle_endianess!(d, reg.endianess);
                 }
             },
             registers::ModbusRegisterFormat::UInt16 => {
                 let d: Result<u16, _> = input.parse();
                 if let Ok(d) = d {
-                    let d = (d as f32 / reg.scaler) as u16;
+                    let d = (d as f64 / reg.scaler) as u16;
                     value = handle_endianess!(d, reg.endianess);
                 }
             },
             registers::ModbusRegisterFormat::Int32 => {
                 let d: Result<i32, _> = input.parse();
                 if let Ok(d) = d {
-                    let d = (d as f32 / reg.scaler) as i32;
+                    let d = (d as f64 / reg.scaler) as i32;
                     value = handle_endianess!(d, reg.endianess);
                 }
             },
             registers::ModbusRegisterFormat::UInt32 => {
                 let d: Result<u32, _> = input.parse();
                 if let Ok(d) = d {
-                    let d = (d as f32 / reg.scaler) as u32;
+                    let d = (d as f64 / reg.scaler) as u32;
                     value = handle_endianess!(d, reg.endianess);
                 }
             },
@@ -66,7 +84,630 @@ pub fn get_data_vec(register_enum: &Register, payload: &String) -> Vec<u8> {
     value
 }
 
-pub fn round_number(number: f64, precision: u32) -> f64 {
-    let scaler = i32::pow(10, precision) as f64;
-    return (number * scaler).round() / scaler;
-}
\ No newline at end of file
+/// Value of a signed 16 bit register in two's complement
+pub fn decode_i16(word: u16) -> f64 {
+    word as i16 as f64
+}
+
+/// Value of a signed 32 bit register pair in two's complement, high word first
+pub fn decode_i32(high: u16, low: u16) -> f64 {
+    (((high as u32) << 16) | low as u32) as i32 as f64
+}
+
+static TRANSACTION_ID: AtomicU16 = AtomicU16::new(1);
+
+/// Transaction id of the next TCP request, a response to an earlier request never matches it
+pub fn next_transaction_id() -> u16 {
+    TRANSACTION_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// CRC16 of RTU frames (polynomial 0xA001, start 0xFFFF), sent low byte first
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for byte in data {
+        crc ^= *byte as u16;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xA001 } else { crc >> 1 };
+        }
+    }
+    crc
+}
+
+/// Bytes of a response needed to know the length of the whole frame
+pub fn response_header_len(proto: ModbusProto) -> usize {
+    match proto {
+        /* MBAP header up to the length field */
+        ModbusProto::TcpUdp => 6,
+        /* Unit id, function and byte count or exception code */
+        _ => 3,
+    }
+}
+
+/// Why a complete response frame does not belong to the request
+#[derive(Debug, PartialEq)]
+pub enum FrameMismatch {
+    /// Answer to an earlier request, the next frame may be the right one
+    Stale(String),
+    Broken(String),
+}
+
+/// Compare the PDU of a response with the one of its request: the function code (exceptions
+/// have the high bit set) and the number of data bytes a read has to return
+fn check_response_pdu(request: &[u8], response: &[u8]) -> Result<(), String> {
+    let (Some(function), Some(answered)) = (request.first(), response.first()) else {
+        return Err("frame without function code".to_string());
+    };
+    if answered & 0x7F != *function {
+        return Err(format!("function {:#04x} instead of {:#04x}", answered & 0x7F, function));
+    }
+    if answered & 0x80 != 0 {
+        return Ok(());
+    }
+
+    let (Some(quantity), Some(byte_count)) = (request.get(3..5), response.get(1)) else {
+        return Ok(());
+    };
+    let quantity = u16::from_be_bytes([quantity[0], quantity[1]]) as usize;
+    let expected = match function {
+        /* Coils and discrete inputs are packed into bits */
+        0x01 | 0x02 => quantity.div_ceil(8),
+        0x03 | 0x04 => quantity * 2,
+        /* Writes echo the request */
+        _ => return Ok(()),
+    };
+    if *byte_count as usize != expected {
+        return Err(format!("{byte_count} data bytes instead of {expected}"));
+    }
+
+    Ok(())
+}
+
+/// Check a response frame against its request: transaction and unit id for TCP, unit id and CRC for RTU.
+/// Function code and byte count have to match for both, on RTU a mismatch is a late answer to an
+/// earlier request as RTU frames carry no transaction id.
+pub fn check_response_frame(request: &[u8], response: &[u8], proto: ModbusProto) -> Result<(), FrameMismatch> {
+    match proto {
+        ModbusProto::TcpUdp => {
+            if response.len() < 9 || request.len() < 8 {
+                return Err(FrameMismatch::Broken(format!("frame of {} bytes is too short", response.len())));
+            }
+            let transaction = u16::from_be_bytes([response[0], response[1]]);
+            if transaction != u16::from_be_bytes([request[0], request[1]]) {
+                return Err(FrameMismatch::Stale(format!("transaction {transaction}")));
+            }
+            if response[6] != request[6] {
+                return Err(FrameMismatch::Broken(format!("unit id {} instead of {}", response[6], request[6])));
+            }
+            check_response_pdu(&request[7..], &response[7..]).map_err(FrameMismatch::Broken)?;
+        },
+        ModbusProto::Rtu => {
+            if response.len() < 5 || request.len() < 4 {
+                return Err(FrameMismatch::Broken(format!("frame of {} bytes is too short", response.len())));
+            }
+            let (frame, crc) = response.split_at(response.len() - 2);
+            if crc16(frame) != u16::from_le_bytes([crc[0], crc[1]]) {
+                return Err(FrameMismatch::Broken("CRC mismatch".to_string()));
+            }
+            if response[0] != request[0] {
+                return Err(FrameMismatch::Broken(format!("unit id {} instead of {}", response[0], request[0])));
+            }
+            check_response_pdu(&request[1..request.len() - 2], &frame[1..]).map_err(FrameMismatch::Stale)?;
+        },
+        /* ASCII frames are checked by the parser */
+        ModbusProto::Ascii => {},
+    }
+
+    Ok(())
+}
+
+/// Exception carried by a response frame, the function code has its high bit set then
+pub fn get_exception(response: &[u8], proto: ModbusProto) -> Option<ModbusException> {
+    let function_pos = match proto {
+        ModbusProto::TcpUdp => 7,
+        ModbusProto::Rtu => 1,
+        /* ASCII frames are hex encoded, the parser reports their exceptions */
+        ModbusProto::Ascii => return None,
+    };
+
+    match response.get(function_pos..function_pos + 2) {
+        Some([function, code]) if function & 0x80 != 0 => Some(ModbusException::from_code(*code)),
+        _ => None,
+    }
+}
+
+/// Most registers (or coils) a single read request may return, keeps every block within the PDU size
+pub const MAX_BLOCK_REGISTERS: u16 = 125;
+
+/// A single read request covering the registers at the given indices
+#[derive(Debug, PartialEq)]
+pub struct ReadBlock {
+    pub input_type: registers::ModbusRegisterType,
+    pub start: u16,
+    pub length: u16,
+    pub registers: Vec<usize>,
+}
+
+impl ReadBlock {
+    pub fn single(reg: &registers::ModbusRegister, index: usize) -> Self {
+        ReadBlock { input_type: reg.input_type.clone(), start: reg.register, length: reg.length, registers: vec![index] }
+    }
+}
+
+/// Address sent on the wire for a register of the definition, 1-based definitions are shifted down by one
+pub fn protocol_address(register: u16, address_base: u8) -> u16 {
+    register.saturating_sub(address_base as u16)
+}
+
+/// Group registers of the same type with contiguous (or overlapping) addresses into blocks,
+/// without batching every register is read on its own
+pub fn plan_reads(regs: &[&registers::ModbusRegister], batch: bool) -> Vec<ReadBlock> {
+    if !batch {
+        return regs.iter().enumerate().map(|(i, r)| ReadBlock::single(r, i)).collect();
+    }
+
+    let mut order: Vec<usize> = (0..regs.len()).collect();
+    order.sort_by_key(|&i| regs[i].register);
+
+    let mut blocks: Vec<ReadBlock> = Vec::new();
+    for i in order {
+        let reg = regs[i];
+        let reg_end = reg.register as u32 + reg.length as u32;
+
+        /* Sorted by address, so the last block of a type is the only candidate */
+        if let Some(block) = blocks.iter_mut().rev().find(|b| b.input_type == reg.input_type) {
+            let block_end = block.start as u32 + block.length as u32;
+            let new_end = block_end.max(reg_end);
+            if reg.register as u32 <= block_end && new_end - (block.start as u32) <= MAX_BLOCK_REGISTERS as u32 {
+                block.length = (new_end - block.start as u32) as u16;
+                block.registers.push(i);
+                continue;
+            }
+        }
+
+        blocks.push(ReadBlock::single(reg, i));
+    }
+
+    blocks
+}
+
+/// Unpack the bits of a coil or discrete input response (least significant bit first), one word per bit
+pub fn parse_bits(mreq: &ModbusRequest, response: &[u8]) -> Result<Vec<u16>, String> {
+    let mut bits = Vec::new();
+    mreq.parse_bool(response, &mut bits).map_err(|e| format!("{:?}", e))?;
+    Ok(bits.iter().map(|b| *b as u16).collect())
+}
+
+/// Value of a register from its words (one word per bit for coils and discrete inputs), strings are returned separately
+pub fn decode_register(reg: &registers::ModbusRegister, words: &[u16]) -> Result<(f64, Option<String>), String> {
+    if words.is_empty() {
+        return Err(format!("Register {} got no data", reg.name));
+    }
+
+    /* Bits stay bits, whatever format is configured for them */
+    if matches!(reg.input_type, registers::ModbusRegisterType::Coil | registers::ModbusRegisterType::DiscreteInput) {
+        return Ok((if words[0] != 0 { 1.0 } else { 0.0 }, None));
+    }
+
+    let value = match reg.format {
+        registers::ModbusRegisterFormat::Coil => if words[0] != 0 { 1.0 } else { 0.0 },
+        registers::ModbusRegisterFormat::Int16 | registers::ModbusRegisterFormat::SunSSF => decode_i16(words[0]),
+        registers::ModbusRegisterFormat::UInt16 => words[0] as f64,
+        registers::ModbusRegisterFormat::Int32 => {
+            if words.len() < 2 {
+                return Err(format!("Register {} is malformed, length is less then INT32", reg.name));
+            }
+            decode_i32(words[0], words[1])
+        },
+        registers::ModbusRegisterFormat::UInt32 => {
+            if words.len() < 2 {
+                return Err(format!("Register {} is malformed, length is less then UInt32", reg.name));
+            }
+            (((words[0] as u32) << 16) | (words[1] as u32)) as f64
+        },
+        registers::ModbusRegisterFormat::Float32 => {
+            if words.len() < 2 {
+                return Err(format!("Register {} is malformed, length is less then Float32", reg.name));
+            }
+            // IEEE 754 float32: combine two u16 registers (big-endian)
+            f32::from_bits(((words[0] as u32) << 16) | (words[1] as u32)) as f64
+        },
+        registers::ModbusRegisterFormat::String => {
+            // Convert u16 registers to string (2 chars per register, big-endian)
+            let chars: Vec<u8> = words.iter()
+                .flat_map(|w| w.to_be_bytes())
+                .filter(|c| *c != 0)
+                .collect();
+            return Ok((0.0, Some(String::from_utf8_lossy(&chars).trim().to_string())));
+        },
+    };
+
+    Ok((value, None))
+}
+
+/// Replace a value by its mapping, "_" maps every value without a mapping of its own
+pub fn apply_mappings(value: f64, mappings: &[Mapping]) -> serde_json::Value {
+    let data = format!("{:?}", value);
+    mappings.iter()
+        .find(|m| m.data == data)
+        .or_else(|| mappings.iter().find(|m| m.data == "_"))
+        .map(|m| m.mapping.clone())
+        .unwrap_or_else(|| serde_json::Value::from(value))
+}
+
+/// Check that a value is a real number within the optional plausibility bounds
+pub fn is_plausible(value: f64, min: Option<f64>, max: Option<f64>) -> bool {
+    if !value.is_finite() {
+        return false;
+    }
+
+    min.is_none_or(|m| value >= m) && max.is_none_or(|m| value <= m)
+}
+
+/// Connections a hub reads its devices on, a serial bus (RTU or RTU over TCP) only takes one request at a time
+pub fn parallel_reads(config: &ModbusHubConfig) -> usize {
+    match config.proto {
+        ModbusProtoConfig::RTU | ModbusProtoConfig::RTUoverTCP => 1,
+        _ => config.max_parallel_reads.max(1) as usize,
+    }
+}
+
+/// Number derived from a name, spreads hubs and devices the same way on every start
+pub fn name_hash(name: &str) -> u32 {
+    let mut hasher = DefaultHasher::new();
+    name.hash(&mut hasher);
+    hasher.finish() as u32
+}
+
+/// Number below n derived from a name, 0 for n = 0
+pub fn spread_below(name: &str, n: u32) -> u32 {
+    if n == 0 {
+        return 0;
+    }
+    name_hash(name) % n
+}
+
+/// Ticks a device starts with so its first read comes up to max_jitter_ticks early. Devices
+/// read on every tick have nothing to spread.
+pub fn jitter_waits(waits_till_read: u32, max_jitter_ticks: u32, spread: u32) -> u32 {
+    let max = max_jitter_ticks.min(waits_till_read.saturating_sub(1));
+    spread % (max + 1)
+}
+
+/// Time a request of the hub's devices may take for the complete response
+pub fn response_timeout(config: &ModbusHubConfig) -> Duration {
+    config.response_timeout_ms.map(Duration::from_millis).unwrap_or(Duration::from_secs(config.read_timeout))
+}
+
+/// Keepalive of the hub's connections, None keeps the OS default
+pub fn tcp_keepalive(config: &ModbusHubConfig) -> Option<TcpKeepalive> {
+    let time = Duration::from_secs(config.tcp_keepalive_secs?);
+    let interval = config.tcp_keepalive_interval_secs.map(Duration::from_secs).unwrap_or(time);
+    Some(TcpKeepalive::new().with_time(time).with_interval(interval))
+}
+
+/// Round to the given number of decimal places, None keeps the full precision
+pub fn round_number(number: f64, decimals: Option<u32>) -> f64 {
+    match decimals {
+        Some(decimals) => {
+            let scaler = 10_f64.powi(decimals as i32);
+            (number * scaler).round() / scaler
+        },
+        None => number,
+    }
+}
+
+/// Name under which a value of a device is visible to templates of other devices on
+/// the same hub: "{device}.{field}", everything but letters, digits and '_' in the
+/// device name is replaced by '_' so the name stays a valid evalexpr identifier
+pub fn shared_value_name(device: &str, field: &str) -> String {
+    let device: String = device.chars()
+        .map(|c| if c.is_alphanumeric() || c == '_' { c } else { '_' })
+        .collect();
+    format!("{device}.{field}")
+}
+
+/// Remember the numeric values of a device for templates of other devices
+pub fn store_shared_values(hub: &str, device: &str, values: &serde_json::Map<String, serde_json::Value>) {
+    let mut shared = SHARED_VALUES.write().unwrap();
+    let hub_values = shared.entry(hub.to_string()).or_default();
+
+    for (field, value) in values.iter() {
+        if let Some(v) = value.as_f64() {
+            hub_values.insert(shared_value_name(device, field), v);
+        }
+    }
+}
+
+/// Make the last known values of all devices of a hub available in a template context
+pub fn add_shared_values(hub: &str, context: &mut HashMapContext<DefaultNumericTypes>) {
+    let shared = SHARED_VALUES.read().unwrap();
+    if let Some(hub_values) = shared.get(hub) {
+        for (name, value) in hub_values.iter() {
+            let _ = context.set_value(name.clone(), evalexpr::Value::Float(*value));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_response_frame() {
+        /* Read holding registers 0..2 of unit 1 */
+        let rtu_request = [0x01, 0x03, 0x00, 0x00, 0x00, 0x02, 0xC4, 0x0B];
+        assert_eq!(crc16(&rtu_request[..6]), 0x0BC4);
+
+        let mut rtu_response = vec![0x01, 0x03, 0x04, 0x00, 0x01, 0x00, 0x02];
+        rtu_response.extend(crc16(&rtu_response).to_le_bytes());
+        assert_eq!(check_response_frame(&rtu_request, &rtu_response, ModbusProto::Rtu), Ok(()));
+
+        let mut corrupted = rtu_response.clone();
+        corrupted[4] ^= 0xFF;
+        assert!(matches!(check_response_frame(&rtu_request, &corrupted, ModbusProto::Rtu), Err(FrameMismatch::Broken(_))));
+
+        let mut other_unit = vec![0x02, 0x03, 0x02, 0x00, 0x01];
+        other_unit.extend(crc16(&other_unit).to_le_bytes());
+        assert!(matches!(check_response_frame(&rtu_request, &other_unit, ModbusProto::Rtu), Err(FrameMismatch::Broken(_))));
+
+        /* A late answer of the same unit to an earlier request is skipped */
+        let mut other_function = vec![0x01, 0x04, 0x04, 0x00, 0x01, 0x00, 0x02];
+        other_function.extend(crc16(&other_function).to_le_bytes());
+        assert_eq!(check_response_frame(&rtu_request, &other_function, ModbusProto::Rtu),
+                   Err(FrameMismatch::Stale("function 0x04 instead of 0x03".to_string())));
+
+        let mut other_length = vec![0x01, 0x03, 0x02, 0x00, 0x01];
+        other_length.extend(crc16(&other_length).to_le_bytes());
+        assert_eq!(check_response_frame(&rtu_request, &other_length, ModbusProto::Rtu),
+                   Err(FrameMismatch::Stale("2 data bytes instead of 4".to_string())));
+
+        /* Exceptions carry no byte count */
+        let mut exception = vec![0x01, 0x83, 0x02];
+        exception.extend(crc16(&exception).to_le_bytes());
+        assert_eq!(check_response_frame(&rtu_request, &exception, ModbusProto::Rtu), Ok(()));
+
+        let tcp_request = [0x00, 0x07, 0x00, 0x00, 0x00, 0x06, 0x01, 0x03, 0x00, 0x00, 0x00, 0x01];
+        let tcp_response = [0x00, 0x07, 0x00, 0x00, 0x00, 0x05, 0x01, 0x03, 0x02, 0x00, 0x2A];
+        assert_eq!(check_response_frame(&tcp_request, &tcp_response, ModbusProto::TcpUdp), Ok(()));
+
+        let mut stale = tcp_response;
+        stale[1] = 0x06;
+        assert_eq!(check_response_frame(&tcp_request, &stale, ModbusProto::TcpUdp), Err(FrameMismatch::Stale("transaction 6".to_string())));
+        assert!(matches!(check_response_frame(&tcp_request, &tcp_response[..6], ModbusProto::TcpUdp), Err(FrameMismatch::Broken(_))));
+
+        let mut other_function = tcp_response;
+        other_function[7] = 0x04;
+        assert_eq!(check_response_frame(&tcp_request, &other_function, ModbusProto::TcpUdp),
+                   Err(FrameMismatch::Broken("function 0x04 instead of 0x03".to_string())));
+
+        let other_length = [0x00, 0x07, 0x00, 0x00, 0x00, 0x07, 0x01, 0x03, 0x04, 0x00, 0x2A, 0x00, 0x2B];
+        assert_eq!(check_response_frame(&tcp_request, &other_length, ModbusProto::TcpUdp),
+                   Err(FrameMismatch::Broken("4 data bytes instead of 2".to_string())));
+
+        /* Coils are packed into bits */
+        let coil_request = [0x00, 0x08, 0x00, 0x00, 0x00, 0x06, 0x01, 0x01, 0x00, 0x00, 0x00, 0x0A];
+        let coil_response = [0x00, 0x08, 0x00, 0x00, 0x00, 0x05, 0x01, 0x01, 0x02, 0xFF, 0x03];
+        assert_eq!(check_response_frame(&coil_request, &coil_response, ModbusProto::TcpUdp), Ok(()));
+
+        assert_ne!(next_transaction_id(), next_transaction_id());
+    }
+
+    #[test]
+    fn test_is_plausible() {
+        assert!(is_plausible(5.0, None, None));
+        assert!(is_plausible(5.0, Some(0.0), Some(5.0)));
+        assert!(!is_plausible(-0.1, Some(0.0), None));
+        assert!(!is_plausible(10.0, None, Some(5.0)));
+        assert!(!is_plausible(f64::NAN, None, None));
+        assert!(!is_plausible(f64::INFINITY, None, None));
+    }
+
+    #[test]
+    fn test_signed_registers() {
+        assert_eq!(decode_i16(0x03E8), 1000.0);
+        assert_eq!(decode_i16(0xFC18), -1000.0);
+        assert_eq!(decode_i16(0xFFFF), -1.0);
+        assert_eq!(decode_i16(0x8000), -32768.0);
+
+        /* -1500 W export */
+        assert_eq!(decode_i32(0xFFFF, 0xFA24), -1500.0);
+        assert_eq!(decode_i32(0x0001, 0x0000), 65536.0);
+        assert_eq!(decode_i32(0x8000, 0x0000), i32::MIN as f64);
+
+        let format: registers::ModbusRegisterFormat = serde_yml::from_str("SInt32").unwrap();
+        assert!(format == registers::ModbusRegisterFormat::Int32);
+    }
+
+    fn reg(name: &str, input_type: &str, register: u16, length: u16, format: &str) -> registers::ModbusRegister {
+        serde_yml::from_str(&format!("name: {name}\ninput_type: {input_type}\nregister: {register}\nlength: {length}\nformat: {format}\n")).unwrap()
+    }
+
+    #[test]
+    fn test_plan_reads() {
+        let regs = [
+            reg("power", "Holding", 10, 2, "Int32"),
+            reg("voltage", "Holding", 12, 1, "UInt16"),
+            reg("status", "Input", 13, 1, "UInt16"),
+            reg("energy", "Holding", 13, 2, "UInt32"),
+            reg("serial", "Holding", 100, 4, "String"),
+            reg("power_low", "Holding", 11, 1, "UInt16"),
+            reg("far_away", "Holding", 14 + MAX_BLOCK_REGISTERS, 2, "UInt32"),
+        ];
+        let refs: Vec<&registers::ModbusRegister> = regs.iter().collect();
+
+        let blocks = plan_reads(&refs, true);
+        assert_eq!(blocks.len(), 4);
+        assert_eq!((blocks[0].start, blocks[0].length, blocks[0].registers.clone()), (10, 5, vec![0, 5, 1, 3]));
+        assert_eq!((blocks[1].start, blocks[1].length, blocks[1].registers.clone()), (13, 1, vec![2]));
+        assert!(blocks[1].input_type == registers::ModbusRegisterType::Input);
+        assert_eq!((blocks[2].start, blocks[2].length, blocks[2].registers.clone()), (100, 4, vec![4]));
+        /* Would make the block starting at 10 exceed the request size */
+        assert_eq!(blocks[3].registers, vec![6]);
+
+        assert_eq!(plan_reads(&refs, false).len(), regs.len());
+    }
+
+    #[test]
+    fn test_decode_register() {
+        assert_eq!(decode_register(&reg("p", "Holding", 0, 2, "Int32"), &[0xFFFF, 0xFA24]).unwrap(), (-1500.0, None));
+        assert_eq!(decode_register(&reg("e", "Holding", 0, 2, "UInt32"), &[0x0001, 0x0000]).unwrap(), (65536.0, None));
+        assert_eq!(decode_register(&reg("f", "Holding", 0, 2, "Float32"), &[0x41C8, 0x0000]).unwrap(), (25.0, None));
+        assert_eq!(decode_register(&reg("c", "Coil", 0, 1, "Coil"), &[1]).unwrap(), (1.0, None));
+        assert_eq!(decode_register(&reg("d", "DiscreteInput", 0, 1, "Int16"), &[1]).unwrap(), (1.0, None));
+        assert_eq!(decode_register(&reg("d", "DiscreteInput", 0, 1, "Int32"), &[0]).unwrap(), (0.0, None));
+        assert_eq!(decode_register(&reg("s", "Holding", 0, 2, "String"), &[0x4142, 0x4300]).unwrap(), (0.0, Some("ABC".to_string())));
+        assert!(decode_register(&reg("p", "Holding", 0, 1, "Int32"), &[0xFFFF]).is_err());
+    }
+
+    #[test]
+    fn test_parse_bits() {
+        /* Ten discrete inputs over TCP, the second data byte only carries two of them */
+        let mut mreq = ModbusRequest::new(1, ModbusProto::TcpUdp);
+        let mut request = Vec::new();
+        mreq.generate_get_discretes(100, 10, &mut request).unwrap();
+        assert_eq!(request[7], 0x02);
+
+        let response = [request[0], request[1], 0x00, 0x00, 0x00, 0x05, 0x01, 0x02, 0x02, 0b1100_1101, 0b1111_1110];
+        assert_eq!(parse_bits(&mreq, &response).unwrap(), vec![1, 0, 1, 1, 0, 0, 1, 1, 0, 1]);
+
+        /* Same for coils over RTU */
+        let mut mreq = ModbusRequest::new(1, ModbusProto::Rtu);
+        mreq.generate_get_coils(0, 3, &mut request).unwrap();
+        let mut response = vec![0x01, 0x01, 0x01, 0b0000_0101, 0x91, 0x8B];
+        assert_eq!(parse_bits(&mreq, &response).unwrap(), vec![1, 0, 1]);
+
+        /* Broken checksum */
+        let last = response.len() - 1;
+        response[last] ^= 0xFF;
+        assert!(parse_bits(&mreq, &response).is_err());
+    }
+
+    #[test]
+    fn test_get_exception() {
+        /* Read holding registers answered with illegal data address */
+        let tcp = [0x00, 0x01, 0x00, 0x00, 0x00, 0x03, 0x01, 0x83, 0x02];
+        assert_eq!(get_exception(&tcp, ModbusProto::TcpUdp), Some(ModbusException::IllegalDataAddress));
+
+        let rtu = [0x01, 0x84, 0x06, 0x00, 0x00];
+        assert_eq!(get_exception(&rtu, ModbusProto::Rtu), Some(ModbusException::SlaveDeviceBusy));
+
+        let valid = [0x00, 0x01, 0x00, 0x00, 0x00, 0x05, 0x01, 0x03, 0x02, 0x12, 0x34];
+        assert_eq!(get_exception(&valid, ModbusProto::TcpUdp), None);
+        assert_eq!(get_exception(&tcp[..8], ModbusProto::TcpUdp), None);
+
+        assert_eq!(ModbusException::from_code(0x0B).to_string(), "gateway target device failed to respond");
+        assert_eq!(ModbusException::from_code(0x42), ModbusException::Unknown(0x42));
+    }
+
+    #[test]
+    fn test_apply_mappings() {
+        let mappings: Vec<Mapping> = serde_yml::from_str("- data: '0.0'\n  mapping: ok\n- data: '_'\n  mapping: fault\n").unwrap();
+        assert_eq!(apply_mappings(0.0, &mappings), "ok");
+        assert_eq!(apply_mappings(3.0, &mappings), "fault");
+        assert_eq!(apply_mappings(3.0, &mappings[..1]), 3.0);
+        assert_eq!(apply_mappings(1.5, &[]), 1.5);
+
+        let template: registers::TemplateRegister = serde_yml::from_str(
+            "name: state\nvalue: 'error_code'\nunit_of_measurement: NONE\ndevice_class: enum\nstate_class: NONE\n\
+             mappings:\n- data: '0.0'\n  mapping: ok\n"
+        ).unwrap();
+        assert_eq!(apply_mappings(0.0, &template.mappings), "ok");
+    }
+
+    #[test]
+    fn test_parallel_reads() {
+        let mut hub: ModbusHubConfig = serde_yml::from_str("name: hub\nhost: 10.0.0.1\nport: 502\nproto: TCP\n").unwrap();
+        assert_eq!(parallel_reads(&hub), 1);
+
+        hub.max_parallel_reads = 4;
+        assert_eq!(parallel_reads(&hub), 4);
+
+        hub.max_parallel_reads = 0;
+        assert_eq!(parallel_reads(&hub), 1);
+
+        hub.max_parallel_reads = 4;
+        hub.proto = ModbusProtoConfig::RTUoverTCP;
+        assert_eq!(parallel_reads(&hub), 1);
+
+        hub.proto = ModbusProtoConfig::RTU;
+        assert_eq!(parallel_reads(&hub), 1);
+    }
+
+    #[test]
+    fn test_protocol_address() {
+        assert_eq!(protocol_address(30001, 0), 30001);
+        assert_eq!(protocol_address(30001, 1), 30000);
+        assert_eq!(protocol_address(0, 1), 0);
+    }
+
+    #[test]
+    fn test_jitter_waits() {
+        assert_eq!(jitter_waits(1, 10, 7), 0);
+        assert_eq!(jitter_waits(0, 10, 7), 0);
+        assert_eq!(jitter_waits(10, 0, 7), 0);
+        /* Never more than a full interval */
+        assert_eq!(jitter_waits(4, 10, 7), 3);
+        assert!((0..100).all(|r| jitter_waits(10, 5, r) <= 5));
+        assert!(["a", "b", "hub/meter"].iter().all(|n| spread_below(n, 3) < 3));
+        assert_eq!(spread_below("hub", 0), 0);
+        /* Stable for the same name */
+        assert_eq!(name_hash("hub/meter"), name_hash("hub/meter"));
+    }
+
+    #[test]
+    fn test_response_timeout() {
+        let mut hub: ModbusHubConfig = serde_yml::from_str("name: hub\nhost: 10.0.0.1\nport: 502\nproto: TCP\nread_timeout: 3\n").unwrap();
+        assert_eq!(response_timeout(&hub), Duration::from_secs(3));
+
+        hub.response_timeout_ms = Some(500);
+        assert_eq!(response_timeout(&hub), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_tcp_keepalive() {
+        let mut hub: ModbusHubConfig = serde_yml::from_str("name: hub\nhost: 10.0.0.1\nport: 502\nproto: TCP\n").unwrap();
+        assert!(tcp_keepalive(&hub).is_none());
+
+        hub.tcp_keepalive_secs = Some(30);
+        assert!(tcp_keepalive(&hub).is_some());
+    }
+
+    #[test]
+    fn test_round_number() {
+        /* 2305 * 0.1 is not exactly 230.5 as float, it must not become 231 either */
+        let voltage = 2305.0 * 0.1_f32 as f64;
+        assert_eq!(round_number(voltage, Some(1)), 230.5);
+        assert_eq!(round_number(voltage, None), voltage);
+        assert_eq!(round_number(1234.56789, Some(3)), 1234.568);
+        assert_eq!(round_number(1234.56789, Some(0)), 1235.0);
+
+        let template: registers::TemplateRegister = serde_yml::from_str(
+            "name: total\nvalue: 'a + b'\nprecision: 2\nunit_of_measurement: kWh\ndevice_class: energy\nstate_class: total\n"
+        ).unwrap();
+        assert_eq!(template.decimals, Some(2));
+    }
+
+    #[test]
+    fn test_cross_device_template() {
+        let mut grid = serde_json::Map::new();
+        grid.insert("power".to_string(), serde_json::Value::from(1200.0));
+        grid.insert("state".to_string(), serde_json::Value::from("ok"));
+        store_shared_values("test_hub", "grid meter", &grid);
+
+        let mut pv = serde_json::Map::new();
+        pv.insert("power".to_string(), serde_json::Value::from(800.0));
+        store_shared_values("test_hub", "pv", &pv);
+
+        let mut context = HashMapContext::<DefaultNumericTypes>::new();
+        add_shared_values("test_hub", &mut context);
+        let _ = context.set_value("battery_power".to_string(), evalexpr::Value::Float(300.0));
+
+        let value = evalexpr::eval_float_with_context("grid_meter.power + pv.power - battery_power", &context).unwrap();
+        assert_eq!(value, 1700.0);
+
+        /* Values of other hubs are not visible */
+        let mut context = HashMapContext::<DefaultNumericTypes>::new();
+        add_shared_values("other_hub", &mut context);
+        assert!(evalexpr::eval_float_with_context("pv.power", &context).is_err());
+    }
+}
@@ -0,0 +1,235 @@
+/*
+    Generic MQTT input for energy2mqtt
+
+    Subscribes to topics other devices publish JSON to, takes the configured fields
+    out of the payload and publishes them like every other meter, including the
+    Home Assistant discovery.
+*/
+
+use std::collections::HashSet;
+
+use log::{debug, error, info, warn};
+use serde_json::Value;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc::Sender;
+
+use crate::config::{ConfigBases, ConfigChange, MqttInputConfig};
+use crate::models::DeviceProtocol;
+use crate::mqtt::home_assistant::{HaComponent2, HaSensor};
+use crate::mqtt::{publish_protocol_count, SubscribeData, Transmission};
+use crate::{get_config_or_panic, get_id, get_unix_ts, MeteringData, CONFIG};
+
+/// Take the configured fields out of a JSON payload, fields missing in the payload are skipped
+pub fn extract_values(conf: &MqttInputConfig, payload: &Value) -> serde_json::Map<String, Value> {
+    let mut values = serde_json::Map::new();
+
+    for field in conf.fields.iter() {
+        let Some(value) = field.pointer().and_then(|p| payload.pointer(&p)) else {
+            debug!("[MQTT input {}] {} not found in payload", conf.name, field.path);
+            continue;
+        };
+
+        /* Devices often send numbers as strings */
+        let number = match value {
+            Value::Number(n) => n.as_f64(),
+            Value::String(s) => s.trim().parse::<f64>().ok(),
+            _ => None,
+        };
+
+        let value = match (number, field.factor) {
+            (Some(n), Some(factor)) => Value::from(n * factor),
+            (Some(n), None) => Value::from(n),
+            (None, _) => value.clone(),
+        };
+        values.insert(field.name.clone(), value);
+
+        if let Some(unit) = &field.unit {
+            values.insert(format!("{}_unit", field.name), Value::from(unit.clone()));
+        }
+    }
+
+    values
+}
+
+fn to_metering(conf: &MqttInputConfig, values: serde_json::Map<String, Value>) -> MeteringData {
+    let mut meter_data = MeteringData::new().unwrap();
+    let timestamp = get_unix_ts();
+
+    meter_data.meter_name = conf.name.clone();
+    meter_data.protocol = DeviceProtocol::MqttInput;
+    meter_data.state_topic_base = conf.protocol.clone();
+    meter_data.id = get_id(conf.protocol.clone(), &conf.name);
+    meter_data.tenant = conf.tenant.clone().unwrap_or_default();
+    meter_data.transmission_time = timestamp;
+    meter_data.metered_time = timestamp;
+    meter_data.metered_values = values;
+
+    meter_data
+}
+
+pub fn build_discovery(conf: &MqttInputConfig) -> HaSensor {
+    let mut disc = HaSensor::new(conf.protocol.clone(), conf.name.clone(), conf.manufacturer.clone(), conf.model.clone())
+        .meter_ids(conf.tenant.clone().unwrap_or_default(), get_id(conf.protocol.clone(), &conf.name));
+
+    for field in conf.fields.iter() {
+        let mut cmp = HaComponent2::new()
+            .name(field.name.clone())
+            .add_information("value_template", Value::from(format!("{{{{ value_json['{}'] }}}}", field.name)));
+
+        if let Some(unit) = &field.unit {
+            cmp = cmp.unit_of_measurement(unit.clone());
+        }
+        if let Some(device_class) = &field.device_class {
+            cmp = cmp.device_class(device_class.clone());
+        }
+        if let Some(state_class) = &field.state_class {
+            cmp = cmp.state_class(state_class.clone());
+        }
+        /* Without any hint the value may be text like ON/OFF */
+        if field.unit.is_none() && field.device_class.is_none() && field.state_class.is_none() {
+            cmp = cmp.non_numeric();
+        }
+
+        let key: String = field.name.chars()
+            .map(|c| if c.is_alphanumeric() { c } else { '_' })
+            .collect();
+        disc.add_cmp(key, cmp);
+    }
+
+    disc
+}
+
+pub struct MqttInputManager {
+    sender: Sender<Transmission>,
+    config_change: tokio::sync::broadcast::Receiver<ConfigChange>,
+    config: Vec<MqttInputConfig>,
+}
+
+impl MqttInputManager {
+    pub fn new(sender: Sender<Transmission>) -> Self {
+        let config: Vec<MqttInputConfig> = get_config_or_panic!("mqtt_input", ConfigBases::MqttInput);
+
+        MqttInputManager {
+            sender,
+            config_change: CONFIG.read().unwrap().get_change_receiver(),
+            config,
+        }
+    }
+
+    pub async fn start_thread(&mut self) {
+        info!("Starting generic MQTT input thread");
+
+        let (callback_sender, mut receiver) = tokio::sync::mpsc::channel(10);
+        let mut subscribed: HashSet<String> = HashSet::new();
+        let mut discovered: HashSet<String> = HashSet::new();
+
+        loop {
+            /* Topics of removed inputs stay subscribed, their messages are ignored below */
+            for conf in self.config.iter() {
+                if subscribed.insert(conf.topic.clone()) {
+                    let register = Transmission::SubscribeAbsolute(SubscribeData {
+                        topic: conf.topic.clone(),
+                        sender: callback_sender.clone(),
+                    });
+                    let _ = self.sender.send(register).await;
+                }
+            }
+            publish_protocol_count(&self.sender, "mqtt_input", self.config.len() as u32).await;
+
+            loop {
+                tokio::select! {
+                    Some((topic, message)) = receiver.recv() => {
+                        let payload: Value = match serde_json::from_str(&message) {
+                            Ok(p) => p,
+                            Err(e) => {
+                                warn!("Payload of {topic} is no JSON: {e}");
+                                continue;
+                            }
+                        };
+
+                        for conf in self.config.iter().filter(|c| c.topic == topic) {
+                            let values = extract_values(conf, &payload);
+                            if values.is_empty() {
+                                debug!("[MQTT input {}] No configured field found in payload", conf.name);
+                                continue;
+                            }
+
+                            if discovered.insert(conf.name.clone()) {
+                                let _ = self.sender.send(Transmission::AutoDiscovery2(build_discovery(conf))).await;
+                            }
+                            let _ = self.sender.send(Transmission::Metering(to_metering(conf, values))).await;
+                        }
+                    },
+                    change = self.config_change.recv() => {
+                        match change {
+                            Ok(change) if change.base == "mqtt_input" => break,
+                            Ok(_) => {},
+                            Err(RecvError::Lagged(n)) => error!("MQTT input missed {n} config changes"),
+                            Err(RecvError::Closed) => return,
+                        }
+                    },
+                }
+            }
+
+            info!("MQTT input config changed, reloading");
+            self.config = get_config_or_panic!("mqtt_input", ConfigBases::MqttInput);
+            discovered.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::MqttInputField;
+
+    fn field(name: &str, path: &str) -> MqttInputField {
+        MqttInputField { name: name.to_string(), path: path.to_string(), unit: None, device_class: None, state_class: None, factor: None }
+    }
+
+    fn input(fields: Vec<MqttInputField>) -> MqttInputConfig {
+        MqttInputConfig {
+            name: "plug".to_string(),
+            topic: "tele/plug/SENSOR".to_string(),
+            protocol: "tasmota".to_string(),
+            manufacturer: None,
+            model: None,
+            fields,
+            tenant: None,
+        }
+    }
+
+    #[test]
+    fn test_field_pointer() {
+        assert_eq!(field("p", "/ENERGY/Power").pointer(), Some("/ENERGY/Power".to_string()));
+        assert_eq!(field("p", "$.ENERGY.Power").pointer(), Some("/ENERGY/Power".to_string()));
+        assert_eq!(field("p", "$.values[0].power").pointer(), Some("/values/0/power".to_string()));
+        assert_eq!(field("p", "$.a[1][2]").pointer(), Some("/a/1/2".to_string()));
+        assert_eq!(field("p", "$").pointer(), Some("".to_string()));
+        assert_eq!(field("p", "ENERGY.Power").pointer(), None);
+        assert_eq!(field("p", "$..Power").pointer(), None);
+        assert_eq!(field("p", "$.a[x]").pointer(), None);
+    }
+
+    #[test]
+    fn test_extract_values() {
+        let mut power = field("power", "$.ENERGY.Power");
+        power.unit = Some("W".to_string());
+        let mut total = field("energy", "/ENERGY/Total");
+        total.factor = Some(1000.0);
+        let conf = input(vec![power, total, field("state", "$.POWER"), field("missing", "$.nothing")]);
+
+        let payload = serde_json::json!({ "POWER": "ON", "ENERGY": { "Power": 42, "Total": "1.5" } });
+        let values = extract_values(&conf, &payload);
+        assert_eq!(values["power"], 42.0);
+        assert_eq!(values["power_unit"], "W");
+        assert_eq!(values["energy"], 1500.0);
+        assert_eq!(values["state"], "ON");
+        assert!(!values.contains_key("missing"));
+
+        let data = to_metering(&conf, values);
+        assert_eq!(data.state_topic_base, "tasmota");
+        assert_eq!(data.id, "tasmota-plug");
+        assert_eq!(build_discovery(&conf).get_entity_discoveries().len(), 4);
+    }
+}
@@ -0,0 +1,99 @@
+/*
+    Compact frames (CI 0x79, 0x7B behind a short header) leave out the DIF/VIF headers of a
+    full frame (CI 0x78, 0x7A behind a short header) and only
+    carry the data. They start with the format signature, a CRC over all DIF/VIF headers, and
+    the CRC of the full frame they were built from, followed by the data of each record.
+    We learn the formats from the full frames a meter sends from time to time.
+*/
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use crc16::{State, EN_13757};
+use log::debug;
+
+use super::{div_vif_parser, OmsParseError};
+
+/* DIF/VIF headers and data lengths of the records */
+type Format = Vec<(Vec<u8>, usize)>;
+
+lazy_static! {
+    static ref FORMATS: Mutex<HashMap<u16, Format>> = Mutex::new(HashMap::new());
+}
+
+fn get_signature(format: &Format) -> u16 {
+    let headers: Vec<u8> = format.iter().flat_map(|(header, _)| header.clone()).collect();
+    State::<EN_13757>::calculate(&headers)
+}
+
+/// Remember the format of a full frame payload for the compact frames to come
+pub fn learn_format(payload: &Vec<u8>) {
+    let format = div_vif_parser::split_records(payload);
+    if format.is_empty() {
+        return;
+    }
+
+    let signature = get_signature(&format);
+    FORMATS.lock().unwrap().entry(signature).or_insert_with(|| {
+        debug!("Learned OMS format {signature:04x} with {} records", format.len());
+        format
+    });
+}
+
+/// Rebuild the full frame payload of a compact frame from a known format
+pub fn expand(compact: &[u8]) -> Result<Vec<u8>, OmsParseError> {
+    if compact.len() < 4 {
+        return Err(OmsParseError::TelegramTooShort);
+    }
+
+    let signature = u16::from_le_bytes([compact[0], compact[1]]);
+    let full_crc = u16::from_le_bytes([compact[2], compact[3]]);
+
+    let formats = FORMATS.lock().unwrap();
+    let format = formats.get(&signature).ok_or(OmsParseError::UnknownFormat(signature))?;
+
+    let mut payload = Vec::new();
+    let mut cur_pos: usize = 4;
+    for (header, len) in format {
+        let data = compact.get(cur_pos..cur_pos + len).ok_or(OmsParseError::TelegramTooShort)?;
+        payload.extend_from_slice(header);
+        payload.extend_from_slice(data);
+        cur_pos += len;
+    }
+
+    if State::<EN_13757>::calculate(&payload) != full_crc {
+        return Err(OmsParseError::CRCMissMatch);
+    }
+
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compact_frame_is_expanded() {
+        /* Volume 12345 l (32 bit, 1 l) and flow temperature 21 °C (8 bit, 1 °C) */
+        let full: Vec<u8> = vec![0x04, 0x13, 0x39, 0x30, 0x00, 0x00, 0x01, 0x5B, 0x15];
+        learn_format(&full);
+
+        let signature = State::<EN_13757>::calculate(&[0x04, 0x13, 0x01, 0x5B]);
+        let crc = State::<EN_13757>::calculate(&full);
+
+        let mut compact = Vec::new();
+        compact.extend_from_slice(&signature.to_le_bytes());
+        compact.extend_from_slice(&crc.to_le_bytes());
+        compact.extend_from_slice(&[0x39, 0x30, 0x00, 0x00, 0x15]);
+        assert_eq!(expand(&compact).unwrap(), full);
+
+        /* Data of another reading does not match the full frame CRC */
+        compact[4] = 0x3A;
+        assert!(matches!(expand(&compact), Err(OmsParseError::CRCMissMatch)));
+
+        /* Truncated data */
+        assert!(matches!(expand(&compact[..7]), Err(OmsParseError::TelegramTooShort)));
+
+        /* Nobody told us about this format */
+        assert!(matches!(expand(&[0x00, 0x00, 0x00, 0x00]), Err(OmsParseError::UnknownFormat(0))));
+    }
+}
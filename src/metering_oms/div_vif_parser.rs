@@ -1,6 +1,8 @@
 use log::debug;
 use serde_json::Value;
 
+use crate::config::OmsVifOverride;
+
 type DifHandler = fn(start: &Vec<u8>, cur_pos: usize) -> (usize /* bytes to skip */, Value /* Data read */);
 
 fn dif_no_data(_start: &Vec<u8>, _cur_pos: usize) -> (usize, Value) {
@@ -253,9 +255,9 @@ fn get_vif_extension_fb(start: &Vec<u8>, cur_pos: usize) -> (usize /* bytes to s
         /*    E11001nn	External Temperature	10(nn-3) °F	0.001°F to 1°F */
         0b01100100..=0b01100111 => (2, VifData{ fildname: "external_temperature".to_string(), scaler: base.powi((vif as i32 & 0x3) - 3) as f64, vif_function: None, unit: "°F".to_string(), vif: vif }),
         /*    E11100nn	Cold / Warm Temperature Limit	10(nn-3) °F	0.001°F to 1°F */
-        0b01110000..=0b01110011 => (2, VifData{ fildname: "cold_warm_temperature_limit".to_string(), scaler: base.powi((vif as i32 & 0x3) - 3) as f64, vif_function: None, unit: "°F".to_string(), vif: vif }),
+        0b01110000..=0b01110011 => (2, VifData{ fildname: "cold_warm_temperature_limit".to_string(), scaler: base.powi((vif as i32 & 0x3) - 3), vif_function: None, unit: "°F".to_string(), vif }),
         /*    E11101nn	Cold / Warm Temperature Limit	10(nn-3) °C	0.001°C to 1°C */
-        0b01110100..=0b01110111 => (2, VifData{ fildname: "cold_warm_temperature_limit".to_string(), scaler: base.powi((vif as i32 & 0x3) - 3) as f64, vif_function: None, unit: "°C".to_string(), vif: vif }),
+        0b01110100..=0b01110111 => (2, VifData{ fildname: "cold_warm_temperature_limit".to_string(), scaler: base.powi((vif as i32 & 0x3) - 3), vif_function: None, unit: "°C".to_string(), vif }),
         /*    E1111nnn	cumul. count max power §	10(nnn-3) W	0.001W to 10000W */
         0b01111000..=0b01111111 => (2, VifData{ fildname: "cumul_count_max_power".to_string(), scaler: base.powi((vif as i32 & 0x7) - 3) as f64, vif_function: None, unit: "W".to_string(), vif: vif }),
         
@@ -418,16 +420,66 @@ fn get_vif_function(start: &Vec<u8>, cur_pos: usize) -> (usize /* bytes to skip
         /*    E1101110	Units for H.C.A.	dimensionless */
         0b01101110 => (1, VifData{ fildname: "hca_units".to_string(), scaler: 1.0, vif_function: None, unit: "".to_string(), vif: vif }),
         /* E111 00nn	Averaging Duration	coded like OnTime	  */
-        0b01110000..=01110011 => (1, VifData{ fildname: "averaging_duration".to_string(), scaler: 0.0, vif_function: Some(parse_on_time), unit: "s".to_string(), vif: vif }),
+        0b01110000..=0b01110011 => (1, VifData{ fildname: "averaging_duration".to_string(), scaler: 0.0, vif_function: Some(parse_on_time), unit: "s".to_string(), vif }),
         /* E111 01nn	Actuality Duration	coded like OnTime	  */
-        0b01110100..=01110111 => (1, VifData{ fildname: "actuality_duration".to_string(), scaler: 0.0, vif_function: Some(parse_on_time), unit: "s".to_string(), vif: vif }),
+        0b01110100..=0b01110111 => (1, VifData{ fildname: "actuality_duration".to_string(), scaler: 0.0, vif_function: Some(parse_on_time), unit: "s".to_string(), vif }),
         _ => (1, VifData{ fildname: format!("unknown_at_{cur_pos}_{vif:x}"), scaler: 1.0, vif_function: None, unit: "unknown".to_string(), vif: vif })
     };
 
     return x;
 }
 
+/// Split a payload into its data records as (DIF/VIF header bytes, length of the data), fillers are skipped
+pub fn split_records(payload: &Vec<u8>) -> Vec<(Vec<u8>, usize)> {
+    let mut ret = Vec::new();
+
+    let mut cur_pos: usize = 0;
+    while cur_pos < payload.len() {
+        let start = cur_pos;
+        let (offset, handler, check_further) = get_dif_function(payload, cur_pos);
+        cur_pos += offset;
+
+        if check_further {
+            let (offset, _) = get_vif_function(payload, cur_pos);
+            cur_pos += offset;
+            let header = payload[start..cur_pos].to_vec();
+
+            let (offset, _) = handler(payload, cur_pos);
+            cur_pos += offset;
+            ret.push((header, offset));
+        }
+    }
+
+    ret
+}
+
 pub fn parse_payload(payload: &Vec<u8>) -> serde_json::Map<String, serde_json::Value> {
+    parse_payload_with(payload, &[])
+}
+
+/// Replace name, scaler and unit of a record by the first matching override
+fn apply_override(vif_data: &mut VifData, overrides: &[OmsVifOverride], position: usize, vif: &[u8]) {
+    let Some(o) = overrides.iter().find(|o| o.matches(position, vif)) else {
+        return;
+    };
+
+    let decoded = vif_data.fildname.clone();
+    if let Some(field) = &o.field {
+        vif_data.fildname = field.clone();
+    }
+    /* The raw value is scaled, special parsing like times is dropped then */
+    if let Some(scaler) = o.scaler {
+        vif_data.scaler = scaler;
+        vif_data.vif_function = None;
+    }
+    if let Some(unit) = &o.unit {
+        vif_data.unit = unit.clone();
+    }
+    debug!("OMS override applied to record at {position}: {decoded} -> {} ({} {})", vif_data.fildname, vif_data.scaler, vif_data.unit);
+}
+
+/// Parse the records of a payload, records matching one of the overrides are corrected
+pub fn parse_payload_with(payload: &Vec<u8>, overrides: &[OmsVifOverride]) -> serde_json::Map<String, serde_json::Value> {
     let mut ret = serde_json::Map::new();
 
     let mut cur_pos: usize = 0;
@@ -438,7 +490,8 @@ pub fn parse_payload(payload: &Vec<u8>) -> serde_json::Map<String, serde_json::V
 
         /* Skip the rest if the DIF is a noop */
         if check_further {
-            let (offset, vif_data) = get_vif_function(payload, cur_pos);
+            let (offset, mut vif_data) = get_vif_function(payload, cur_pos);
+            apply_override(&mut vif_data, overrides, cur_pos, &payload[cur_pos..cur_pos + offset]);
             cur_pos += offset;
 
             /* we get a handler which allows us to do fancy stuff like reading int or bcd */
@@ -461,4 +514,59 @@ pub fn parse_payload(payload: &Vec<u8>) -> serde_json::Map<String, serde_json::V
     }
 
     return ret;
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vif_override(vif: Option<&str>, position: Option<usize>, field: Option<&str>, scaler: Option<f64>, unit: Option<&str>) -> OmsVifOverride {
+        OmsVifOverride {
+            vif: vif.map(str::to_string),
+            position,
+            field: field.map(str::to_string),
+            scaler,
+            unit: unit.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn test_duration_vifs() {
+        /* Averaging duration 5 min, actuality duration 30 s, manufacturer specific 0xFF */
+        let payload: Vec<u8> = vec![0x01, 0x71, 0x05, 0x01, 0x74, 0x1E, 0x01, 0xFF, 0x07];
+
+        let parsed = parse_payload(&payload);
+        assert_eq!(parsed["averaging_duration"], 300);
+        assert_eq!(parsed["actuality_duration"], 30);
+        assert_eq!(parsed["unknown_at_7_ff"], 7);
+    }
+
+    #[test]
+    fn test_parse_payload_with_overrides() {
+        /* Reserved 0x6F record with 10000 (VIF at 1), volume record of 5 l (VIF at 7) */
+        let payload: Vec<u8> = vec![0x04, 0x6F, 0x10, 0x27, 0x00, 0x00, 0x01, 0x13, 0x05];
+
+        let plain = parse_payload(&payload);
+        assert_eq!(plain["unknown_at_1_6f"], 10000);
+        assert_eq!(plain["unknown_at_1_6f_unit"], "unknown");
+        assert_eq!(plain["volume"], 0.005);
+
+        let overrides = vec![
+            vif_override(Some("6f"), None, Some("valve_cycles"), Some(0.5), Some("")),
+            vif_override(None, Some(7), None, Some(1.0), Some("l")),
+            /* Neither vif nor position never applies */
+            vif_override(None, None, Some("ignored"), None, None),
+        ];
+        let parsed = parse_payload_with(&payload, &overrides);
+        assert!(!parsed.contains_key("unknown_at_1_6f"));
+        assert_eq!(parsed["valve_cycles"], 5000.0);
+        assert_eq!(parsed["valve_cycles_unit"], "");
+        assert_eq!(parsed["volume"], 5);
+        assert_eq!(parsed["volume_unit"], "l");
+        assert!(!parsed.contains_key("ignored"));
+
+        /* Both given, both have to match */
+        let overrides = vec![vif_override(Some("13"), Some(0), Some("wrong"), None, None)];
+        assert!(parse_payload_with(&payload, &overrides).contains_key("volume"));
+    }
+}
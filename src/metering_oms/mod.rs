@@ -1,6 +1,7 @@
 use lazy_static::lazy_static;
 use std::sync::Mutex;
-use crate::{models::DeviceProtocol, mqtt::{SubscribeData, Transmission}, MeteringData};
+use crate::{config::OmsVifOverride, models::DeviceProtocol, mqtt::{home_assistant::build_metering_discovery, MeterErrorData, SubscribeData, Transmission}, MeteringData};
+use std::collections::{HashMap, HashSet};
 use log::{debug, error, info};
 use tokio::sync::mpsc::Sender;
 use hex;
@@ -9,11 +10,19 @@ use thiserror::Error;
 pub mod utils;
 pub mod structs;
 pub mod div_vif_parser;
+pub mod compact_frame;
 
 pub struct OmsManager {
     sender: Sender<Transmission>,
+    /* Meters we already sent a Home Assistant discovery for */
+    discovered: HashSet<String>,
+    /* Last access number and reception time per sender DIN address */
+    last_access: HashMap<String, (u64, u64)>,
 }
 
+/// Receptions of the same telegram by several receivers arrive within this many seconds
+const DEDUP_WINDOW_SECS: u64 = 30;
+
 lazy_static! {
     static ref waitFor: Mutex<i32> = Mutex::new(0);
 }
@@ -23,6 +32,8 @@ impl OmsManager {
     pub fn new(sender: Sender<Transmission>) -> Self {
         return OmsManager { 
             sender: sender,
+            discovered: HashSet::new(),
+            last_access: HashMap::new(),
          }
     }
 
@@ -54,16 +65,74 @@ impl OmsManager {
 
             let dec = dec.unwrap();
 
-            let dec = parse_oms_telegram(&dec, crc);
+            let telegram = dec;
+            let dec = parse_oms_telegram(&telegram, crc);
             match dec {
-                Ok(doc) => { let _ = self.sender.send(Transmission::Metering(doc)).await; },
-                Err(e) => { error!("OMS telegram can not be parsed: {e:?}"); },
+                Ok(mut doc) => {
+                    let config = doc.metered_values.get("proto")
+                        .and_then(|p| p.get("din_addr_sender"))
+                        .and_then(|v| v.as_str())
+                        .and_then(|din_addr| utils::get_meter_config(&din_addr.to_string()));
+                    let deduplicate = config.as_ref().is_none_or(|c| c.deduplicate);
+                    if deduplicate && self.is_duplicate(&doc, crate::get_unix_ts()) {
+                        debug!("Dropping duplicate OMS telegram of {}", doc.meter_name);
+                        continue;
+                    }
+
+                    if !self.discovered.contains(&doc.meter_name) {
+                        let proto = doc.metered_values.get("proto");
+                        let manu = proto.and_then(|p| p.get("manufacturer")).and_then(|v| v.as_str()).map(|v| v.to_string());
+                        let model = proto.and_then(|p| p.get("device_medium")).and_then(|v| v.as_str()).map(|v| v.to_string());
+                        let _ = self.sender.send(Transmission::AutoDiscovery2(build_metering_discovery(&doc, manu, model))).await;
+                        self.discovered.insert(doc.meter_name.clone());
+                    }
+
+                    if !config.is_some_and(|c| c.include_raw) {
+                        strip_raw(&mut doc);
+                    }
+                    let _ = self.sender.send(Transmission::Metering(doc)).await;
+                },
+                Err(e) => {
+                    error!("OMS telegram can not be parsed: {e:?}");
+                    /* Configured meters show the error in HA, foreign meters are none of our business */
+                    let config = utils::get_din_addr(&telegram).and_then(|din_addr| utils::get_meter_config(&din_addr));
+                    if let Some(config) = config {
+                        let _ = self.sender.send(Transmission::MeterError(MeterErrorData {
+                            protocol: DeviceProtocol::OMS.to_string(),
+                            meter_name: config.name,
+                            error: e.to_string(),
+                        })).await;
+                    }
+                },
             }
         }
     }
 }
 
 
+impl OmsManager {
+    /// A telegram is a duplicate if the sender repeats its access number within the window
+    fn is_duplicate(&mut self, doc: &MeteringData, now: u64) -> bool {
+        let proto = doc.metered_values.get("proto");
+        let din_addr = proto.and_then(|p| p.get("din_addr_sender")).and_then(|v| v.as_str());
+        let access_no = proto.and_then(|p| p.get("transmission_counter")).and_then(|v| v.as_u64());
+
+        let (din_addr, access_no) = match (din_addr, access_no) {
+            (Some(d), Some(a)) => (d, a),
+            _ => return false,
+        };
+
+        let duplicate = self.last_access.get(din_addr)
+            .is_some_and(|(last_no, last_ts)| *last_no == access_no && now.saturating_sub(*last_ts) <= DEDUP_WINDOW_SECS);
+
+        if !duplicate {
+            self.last_access.insert(din_addr.to_string(), (access_no, now));
+        }
+
+        duplicate
+    }
+}
+
 /// Custom error types for OMS Parsing
 #[derive(Error, Debug)]
 pub enum OmsParseError {
@@ -83,10 +152,12 @@ pub enum OmsParseError {
     SecurityModeNotSupported,
     #[error("Decryption failed")]
     DecryptionFailed,
-    #[error("CI Field not supported")]
-    SecurityCiTypeNotSupported,
+    #[error("CI field {0:#04x} not supported")]
+    SecurityCiTypeNotSupported(u8),
     #[error("Sensor not configured")]
     SensorNotConfigured,
+    #[error("Compact frame of unknown format {0:04x}")]
+    UnknownFormat(u16),
 }
 
 /*macro_rules! bit_set {
@@ -110,7 +181,7 @@ fn parse_oms_telegram_internal(
     /* Some definitions direction slave to master only */
     let tpl_no_header_ids : Vec<u8> = vec![0x66, 0x70, 0x71];
     /* Annex D D.2 */
-    let tpl_short_header_ids : Vec<u8> = vec![0x67, 0x6E, 0x74, 0x7A, 0x7D, 0x7F, 0x88, 0x9E, 0xC1, 0xC4];
+    let tpl_short_header_ids : Vec<u8> = vec![0x67, 0x6E, 0x74, 0x7A, 0x7B, 0x7D, 0x7F, 0x88, 0x9E, 0xC1, 0xC4];
     /* Annex D D.2 */
     let tpl_long_header_ids: Vec<u8> = vec![0x68, 0x6F, 0x72, 0x75, 0x7C, 0x7E, 0x9F, 0xC2, 0xC5];
 
@@ -154,16 +225,15 @@ fn parse_oms_telegram_internal(
     }
 
     let manfucturer = utils::get_manufacturer(&telegram);
-    protocol_map.insert("manufacturer".to_string(), manfucturer.clone().into());
+    protocol_map.insert("manufacturer".to_string(), manfucturer.into());
     let ident_no = utils::get_ident_no(&telegram);
-    protocol_map.insert("device_number".to_string(), ident_no.clone().into());
+    protocol_map.insert("device_number".to_string(), ident_no.into());
     let version = format!("{:02x}",telegram[8]);
-    protocol_map.insert("version_number".to_string(), version.clone().into());
-    let device_type = format!("{:x}",telegram[9]);
-    protocol_map.insert("device_medium".to_string(), utils::get_device_medium(&device_type).into());
+    protocol_map.insert("version_number".to_string(), version.into());
+    protocol_map.insert("device_medium".to_string(), utils::get_device_medium(telegram[9]).into());
 
     /* We follow the naming based on DIN 43863-5:2012 for the meter data */
-    let din_addr = format!("{device_type}{manfucturer}{version}{ident_no}");
+    let din_addr = utils::get_din_addr(&telegram).unwrap();
 
     /*
         A long header can change the identification of the meter but not the sender,
@@ -190,8 +260,11 @@ fn parse_oms_telegram_internal(
     /* Format based Issue 5.0.1 / 2023-12 (RELEASE) 7.2.4.1 General */
     let config_field : u16;
 
+    /* 0x7B carries a compact frame behind the short header, like 0x79 does without header */
+    let compact = ci == 0x7B;
+
     if tpl_short_header_ids.contains(&ci) {
-        protocol_map.insert("ci_field".to_string(), serde_json::Value::from("short"));
+        protocol_map.insert("ci_field".to_string(), serde_json::Value::from(if compact { "short_compact" } else { "short" }));
         access_no = telegram[11] as u8;
         status = telegram[12] as u32;
         config_field = (telegram[14] as u16) << 8 | telegram[13] as u16;
@@ -199,12 +272,29 @@ fn parse_oms_telegram_internal(
     } else if tpl_long_header_ids.contains(&ci) {
         protocol_map.insert("ci_field".to_string(), serde_json::Value::from("long"));
         todo!("Support long header");
+    } else if ci == 0x78 || ci == 0x79 {
+        /* Unencrypted frames without TPL header, compact ones only carry the data of a known format */
+        let end = std::cmp::min(len as usize + 1, telegram_len);
+        let mut data = telegram[11..end].to_vec();
+        if ci == 0x79 {
+            protocol_map.insert("ci_field".to_string(), serde_json::Value::from("compact"));
+            data = compact_frame::expand(&data)?;
+        } else {
+            protocol_map.insert("ci_field".to_string(), serde_json::Value::from("full"));
+            compact_frame::learn_format(&data);
+        }
+
+        mr.meter_name = config.name;
+        mr.tenant = config.tenant.unwrap_or_default();
+        let mut mr = add_payload(mr, &data, protocol_map, &config.vif_overrides);
+        crate::transform::apply_transforms(&mr.meter_name, &mut mr.metered_values, &config.transforms);
+        return Ok(mr);
     } else if tpl_no_header_ids.contains(&ci) {
         info!("Message ignored, M-Bus will be implemented in later versions");
         return Err(OmsParseError::WiredProtocolNotSupported);
     } else {
-        /* OMS LPWAN may be one of those not supported currently */
-        return Err(OmsParseError::SecurityCiTypeNotSupported);
+        /* OMS LPWAN and the compact frames behind a long header (0x73) are not supported currently */
+        return Err(OmsParseError::SecurityCiTypeNotSupported(ci));
     }
 
     /* Check status for errors */
@@ -237,8 +327,14 @@ fn parse_oms_telegram_internal(
                 }
                 
                 dec_data = utils::remove_oms_filler(&dec_data);
+                if compact {
+                    dec_data = compact_frame::expand(&dec_data)?;
+                } else {
+                    compact_frame::learn_format(&dec_data);
+                }
 
                 mr.meter_name = config.name;
+                mr.tenant = config.tenant.unwrap_or_default();
             },
         7 => {
 
@@ -246,17 +342,27 @@ fn parse_oms_telegram_internal(
         _ => { return Err(OmsParseError::SecurityModeNotSupported); }
     }
 
-    /* Add the decrypted payload to the document */
+    let mut mr = add_payload(mr, &dec_data, protocol_map, &config.vif_overrides);
+    crate::transform::apply_transforms(&mr.meter_name, &mut mr.metered_values, &config.transforms);
+    return Ok(mr);
+}
+
+/// Add the decrypted payload, its values and the protocol information to the document
+fn add_payload(mut mr: MeteringData, dec_data: &Vec<u8>, protocol_map: serde_json::Map<String, serde_json::Value>, overrides: &[OmsVifOverride]) -> MeteringData {
     mr.metered_values.insert("payload".to_string(), (dec_data.iter().map(|byte| format!("{:02X}", byte)).collect::<String>()).into());
 
-    let mut parsed_data = div_vif_parser::parse_payload(&dec_data);
+    let mut parsed_data = div_vif_parser::parse_payload_with(dec_data, overrides);
     mr.metered_values.append(&mut parsed_data);
 
     mr.metered_values.insert("proto".to_string(), protocol_map.into());
-    return Ok(mr);
+    mr
 }
 
-
+/// Remove the payload and telegram details only needed for debugging
+fn strip_raw(mr: &mut MeteringData) {
+    mr.metered_values.remove("payload");
+    mr.metered_values.remove("proto");
+}
 
 #[cfg(test)]
 mod oms_parse_tests {
@@ -444,6 +550,11 @@ mod oms_parse_tests {
             name: "Test OMS Meter".to_string(),
             id: "3ELS3312345678".to_string(),
             key: key.to_string(),
+            tenant: None,
+            deduplicate: true,
+            include_raw: false,
+            transforms: std::collections::BTreeMap::new(),
+            vif_overrides: Vec::new(),
         };
 
         let result = parse_oms_telegram_internal(&data, true, Some(test_config));
@@ -491,11 +602,147 @@ mod oms_parse_tests {
         }
     }
 
+    #[test]
+    fn test_full_and_compact_frames() {
+        let config = OmsConfig {
+            name: "Water".to_string(),
+            id: "7ELS3312345679".to_string(),
+            key: "".to_string(),
+            tenant: None,
+            deduplicate: true,
+            include_raw: false,
+            transforms: [("flow_kelvin".to_string(), "flow_temperature + 273".to_string())].into(),
+            vif_overrides: Vec::new(),
+        };
+
+        /* Header of an unencrypted water meter without TPL header */
+        let header: Vec<u8> = vec![0x93, 0x15, 0x79, 0x56, 0x34, 0x12, 0x33, 0x07];
+        /* Volume 12345 l, flow temperature 21 °C */
+        let records: Vec<u8> = vec![0x04, 0x13, 0x39, 0x30, 0x00, 0x00, 0x01, 0x5B, 0x15];
+
+        let mut full = vec![0x00, 0x44];
+        full.extend_from_slice(&header);
+        full.push(0x78);
+        full.extend_from_slice(&records);
+        full[0] = (full.len() - 1) as u8;
+
+        let result = parse_oms_telegram_internal(&full, false, Some(config.clone())).unwrap();
+        assert_eq!(result.meter_name, "Water");
+        assert_eq!(result.metered_values["volume"], 12.345);
+
+        /* The next reading only carries the data */
+        let next_records: Vec<u8> = vec![0x04, 0x13, 0x3A, 0x30, 0x00, 0x00, 0x01, 0x5B, 0x16];
+        let signature = crc16::State::<crc16::EN_13757>::calculate(&[0x04, 0x13, 0x01, 0x5B]);
+        let crc = crc16::State::<crc16::EN_13757>::calculate(&next_records);
+
+        let mut compact = vec![0x00, 0x44];
+        compact.extend_from_slice(&header);
+        compact.push(0x79);
+        compact.extend_from_slice(&signature.to_le_bytes());
+        compact.extend_from_slice(&crc.to_le_bytes());
+        compact.extend_from_slice(&[0x3A, 0x30, 0x00, 0x00, 0x16]);
+        compact[0] = (compact.len() - 1) as u8;
+
+        let result = parse_oms_telegram_internal(&compact, false, Some(config)).unwrap();
+        assert_eq!(result.metered_values["volume"], 12.346);
+        assert_eq!(result.metered_values["flow_temperature"], 22);
+        assert_eq!(result.metered_values["flow_kelvin"], 295.0);
+        assert_eq!(result.metered_values["proto"]["ci_field"], "compact");
+
+        /* Without include_raw only the decoded values are published */
+        let mut result = result;
+        strip_raw(&mut result);
+        assert!(!result.metered_values.contains_key("payload"));
+        assert!(!result.metered_values.contains_key("proto"));
+        assert_eq!(result.metered_values["volume"], 12.346);
+    }
+
+    #[test]
+    fn test_encrypted_compact_frame() {
+        use aes::cipher::{block_padding::NoPadding, BlockEncryptMut, KeyIvInit};
+
+        let key: Vec<u8> = (1..=16).collect();
+        let config = OmsConfig {
+            name: "Heat".to_string(),
+            id: "4ELS3312345680".to_string(),
+            key: hex::encode(&key),
+            tenant: None,
+            deduplicate: true,
+            include_raw: false,
+            transforms: std::collections::BTreeMap::new(),
+            vif_overrides: Vec::new(),
+        };
+
+        /* Format learned from an earlier full frame: volume 32 bit in l, flow temperature 8 bit in °C */
+        compact_frame::learn_format(&vec![0x04, 0x13, 0x00, 0x00, 0x00, 0x00, 0x01, 0x5B, 0x00]);
+        let records: Vec<u8> = vec![0x04, 0x13, 0x10, 0x27, 0x00, 0x00, 0x01, 0x5B, 0x30];
+        let signature = crc16::State::<crc16::EN_13757>::calculate(&[0x04, 0x13, 0x01, 0x5B]);
+        let crc = crc16::State::<crc16::EN_13757>::calculate(&records);
+
+        let mut plain = vec![0x2F, 0x2F];
+        plain.extend_from_slice(&signature.to_le_bytes());
+        plain.extend_from_slice(&crc.to_le_bytes());
+        plain.extend_from_slice(&[0x10, 0x27, 0x00, 0x00, 0x30]);
+        plain.resize(16, 0x2F);
+
+        /* Short header with access number 0x2A, status ok and security mode 5 with one block */
+        let header: Vec<u8> = vec![0x93, 0x15, 0x80, 0x56, 0x34, 0x12, 0x33, 0x04];
+        let access_no = 0x2A;
+        let mut iv = header.clone();
+        iv.extend_from_slice(&[access_no; 8]);
+        let encrypted = cbc::Encryptor::<aes::Aes128>::new_from_slices(&key, &iv).unwrap()
+            .encrypt_padded_vec_mut::<NoPadding>(&plain);
+
+        let mut telegram = vec![0x00, 0x44];
+        telegram.extend_from_slice(&header);
+        telegram.extend_from_slice(&[0x7B, access_no, 0x00, 0x10, 0x05]);
+        telegram.extend_from_slice(&encrypted);
+        telegram[0] = (telegram.len() - 1) as u8;
+
+        let result = parse_oms_telegram_internal(&telegram, false, Some(config.clone())).unwrap();
+        assert_eq!(result.metered_values["volume"], 10.0);
+        assert_eq!(result.metered_values["flow_temperature"], 48);
+        assert_eq!(result.metered_values["proto"]["ci_field"], "short_compact");
+
+        /* Compact frames behind a long header are rejected with their CI */
+        telegram[10] = 0x73;
+        let err = parse_oms_telegram_internal(&telegram, false, Some(config)).err().unwrap();
+        assert_eq!(err.to_string(), "CI field 0x73 not supported");
+    }
+
+    #[test]
+    fn test_duplicate_telegrams_are_dropped() {
+        let (tx, _rx) = tokio::sync::mpsc::channel(10);
+        let mut manager = OmsManager::new(tx);
+
+        let telegram = |din_addr: &str, access_no: u64| {
+            let mut doc = MeteringData::new().unwrap();
+            doc.metered_values.insert("proto".to_string(), serde_json::json!({
+                "din_addr_sender": din_addr,
+                "transmission_counter": access_no,
+            }));
+            doc
+        };
+
+        assert!(!manager.is_duplicate(&telegram("3ELS3312345678", 42), 1000));
+        /* Same telegram from a second receiver */
+        assert!(manager.is_duplicate(&telegram("3ELS3312345678", 42), 1002));
+        /* Another meter with the same access number */
+        assert!(!manager.is_duplicate(&telegram("7ELS3387654321", 42), 1002));
+        /* Next transmission */
+        assert!(!manager.is_duplicate(&telegram("3ELS3312345678", 43), 1010));
+        /* The access number wrapped around long after the window */
+        assert!(!manager.is_duplicate(&telegram("3ELS3312345678", 43), 1010 + DEDUP_WINDOW_SECS + 1));
+    }
+
     #[test]
     fn test_device_medium() {
-        assert_eq!(utils::get_device_medium(&"2".to_string()), "Electricity");
-        assert_eq!(utils::get_device_medium(&"3".to_string()), "Gas");
-        assert_eq!(utils::get_device_medium(&"7".to_string()), "Water (cold)");
-        assert_eq!(utils::get_device_medium(&"99".to_string()), "unknown");
+        assert_eq!(utils::get_device_medium(0x02), "electricity");
+        assert_eq!(utils::get_device_medium(0x03), "gas");
+        assert_eq!(utils::get_device_medium(0x07), "water");
+        assert_eq!(utils::get_device_medium(0x0A), "cooling_outlet");
+        assert_eq!(utils::get_device_medium(0x16), "cold_water");
+        assert_eq!(utils::get_device_medium(0x1A), "smoke_detector");
+        assert_eq!(utils::get_device_medium(0x99), "unknown_0x99");
     }
 }
\ No newline at end of file
@@ -1,6 +1,6 @@
 use crc16::{State, EN_13757};
 use aes::cipher::{block_padding::NoPadding, generic_array::GenericArray, BlockDecryptMut, KeyIvInit};
-use crate::{config::{ConfigBases, OmsConfig}, get_config_or_panic, CONFIG};
+use crate::{config::{ConfigBases, OmsConfig}, get_config_or_panic, obis_utils, CONFIG};
 
 use super::OmsParseError;
 type Aes128CbcDec = cbc::Decryptor<aes::Aes128>;
@@ -53,21 +53,22 @@ pub fn verifiy_crc(telegram: &Vec<u8>) -> Result<Vec<u8>, OmsParseError> {
     return Ok(result);
 }
 
-/* Taken from: https://www.m-bus.de/man.html */
 pub fn get_manufacturer(telegram: &Vec<u8>) -> String {
-    let mut m : u16 = ((telegram[3] as u16) << 8) as u16;
-    m += telegram[2] as u16;
-
-    return format!("{}{}{}",
-                    String::from_utf8(vec![(((m >> 10) & 0x1F) + 64) as u8]).unwrap(),
-                    String::from_utf8(vec![(((m >> 5) & 0x1F) + 64) as u8]).unwrap(),
-                    String::from_utf8(vec![((m & 0x1F) + 64) as u8]).unwrap());
+    return obis_utils::decode_flag_manufacturer(u16::from_le_bytes([telegram[2], telegram[3]]));
 }
 
 pub fn get_ident_no(telegram: &Vec<u8>) -> String {
   return format!("{:02x}{:02x}{:02x}{:02x}",telegram[7], telegram[6], telegram[5], telegram[4]);
 }
 
+/// Sender address following DIN 43863-5:2012, the first block is the same with or without CRC
+pub fn get_din_addr(telegram: &Vec<u8>) -> Option<String> {
+    if telegram.len() < 10 {
+        return None;
+    }
+    Some(format!("{:x}{}{:02x}{}", telegram[9], get_manufacturer(telegram), telegram[8], get_ident_no(telegram)))
+}
+
 pub fn get_meter_config(din_addr: &String) -> Option<OmsConfig> {
     let conf = get_config_or_panic!("oms", ConfigBases::Oms);
         
@@ -90,26 +91,54 @@ pub fn remove_oms_filler(original: &Vec<u8>) -> Vec<u8> {
     return ret;
 }
 
-pub fn get_device_medium(device_type: &String) -> String {
-    return match device_type as &str {
-        "2" => "Electricity",
-        "3" => "Gas",
-        "4" => "Heat",
-        "6" => "Water (hot)",
-        "7" => "Water (cold)",
-        "8" => "Heat Cost Allocator",
-        "A" => "Cooling",
-        "B" => "Cooling",
-        "C" => "Heat",
-        "D" => "Heat / Cooling Combined",
-        /* Warning those will break the DIN ID creation */
-        "15" => "Water (hot)",
-        "16" => "Water (cold)",
-        "20" => "Breaker / Valve",
-        "21" => "Breaker / Valve",
-        _ => { "unknown" },
-    }.to_string();
+/// Medium (device type) of the A field as lowercase name, EN 13757-3 / OMS Vol. 2 table of device types
+pub fn get_device_medium(medium: u8) -> String {
+    let name = match medium {
+        0x00 => "other",
+        0x01 => "oil",
+        0x02 => "electricity",
+        0x03 => "gas",
+        0x04 => "heat",
+        0x05 => "steam",
+        0x06 => "warm_water",
+        0x07 => "water",
+        0x08 => "heat_cost_allocator",
+        0x09 => "compressed_air",
+        0x0A => "cooling_outlet",
+        0x0B => "cooling_inlet",
+        0x0C => "heat_inlet",
+        0x0D => "heat_cooling",
+        0x0E => "bus_system",
+        0x0F => "unknown_medium",
+        0x10 => "irrigation_water",
+        0x11 => "water_data_logger",
+        0x12 => "gas_data_logger",
+        0x13 => "gas_converter",
+        0x14 => "calorific_value",
+        0x15 => "hot_water",
+        0x16 => "cold_water",
+        0x17 => "dual_water",
+        0x18 => "pressure",
+        0x19 => "ad_converter",
+        0x1A => "smoke_detector",
+        0x1B => "room_sensor",
+        0x1C => "gas_detector",
+        0x20 => "breaker",
+        0x21 => "valve",
+        0x25 => "customer_unit",
+        0x28 => "waste_water",
+        0x29 => "garbage",
+        0x31 => "communication_controller",
+        0x32 => "unidirectional_repeater",
+        0x33 => "bidirectional_repeater",
+        0x36 => "radio_converter_system",
+        0x37 => "radio_converter_meter",
+        _ => return format!("unknown_0x{medium:02x}"),
+    };
+
+    name.to_string()
 }
+
 pub fn decrypt_mode5(telegram: &Vec<u8>, access_no: u8, start_encryption: usize, key: &Vec<u8>) -> Vec<u8> {
     let iv : Vec<u8> = vec![
         telegram[2],    /* M-Field */
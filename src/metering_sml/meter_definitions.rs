@@ -1,5 +1,35 @@
 use super::structs::{MeterDefinition, MeterType};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "api")]
+use utoipa::ToSchema;
+
+use crate::obis_utils;
+
+/// A known meter type, SML telegrams are matched by their manufacturer code
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "api", derive(ToSchema))]
+pub struct SmlTemplate {
+    pub name: String,
+    pub description: String,
+    pub manufacturer_codes: Vec<String>,
+    pub obis_codes: usize,
+    /// Provided in the definitions directory instead of shipped with energy2mqtt
+    pub user_provided: bool,
+}
+
+/// User provided meter definition, YAML or JSON
+#[derive(Deserialize)]
+struct MeterDefinitionFile {
+    manufacturer_codes: Vec<String>,
+    /// OBIS code to field name
+    obis_mapping: HashMap<String, String>,
+    #[serde(default)]
+    description: String,
+}
 
 pub fn get_supported_meters() -> HashMap<String, MeterDefinition> {
     let mut meters = HashMap::new();
@@ -275,6 +305,97 @@ fn get_generic_obis_mapping() -> HashMap<String, String> {
     map
 }
 
+/// Parse and check a user provided definition, `name` identifies the meter type
+fn parse_definition(name: &str, contents: &str) -> Result<MeterDefinition, String> {
+    let file: MeterDefinitionFile = serde_yml::from_str(contents).map_err(|e| e.to_string())?;
+
+    if file.manufacturer_codes.is_empty() {
+        return Err("manufacturer_codes must not be empty".to_string());
+    }
+    if file.obis_mapping.is_empty() {
+        return Err("obis_mapping must not be empty".to_string());
+    }
+    if let Some(code) = file.obis_mapping.keys().find(|c| !obis_utils::validate_obis_code(c)) {
+        return Err(format!("{code} is not a valid OBIS code"));
+    }
+
+    let mut supported_obis_codes: Vec<String> = file.obis_mapping.keys().cloned().collect();
+    supported_obis_codes.sort();
+
+    Ok(MeterDefinition {
+        meter_type: MeterType::Custom(name.to_string()),
+        manufacturer_codes: file.manufacturer_codes,
+        supported_obis_codes,
+        obis_mapping: file.obis_mapping,
+        description: file.description,
+    })
+}
+
+/// Load the user provided definitions of a directory, named by their file without extension.
+/// A definition named like a built-in one (e.g. EMH.yaml) replaces it.
+pub fn load_definitions(dir: &Path) -> HashMap<String, MeterDefinition> {
+    let mut meters = HashMap::new();
+    let Ok(entries) = fs::read_dir(dir) else {
+        return meters;
+    };
+
+    for path in entries.flatten().map(|e| e.path()) {
+        if !path.extension().is_some_and(|e| e == "yaml" || e == "json") {
+            continue;
+        }
+        let Some(name) = path.file_stem().map(|s| s.to_string_lossy().to_string()) else {
+            continue;
+        };
+
+        match fs::read_to_string(&path).map_err(|e| e.to_string()).and_then(|c| parse_definition(&name, &c)) {
+            Ok(def) => {
+                info!("Loaded SML definition {name} ({} OBIS codes) from {}", def.obis_mapping.len(), path.display());
+                meters.insert(name, def);
+            },
+            Err(e) => error!("Ignoring invalid SML definition {}: {e}", path.display()),
+        }
+    }
+
+    meters
+}
+
+/// Built-in definitions and the user provided ones of a directory, sorted by name
+fn templates_in(user_dir: &Path) -> Vec<SmlTemplate> {
+    let user = load_definitions(user_dir);
+    let user_names: Vec<String> = user.keys().cloned().collect();
+    let mut meters = get_supported_meters();
+    meters.extend(user);
+
+    let mut templates: Vec<SmlTemplate> = meters.into_iter()
+        .map(|(name, def)| SmlTemplate {
+            user_provided: user_names.contains(&name),
+            name,
+            description: def.description,
+            manufacturer_codes: def.manufacturer_codes,
+            obis_codes: def.supported_obis_codes.len(),
+        })
+        .collect();
+
+    templates.sort_by(|a, b| a.name.cmp(&b.name));
+    templates
+}
+
+/// All meter definitions the SML decoder knows
+pub fn list_templates() -> Vec<SmlTemplate> {
+    templates_in(&crate::CONFIG.read().unwrap().get_definitions_path().join("sml"))
+}
+
+/// Definitions in the order meters are matched against them, the user provided ones first and both sorted
+/// by name. A user provided definition named like a built-in one (e.g. EMH.yaml) replaces it.
+pub fn definitions_in_match_order(user: HashMap<String, MeterDefinition>) -> Vec<MeterDefinition> {
+    let user: BTreeMap<String, MeterDefinition> = user.into_iter().collect();
+    let builtin: BTreeMap<String, MeterDefinition> = get_supported_meters().into_iter()
+        .filter(|(name, _)| !user.contains_key(name))
+        .collect();
+
+    user.into_values().chain(builtin.into_values()).collect()
+}
+
 // Helper function to get meter by manufacturer code
 pub fn get_meter_by_manufacturer(manufacturer_code: &str) -> Option<MeterDefinition> {
     let meters = get_supported_meters();
@@ -336,6 +457,64 @@ mod tests {
         assert!(unknown.is_none());
     }
 
+    #[test]
+    fn test_load_definitions() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("dzg.yaml"), "manufacturer_codes: [DZG]\ndescription: DZG DVS74\n\
+            obis_mapping:\n  '1-0:1.8.0': energy_import\n  '1-0:16.7.0': power\n").unwrap();
+        std::fs::write(dir.path().join("holley.json"), r#"{"manufacturer_codes": ["HLY"], "obis_mapping": {"1-0:1.8.0": "import"}}"#).unwrap();
+        std::fs::write(dir.path().join("no_codes.yaml"), "manufacturer_codes: []\nobis_mapping:\n  '1-0:1.8.0': import\n").unwrap();
+        std::fs::write(dir.path().join("bad_obis.yaml"), "manufacturer_codes: [XYZ]\nobis_mapping:\n  energy: import\n").unwrap();
+
+        let meters = load_definitions(dir.path());
+        assert_eq!(meters.len(), 2);
+
+        let dzg = &meters["dzg"];
+        assert_eq!(dzg.meter_type, MeterType::Custom("dzg".to_string()));
+        assert_eq!(dzg.supported_obis_codes, vec!["1-0:1.8.0".to_string(), "1-0:16.7.0".to_string()]);
+        assert_eq!(dzg.obis_mapping["1-0:16.7.0"], "power");
+        assert_eq!(meters["holley"].manufacturer_codes, vec!["HLY".to_string()]);
+
+        assert!(load_definitions(&dir.path().join("missing")).is_empty());
+    }
+
+    #[test]
+    fn test_list_templates() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("EMH.yaml"), "manufacturer_codes: [EMH]\ndescription: Own EMH\n\
+            obis_mapping:\n  '1-0:1.8.0': energy_import\n").unwrap();
+
+        let templates = templates_in(dir.path());
+        assert_eq!(templates.len(), get_supported_meters().len());
+        assert!(templates.windows(2).all(|w| w[0].name < w[1].name));
+
+        let emh = templates.iter().find(|t| t.name == "EMH").unwrap();
+        assert_eq!(emh.description, "Own EMH");
+        assert_eq!(emh.obis_codes, 1);
+        assert!(emh.user_provided);
+        assert!(templates.iter().any(|t| t.name == "Generic" && !t.user_provided));
+    }
+
+    #[test]
+    fn test_definitions_in_match_order() {
+        let mut user = HashMap::new();
+        for name in ["zpa", "emh2", "EasyMeter"] {
+            let def = parse_definition(name, "manufacturer_codes: [EMH]\nobis_mapping:\n  '1-0:1.8.0': import\n").unwrap();
+            user.insert(name.to_string(), def);
+        }
+
+        let types: Vec<MeterType> = definitions_in_match_order(user).into_iter().map(|d| d.meter_type).collect();
+        assert_eq!(types, vec![
+            MeterType::Custom("EasyMeter".to_string()),
+            MeterType::Custom("emh2".to_string()),
+            MeterType::Custom("zpa".to_string()),
+            MeterType::EMH,
+            MeterType::Generic,
+            MeterType::Iskraemeco,
+            MeterType::Itron,
+        ]);
+    }
+
     #[test]
     fn test_get_all_supported_obis_codes() {
         let codes = get_all_supported_obis_codes();
@@ -1,6 +1,6 @@
-use crate::{models::DeviceProtocol, mqtt::{SubscribeData, Transmission, MeteringData, TranmissionValueType}};
-use log::{debug, error, info};
-use std::collections::HashMap;
+use crate::{config::SmlMeterConfig, models::DeviceProtocol, mqtt::{home_assistant::{component_key, error_component, HaComponent2, HaSensor}, MeterErrorData, SubscribeData, Transmission, MeteringData, TranmissionValueType}, obis_utils};
+use log::{debug, error, info, warn};
+use std::collections::HashSet;
 use tokio::sync::mpsc::Sender;
 
 pub mod structs;
@@ -20,16 +20,26 @@ pub enum SmlError {
     ConfigError(String),
 }
 
+/// Published field name, OBIS code without F group and unit of a value
+type SmlField = (String, String, Option<String>);
+
 pub struct SmlManager {
     sender: Sender<Transmission>,
-    device_definitions: HashMap<String, MeterDefinition>,
+    /// Checked in this order, the first definition matching a meter wins
+    device_definitions: Vec<MeterDefinition>,
+    /* Server ids we already sent a Home Assistant discovery for */
+    discovered: HashSet<String>,
 }
 
 impl SmlManager {
     pub fn new(sender: Sender<Transmission>) -> Self {
+        let user_dir = crate::CONFIG.read().unwrap().get_definitions_path().join("sml");
+        let device_definitions = meter_definitions::definitions_in_match_order(meter_definitions::load_definitions(&user_dir));
+
         Self {
             sender,
-            device_definitions: meter_definitions::get_supported_meters(),
+            device_definitions,
+            discovered: HashSet::new(),
         }
     }
 
@@ -59,18 +69,25 @@ impl SmlManager {
         }
     }
 
-    async fn handle_sml_message(&self, payload: &[u8]) {
+    async fn handle_sml_message(&mut self, payload: &[u8]) {
         debug!("Received SML message with {} bytes", payload.len());
         
         match parse_sml_message(payload) {
             Ok(sml_file) => {
                 info!("Successfully parsed SML message with {} entries", sml_file.messages.len());
                 
+                /* Attention responses may leave out the server id the list responses of the same file carry */
+                let file_server_id = sml_file.messages.iter()
+                    .find_map(|m| m.message_body.get_list_response.as_ref().and_then(|r| r.server_id.clone()));
+
                 // Process each SML message in the file
                 for message in &sml_file.messages {
                     if let Some(get_list_response) = &message.message_body.get_list_response {
                         self.process_get_list_response(get_list_response, &message.client_id).await;
                     }
+                    if let Some(attention) = &message.message_body.attention_response {
+                        self.process_attention(attention, file_server_id.as_deref()).await;
+                    }
                 }
             }
             Err(e) => {
@@ -79,7 +96,28 @@ impl SmlManager {
         }
     }
 
-    async fn process_get_list_response(&self, response: &SmlGetListResponse, _client_id: &Option<Vec<u8>>) {
+    /// Show errors the meter reports (e.g. PIN required) on its error topic, acknowledgements are only logged
+    async fn process_attention(&mut self, attention: &SmlAttentionMessage, file_server_id: Option<&[u8]>) {
+        let server_id = attention.server_id.as_deref().or(file_server_id).map(hex::encode);
+        let server_id_str = server_id.clone().unwrap_or_else(|| "unknown".to_string());
+
+        let Some(text) = describe_attention(&attention.attention_no, attention.attention_msg.as_deref()) else {
+            debug!("SML meter {} acknowledged with {}", server_id_str, hex::encode(&attention.attention_no));
+            return;
+        };
+
+        warn!("SML meter {}: {}", server_id_str, text);
+        let meter_config = attention_meter(server_id.as_deref(), &crate::CONFIG.read().unwrap().config.sml);
+        /* Same spelling as the list responses use, the configured id may be upper case */
+        let server_id = meter_config.as_ref().map_or(server_id_str, |m| m.server_id.to_lowercase());
+        let _ = self.sender.send(Transmission::MeterError(MeterErrorData {
+            protocol: DeviceProtocol::SML.to_string(),
+            meter_name: meter_name(&server_id, meter_config.as_ref()),
+            error: text,
+        })).await;
+    }
+
+    async fn process_get_list_response(&mut self, response: &SmlGetListResponse, _client_id: &Option<Vec<u8>>) {
         let server_id = response.server_id.as_ref()
             .map(|id| hex::encode(id))
             .unwrap_or_else(|| "unknown".to_string());
@@ -87,39 +125,24 @@ impl SmlManager {
         debug!("Processing GetList response from server: {}", server_id);
 
         // Identify meter type based on server ID or other characteristics
-        let meter_type = self.identify_meter_type(&server_id, &response.val_list);
-        
-        // Convert SML entries to metered values
-        let mut metered_values = serde_json::Map::new();
+        let meter_type = self.identify_meter_type(response.server_id.as_deref().unwrap_or_default(), &response.val_list);
         
-        for entry in &response.val_list {
-            if let Some(obis_code) = &entry.obis_code {
-                let obis_str = format_obis_code(obis_code);
-                
-                if let Some(value) = &entry.value {
-                    let (mut value_str, unit) = parse_sml_value(value);
-                    
-                    // Apply scaler and unit if present
-                    if entry.scaler.is_some() || entry.unit.is_some() {
-                        let (scaled_value, final_unit) = apply_scaler_and_unit(&value_str, entry.scaler, entry.unit);
-                        value_str = scaled_value;
-                        if let Some(u) = final_unit {
-                            value_str = format!("{} {}", value_str, u);
-                        }
-                    } else if let Some(u) = unit {
-                        value_str = format!("{} {}", value_str, u);
-                    }
-                    
-                    // Map to field name if we have a meter definition
-                    let field_name = if let Some(field_name) = self.get_field_mapping(&meter_type, &obis_str) {
-                        field_name
-                    } else {
-                        // Use OBIS code as field name if no mapping available
-                        obis_str
-                    };
-                    
-                    metered_values.insert(field_name, serde_json::Value::String(value_str));
-                }
+        let meter_config = crate::CONFIG.read().unwrap().config.sml.iter()
+            .find(|m| m.server_id.eq_ignore_ascii_case(&server_id))
+            .cloned();
+        let meter_name = meter_name(&server_id, meter_config.as_ref());
+        let (metered_values, fields) = self.collect_values(&server_id, &meter_type, &response.val_list, meter_config.as_ref());
+
+        let tenant = meter_config.as_ref().and_then(|m| m.tenant.clone()).unwrap_or_default();
+
+        // Announce new meters to Home Assistant once
+        if !self.discovered.contains(&server_id) {
+            let disc = build_discovery(&meter_name, &fields)
+                .meter_ids(tenant.clone(), format!("sml-{}", server_id));
+            if let Err(e) = self.sender.send(Transmission::AutoDiscovery2(disc)).await {
+                error!("Failed to send SML discovery: {}", e);
+            } else {
+                self.discovered.insert(server_id.clone());
             }
         }
 
@@ -127,8 +150,8 @@ impl SmlManager {
         let current_time = crate::get_unix_ts();
         let metering_data = MeteringData {
             id: format!("sml-{}", server_id),
-            meter_name: format!("SML-{}", server_id),
-            tenant: "default".to_string(),
+            meter_name,
+            tenant,
             protocol: DeviceProtocol::SML,
             transmission_time: current_time,
             transmission_type: TranmissionValueType::Now,
@@ -145,10 +168,14 @@ impl SmlManager {
         }
     }
 
-    fn identify_meter_type(&self, server_id: &str, val_list: &[SmlListEntry]) -> MeterType {
-        // Try to identify meter type based on server ID patterns
-        for (pattern, meter_def) in &self.device_definitions {
-            if server_id.contains(pattern) {
+    fn identify_meter_type(&self, server_id: &[u8], val_list: &[SmlListEntry]) -> MeterType {
+        // The manufacturer code embedded in the server id tells us which definition to use
+        let info = extract_server_id_info(server_id);
+        if let Some(code) = &info.manufacturer_code {
+            let definition = self.device_definitions.iter()
+                .find(|meter_def| meter_def.manufacturer_codes.iter().any(|c| c == code));
+            if let Some(meter_def) = definition {
+                debug!("SML meter {} identified as {:?} by manufacturer {}", info.hex_id, meter_def.meter_type, code);
                 return meter_def.meter_type.clone();
             }
         }
@@ -172,9 +199,71 @@ impl SmlManager {
         MeterType::Generic
     }
 
+    /// Decoded values keyed by OBIS code, or by field name if the meter asks for it, plus the fields to announce
+    fn collect_values(&self, server_id: &str, meter_type: &MeterType, val_list: &[SmlListEntry], meter_config: Option<&SmlMeterConfig>) -> (serde_json::Map<String, serde_json::Value>, Vec<SmlField>) {
+        let field_names = meter_config.is_some_and(|m| m.field_names);
+        let include_raw = meter_config.is_some_and(|m| m.include_raw);
+
+        let mut metered_values = serde_json::Map::new();
+        let mut fields = Vec::new();
+        /* With field names, codes without mapping are only of interest for debugging */
+        let mut raw_fields = Vec::new();
+
+        for entry in val_list {
+            if let Some(obis_code) = &entry.obis_code {
+                let full_obis = format_obis_code(obis_code);
+                let obis_str = short_obis_code(&full_obis).to_string();
+                if meter_config.is_some_and(|m| !m.obis_allowed(&obis_str)) {
+                    continue;
+                }
+                
+                if let Some(value) = &entry.value {
+                    let (mut value_str, mut unit) = parse_sml_value(value);
+                    
+                    // Apply scaler and unit if present
+                    if entry.scaler.is_some() || entry.unit.is_some() {
+                        let (scaled_value, final_unit) = apply_scaler_and_unit(&value_str, entry.scaler, entry.unit);
+                        value_str = scaled_value;
+                        unit = final_unit;
+                    }
+
+                    if let Some(u) = &unit {
+                        value_str = format!("{} {}", value_str, u);
+                    }
+                    
+                    let field_name = if !field_names {
+                        full_obis
+                    } else if let Some(field_name) = self.get_field_mapping(meter_type, &obis_str) {
+                        field_name
+                    } else {
+                        // Use OBIS code as field name if no mapping available
+                        raw_fields.push(obis_str.clone());
+                        obis_str.clone()
+                    };
+                    
+                    metered_values.insert(field_name.clone(), serde_json::Value::String(value_str));
+                    if include_raw || !raw_fields.contains(&field_name) {
+                        fields.push((field_name, obis_str, unit));
+                    }
+                }
+            }
+        }
+
+        if let Some(meter_config) = meter_config {
+            crate::transform::apply_transforms(server_id, &mut metered_values, &meter_config.transforms);
+        }
+        if !include_raw {
+            for field in &raw_fields {
+                metered_values.remove(field);
+            }
+        }
+
+        (metered_values, fields)
+    }
+
     fn get_field_mapping(&self, meter_type: &MeterType, obis_code: &str) -> Option<String> {
         // Look up field mapping in meter definitions
-        for meter_def in self.device_definitions.values() {
+        for meter_def in self.device_definitions.iter() {
             if meter_def.meter_type == *meter_type {
                 if let Some(field_name) = meter_def.obis_mapping.get(obis_code) {
                     return Some(field_name.clone());
@@ -182,20 +271,63 @@ impl SmlManager {
             }
         }
         
-        // Fallback to standard OBIS mappings
-        match obis_code {
-            "1-0:1.8.0" => Some("total_energy_consumed".to_string()),
-            "1-0:2.8.0" => Some("total_energy_delivered".to_string()),
-            "1-0:16.7.0" => Some("current_power".to_string()),
-            "1-0:32.7.0" => Some("voltage_l1".to_string()),
-            "1-0:52.7.0" => Some("voltage_l2".to_string()),
-            "1-0:72.7.0" => Some("voltage_l3".to_string()),
-            "1-0:31.7.0" => Some("current_l1".to_string()),
-            "1-0:51.7.0" => Some("current_l2".to_string()),
-            "1-0:71.7.0" => Some("current_l3".to_string()),
-            _ => None,
+        // Fallback to standard OBIS mappings of the medium (electricity, gas, water, heat)
+        let medium = obis_utils::get_obis_medium(obis_code)?;
+        obis_utils::get_obis_mapping_for_medium(medium)
+            .get(obis_code)
+            .map(|field_name| field_name.to_string())
+    }
+}
+
+/// Configured meter an attention response belongs to, without a server id it can only be the single one configured
+fn attention_meter(server_id: Option<&str>, meters: &[SmlMeterConfig]) -> Option<SmlMeterConfig> {
+    match server_id {
+        Some(id) => meters.iter().find(|m| m.server_id.eq_ignore_ascii_case(id)).cloned(),
+        None if meters.len() == 1 => meters.first().cloned(),
+        None => None,
+    }
+}
+
+/// Configured name of the meter, SML-<server id> if there is none
+fn meter_name(server_id: &str, config: Option<&SmlMeterConfig>) -> String {
+    config.and_then(|c| c.name.clone())
+        .filter(|n| !n.is_empty())
+        .unwrap_or_else(|| format!("SML-{}", server_id))
+}
+
+/// Build the Home Assistant discovery of a meter from its (field name, OBIS code, unit) list
+fn build_discovery(meter_name: &str, fields: &[SmlField]) -> HaSensor {
+    let mut disc = HaSensor::new(DeviceProtocol::SML.to_string(), meter_name.to_string(), None, None);
+
+    for (field_name, obis_code, unit) in fields {
+        let name = obis_utils::get_obis_description(obis_code).unwrap_or(field_name);
+        /* Keys like 1-0:1.8.0.255 can't be used with the dot notation */
+        let mut cmp = HaComponent2::new()
+            .name(name.to_string())
+            .add_information("value_template", serde_json::Value::from(format!("{{{{ value_json['{field_name}'] }}}}")));
+
+        match obis_utils::get_ha_unit_info(obis_code) {
+            Some(info) => {
+                /* Values are published as "<value> <unit>", HA only needs the number */
+                cmp = cmp.unit_info(&info)
+                    .add_information("value_template", serde_json::Value::from(format!("{{{{ value_json['{field_name}'].split(' ')[0] | float }}}}")));
+
+                /* The meter tells us the unit it is sending (e.g. Wh instead of kWh) */
+                if let Some(u) = unit {
+                    cmp = cmp.unit_of_measurement(u.clone());
+                }
+            }
+            None => {
+                cmp = cmp.non_numeric();
+            }
         }
+
+        disc.add_cmp(component_key(field_name), cmp);
     }
+    /* Filled by attention responses of the meter */
+    disc.add_cmp("read_error".to_string(), error_component(&DeviceProtocol::SML.to_string(), meter_name));
+
+    disc
 }
 
 #[cfg(test)]
@@ -214,15 +346,166 @@ mod tests {
         let (tx, _rx) = tokio::sync::mpsc::channel(10);
         let manager = SmlManager::new(tx);
         
-        // Test EMH identification
-        let emh_server_id = "EMH0123456789";
+        // Test EMH identification by the manufacturer code
+        let emh_server_id = [0x0a, 0x01, b'E', b'M', b'H', 0x00, 0x00, 0x12, 0x34, 0x56];
         let empty_list = Vec::new();
-        let _meter_type = manager.identify_meter_type(emh_server_id, &empty_list);
-        // This would depend on the actual meter definitions loaded
+        let meter_type = manager.identify_meter_type(&emh_server_id, &empty_list);
+        assert_eq!(meter_type, MeterType::EMH);
         
         // Test generic fallback
-        let unknown_server_id = "UNKNOWN123";
-        let meter_type = manager.identify_meter_type(unknown_server_id, &empty_list);
+        let unknown_server_id = [0x0a, 0x01, 0x02, 0x03];
+        let meter_type = manager.identify_meter_type(&unknown_server_id, &empty_list);
         assert_eq!(meter_type, MeterType::Generic);
+
+        // Unknown manufacturers still use the OBIS heuristics
+        let entries = vec![SmlListEntry {
+            obis_code: Some(vec![129, 129, 199, 130, 3, 255]),
+            status: None,
+            val_time: None,
+            unit: None,
+            scaler: None,
+            value: None,
+            value_signature: None,
+        }];
+        assert_eq!(manager.identify_meter_type(&unknown_server_id, &entries), MeterType::EMH);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_build_discovery_uses_unit_registry() {
+        let fields = vec![
+            ("total_energy_consumed".to_string(), "1-0:1.8.0".to_string(), Some("Wh".to_string())),
+            ("device_id".to_string(), "1-0:0.0.0".to_string(), None),
+        ];
+
+        let disc = build_discovery("SML-0a01", &fields);
+        let entities = disc.get_entity_discoveries();
+        assert_eq!(entities.len(), 3);
+
+        let energy = &entities[0].payload;
+        assert_eq!(energy["device_class"], "energy");
+        assert_eq!(energy["state_class"], "total_increasing");
+        assert_eq!(energy["unit_of_measurement"], "Wh");
+        assert_eq!(energy["value_template"], "{{ value_json['total_energy_consumed'].split(' ')[0] | float }}");
+
+        let id = &entities[1].payload;
+        assert!(id.get("device_class").is_none());
+        assert!(id.get("state_class").is_none());
+        assert_eq!(id["value_template"], "{{ value_json['device_id'] }}");
+    }
+
+    #[test]
+    fn test_build_discovery_with_obis_keys() {
+        let fields = vec![("1-0:1.8.0.255".to_string(), "1-0:1.8.0".to_string(), Some("Wh".to_string()))];
+
+        let entities = build_discovery("SML-0a01", &fields).get_entity_discoveries();
+        let energy = &entities[0];
+        assert_eq!(energy.payload["value_template"], "{{ value_json['1-0:1.8.0.255'].split(' ')[0] | float }}");
+        assert!(energy.topic.contains("/1_0_1_8_0_255/"));
+        assert_eq!(energy.payload["unique_id"], "e2m_sml_sml-0a01_1_0_1_8_0_255");
+    }
+
+    #[test]
+    fn test_meter_name() {
+        assert_eq!(meter_name("0a01", None), "SML-0a01");
+
+        let mut config: SmlMeterConfig = serde_yml::from_str("server_id: 0a01\n").unwrap();
+        assert_eq!(meter_name("0a01", Some(&config)), "SML-0a01");
+        config.name = Some("Grid".to_string());
+        assert_eq!(meter_name("0a01", Some(&config)), "Grid");
+    }
+
+    #[tokio::test]
+    async fn test_attention_is_reported_as_error() {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(10);
+        let mut manager = SmlManager::new(tx);
+
+        let attention = |code: u8| SmlAttentionMessage {
+            server_id: Some(vec![0x0a, 0x01]),
+            attention_no: vec![0x81, 0x81, 0xC7, 0xC7, code, 0x02],
+            attention_msg: None,
+            attention_details: None,
+        };
+        manager.process_attention(&attention(0xFD), None).await;
+        assert!(rx.try_recv().is_err());
+
+        manager.process_attention(&attention(0xFE), None).await;
+        let Ok(Transmission::MeterError(error)) = rx.try_recv() else {
+            panic!("attention was not reported");
+        };
+        assert_eq!(error.protocol, "SML");
+        assert_eq!(error.meter_name, "SML-0a01");
+        assert_eq!(error.error, "attention 8181c7c7fe02: authentication failed");
+    }
+
+    #[test]
+    fn test_attention_meter() {
+        let meters: Vec<SmlMeterConfig> = serde_yml::from_str("- server_id: 0A01\n  name: main\n").unwrap();
+        assert_eq!(attention_meter(Some("0a01"), &meters).and_then(|m| m.name), Some("main".to_string()));
+        assert!(attention_meter(Some("0a02"), &meters).is_none());
+        /* Without server id only a single configured meter is a match */
+        assert_eq!(attention_meter(None, &meters).and_then(|m| m.name), Some("main".to_string()));
+        let meters: Vec<SmlMeterConfig> = serde_yml::from_str("- server_id: 0a01\n- server_id: 0a02\n").unwrap();
+        assert!(attention_meter(None, &meters).is_none());
+    }
+
+    fn test_entries() -> Vec<SmlListEntry> {
+        let entry = |obis_code: Vec<u8>, value: u32| SmlListEntry {
+            obis_code: Some(obis_code),
+            status: None,
+            val_time: None,
+            unit: None,
+            scaler: None,
+            value: Some(SmlValue::UInt32(value)),
+            value_signature: None,
+        };
+        vec![entry(vec![1, 0, 1, 8, 0, 255], 1234), entry(vec![1, 0, 96, 50, 1, 1], 7)]
+    }
+
+    #[tokio::test]
+    async fn test_values_are_published_by_obis_code() {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(10);
+        let mut manager = SmlManager::new(tx);
+
+        let response = SmlGetListResponse {
+            client_id: None,
+            server_id: Some(vec![0x0a, 0x01, 0x02, 0x03]),
+            list_name: None,
+            act_sensor_time: None,
+            val_list: test_entries(),
+            list_signature: None,
+            act_gateway_time: None,
+        };
+        manager.process_get_list_response(&response, &None).await;
+
+        let mut published = None;
+        while let Ok(transmission) = rx.try_recv() {
+            if let Transmission::Metering(data) = transmission {
+                published = Some(data);
+            }
+        }
+
+        let values = published.unwrap().metered_values;
+        assert_eq!(values.len(), 2);
+        assert_eq!(values["1-0:1.8.0.255"], "1234");
+        assert_eq!(values["1-0:96.50.1.1"], "7");
+    }
+
+    #[test]
+    fn test_field_names_drop_unmapped_codes() {
+        let (tx, _rx) = tokio::sync::mpsc::channel(10);
+        let manager = SmlManager::new(tx);
+        let mut config: SmlMeterConfig = serde_yml::from_str("server_id: 0a01\n\
+            field_names: true\n").unwrap();
+
+        let (values, fields) = manager.collect_values("0a01", &MeterType::Generic, &test_entries(), Some(&config));
+        assert_eq!(values.len(), 1);
+        assert_eq!(values["total_energy_consumed"], "1234");
+        assert_eq!(fields.len(), 1);
+
+        config.include_raw = true;
+        let (values, fields) = manager.collect_values("0a01", &MeterType::Generic, &test_entries(), Some(&config));
+        assert_eq!(values.len(), 2);
+        assert_eq!(values["1-0:96.50.1.1"], "7");
+        assert_eq!(fields.len(), 2);
+    }
+}
@@ -7,6 +7,7 @@ pub enum MeterType {
     EasyMeter,     // EasyMeter (if they support SML)
     Itron,         // Itron OpenWay 3.HZ
     Generic,       // Unknown/Generic meters
+    Custom(String), // User provided definition, named by its file
 }
 
 #[derive(Debug, Clone)]
@@ -1,4 +1,5 @@
 use super::structs::*;
+use crate::obis_utils;
 use log::debug;
 use chrono;
 
@@ -13,6 +14,11 @@ pub fn format_obis_code(obis_bytes: &[u8]) -> String {
     hex::encode(obis_bytes)
 }
 
+/// OBIS code as used by the mappings, F = 255 (not used) is left out: 1-0:1.8.0.255 becomes 1-0:1.8.0
+pub fn short_obis_code(code: &str) -> &str {
+    code.strip_suffix(".255").unwrap_or(code)
+}
+
 pub fn parse_sml_value(value: &SmlValue) -> (String, Option<String>) {
     match value {
         SmlValue::Bool(b) => (b.to_string(), None),
@@ -73,59 +79,72 @@ pub fn apply_scaler_and_unit(value_str: &str, scaler: Option<i8>, unit: Option<u
 pub fn extract_server_id_info(server_id: &[u8]) -> ServerIdInfo {
     let hex_id = hex::encode(server_id);
     
-    // Try to identify manufacturer based on server ID patterns
-    let manufacturer = identify_manufacturer_from_server_id(&hex_id);
+    // The manufacturer is part of the server id
+    let manufacturer_code = manufacturer_code_from_server_id(server_id);
+    let manufacturer = manufacturer_code.as_deref()
+        .map(|code| get_manufacturer_name(code).unwrap_or(code))
+        .unwrap_or("Unknown")
+        .to_string();
     
     ServerIdInfo {
         hex_id: hex_id.clone(),
         manufacturer,
+        manufacturer_code,
         raw_bytes: server_id.to_vec(),
     }
 }
 
-pub fn identify_manufacturer_from_server_id(server_id: &str) -> String {
-    let id_upper = server_id.to_uppercase();
-    
-    // Common manufacturer patterns in SML server IDs
-    if id_upper.starts_with("EMH") || id_upper.contains("EMH") {
-        return "EMH".to_string();
-    }
-    
-    if id_upper.starts_with("ISK") || id_upper.contains("ISK") {
-        return "Iskraemeco".to_string();
-    }
-    
-    if id_upper.starts_with("EAS") || id_upper.contains("EAS") {
-        return "EasyMeter".to_string();
+/// FLAG manufacturer code (e.g. "EMH") of a server id, the first byte tells the layout:
+/// - 0x01/0x02 (wired/wireless M-Bus): M-Bus packed M field in bytes 1 and 2
+/// - 0x06/0x09/0x0A (DIN 43863-5, printed as "1 ISK 00 12345678"): the code in bytes 2 to 4
+pub fn manufacturer_code_from_server_id(server_id: &[u8]) -> Option<String> {
+    let code = match server_id {
+        [0x01 | 0x02, lsb, msb, ..] => {
+            obis_utils::decode_flag_manufacturer(u16::from_le_bytes([*lsb, *msb]))
+        },
+        [0x06 | 0x09 | 0x0A, _, flag @ ..] if flag.len() >= 3 => {
+            String::from_utf8_lossy(&flag[0..3]).to_string()
+        },
+        _ => return None,
+    };
+
+    if !obis_utils::is_valid_flag_code(&code) {
+        debug!("Server id {} carries no valid manufacturer code", hex::encode(server_id));
+        return None;
     }
-    
-    if id_upper.starts_with("ITR") || id_upper.contains("ITR") {
-        return "Itron".to_string();
+
+    Some(code)
+}
+
+/// Readable name of a FLAG manufacturer code
+pub fn get_manufacturer_name(code: &str) -> Option<&'static str> {
+    match code {
+        "EMH" => Some("EMH"),
+        "ISK" => Some("Iskraemeco"),
+        "EAS" | "ESY" => Some("EasyMeter"),
+        "ITR" | "ITO" => Some("Itron"),
+        "SIE" => Some("Siemens"),
+        "LGZ" => Some("Landis+Gyr"),
+        "DZG" => Some("DZG"),
+        "EBZ" => Some("eBZ"),
+        "HLY" => Some("Holley"),
+        _ => None,
     }
-    
-    // Check for specific patterns
-    if server_id.len() >= 10 {
-        if id_upper.starts_with("1E") {
-            return "EMH".to_string();
-        }
-        if id_upper.starts_with("1I") {
-            return "Iskraemeco".to_string();
-        }
-        if id_upper.starts_with("1S") {
-            return "Siemens".to_string();
-        }
-        if id_upper.starts_with("1L") {
-            return "Landis+Gyr".to_string();
-        }
+}
+
+/// Manufacturer of a hex encoded server id, the FLAG code itself if we don't know its name
+pub fn identify_manufacturer_from_server_id(server_id: &str) -> String {
+    match hex::decode(server_id) {
+        Ok(bytes) => extract_server_id_info(&bytes).manufacturer,
+        Err(_) => "Unknown".to_string(),
     }
-    
-    "Unknown".to_string()
 }
 
 #[derive(Debug, Clone)]
 pub struct ServerIdInfo {
     pub hex_id: String,
     pub manufacturer: String,
+    pub manufacturer_code: Option<String>,
     pub raw_bytes: Vec<u8>,
 }
 
@@ -219,6 +238,29 @@ pub fn get_common_sml_obis_mappings() -> std::collections::HashMap<String, Strin
     map
 }
 
+/// Error text of an SML attention response, None for the positive acknowledgements (81 81 C7 C7 FD xx)
+pub fn describe_attention(attention_no: &[u8], attention_msg: Option<&[u8]>) -> Option<String> {
+    if let [0x81, 0x81, 0xC7, 0xC7, 0xFD, _] = attention_no {
+        return None;
+    }
+
+    let meaning = match attention_no {
+        [0x81, 0x81, 0xC7, 0xC7, 0xFE, 0x00] => "error without further details",
+        [0x81, 0x81, 0xC7, 0xC7, 0xFE, 0x01] => "unknown SML designator",
+        [0x81, 0x81, 0xC7, 0xC7, 0xFE, 0x02] => "authentication failed",
+        [0x81, 0x81, 0xC7, 0xC7, 0xFE, 0x03] => "destination address not available",
+        [0x81, 0x81, 0xC7, 0xC7, 0xFE, 0x04] => "request not available",
+        _ => "meter reported a problem",
+    };
+
+    let mut text = format!("attention {}: {meaning}", hex::encode(attention_no));
+    /* The meter may add a text of its own, e.g. which PIN is missing */
+    if let Some(msg) = attention_msg.map(String::from_utf8_lossy).filter(|m| !m.trim().is_empty()) {
+        text.push_str(&format!(" ({})", msg.trim()));
+    }
+    Some(text)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -228,6 +270,8 @@ mod tests {
         let obis_bytes = [0x01, 0x00, 0x01, 0x08, 0x00, 0xFF];
         let formatted = format_obis_code(&obis_bytes);
         assert_eq!(formatted, "1-0:1.8.0.255");
+        assert_eq!(short_obis_code(&formatted), "1-0:1.8.0");
+        assert_eq!(short_obis_code("1-0:1.8.0.1"), "1-0:1.8.0.1");
     }
 
     #[test]
@@ -245,14 +289,43 @@ mod tests {
         assert_eq!(unit, Some("W".to_string()));
     }
 
+    #[test]
+    fn test_describe_attention() {
+        assert_eq!(describe_attention(&[0x81, 0x81, 0xC7, 0xC7, 0xFD, 0x00], None), None);
+        assert_eq!(describe_attention(&[0x81, 0x81, 0xC7, 0xC7, 0xFE, 0x02], Some(b"PIN required")).unwrap(),
+                   "attention 8181c7c7fe02: authentication failed (PIN required)");
+        assert_eq!(describe_attention(&[0x81, 0x81, 0xC7, 0xC7, 0xFE, 0x42], Some(b" ")).unwrap(),
+                   "attention 8181c7c7fe42: meter reported a problem");
+    }
+
     #[test]
     fn test_identify_manufacturer() {
-        assert_eq!(identify_manufacturer_from_server_id("EMH12345"), "EMH");
-        assert_eq!(identify_manufacturer_from_server_id("ISK67890"), "Iskraemeco");
-        assert_eq!(identify_manufacturer_from_server_id("1E2D3F4A567890"), "EMH"); // Should match prefix "1E"
+        // DIN 43863-5 server ids ("1 EMH 00 ...", "1 ISK 00 ...")
+        assert_eq!(identify_manufacturer_from_server_id("0a01454d480000123456"), "EMH");
+        assert_eq!(identify_manufacturer_from_server_id("0a0149534b0004abcdef"), "Iskraemeco");
+        // Wireless M-Bus server id with a packed M field
+        assert_eq!(identify_manufacturer_from_server_id("02fa3078563412010e"), "Landis+Gyr");
+        // Valid but unknown codes are reported as is
+        assert_eq!(identify_manufacturer_from_server_id("0a01414243000012345678"), "ABC");
+        assert_eq!(identify_manufacturer_from_server_id("0a0102030405"), "Unknown");
         assert_eq!(identify_manufacturer_from_server_id("UNKNOWN"), "Unknown");
     }
 
+    #[test]
+    fn test_extract_server_id_info() {
+        let info = extract_server_id_info(&[0x02, 0xa8, 0x15, 0x78, 0x56, 0x34, 0x12, 0x01, 0x02]);
+        assert_eq!(info.hex_id, "02a815785634120102");
+        assert_eq!(info.manufacturer_code.as_deref(), Some("EMH"));
+        assert_eq!(info.manufacturer, "EMH");
+
+        let info = extract_server_id_info(&[0x0a, 0x01, b'L', b'G', b'Z', 0x00, 0x01, 0x02, 0x03, 0x04]);
+        assert_eq!(info.manufacturer_code.as_deref(), Some("LGZ"));
+
+        let info = extract_server_id_info(&[0x1e, 0x2d, 0x3f, 0x4a]);
+        assert_eq!(info.manufacturer_code, None);
+        assert_eq!(info.manufacturer, "Unknown");
+    }
+
     #[test]
     fn test_crc16_calculation() {
         let data = [0x1B, 0x1B, 0x1B, 0x1B, 0x01, 0x01, 0x01, 0x01];
@@ -0,0 +1,583 @@
+/*
+    Tibber integration for energy2mqtt
+
+    Polls the Tibber GraphQL API for every configured account and publishes the
+    current spot price per home. Homes with a Tibber Pulse additionally get their
+    live power and consumption through the liveMeasurement subscription.
+*/
+
+use std::collections::{HashMap, HashSet};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{debug, error, info, warn};
+use serde_json::Value;
+use tokio::sync::mpsc::Sender;
+use tokio::task::JoinHandle;
+
+use crate::config::{ConfigBases, ConfigChange, ConfigOperation, MqttTlsConfig, TibberConfig};
+use crate::models::DeviceProtocol;
+use crate::mqtt::home_assistant::{HaComponent2, HaSensor};
+use crate::mqtt::{publish_protocol_count, Transmission};
+use crate::{get_config_or_panic, get_id, get_unix_ts, MeteringData, CONFIG};
+
+const TIBBER_API_URL: &str = "https://api.tibber.com/v1-beta/gql";
+
+const TIBBER_QUERY: &str = "{ viewer { websocketSubscriptionUrl homes { id appNickname address { address1 } \
+    features { realTimeConsumptionEnabled } \
+    currentSubscription { priceInfo { current { total energy tax level currency startsAt } } } } } }";
+
+/// Seconds to wait before subscribing again after the live data stopped
+const TIBBER_LIVE_RETRY: u64 = 60;
+
+/// Values of one home as reported by Tibber
+#[derive(Debug, Clone, PartialEq)]
+pub struct TibberHome {
+    pub id: String,
+    pub name: String,
+    pub currency: String,
+    pub price_total: Option<f64>,
+    pub price_energy: Option<f64>,
+    pub price_tax: Option<f64>,
+    pub price_level: Option<String>,
+    /// Has a Tibber Pulse, live data is available by subscription
+    pub live_enabled: bool,
+}
+
+/// Live values of a home, pushed by Tibber every few seconds
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TibberLive {
+    pub power: Option<f64>,
+    pub power_production: Option<f64>,
+    /// Consumption and its cost since midnight
+    pub accumulated_consumption: Option<f64>,
+    pub accumulated_cost: Option<f64>,
+    /// Reading of the meter in kWh
+    pub last_meter_consumption: Option<f64>,
+}
+
+/// Sanitize a name for use as device ID
+pub(crate) fn sanitize_id(name: &str) -> String {
+    name.to_lowercase()
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+/// Parse the GraphQL response into the homes of the account
+pub fn parse_homes(response: &Value) -> Result<Vec<TibberHome>, String> {
+    if let Some(errors) = response.get("errors").and_then(|e| e.as_array()) {
+        if let Some(msg) = errors.first().and_then(|e| e.get("message")).and_then(|m| m.as_str()) {
+            return Err(msg.to_string());
+        }
+    }
+
+    let homes = response.pointer("/data/viewer/homes")
+        .and_then(|h| h.as_array())
+        .ok_or("No homes found in Tibber response")?;
+
+    let mut result = Vec::new();
+    for (idx, home) in homes.iter().enumerate() {
+        let id = home.get("id").and_then(|v| v.as_str()).unwrap_or_default().to_string();
+
+        /* Prefer the nickname from the app, fall back to the address */
+        let name = home.get("appNickname").and_then(|v| v.as_str())
+            .or_else(|| home.pointer("/address/address1").and_then(|v| v.as_str()))
+            .map(|s| s.to_string())
+            .unwrap_or(format!("home_{idx}"));
+
+        let price = home.pointer("/currentSubscription/priceInfo/current");
+
+        result.push(TibberHome {
+            id,
+            name,
+            currency: price.and_then(|p| p.get("currency")).and_then(|v| v.as_str()).unwrap_or("EUR").to_string(),
+            price_total: price.and_then(|p| p.get("total")).and_then(|v| v.as_f64()),
+            price_energy: price.and_then(|p| p.get("energy")).and_then(|v| v.as_f64()),
+            price_tax: price.and_then(|p| p.get("tax")).and_then(|v| v.as_f64()),
+            price_level: price.and_then(|p| p.get("level")).and_then(|v| v.as_str()).map(|s| s.to_string()),
+            live_enabled: home.pointer("/features/realTimeConsumptionEnabled").and_then(|v| v.as_bool()).unwrap_or(false),
+        });
+    }
+
+    Ok(result)
+}
+
+/// Websocket the live data of the account is subscribed at
+pub fn parse_subscription_url(response: &Value) -> Option<String> {
+    response.pointer("/data/viewer/websocketSubscriptionUrl")
+        .and_then(|v| v.as_str())
+        .map(|s| s.to_string())
+}
+
+/// Live values of a graphql-transport-ws message, None for messages without data
+pub fn parse_live_message(msg: &Value) -> Result<Option<TibberLive>, String> {
+    match msg.get("type").and_then(|t| t.as_str()) {
+        Some("next") => {
+            if let Some(error) = msg.pointer("/payload/errors/0/message").and_then(|m| m.as_str()) {
+                return Err(error.to_string());
+            }
+            let Some(live) = msg.pointer("/payload/data/liveMeasurement") else {
+                return Ok(None);
+            };
+            let value = |key: &str| live.get(key).and_then(|v| v.as_f64());
+
+            Ok(Some(TibberLive {
+                power: value("power"),
+                power_production: value("powerProduction"),
+                accumulated_consumption: value("accumulatedConsumption"),
+                accumulated_cost: value("accumulatedCost"),
+                last_meter_consumption: value("lastMeterConsumption"),
+            }))
+        },
+        Some("error") => Err(msg.pointer("/payload/0/message").and_then(|m| m.as_str())
+            .unwrap_or("subscription failed").to_string()),
+        Some("complete") => Err("subscription ended by Tibber".to_string()),
+        _ => Ok(None),
+    }
+}
+
+/// Subscribe the live data of a home and forward it until the connection fails or the account is stopped.
+/// tungstenite is blocking so this runs on its own thread.
+fn subscribe_live(url: &str, token: &str, home_id: &str, tx: &tokio::sync::mpsc::Sender<(String, TibberLive)>) -> Result<(), String> {
+    use tungstenite::client::IntoClientRequest;
+    use tungstenite::http::HeaderValue;
+    use tungstenite::{Connector, Message};
+
+    let mut request = url.into_client_request().map_err(|e| format!("Invalid subscription URL {url}: {e}"))?;
+    request.headers_mut().insert("Sec-WebSocket-Protocol", HeaderValue::from_static("graphql-transport-ws"));
+    /* Tibber rejects clients without a user agent */
+    request.headers_mut().insert("User-Agent", HeaderValue::from_static(concat!("energy2mqtt/", env!("CARGO_PKG_VERSION"))));
+
+    let host = request.uri().host().unwrap_or_default().to_string();
+    let port = request.uri().port_u16().unwrap_or(443);
+    let stream = TcpStream::connect((host.as_str(), port)).map_err(|e| format!("Connecting to {host} failed: {e}"))?;
+    /* Tibber sends data every few seconds, a silent connection is dead */
+    let _ = stream.set_read_timeout(Some(Duration::from_secs(TIBBER_LIVE_RETRY)));
+
+    let tls = crate::mqtt::tls::build_client_config(&MqttTlsConfig::default())?;
+    let (mut socket, _) = tungstenite::client_tls_with_config(request, stream, None, Some(Connector::Rustls(Arc::new(tls))))
+        .map_err(|e| format!("Websocket handshake failed: {e}"))?;
+
+    let init = serde_json::json!({ "type": "connection_init", "payload": { "token": token } });
+    socket.send(Message::text(init.to_string())).map_err(|e| e.to_string())?;
+
+    loop {
+        let msg = socket.read().map_err(|e| format!("Connection lost: {e}"))?;
+        let Message::Text(text) = msg else {
+            continue;
+        };
+        let msg: Value = serde_json::from_str(text.as_str()).map_err(|e| format!("Invalid message: {e}"))?;
+
+        match msg.get("type").and_then(|t| t.as_str()) {
+            Some("connection_ack") => {
+                let query = format!("subscription {{ liveMeasurement(homeId: \"{home_id}\") {{ power powerProduction \
+                    accumulatedConsumption accumulatedCost lastMeterConsumption }} }}");
+                let subscribe = serde_json::json!({ "id": home_id, "type": "subscribe", "payload": { "query": query } });
+                socket.send(Message::text(subscribe.to_string())).map_err(|e| e.to_string())?;
+            },
+            Some("ping") => {
+                socket.send(Message::text(serde_json::json!({ "type": "pong" }).to_string())).map_err(|e| e.to_string())?;
+            },
+            _ => {
+                if let Some(live) = parse_live_message(&msg)? {
+                    /* The receiver is gone once the account is stopped or reconfigured */
+                    if tx.blocking_send((home_id.to_string(), live)).is_err() {
+                        return Ok(());
+                    }
+                }
+            },
+        }
+    }
+}
+
+/// Keep the live data of a home subscribed, reconnecting after failures
+fn watch_live(name: String, url: String, token: String, home_id: String, tx: tokio::sync::mpsc::Sender<(String, TibberLive)>) {
+    while !tx.is_closed() {
+        match subscribe_live(&url, &token, &home_id, &tx) {
+            Ok(()) => return,
+            Err(e) => warn!("[Tibber {name}] Live data of home {home_id} stopped: {e}"),
+        }
+        std::thread::sleep(Duration::from_secs(TIBBER_LIVE_RETRY));
+    }
+}
+
+/// Query the Tibber API, ureq is blocking so this runs on the blocking pool
+async fn query_tibber(token: String) -> Result<Value, String> {
+    let result = tokio::task::spawn_blocking(move || {
+        ureq::post(TIBBER_API_URL)
+            .set("Authorization", &format!("Bearer {token}"))
+            .timeout(Duration::from_secs(30))
+            .send_json(serde_json::json!({ "query": TIBBER_QUERY }))
+            .map_err(|e| format!("Request failed: {e}"))?
+            .into_json::<Value>()
+            .map_err(|e| format!("Invalid response: {e}"))
+    }).await;
+
+    match result {
+        Ok(r) => r,
+        Err(e) => Err(format!("Request task failed: {e}")),
+    }
+}
+
+fn build_discovery(device_id: &str, home: &TibberHome) -> HaSensor {
+    let mut disc = HaSensor::new(
+        DeviceProtocol::Tibber.to_string(),
+        device_id.to_string(),
+        Some("Tibber".to_string()),
+        Some("Home".to_string()),
+    )
+    .device_name(home.name.clone());
+
+    let price_unit = format!("{}/kWh", home.currency);
+
+    let cmp = HaComponent2::new()
+        .name("Price".to_string())
+        .device_class("monetary".to_string())
+        .unit_of_measurement(price_unit.clone())
+        .del_information("state_class");
+    disc.add_cmp("price_total".to_string(), cmp);
+
+    let cmp = HaComponent2::new()
+        .name("Price Energy".to_string())
+        .device_class("monetary".to_string())
+        .unit_of_measurement(price_unit.clone())
+        .del_information("state_class");
+    disc.add_cmp("price_energy".to_string(), cmp);
+
+    let cmp = HaComponent2::new()
+        .name("Price Tax".to_string())
+        .device_class("monetary".to_string())
+        .unit_of_measurement(price_unit)
+        .del_information("state_class");
+    disc.add_cmp("price_tax".to_string(), cmp);
+
+    let cmp = HaComponent2::new()
+        .name("Price Level".to_string())
+        .non_numeric();
+    disc.add_cmp("price_level".to_string(), cmp);
+
+    if home.live_enabled {
+        let cmp = HaComponent2::new()
+            .name("Power".to_string())
+            .device_class("power".to_string())
+            .unit_of_measurement("W".to_string());
+        disc.add_cmp("power".to_string(), cmp);
+
+        let cmp = HaComponent2::new()
+            .name("Power Production".to_string())
+            .device_class("power".to_string())
+            .unit_of_measurement("W".to_string());
+        disc.add_cmp("power_production".to_string(), cmp);
+
+        let cmp = HaComponent2::new()
+            .name("Consumption Today".to_string())
+            .device_class("energy".to_string())
+            .unit_of_measurement("kWh".to_string())
+            .state_class("total_increasing".to_string());
+        disc.add_cmp("accumulated_consumption".to_string(), cmp);
+
+        let cmp = HaComponent2::new()
+            .name("Cost Today".to_string())
+            .device_class("monetary".to_string())
+            .unit_of_measurement(home.currency.clone())
+            .del_information("state_class");
+        disc.add_cmp("accumulated_cost".to_string(), cmp);
+
+        let cmp = HaComponent2::new()
+            .name("Meter Reading".to_string())
+            .device_class("energy".to_string())
+            .unit_of_measurement("kWh".to_string())
+            .state_class("total_increasing".to_string());
+        disc.add_cmp("last_meter_consumption".to_string(), cmp);
+    }
+
+    disc
+}
+
+fn home_to_metering(device_id: &str, home: &TibberHome, live: Option<&TibberLive>) -> MeteringData {
+    let mut meter_data = MeteringData::new().unwrap();
+    let timestamp = get_unix_ts();
+
+    meter_data.meter_name = device_id.to_string();
+    meter_data.protocol = DeviceProtocol::Tibber;
+    meter_data.id = get_id("tibber".to_string(), &device_id.to_string());
+    meter_data.transmission_time = timestamp;
+    meter_data.metered_time = timestamp;
+
+    let live = live.cloned().unwrap_or_default();
+    let values = [
+        ("price_total", home.price_total),
+        ("price_energy", home.price_energy),
+        ("price_tax", home.price_tax),
+        ("power", live.power),
+        ("power_production", live.power_production),
+        ("accumulated_consumption", live.accumulated_consumption),
+        ("accumulated_cost", live.accumulated_cost),
+        ("last_meter_consumption", live.last_meter_consumption),
+    ];
+
+    for (key, value) in values {
+        if let Some(v) = value {
+            meter_data.metered_values.insert(key.to_string(), Value::from(v));
+        }
+    }
+
+    if let Some(level) = &home.price_level {
+        meter_data.metered_values.insert("price_level".to_string(), Value::from(level.clone()));
+    }
+
+    meter_data
+}
+
+async fn poll_account(conf: TibberConfig, sender: Sender<Transmission>) {
+    let interval = Duration::from_secs(std::cmp::max(conf.poll_interval, 60));
+    let mut discovered: HashSet<String> = HashSet::new();
+    let tenant = conf.tenant.clone().unwrap_or_default();
+
+    /* Last prices and live values per home id, each update publishes both */
+    let mut homes: HashMap<String, (String, TibberHome)> = HashMap::new();
+    let mut live: HashMap<String, TibberLive> = HashMap::new();
+    /* Dropped with this task, which ends the live subscriptions */
+    let (live_tx, mut live_rx) = tokio::sync::mpsc::channel::<(String, TibberLive)>(16);
+    let mut next_poll = tokio::time::Instant::now();
+
+    loop {
+        let updated = tokio::select! {
+            _ = tokio::time::sleep_until(next_poll) => {
+                next_poll += interval;
+                poll_homes(&conf, &mut homes, &live_tx).await
+            },
+            Some((home_id, values)) = live_rx.recv() => {
+                live.insert(home_id.clone(), values);
+                vec![home_id]
+            },
+        };
+
+        for home_id in updated {
+            let Some((device_id, home)) = homes.get(&home_id) else {
+                continue;
+            };
+
+            if !discovered.contains(&home.id) {
+                let disc = build_discovery(device_id, home)
+                    .meter_ids(tenant.clone(), get_id("tibber".to_string(), device_id));
+                let _ = sender.send(Transmission::AutoDiscovery2(disc)).await;
+                discovered.insert(home.id.clone());
+            }
+
+            let mut meter_data = home_to_metering(device_id, home, live.get(&home_id));
+            meter_data.tenant = tenant.clone();
+            let _ = sender.send(Transmission::Metering(meter_data)).await;
+        }
+    }
+}
+
+/// Query the homes of an account, subscribe the live data of new homes with a Pulse
+/// and return the ids of the homes to publish
+async fn poll_homes(conf: &TibberConfig, homes: &mut HashMap<String, (String, TibberHome)>,
+                    live_tx: &tokio::sync::mpsc::Sender<(String, TibberLive)>) -> Vec<String> {
+    let response = match query_tibber(conf.account_token.clone()).await {
+        Ok(response) => response,
+        Err(e) => {
+            error!("[Tibber {}] {}", conf.name, e);
+            return Vec::new();
+        }
+    };
+
+    let polled = match parse_homes(&response) {
+        Ok(polled) => polled,
+        Err(e) => {
+            error!("[Tibber {}] API returned an error: {}", conf.name, e);
+            return Vec::new();
+        }
+    };
+    debug!("[Tibber {}] Received data for {} homes", conf.name, polled.len());
+
+    let url = parse_subscription_url(&response);
+    let mut updated = Vec::new();
+    for home in polled {
+        let device_id = sanitize_id(&format!("{}_{}", conf.name, home.name));
+
+        if home.live_enabled && !homes.contains_key(&home.id) {
+            match &url {
+                Some(url) => {
+                    info!("[Tibber {}] Subscribing live data of {}", conf.name, home.name);
+                    let (name, url, token, home_id, tx) = (conf.name.clone(), url.clone(), conf.account_token.clone(),
+                                                           home.id.clone(), live_tx.clone());
+                    std::thread::spawn(move || watch_live(name, url, token, home_id, tx));
+                },
+                None => warn!("[Tibber {}] No subscription URL, live data of {} is not available", conf.name, home.name),
+            }
+        }
+
+        updated.push(home.id.clone());
+        homes.insert(home.id.clone(), (device_id, home));
+    }
+
+    updated
+}
+
+pub struct TibberManager {
+    sender: Sender<Transmission>,
+    config_change: tokio::sync::broadcast::Receiver<ConfigChange>,
+    threads: Vec<JoinHandle<()>>,
+    config: Vec<TibberConfig>,
+}
+
+impl TibberManager {
+    pub fn new(sender: Sender<Transmission>) -> Self {
+        let config: Vec<TibberConfig> = get_config_or_panic!("tibber", ConfigBases::Tibber);
+
+        return TibberManager {
+            sender,
+            config_change: CONFIG.read().unwrap().get_change_receiver(),
+            threads: Vec::new(),
+            config,
+        };
+    }
+
+    pub async fn start_thread(&mut self) -> ! {
+        /* There may be not config to start with, so sleep until there is  */
+        if self.config.is_empty() {
+            info!("No Tibber accounts found, waiting for a config change to wake me up");
+            loop {
+                let change = self.config_change.recv().await.unwrap();
+                if change.operation != ConfigOperation::ADD || change.base != "tibber" {
+                    continue;
+                }
+
+                /* we need to read the config now as this change is about our part of the code */
+                self.config = get_config_or_panic!("tibber", ConfigBases::Tibber);
+                break;
+            }
+        }
+
+        info!("Started Tibber configuration");
+        loop {
+            let mut account_count = 0;
+
+            for conf in self.config.iter() {
+                account_count += 1;
+                info!("Starting Tibber polling for {}", conf.name);
+
+                let handle = tokio::spawn(poll_account(conf.clone(), self.sender.clone()));
+                self.threads.push(handle);
+            }
+
+            publish_protocol_count(&self.sender, "tibber", account_count).await;
+
+            info!("All Tibber {account_count} accounts setup, waiting for config changes");
+
+            loop {
+                let change = self.config_change.recv().await.unwrap();
+                if change.base == "tibber" {
+                    break;
+                }
+            }
+
+            /* We are waken up because some of our config changed so stop the threads and start over */
+            info!("Tibber is stopping threads");
+            for thread in self.threads.iter() {
+                thread.abort();
+            }
+
+            self.threads.clear();
+
+            /* Reload the config before restarting */
+            self.config = get_config_or_panic!("tibber", ConfigBases::Tibber);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_homes() {
+        let response = serde_json::json!({
+            "data": { "viewer": { "websocketSubscriptionUrl": "wss://websocket-api.tibber.com/v1-beta/gql/subscriptions", "homes": [
+                {
+                    "id": "abc",
+                    "appNickname": "Holiday Home",
+                    "address": { "address1": "Street 1" },
+                    "currentSubscription": { "priceInfo": { "current": {
+                        "total": 0.3012, "energy": 0.2, "tax": 0.1012, "level": "NORMAL", "currency": "EUR"
+                    } } },
+                    "features": { "realTimeConsumptionEnabled": true }
+                },
+                {
+                    "id": "def",
+                    "appNickname": null,
+                    "address": { "address1": "Street 2" },
+                    "currentSubscription": null,
+                    "features": null
+                }
+            ] } }
+        });
+
+        let homes = parse_homes(&response).unwrap();
+        assert_eq!(homes.len(), 2);
+        assert_eq!(homes[0].name, "Holiday Home");
+        assert_eq!(homes[0].price_total, Some(0.3012));
+        assert_eq!(homes[0].price_level, Some("NORMAL".to_string()));
+        assert!(homes[0].live_enabled);
+
+        assert_eq!(homes[1].name, "Street 2");
+        assert_eq!(homes[1].price_total, None);
+        assert!(!homes[1].live_enabled);
+
+        assert_eq!(parse_subscription_url(&response).unwrap(), "wss://websocket-api.tibber.com/v1-beta/gql/subscriptions");
+    }
+
+    #[test]
+    fn test_parse_live_message() {
+        let msg = serde_json::json!({ "id": "abc", "type": "next", "payload": { "data": { "liveMeasurement": {
+            "power": 1520.0, "powerProduction": 0.0, "accumulatedConsumption": 7.25, "accumulatedCost": 2.18,
+            "lastMeterConsumption": 12034.5
+        } } } });
+        let live = parse_live_message(&msg).unwrap().unwrap();
+        assert_eq!(live.power, Some(1520.0));
+        assert_eq!(live.accumulated_consumption, Some(7.25));
+        assert_eq!(live.last_meter_consumption, Some(12034.5));
+
+        assert_eq!(parse_live_message(&serde_json::json!({ "type": "connection_ack" })), Ok(None));
+        let msg = serde_json::json!({ "id": "abc", "type": "error", "payload": [ { "message": "not authorized" } ] });
+        assert_eq!(parse_live_message(&msg), Err("not authorized".to_string()));
+        assert!(parse_live_message(&serde_json::json!({ "id": "abc", "type": "complete" })).is_err());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let response = serde_json::json!({ "errors": [ { "message": "invalid token" } ] });
+        assert_eq!(parse_homes(&response), Err("invalid token".to_string()));
+    }
+
+    #[test]
+    fn test_home_to_metering() {
+        let home = TibberHome {
+            id: "abc".to_string(),
+            name: "Home".to_string(),
+            currency: "EUR".to_string(),
+            price_total: Some(0.3),
+            price_energy: None,
+            price_tax: None,
+            price_level: Some("CHEAP".to_string()),
+            live_enabled: true,
+        };
+
+        let data = home_to_metering("main_home", &home, None);
+        assert_eq!(data.meter_name, "main_home");
+        assert_eq!(data.metered_values.get("price_total"), Some(&Value::from(0.3)));
+        assert_eq!(data.metered_values.get("price_level"), Some(&Value::from("CHEAP")));
+        assert!(data.metered_values.get("price_energy").is_none());
+        assert!(data.metered_values.get("power").is_none());
+
+        let live = TibberLive { power: Some(850.0), ..Default::default() };
+        let data = home_to_metering("main_home", &home, Some(&live));
+        assert_eq!(data.metered_values.get("power"), Some(&Value::from(850.0)));
+        assert_eq!(data.metered_values.get("price_total"), Some(&Value::from(0.3)));
+    }
+}
@@ -8,7 +8,9 @@
     - Grid Meters (AC meters connected to grid)
     - Batteries (battery storage systems)
     - PV Chargers (solar charge controllers)
+    - PV Inverters (grid-tied AC PV inverters)
     - VEBus (inverter/charger devices)
+    - Tanks, temperature sensors and digital inputs (environment)
 */
 
 use std::sync::Arc;
@@ -18,12 +20,13 @@ use serde_json::Value;
 use tokio::sync::{mpsc::Sender, Mutex};
 use crate::{
     metering_victron::{utils::{self, read_topic_u64, read_topic_u64_cluster, set_topic}, Topic, VictronCluster},
-    mqtt::{Transmission, home_assistant::{HaSensor, HaComponent2}}
+    get_id,
+    mqtt::{Transmission, home_assistant::{get_command_topic, phase_entity_name, HaSensor, HaComponent2}}
 };
 use super::VictronData;
 
 /// Sanitize a name for use as device ID (lowercase, spaces to underscores)
-fn sanitize_id(name: &str) -> String {
+pub(crate) fn sanitize_id(name: &str) -> String {
     name.to_lowercase().replace(" ", "_").replace("-", "_")
 }
 
@@ -99,7 +102,7 @@ fn build_grid_meter_discovery(
 
         // Phase energy positive
         let cmp = HaComponent2::new()
-            .name(format!("Energy Imported L{}", p))
+            .name(phase_entity_name("Energy Imported", p))
             .device_class("energy".to_string())
             .unit_of_measurement("kWh".to_string())
             .state_class("total_increasing".to_string());
@@ -107,7 +110,7 @@ fn build_grid_meter_discovery(
 
         // Phase energy negative
         let cmp = HaComponent2::new()
-            .name(format!("Energy Exported L{}", p))
+            .name(phase_entity_name("Energy Exported", p))
             .device_class("energy".to_string())
             .unit_of_measurement("kWh".to_string())
             .state_class("total_increasing".to_string());
@@ -115,7 +118,7 @@ fn build_grid_meter_discovery(
 
         // Phase voltage
         let cmp = HaComponent2::new()
-            .name(format!("Voltage L{}", p))
+            .name(phase_entity_name("Voltage", p))
             .device_class("voltage".to_string())
             .unit_of_measurement("V".to_string())
             .state_class("measurement".to_string());
@@ -123,7 +126,7 @@ fn build_grid_meter_discovery(
 
         // Phase current
         let cmp = HaComponent2::new()
-            .name(format!("Current L{}", p))
+            .name(phase_entity_name("Current", p))
             .device_class("current".to_string())
             .unit_of_measurement("A".to_string())
             .state_class("measurement".to_string());
@@ -131,7 +134,7 @@ fn build_grid_meter_discovery(
 
         // Phase power
         let cmp = HaComponent2::new()
-            .name(format!("Power L{}", p))
+            .name(phase_entity_name("Power", p))
             .device_class("power".to_string())
             .unit_of_measurement("W".to_string())
             .state_class("measurement".to_string());
@@ -359,6 +362,83 @@ fn build_pv_charger_discovery(
     disc
 }
 
+/// Build Home Assistant discovery for a PV Inverter (grid-tied, AC coupled)
+fn build_pv_inverter_discovery(
+    devname: &str,
+    instance: u64,
+    productname: &str,
+    nr_phases: u64,
+) -> HaSensor {
+    let proto = crate::models::DeviceProtocol::Victron.to_string();
+    let device_id = format!("{}_pvinverter_{}", sanitize_id(devname), instance);
+
+    let mut disc = HaSensor::new(
+        proto.clone(),
+        device_id.clone(),
+        Some("Victron".to_string()),
+        Some(productname.to_string()),
+    )
+    .device_name(format!("PV Inverter {}", instance))
+    .via(format!("e2m_{}_{}", proto, sanitize_id(devname)));
+
+    // Total AC power
+    let cmp = HaComponent2::new()
+        .name("Power".to_string())
+        .device_class("power".to_string())
+        .unit_of_measurement("W".to_string())
+        .state_class("measurement".to_string());
+    disc.add_cmp("power".to_string(), cmp);
+
+    // Total yield
+    let cmp = HaComponent2::new()
+        .name("Yield Total".to_string())
+        .device_class("energy".to_string())
+        .unit_of_measurement("kWh".to_string())
+        .state_class("total_increasing".to_string());
+    disc.add_cmp("yield_total".to_string(), cmp);
+
+    // Status code as reported by the inverter
+    let cmp = HaComponent2::new()
+        .name("Status".to_string())
+        .non_numeric();
+    disc.add_cmp("status_code".to_string(), cmp);
+
+    // Per-phase measurements
+    for p in 1..=nr_phases {
+        let phase_suffix = format!("l{}", p);
+
+        let cmp = HaComponent2::new()
+            .name(phase_entity_name("Power", p))
+            .device_class("power".to_string())
+            .unit_of_measurement("W".to_string())
+            .state_class("measurement".to_string());
+        disc.add_cmp(format!("power_{}", phase_suffix), cmp);
+
+        let cmp = HaComponent2::new()
+            .name(phase_entity_name("Voltage", p))
+            .device_class("voltage".to_string())
+            .unit_of_measurement("V".to_string())
+            .state_class("measurement".to_string());
+        disc.add_cmp(format!("voltage_{}", phase_suffix), cmp);
+
+        let cmp = HaComponent2::new()
+            .name(phase_entity_name("Current", p))
+            .device_class("current".to_string())
+            .unit_of_measurement("A".to_string())
+            .state_class("measurement".to_string());
+        disc.add_cmp(format!("current_{}", phase_suffix), cmp);
+
+        let cmp = HaComponent2::new()
+            .name(phase_entity_name("Yield", p))
+            .device_class("energy".to_string())
+            .unit_of_measurement("kWh".to_string())
+            .state_class("total_increasing".to_string());
+        disc.add_cmp(format!("yield_{}", phase_suffix), cmp);
+    }
+
+    disc
+}
+
 /// Build Home Assistant discovery for a VEBus device (Inverter/Charger)
 fn build_vebus_discovery(
     devname: &str,
@@ -410,7 +490,7 @@ fn build_vebus_discovery(
 
     for p in 1..=3 {
         let cmp = HaComponent2::new()
-            .name(format!("AC Output Power L{}", p))
+            .name(phase_entity_name("AC Output Power", p))
             .device_class("power".to_string())
             .unit_of_measurement("W".to_string())
             .state_class("measurement".to_string());
@@ -427,7 +507,7 @@ fn build_vebus_discovery(
 
     for p in 1..=3 {
         let cmp = HaComponent2::new()
-            .name(format!("AC Input Power L{}", p))
+            .name(phase_entity_name("AC Input Power", p))
             .device_class("power".to_string())
             .unit_of_measurement("W".to_string())
             .state_class("measurement".to_string());
@@ -445,6 +525,203 @@ fn build_vebus_discovery(
     disc
 }
 
+/// Fluid of a tank as reported in /FluidType
+fn tank_fluid_name(fluid_type: u64) -> &'static str {
+    match fluid_type {
+        0 => "Fuel",
+        1 => "Fresh Water",
+        2 => "Waste Water",
+        3 => "Live Well",
+        4 => "Oil",
+        5 => "Black Water",
+        6 => "Gasoline",
+        7 => "Diesel",
+        8 => "LPG",
+        9 => "LNG",
+        10 => "Hydraulic Oil",
+        11 => "Raw Water",
+        _ => "Tank",
+    }
+}
+
+/// Location of a temperature sensor as reported in /TemperatureType
+fn temperature_type_name(temperature_type: u64) -> &'static str {
+    match temperature_type {
+        0 => "Battery",
+        1 => "Fridge",
+        3 => "Room",
+        4 => "Outdoor",
+        5 => "Water Heater",
+        6 => "Freezer",
+        _ => "Temperature",
+    }
+}
+
+/// Name and Home Assistant device class of a digital input as reported in /Type
+fn digital_input_kind(input_type: u64) -> (&'static str, Option<&'static str>) {
+    match input_type {
+        2 => ("Door", Some("door")),
+        3 => ("Bilge Pump", Some("running")),
+        4 => ("Bilge Alarm", Some("moisture")),
+        5 => ("Burglar Alarm", Some("safety")),
+        6 => ("Smoke Alarm", Some("smoke")),
+        7 => ("Fire Alarm", Some("smoke")),
+        8 => ("CO2 Alarm", Some("gas")),
+        9 => ("Generator", Some("running")),
+        _ => ("Digital Input", None),
+    }
+}
+
+/// Build Home Assistant discovery for a Tank sensor
+fn build_tank_discovery(
+    devname: &str,
+    instance: u64,
+    productname: &str,
+    fluid_type: u64,
+) -> HaSensor {
+    let proto = crate::models::DeviceProtocol::Victron.to_string();
+    let device_id = format!("{}_tank_{}", sanitize_id(devname), instance);
+
+    let mut disc = HaSensor::new(
+        proto.clone(),
+        device_id.clone(),
+        Some("Victron".to_string()),
+        Some(productname.to_string()),
+    )
+    .device_name(format!("{} Tank {}", tank_fluid_name(fluid_type), instance))
+    .via(format!("e2m_{}_{}", proto, sanitize_id(devname)));
+
+    // Fill level
+    let cmp = HaComponent2::new()
+        .name("Level".to_string())
+        .unit_of_measurement("%".to_string())
+        .state_class("measurement".to_string());
+    disc.add_cmp("level".to_string(), cmp);
+
+    // Remaining volume
+    let cmp = HaComponent2::new()
+        .name("Remaining".to_string())
+        .device_class("volume_storage".to_string())
+        .unit_of_measurement("m³".to_string())
+        .state_class("measurement".to_string());
+    disc.add_cmp("remaining".to_string(), cmp);
+
+    // Capacity as configured on the GX device
+    let cmp = HaComponent2::new()
+        .name("Capacity".to_string())
+        .device_class("volume_storage".to_string())
+        .unit_of_measurement("m³".to_string())
+        .state_class("measurement".to_string())
+        .cat_diagnostic();
+    disc.add_cmp("capacity".to_string(), cmp);
+
+    disc
+}
+
+/// Build Home Assistant discovery for a Temperature sensor
+fn build_temperature_discovery(
+    devname: &str,
+    instance: u64,
+    productname: &str,
+    temperature_type: u64,
+    has_humidity: bool,
+    has_pressure: bool,
+) -> HaSensor {
+    let proto = crate::models::DeviceProtocol::Victron.to_string();
+    let device_id = format!("{}_temperature_{}", sanitize_id(devname), instance);
+
+    let mut disc = HaSensor::new(
+        proto.clone(),
+        device_id.clone(),
+        Some("Victron".to_string()),
+        Some(productname.to_string()),
+    )
+    .device_name(format!("{} Sensor {}", temperature_type_name(temperature_type), instance))
+    .via(format!("e2m_{}_{}", proto, sanitize_id(devname)));
+
+    // Temperature
+    let cmp = HaComponent2::new()
+        .name("Temperature".to_string())
+        .device_class("temperature".to_string())
+        .unit_of_measurement("°C".to_string())
+        .state_class("measurement".to_string());
+    disc.add_cmp("temperature".to_string(), cmp);
+
+    // Humidity, only for sensors like the Ruuvi tag
+    if has_humidity {
+        let cmp = HaComponent2::new()
+            .name("Humidity".to_string())
+            .device_class("humidity".to_string())
+            .unit_of_measurement("%".to_string())
+            .state_class("measurement".to_string());
+        disc.add_cmp("humidity".to_string(), cmp);
+    }
+
+    // Air pressure
+    if has_pressure {
+        let cmp = HaComponent2::new()
+            .name("Pressure".to_string())
+            .device_class("pressure".to_string())
+            .unit_of_measurement("hPa".to_string())
+            .state_class("measurement".to_string());
+        disc.add_cmp("pressure".to_string(), cmp);
+    }
+
+    disc
+}
+
+/// Build Home Assistant discovery for a Digital Input
+fn build_digital_input_discovery(
+    devname: &str,
+    instance: u64,
+    productname: &str,
+    input_type: u64,
+) -> HaSensor {
+    let proto = crate::models::DeviceProtocol::Victron.to_string();
+    let device_id = format!("{}_digitalinput_{}", sanitize_id(devname), instance);
+    let (input_name, device_class) = digital_input_kind(input_type);
+
+    let mut disc = HaSensor::new(
+        proto.clone(),
+        device_id.clone(),
+        Some("Victron".to_string()),
+        Some(productname.to_string()),
+    )
+    .device_name(format!("{} {}", input_name, instance))
+    .via(format!("e2m_{}_{}", proto, sanitize_id(devname)));
+
+    // InputState is 1 while the input is active, independent of the configured labels
+    let mut cmp = HaComponent2::new()
+        .name("State".to_string())
+        .platform("binary_sensor".to_string())
+        .del_information("state_class")
+        .add_information("payload_on", Value::from("1"))
+        .add_information("payload_off", Value::from("0"));
+    if let Some(device_class) = device_class {
+        cmp = cmp.device_class(device_class.to_string());
+    }
+    disc.add_cmp("state".to_string(), cmp);
+
+    disc
+}
+
+/// Register a writable topic, Home Assistant commands on the returned topic are written to Victron
+async fn register_write_topic(
+    client: &AsyncClient,
+    data: &Arc<Mutex<VictronData>>,
+    topic: &str,
+    json_key: String,
+    device_id: String,
+) -> String {
+    let proto = crate::models::DeviceProtocol::Victron.to_string();
+    let command_topic = get_command_topic(&proto, &device_id, &json_key);
+
+    register_topic(client, data, topic, json_key, device_id).await;
+    data.lock().await.add_write_topic(command_topic.clone(), topic.to_string());
+
+    command_topic
+}
+
 /// Register topic for reading and JSON key mapping with device tracking
 async fn register_topic(
     client: &AsyncClient,
@@ -465,6 +742,7 @@ pub async fn run_initial_detection(
     let devname = data.lock().await.conf.name.clone();
     let portal_id = utils::get_portal(&data).await;
     let clusters = data.lock().await.conf.clusters.clone();
+    let tenant = data.lock().await.conf.tenant.clone().unwrap_or_default();
 
     info!("{log_prefix} Starting detection for Victron portal {}", portal_id);
 
@@ -607,7 +885,7 @@ pub async fn run_initial_detection(
             // Send Grid Meter discovery
             let disc = build_grid_meter_discovery(&devname, i, &serial, &productname, nr_phases);
             disc.get_disc_topic();
-            let _ = sender.send(Transmission::AutoDiscovery2(disc)).await;
+            let _ = sender.send(Transmission::AutoDiscovery2(disc.meter_ids(tenant.clone(), get_id("victron".to_string(), &meter_device_id)))).await;
         }
 
         if !grid_meter_found {
@@ -720,7 +998,7 @@ pub async fn run_initial_detection(
 
             // Send Battery discovery
             let disc = build_battery_discovery(&devname, b, &manufacturer, &productname, is_pylontech);
-            let _ = sender.send(Transmission::AutoDiscovery2(disc)).await;
+            let _ = sender.send(Transmission::AutoDiscovery2(disc.meter_ids(tenant.clone(), get_id("victron".to_string(), &battery_device_id)))).await;
         }
     }
 
@@ -729,7 +1007,7 @@ pub async fn run_initial_detection(
         info!("{log_prefix} Solar cluster disabled, skipping");
     }
 
-    let pv_charger_count = if clusters.solar.enabled {
+    let pv_tracker_count = if clusters.solar.enabled {
         read_topic_u64(client, data,
             &format!("N/{portal_id}/system/0/Dc/Pv/NumberOfTrackers"),
             "_pv_tracker_count".to_string()).await.unwrap_or(0)
@@ -737,10 +1015,11 @@ pub async fn run_initial_detection(
         0
     };
 
-    if pv_charger_count > 0 {
-        info!("{log_prefix} System has {pv_charger_count} PV trackers");
+    let mut pv_charger_instances: Vec<u64> = Vec::new();
+    if clusters.solar.enabled {
+        info!("{log_prefix} System has {pv_tracker_count} PV trackers");
 
-        // Get list of solar chargers
+        // Get list of solar chargers from the system service
         let chargers_data = utils::read_topic_value(client, data,
             &format!("N/{portal_id}/system/0/Dc/Pv/Chargers"),
             "_pv_chargers".to_string()).await
@@ -748,86 +1027,100 @@ pub async fn run_initial_detection(
 
         if let Some(chargers_array) = chargers_data.as_array() {
             for (c, charger) in chargers_array.iter().enumerate() {
-                let instance = charger.as_u64().unwrap_or(c as u64);
+                pv_charger_instances.push(charger.as_u64().unwrap_or(c as u64));
+            }
+        }
 
-                let base_topic = format!("N/{portal_id}/solarcharger/{instance}");
-                data.lock().await.add_read_topic(format!("{base_topic}/"));
+        // Not every GX firmware publishes the list, so also enumerate the solarcharger service itself
+        for instance in utils::discover_service_instances(client, data, "solarcharger").await {
+            if !pv_charger_instances.contains(&instance) {
+                pv_charger_instances.push(instance);
+            }
+        }
+    }
 
-                let productname = utils::read_topic_string(client, data,
-                    &format!("{base_topic}/ProductName"),
-                    format!("pv_{c}_productname")).await
-                    .unwrap_or("Solar Charger".to_string());
+    let pv_charger_count = pv_charger_instances.len();
+    if pv_charger_count > 0 {
+        info!("{log_prefix} Found {pv_charger_count} solar chargers");
 
-                let nr_trackers = read_topic_u64(client, data,
-                    &format!("{base_topic}/NrOfTrackers"),
-                    format!("pv_{c}_nr_trackers")).await.unwrap_or(1);
+        for (c, instance) in pv_charger_instances.iter().enumerate() {
+            let base_topic = format!("N/{portal_id}/solarcharger/{instance}");
+            data.lock().await.add_read_topic(format!("{base_topic}/"));
 
-                // Build device ID for JSON keys
-                let pv_device_id = format!("{}_pv_{}", sanitize_id(&devname), c);
+            let productname = utils::read_topic_string(client, data,
+                &format!("{base_topic}/ProductName"),
+                format!("pv_{c}_productname")).await
+                .unwrap_or("Solar Charger".to_string());
 
-                // Register PV charger topics
-                register_topic(client, data,
-                    &format!("{base_topic}/Yield/Power"),
-                    "pv_power".to_string(),
-                    pv_device_id.clone()).await;
+            let nr_trackers = read_topic_u64(client, data,
+                &format!("{base_topic}/NrOfTrackers"),
+                format!("pv_{c}_nr_trackers")).await.unwrap_or(1);
 
-                register_topic(client, data,
-                    &format!("{base_topic}/Pv/V"),
-                    "pv_voltage".to_string(),
-                    pv_device_id.clone()).await;
+            // Build device ID for JSON keys
+            let pv_device_id = format!("{}_pv_{}", sanitize_id(&devname), c);
 
-                register_topic(client, data,
-                    &format!("{base_topic}/Pv/I"),
-                    "pv_current".to_string(),
-                    pv_device_id.clone()).await;
+            // Register PV charger topics
+            register_topic(client, data,
+                &format!("{base_topic}/Yield/Power"),
+                "pv_power".to_string(),
+                pv_device_id.clone()).await;
 
-                register_topic(client, data,
-                    &format!("{base_topic}/Yield/User"),
-                    "yield_total".to_string(),
-                    pv_device_id.clone()).await;
+            register_topic(client, data,
+                &format!("{base_topic}/Pv/V"),
+                "pv_voltage".to_string(),
+                pv_device_id.clone()).await;
 
-                register_topic(client, data,
-                    &format!("{base_topic}/History/Daily/0/Yield"),
-                    "yield_today".to_string(),
-                    pv_device_id.clone()).await;
+            register_topic(client, data,
+                &format!("{base_topic}/Pv/I"),
+                "pv_current".to_string(),
+                pv_device_id.clone()).await;
 
-                register_topic(client, data,
-                    &format!("{base_topic}/History/Daily/0/MaxPower"),
-                    "max_power_today".to_string(),
-                    pv_device_id.clone()).await;
+            register_topic(client, data,
+                &format!("{base_topic}/Yield/User"),
+                "yield_total".to_string(),
+                pv_device_id.clone()).await;
 
-                register_topic(client, data,
-                    &format!("{base_topic}/Dc/0/Voltage"),
-                    "battery_voltage".to_string(),
-                    pv_device_id.clone()).await;
+            register_topic(client, data,
+                &format!("{base_topic}/History/Daily/0/Yield"),
+                "yield_today".to_string(),
+                pv_device_id.clone()).await;
+
+            register_topic(client, data,
+                &format!("{base_topic}/History/Daily/0/MaxPower"),
+                "max_power_today".to_string(),
+                pv_device_id.clone()).await;
 
+            register_topic(client, data,
+                &format!("{base_topic}/Dc/0/Voltage"),
+                "battery_voltage".to_string(),
+                pv_device_id.clone()).await;
+
+            register_topic(client, data,
+                &format!("{base_topic}/Dc/0/Current"),
+                "battery_current".to_string(),
+                pv_device_id.clone()).await;
+
+            register_topic(client, data,
+                &format!("{base_topic}/State"),
+                "charger_state".to_string(),
+                pv_device_id.clone()).await;
+
+            // Per-tracker measurements
+            for t in 0..nr_trackers {
                 register_topic(client, data,
-                    &format!("{base_topic}/Dc/0/Current"),
-                    "battery_current".to_string(),
+                    &format!("{base_topic}/Pv/{t}/P"),
+                    format!("pv_power_tracker_{t}"),
                     pv_device_id.clone()).await;
 
                 register_topic(client, data,
-                    &format!("{base_topic}/State"),
-                    "charger_state".to_string(),
+                    &format!("{base_topic}/Pv/{t}/V"),
+                    format!("pv_voltage_tracker_{t}"),
                     pv_device_id.clone()).await;
-
-                // Per-tracker measurements
-                for t in 0..nr_trackers {
-                    register_topic(client, data,
-                        &format!("{base_topic}/Pv/{t}/P"),
-                        format!("pv_power_tracker_{t}"),
-                        pv_device_id.clone()).await;
-
-                    register_topic(client, data,
-                        &format!("{base_topic}/Pv/{t}/V"),
-                        format!("pv_voltage_tracker_{t}"),
-                        pv_device_id.clone()).await;
-                }
-
-                // Send PV Charger discovery
-                let disc = build_pv_charger_discovery(&devname, c as u64, &productname, nr_trackers);
-                let _ = sender.send(Transmission::AutoDiscovery2(disc)).await;
             }
+
+            // Send PV Charger discovery
+            let disc = build_pv_charger_discovery(&devname, c as u64, &productname, nr_trackers);
+            let _ = sender.send(Transmission::AutoDiscovery2(disc.meter_ids(tenant.clone(), get_id("victron".to_string(), &pv_device_id)))).await;
         }
     }
 
@@ -842,6 +1135,79 @@ pub async fn run_initial_detection(
         // This will be published as part of the hub device
     }
 
+    // ========== PV INVERTERS CLUSTER ==========
+    let pv_inverter_instances = if clusters.solar.enabled {
+        utils::discover_service_instances(client, data, "pvinverter").await
+    } else {
+        Vec::new()
+    };
+
+    if !pv_inverter_instances.is_empty() {
+        info!("{log_prefix} Found {} PV inverters", pv_inverter_instances.len());
+    }
+
+    for instance in pv_inverter_instances {
+        let base_topic = format!("N/{portal_id}/pvinverter/{instance}");
+        data.lock().await.add_read_topic(format!("{base_topic}/"));
+
+        let productname = utils::read_topic_string(client, data,
+            &format!("{base_topic}/ProductName"),
+            format!("pvinverter_{instance}_productname")).await
+            .unwrap_or("PV Inverter".to_string());
+
+        let nr_phases = read_topic_u64(client, data,
+            &format!("{base_topic}/Ac/NumberOfPhases"),
+            format!("pvinverter_{instance}_nr_phases")).await.unwrap_or(1);
+
+        info!("{log_prefix} PV inverter {instance} has {nr_phases} phases");
+
+        // Build device ID for JSON keys
+        let pvinverter_device_id = format!("{}_pvinverter_{}", sanitize_id(&devname), instance);
+
+        register_topic(client, data,
+            &format!("{base_topic}/Ac/Power"),
+            "power".to_string(),
+            pvinverter_device_id.clone()).await;
+
+        register_topic(client, data,
+            &format!("{base_topic}/Ac/Energy/Forward"),
+            "yield_total".to_string(),
+            pvinverter_device_id.clone()).await;
+
+        register_topic(client, data,
+            &format!("{base_topic}/StatusCode"),
+            "status_code".to_string(),
+            pvinverter_device_id.clone()).await;
+
+        for p in 1..=nr_phases {
+            let phase_suffix = format!("l{}", p);
+
+            register_topic(client, data,
+                &format!("{base_topic}/Ac/L{p}/Power"),
+                format!("power_{phase_suffix}"),
+                pvinverter_device_id.clone()).await;
+
+            register_topic(client, data,
+                &format!("{base_topic}/Ac/L{p}/Voltage"),
+                format!("voltage_{phase_suffix}"),
+                pvinverter_device_id.clone()).await;
+
+            register_topic(client, data,
+                &format!("{base_topic}/Ac/L{p}/Current"),
+                format!("current_{phase_suffix}"),
+                pvinverter_device_id.clone()).await;
+
+            register_topic(client, data,
+                &format!("{base_topic}/Ac/L{p}/Energy/Forward"),
+                format!("yield_{phase_suffix}"),
+                pvinverter_device_id.clone()).await;
+        }
+
+        // Send PV Inverter discovery
+        let disc = build_pv_inverter_discovery(&devname, instance, &productname, nr_phases);
+        let _ = sender.send(Transmission::AutoDiscovery2(disc.meter_ids(tenant.clone(), get_id("victron".to_string(), &pvinverter_device_id)))).await;
+    }
+
     // ========== VEBUS CLUSTER ==========
     if !clusters.inverter_flow.enabled {
         info!("{log_prefix} Inverter flow cluster disabled, skipping");
@@ -928,10 +1294,195 @@ pub async fn run_initial_detection(
 
         // Send VEBus discovery
         let disc = build_vebus_discovery(&devname, vebus_instance, &productname);
-        let _ = sender.send(Transmission::AutoDiscovery2(disc)).await;
+        let _ = sender.send(Transmission::AutoDiscovery2(disc.meter_ids(tenant.clone(), get_id("victron".to_string(), &vebus_device_id)))).await;
+    }
+
+    // ========== ENVIRONMENT CLUSTER ==========
+    if !clusters.environment.enabled {
+        info!("{log_prefix} Environment cluster disabled, skipping");
+    }
+
+    let (tank_instances, temperature_instances, digital_input_instances) = if clusters.environment.enabled {
+        (utils::discover_service_instances(client, data, "tank").await,
+         utils::discover_service_instances(client, data, "temperature").await,
+         utils::discover_service_instances(client, data, "digitalinput").await)
+    } else {
+        (Vec::new(), Vec::new(), Vec::new())
+    };
+
+    if !tank_instances.is_empty() {
+        info!("{log_prefix} Found {} tanks", tank_instances.len());
     }
 
+    for instance in tank_instances {
+        let base_topic = format!("N/{portal_id}/tank/{instance}");
+        data.lock().await.add_read_topic(format!("{base_topic}/"));
+
+        let productname = utils::read_topic_string(client, data,
+            &format!("{base_topic}/ProductName"),
+            format!("tank_{instance}_productname")).await
+            .unwrap_or("Tank Sensor".to_string());
+
+        let fluid_type = read_topic_u64(client, data,
+            &format!("{base_topic}/FluidType"),
+            format!("_tank_{instance}_fluid_type")).await.unwrap_or(u64::MAX);
 
+        // Build device ID for JSON keys
+        let tank_device_id = format!("{}_tank_{}", sanitize_id(&devname), instance);
+
+        register_topic(client, data,
+            &format!("{base_topic}/Level"),
+            "level".to_string(),
+            tank_device_id.clone()).await;
+
+        register_topic(client, data,
+            &format!("{base_topic}/Remaining"),
+            "remaining".to_string(),
+            tank_device_id.clone()).await;
+
+        register_topic(client, data,
+            &format!("{base_topic}/Capacity"),
+            "capacity".to_string(),
+            tank_device_id.clone()).await;
+
+        // Send Tank discovery
+        let disc = build_tank_discovery(&devname, instance, &productname, fluid_type);
+        let _ = sender.send(Transmission::AutoDiscovery2(disc.meter_ids(tenant.clone(), get_id("victron".to_string(), &tank_device_id)))).await;
+    }
+
+    if !temperature_instances.is_empty() {
+        info!("{log_prefix} Found {} temperature sensors", temperature_instances.len());
+    }
+
+    for instance in temperature_instances {
+        let base_topic = format!("N/{portal_id}/temperature/{instance}");
+        data.lock().await.add_read_topic(format!("{base_topic}/"));
+
+        let productname = utils::read_topic_string(client, data,
+            &format!("{base_topic}/ProductName"),
+            format!("temperature_{instance}_productname")).await
+            .unwrap_or("Temperature Sensor".to_string());
+
+        let temperature_type = read_topic_u64(client, data,
+            &format!("{base_topic}/TemperatureType"),
+            format!("_temperature_{instance}_type")).await.unwrap_or(u64::MAX);
+
+        // Build device ID for JSON keys
+        let temperature_device_id = format!("{}_temperature_{}", sanitize_id(&devname), instance);
+
+        register_topic(client, data,
+            &format!("{base_topic}/Temperature"),
+            "temperature".to_string(),
+            temperature_device_id.clone()).await;
+
+        // Humidity and pressure are only published by some sensors, a null value means not supported
+        let mut has_humidity = false;
+        let humidity_topic = format!("{base_topic}/Humidity");
+        let humidity = utils::read_topic_value(client, data, &humidity_topic,
+            format!("_temperature_{instance}_humidity")).await;
+        if humidity.is_some_and(|v| !v.is_null()) {
+            has_humidity = true;
+            register_topic(client, data, &humidity_topic, "humidity".to_string(), temperature_device_id.clone()).await;
+        }
+
+        let mut has_pressure = false;
+        let pressure_topic = format!("{base_topic}/Pressure");
+        let pressure = utils::read_topic_value(client, data, &pressure_topic,
+            format!("_temperature_{instance}_pressure")).await;
+        if pressure.is_some_and(|v| !v.is_null()) {
+            has_pressure = true;
+            register_topic(client, data, &pressure_topic, "pressure".to_string(), temperature_device_id.clone()).await;
+        }
+
+        // Send Temperature discovery
+        let disc = build_temperature_discovery(&devname, instance, &productname, temperature_type, has_humidity, has_pressure);
+        let _ = sender.send(Transmission::AutoDiscovery2(disc.meter_ids(tenant.clone(), get_id("victron".to_string(), &temperature_device_id)))).await;
+    }
+
+    if !digital_input_instances.is_empty() {
+        info!("{log_prefix} Found {} digital inputs", digital_input_instances.len());
+    }
+
+    for instance in digital_input_instances {
+        let base_topic = format!("N/{portal_id}/digitalinput/{instance}");
+        data.lock().await.add_read_topic(format!("{base_topic}/"));
+
+        let productname = utils::read_topic_string(client, data,
+            &format!("{base_topic}/ProductName"),
+            format!("digitalinput_{instance}_productname")).await
+            .unwrap_or("Digital Input".to_string());
+
+        let input_type = read_topic_u64(client, data,
+            &format!("{base_topic}/Type"),
+            format!("_digitalinput_{instance}_type")).await.unwrap_or(0);
+
+        // Build device ID for JSON keys
+        let digital_input_device_id = format!("{}_digitalinput_{}", sanitize_id(&devname), instance);
+
+        register_topic(client, data,
+            &format!("{base_topic}/InputState"),
+            "state".to_string(),
+            digital_input_device_id.clone()).await;
+
+        // Send Digital Input discovery
+        let disc = build_digital_input_discovery(&devname, instance, &productname, input_type);
+        let _ = sender.send(Transmission::AutoDiscovery2(disc.meter_ids(tenant.clone(), get_id("victron".to_string(), &digital_input_device_id)))).await;
+    }
+
+    // ========== CONTROL SETPOINTS ==========
+    let hub_device_id = sanitize_id(&devname);
+
+    // ESS grid setpoint, only present if ESS is installed
+    let setpoint_topic = format!("N/{portal_id}/settings/0/Settings/CGwacs/AcPowerSetPoint");
+    let setpoint = utils::read_topic_value(client, data, &setpoint_topic, "_ess_setpoint".to_string()).await;
+
+    if setpoint.is_some() {
+        info!("{log_prefix} ESS found, grid setpoint is writable");
+        data.lock().await.add_read_topic(format!("N/{portal_id}/settings/0/Settings/CGwacs/"));
+
+        let cmd_topic = register_write_topic(client, data, &setpoint_topic,
+            "ess_grid_setpoint".to_string(), hub_device_id.clone()).await;
+
+        let cmp = HaComponent2::new()
+            .name("ESS Grid Setpoint".to_string())
+            .platform("number".to_string())
+            .device_class("power".to_string())
+            .unit_of_measurement("W".to_string())
+            .del_information("state_class")
+            .add_information("command_topic", Value::from(cmd_topic))
+            .add_information("min", Value::from(-32000))
+            .add_information("max", Value::from(32000))
+            .add_information("step", Value::from(10))
+            .add_information("mode", Value::from("box"));
+        hub_disc.add_cmp("ess_grid_setpoint".to_string(), cmp);
+    }
+
+    // GX relays, only the ones reporting a state are exposed
+    for r in 0..2 {
+        let relay_topic = format!("N/{portal_id}/system/0/Relay/{r}/State");
+        let state = read_topic_u64(client, data, &relay_topic, format!("_relay_{r}_state")).await;
+
+        if state.is_none() {
+            continue;
+        }
+
+        info!("{log_prefix} Relay {r} is writable");
+        data.lock().await.add_read_topic(format!("N/{portal_id}/system/0/Relay/{r}/"));
+
+        let cmd_topic = register_write_topic(client, data, &relay_topic,
+            format!("relay_{r}"), hub_device_id.clone()).await;
+
+        let cmp = HaComponent2::new()
+            .name(format!("Relay {}", r + 1))
+            .platform("switch".to_string())
+            .del_information("state_class")
+            .add_information("command_topic", Value::from(cmd_topic))
+            .add_information("payload_on", Value::from("ON"))
+            .add_information("payload_off", Value::from("OFF"))
+            .add_information("state_on", Value::from("1"))
+            .add_information("state_off", Value::from("0"));
+        hub_disc.add_cmp(format!("relay_{r}"), cmp);
+    }
 
     /* A parent device needs to have at least one information otherwise Home Assistant will add it as "unnamed device" */
     let cmp = HaComponent2::new()
@@ -940,8 +1491,40 @@ pub async fn run_initial_detection(
         
     hub_disc.add_cmp("portal_id".to_string(), cmp);
 
-    let _ = sender.send(Transmission::AutoDiscovery2(hub_disc)).await;
+    let _ = sender.send(Transmission::AutoDiscovery2(hub_disc.meter_ids(tenant, get_id("victron".to_string(), &hub_device_id)))).await;
 
     info!("{log_prefix} Detection completed for Victron portal {}", portal_id);
     true
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_environment_discoveries() {
+        let disc = build_tank_discovery("Cerbo GX", 20, "Tank sensor", 1);
+        let entities = disc.get_entity_discoveries();
+        assert_eq!(entities.len(), 3);
+        assert_eq!(entities[0].payload["device"]["name"], "Fresh Water Tank 20");
+        assert_eq!(entities[1].payload["device_class"], "volume_storage");
+
+        let disc = build_temperature_discovery("Cerbo GX", 24, "Ruuvi", 4, true, false);
+        let entities = disc.get_entity_discoveries();
+        assert_eq!(entities.len(), 2);
+        assert_eq!(entities[0].payload["device"]["name"], "Outdoor Sensor 24");
+        assert_eq!(entities[1].payload["device_class"], "humidity");
+
+        let disc = build_digital_input_discovery("Cerbo GX", 1, "Digital input", 2);
+        let entities = disc.get_entity_discoveries();
+        assert_eq!(entities.len(), 1);
+        assert!(entities[0].topic.starts_with("homeassistant/binary_sensor/e2m_victron_cerbo_gx_digitalinput_1/"));
+        assert_eq!(entities[0].payload["device_class"], "door");
+        assert_eq!(entities[0].payload["payload_on"], "1");
+        assert!(entities[0].payload.get("state_class").is_none());
+
+        /* Unknown input types still get a binary sensor, only without device class */
+        let disc = build_digital_input_discovery("Cerbo GX", 2, "Digital input", 42);
+        assert!(disc.get_entity_discoveries()[0].payload.get("device_class").is_none());
+    }
+}
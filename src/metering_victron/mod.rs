@@ -4,7 +4,7 @@ use tokio::task::JoinHandle;
 use tokio::time::sleep;
 use crate::config::{ConfigChange, ConfigOperation, VictronConfig};
 use crate::models::DeviceProtocol;
-use crate::mqtt::{publish_protocol_count, Transmission};
+use crate::mqtt::{publish_protocol_count, MeterErrorData, SubscribeData, Transmission};
 use crate::config::ConfigBases;
 use crate::{get_config_or_panic, get_id, get_unix_ts, MeteringData, CONFIG};
 use log::{debug, error, info};
@@ -18,6 +18,11 @@ use std::time::{self, Duration};
 pub mod utils;
 pub mod detect;
 
+/// Seconds without any updated topic before the GX device is considered gone, see stale_seconds
+const VICTRON_STALE_SECONDS: u64 = 180;
+/// Lower bound for the delay between read requests, anything below floods the GX device
+const VICTRON_MIN_READ_DELAY_MS: u64 = 10;
+
 /// Cluster identifier for filtering exports
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum VictronCluster {
@@ -106,6 +111,10 @@ pub struct VictronData {
     pub topic_mapping: HashMap<String, Option<Topic>>,
     /// Maps topic to its cluster for filtering
     pub topic_clusters: HashMap<String, VictronCluster>,
+    /// Device instances seen per service (e.g. "pvinverter" -> [20, 21])
+    pub service_instances: HashMap<String, Vec<u64>>,
+    /// Maps energy2mqtt command topics to the writable Victron N/ topic
+    pub write_topics: HashMap<String, String>,
     pub conf: VictronConfig,
 }
 
@@ -116,6 +125,8 @@ impl VictronData {
             read_topics: Vec::new(),
             topic_mapping: HashMap::new(),
             topic_clusters: HashMap::new(),
+            service_instances: HashMap::new(),
+            write_topics: HashMap::new(),
             conf: conf.clone(),
         };
     }
@@ -151,6 +162,14 @@ impl VictronData {
         self.portal_id = portal;
     }
 
+    /// Forget the portal and everything detected for it, detection starts over once the portal is known again
+    pub fn reset_detection(&mut self) {
+        self.portal_id.clear();
+        self.read_topics.clear();
+        self.topic_mapping.clear();
+        self.write_topics.clear();
+    }
+
     pub fn add_read_topic(&mut self, topic: String) {
         let topic = topic.replacen("N/", "R/",1);
         if self.read_topics.contains(&topic) {
@@ -159,6 +178,26 @@ impl VictronData {
 
         self.read_topics.push(topic);
     }
+
+    pub fn add_write_topic(&mut self, command_topic: String, victron_topic: String) {
+        self.write_topics.insert(command_topic, victron_topic);
+    }
+
+    /// Remember a device instance announced via N/{portal}/{service}/{instance}/DeviceInstance
+    pub fn add_service_instance(&mut self, service: &str, instance: u64) {
+        let instances = self.service_instances.entry(service.to_string()).or_default();
+        if !instances.contains(&instance) {
+            instances.push(instance);
+        }
+    }
+}
+
+/// Multiply a numeric value with the configured factor of its field
+fn scale_value(val: Value, factor: Option<&f64>) -> Value {
+    match (factor, val.as_f64()) {
+        (Some(factor), Some(f)) => serde_json::Number::from_f64(f * factor).map(Value::Number).unwrap_or(val),
+        _ => val,
+    }
 }
 
 /// Round a JSON value to 3 decimal places if it's a float
@@ -178,6 +217,35 @@ fn round_value(val: Value) -> Value {
     }
 }
 
+/// Time between two read cycles and data processing runs, at least one second
+fn update_interval(conf: &VictronConfig) -> Duration {
+    Duration::from_secs(std::cmp::max(conf.update_interval, 1))
+}
+
+/// Seconds without any updated topic before detection starts over, slow update intervals get three cycles
+fn stale_seconds(conf: &VictronConfig) -> u64 {
+    std::cmp::max(VICTRON_STALE_SECONDS, conf.update_interval.saturating_mul(3))
+}
+
+/// Delay between two read requests, the whole cycle has to fit into the update interval
+fn read_delay(conf: &VictronConfig, topic_count: usize) -> Duration {
+    let mut delay_ms = conf.read_delay_ms;
+
+    if topic_count > 0 {
+        let budget_ms = conf.update_interval * 1000 / topic_count as u64;
+        delay_ms = std::cmp::min(delay_ms, budget_ms);
+    }
+
+    Duration::from_millis(std::cmp::max(delay_ms, VICTRON_MIN_READ_DELAY_MS))
+}
+
+/// Connection options of a GX device without TLS, the GX needs no credentials
+pub fn client_options(client_name: &str, host: &str, port: u16) -> MqttOptions {
+    let mut mqttoptions = MqttOptions::new(client_name, host, port);
+    mqttoptions.set_keep_alive(Duration::from_secs(5));
+    mqttoptions
+}
+
 impl VictronManager {
     pub fn new(sender: Sender<Transmission>) -> Self {
         let config: Vec<VictronConfig> = get_config_or_panic!("victron", ConfigBases::Victron);
@@ -220,12 +288,17 @@ impl VictronManager {
                 device_count += 1;
                 info!("Starting MQTT connection to {}:{}", conf.broker_host, conf.broker_port);
 
-                let mut mqttoptions   = MqttOptions::new(
-                                                    conf.client_name.clone(),
-                                                    conf.broker_host.clone(),
-                                                    conf.broker_port);
-
-                mqttoptions.set_keep_alive(Duration::from_secs(5));
+                let mut mqttoptions = client_options(&conf.client_name, &conf.broker_host, conf.broker_port);
+                /* Never fall back to plaintext, the credentials would be sent in the clear */
+                if let Err(e) = crate::mqtt::tls::configure_tls(&mut mqttoptions, &conf.tls) {
+                    error!("TLS setup for {}:{} failed, not connecting: {}", conf.broker_host, conf.broker_port, e);
+                    let _ = self.sender.send(Transmission::MeterError(MeterErrorData {
+                        protocol: DeviceProtocol::Victron.to_string(),
+                        meter_name: conf.name.clone(),
+                        error: format!("TLS setup failed: {e}"),
+                    })).await;
+                    continue;
+                }
 
                 let (client, mut eventloop) = AsyncClient::new(mqttoptions, 10);
                 let reconnect_c = client.clone();
@@ -242,12 +315,14 @@ impl VictronManager {
                     info!("[{host}:{port}] MQTT Eventloop starting ...");
 
                     let mut last_error = String::new();
-                    let mut counter = 0;
+                    let mut backoff = crate::mqtt::backoff::Backoff::from_config();
                     loop {
                         match eventloop.poll().await {
                             Ok(Event::Incoming(Packet::Publish(p))) => {
                                 let topic = p.topic;
-                                let payload = String::from_utf8(p.payload.to_vec()).unwrap();
+                                let Some(payload) = crate::mqtt::payload_to_string(&topic, &p.payload) else {
+                                    continue;
+                                };
 
                                 /* Victron resets data if not read again, make sure to igonore it! */
                                 if payload == "" {
@@ -266,6 +341,17 @@ impl VictronManager {
                                 }
 
                                 let mut data = data_clone.lock().await;
+
+                                if topic.ends_with("/DeviceInstance") {
+                                    /* Service enumeration: N/{portal}/{service}/{instance}/DeviceInstance */
+                                    let parts: Vec<&str> = topic.split("/").collect();
+                                    if parts.len() == 5 {
+                                        if let Ok(instance) = parts[3].parse::<u64>() {
+                                            data.add_service_instance(parts[2], instance);
+                                        }
+                                    }
+                                }
+
                                 if data.topic_mapping.contains_key(&topic) {
                                         let tdata = match data.topic_mapping.get(&topic).unwrap() {
                                             Some(old) => { Topic::create_from(old, payload.clone())},
@@ -282,6 +368,8 @@ impl VictronManager {
                             },
                             Ok(Event::Incoming(Packet::ConnAck(_))) => {
                                 info!("[{host}:{port}] Connected, resubscribing everything");
+                                backoff.reset();
+                                last_error.clear();
                                 let _ = reconnect_c.subscribe("N/+/system/0/Serial",rumqttc::QoS::AtLeastOnce).await;
                                 loop {
                                     match data_clone.try_lock() {
@@ -306,17 +394,15 @@ impl VictronManager {
                             },
                             Ok(_) => {},
                             Err(e) => {
-                                if e.to_string() == last_error {
-                                    /* Rate limting */
-                                    counter += 1;
-                                    if counter < 100_000 {
-                                        continue;
-                                    }
+                                let delay = backoff.next_delay();
+
+                                /* The same error on every attempt is only logged once */
+                                if e.to_string() != last_error {
+                                    error!("[{host}:{port}] Error in MQTT {:?}, retrying in {:?}", e, delay);
+                                    last_error = e.to_string();
                                 }
 
-                                counter = 0;
-                                error!("[{host}:{port}] Error in MQTT {:?}", e);
-                                last_error = e.to_string();
+                                sleep(delay).await;
                             }
                         }
                     }
@@ -326,11 +412,11 @@ impl VictronManager {
 
                 let data_clone = data.clone();
                 let client_clone = client.clone();
+                let interval = update_interval(conf);
                 handle = tokio::spawn(async move {
-                    let duration = Duration::from_secs(60);
                     loop {
-                        /* Every minute we will issue a read request if any of our data is older than 60 seconds */
-                        sleep(duration).await;
+                        /* Every update interval we will issue a read request if any of our data is older than the interval */
+                        sleep(interval).await;
 
                         let now = get_unix_ts();
                         let mut need_read = false;
@@ -342,7 +428,7 @@ impl VictronManager {
                                 if t.device_id.is_empty() || t.json_key.starts_with("_") {
                                     continue; /* Only for the data used in the devices */
                                 }
-                                if t.updated < now - 60 {
+                                if t.updated + interval.as_secs() < now {
                                     /* For the first outdated value to be found we exit */
                                     need_read = true;
                                     break;
@@ -353,11 +439,14 @@ impl VictronManager {
 
                         if need_read {
                             let read_topics = data_clone.lock().await.read_topics.clone();
+                            let conf = data_clone.lock().await.conf.clone();
+                            let delay = read_delay(&conf, read_topics.len());
 
                             for topic in read_topics {
                                 debug!("Sending read request for {topic}");
                                 let _ = client_clone.publish(topic, 
                                             rumqttc::QoS::AtLeastOnce, false, "").await;
+                                sleep(delay).await;
                             }
                         }
                     }
@@ -388,11 +477,36 @@ impl VictronManager {
                         }
 
                         let _ = detect::run_initial_detection(&client, &data, &send_dupe, format!("[{host}:{port}]")).await;
+                        /* Topics not updated since the detection count from its end */
+                        let detected_at = get_unix_ts();
                         let mut read_out = true;
 
+                        /* Register the command topics found during detection, Home Assistant writes to them */
+                        let (cmd_sender, mut cmd_receiver) = tokio::sync::mpsc::channel(10);
+                        let write_topics = data.lock().await.write_topics.clone();
+                        for topic in write_topics.keys() {
+                            let _ = send_dupe.send(Transmission::Subscribe(SubscribeData {
+                                                        topic: topic.clone(),
+                                                        sender: cmd_sender.clone() })).await;
+                        }
+
                         loop {
-                            /* Trigger reading, but copy the list because we are not allowed to keep the lock */
-                            sleep(Duration::from_secs(5u64)).await;
+                            tokio::select! {
+                                /* Trigger reading, but copy the list because we are not allowed to keep the lock */
+                                _ = sleep(interval) => {},
+                                /* We got a write command, publish it on the GX broker and wait for the next tick */
+                                Some((topic, payload)) = cmd_receiver.recv() => {
+                                    match write_topics.get(&topic) {
+                                        Some(victron_topic) => {
+                                            utils::write_topic(&client, victron_topic, &payload, format!("[{host}:{port}]")).await;
+                                        },
+                                        None => {
+                                            error!("[{host}:{port}] Received command for unknown topic {topic}");
+                                        }
+                                    }
+                                    continue;
+                                }
+                            }
 
                             let config = data.lock().await.conf.clone();
                             let topics = data.lock().await.topic_mapping.clone();
@@ -400,6 +514,7 @@ impl VictronManager {
 
                             // Group topics by device_id
                             let mut device_data: HashMap<String, HashMap<String, Value>> = HashMap::new();
+                            let mut newest_update = detected_at;
 
                             for entry in topics.iter() {
                                 let tname = entry.0.clone();
@@ -415,6 +530,8 @@ impl VictronManager {
                                 /* skip internal json data */
                                 if tdata.json_key.starts_with("_") { continue; }
 
+                                newest_update = std::cmp::max(newest_update, tdata.updated);
+
                                 /* Skip topics without a device_id (hub-level data) */
                                 let device_id = if tdata.device_id.is_empty() {
                                     config.name.clone().to_lowercase().replace(" ", "_").replace("-", "_")
@@ -431,10 +548,11 @@ impl VictronManager {
                                     Ok(v) => {
                                         match v.get("value") {
                                             Some(val) => {
+                                                let val = scale_value(val.clone(), config.scale.get(&tdata.json_key));
                                                 device_data
                                                     .entry(device_id)
                                                     .or_insert_with(HashMap::new)
-                                                    .insert(tdata.json_key, round_value(val.clone()));
+                                                    .insert(tdata.json_key, round_value(val));
                                             }
                                             None => { info!("[{host}:{port} {tname}] Malformed JSON found") }
                                         }
@@ -450,6 +568,7 @@ impl VictronManager {
                                 meter_data.meter_name = device_id.clone();
                                 meter_data.protocol = DeviceProtocol::Victron;
                                 meter_data.id = get_id("victron".to_string(), &device_id);
+                                meter_data.tenant = config.tenant.clone().unwrap_or_default();
                                 meter_data.transmission_time = timestamp;
                                 meter_data.metered_time = timestamp;
 
@@ -460,6 +579,19 @@ impl VictronManager {
 
                                 let _ = send_dupe.send(Transmission::Metering(meter_data)).await;
                             }
+
+                            /* A rebooted GX device or broker leaves us with a topic map nobody updates anymore */
+                            if newest_update + stale_seconds(&config) < timestamp {
+                                error!("[{host}:{port}] No Victron data received for {} seconds, restarting detection",
+                                        timestamp - newest_update);
+
+                                /* Forget the portal and ask for it again, detection will run once it is known */
+                                data.lock().await.reset_detection();
+                                let _ = client.subscribe("N/+/system/0/Serial", rumqttc::QoS::AtLeastOnce).await;
+                                let _ = client.publish(format!("R/{portal_id}/keepalive"),
+                                            rumqttc::QoS::AtLeastOnce, false, "").await;
+                                break;
+                            }
                         }
                     }
                 });
@@ -491,3 +623,59 @@ impl VictronManager {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conf(update_interval: u64, read_delay_ms: u64) -> VictronConfig {
+        let yaml = format!("name: gx\nbroker_host: localhost\nupdate_interval: {update_interval}\nread_delay_ms: {read_delay_ms}\n");
+        serde_yml::from_str(&yaml).unwrap()
+    }
+
+    #[test]
+    fn test_read_delay() {
+        // Small installations use the configured delay
+        assert_eq!(read_delay(&conf(10, 100), 5), Duration::from_millis(100));
+        // Large installations are squeezed into the update interval
+        assert_eq!(read_delay(&conf(10, 500), 40), Duration::from_millis(250));
+        // But never below the minimum
+        assert_eq!(read_delay(&conf(1, 100), 1000), Duration::from_millis(VICTRON_MIN_READ_DELAY_MS));
+        assert_eq!(read_delay(&conf(10, 0), 0), Duration::from_millis(VICTRON_MIN_READ_DELAY_MS));
+    }
+
+    #[test]
+    fn test_scale_value() {
+        assert_eq!(scale_value(Value::from(1.2345), Some(&1000.0)), Value::from(1234.5));
+        assert_eq!(round_value(scale_value(Value::from(3), Some(&0.1))), Value::from(0.3));
+        assert_eq!(scale_value(Value::from(1.5), None), Value::from(1.5));
+        assert_eq!(scale_value(Value::from("Bulk"), Some(&10.0)), Value::from("Bulk"));
+    }
+
+    #[test]
+    fn test_update_interval() {
+        assert_eq!(update_interval(&conf(30, 100)), Duration::from_secs(30));
+        // Zero would poll the GX device in a tight loop
+        assert_eq!(update_interval(&conf(0, 100)), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_reset_detection() {
+        let mut data = VictronData::new(&conf(5, 100));
+        data.set_portal("c0619ab1234".to_string());
+        data.topic_mapping.insert("N/c0619ab1234/grid/30/Ac/Power".to_string(), None);
+        data.add_read_topic("N/c0619ab1234/grid/30/Ac/Power".to_string());
+        data.add_write_topic("energy2mqtt/cmd/victron/gx/ess_mode".to_string(), "N/c0619ab1234/settings/0/Mode".to_string());
+
+        data.reset_detection();
+        assert!(data.portal_id.is_empty());
+        assert!(data.topic_mapping.is_empty() && data.read_topics.is_empty() && data.write_topics.is_empty());
+    }
+
+    #[test]
+    fn test_stale_seconds() {
+        assert_eq!(stale_seconds(&conf(5, 100)), 180);
+        assert_eq!(stale_seconds(&conf(60, 100)), 180);
+        assert_eq!(stale_seconds(&conf(300, 100)), 900);
+    }
+}
@@ -1,5 +1,5 @@
 use std::{sync::Arc, time::Duration};
-use log::debug;
+use log::{debug, error, info};
 use rumqttc::AsyncClient;
 use serde_json::Value;
 use tokio::{sync::Mutex, time::sleep};
@@ -57,16 +57,13 @@ pub async fn get_topic(data: &Arc<Mutex<VictronData>>, key: &String) -> Option<T
     return Some(d);
 }
 
+/// Value of a Victron payload as counter, fractional values are rounded instead of being dropped
 pub fn victron_value_to_u64(value: &String, default: u64) -> u64 {
-    let doc = serde_json::from_str::<Value>(value);
-    match doc {
-        Err(_) => { return default; }
-        Ok(v) => {
-            match v.get("value") {
-                Some(v) => { return v.as_u64().unwrap_or(default); }
-                None => { return default; }
-            }
-        }
+    match victron_value_to_value(value, Value::Null) {
+        Value::Number(n) => n.as_u64()
+            .or_else(|| n.as_f64().filter(|f| *f >= 0.0).map(|f| f.round() as u64))
+            .unwrap_or(default),
+        _ => default,
     }
 }
 
@@ -96,103 +93,49 @@ pub fn victron_value_to_value(value: &String, default: Value) -> Value {
     }
 }
 
-pub async fn read_topic_u64(client: &AsyncClient, data: &Arc<Mutex<VictronData>>, topic: &String, json_key: String) -> Option<u64> {
-
-    let t = Topic::new_with_key("".to_string(), json_key);
-    set_topic(client, data, &topic, Some(t)).await;
+/// Request a topic from the GX device and wait up to five seconds for its payload
+async fn read_topic_payload(
+    client: &AsyncClient,
+    data: &Arc<Mutex<VictronData>>,
+    topic: &String,
+    json_key: String,
+    cluster: Option<VictronCluster>,
+) -> Option<String> {
+    let t = Topic::new_with_key("".to_string(), json_key.clone());
+    match cluster {
+        Some(cluster) => set_topic_with_cluster(client, data, topic, Some(t), &json_key, cluster).await,
+        None => set_topic(client, data, topic, Some(t)).await,
+    }
 
-    let _ = client.publish(topic.clone().replacen("N", "R", 1), 
+    let _ = client.publish(topic.clone().replacen("N", "R", 1),
                             rumqttc::QoS::AtLeastOnce, false, "").await;
 
-    /* We wait up to five second */
-    let mut res: Option<Topic> = None;
     for _ in 0..=500 {
         sleep(Duration::from_millis(10)).await;
-        res = get_topic(data, &topic).await;
-        if res.is_some() {
-            if res.clone().unwrap().payload != "" {
-                break;
+        if let Some(res) = get_topic(data, topic).await {
+            if !res.payload.is_empty() {
+                return Some(res.payload);
             }
         }
     }
 
-    if res.is_none() {
-        return None;
-    }
-
-    let topic_data = res.clone().unwrap();
-    if topic_data.payload == "" {
-        debug!("{topic} No payload found, so returning default ");
-        return None;
-    }
+    debug!("{topic} No payload found, so returning default ");
+    None
+}
 
-    return Some(victron_value_to_u64(&topic_data.payload, 0));
+pub async fn read_topic_u64(client: &AsyncClient, data: &Arc<Mutex<VictronData>>, topic: &String, json_key: String) -> Option<u64> {
+    read_topic_payload(client, data, topic, json_key, None).await
+        .map(|payload| victron_value_to_u64(&payload, 0))
 }
 
 pub async fn read_topic_string(client: &AsyncClient, data: &Arc<Mutex<VictronData>>, topic: &String, json_key: String) -> Option<String> {
-
-    let t = Topic::new_with_key("".to_string(), json_key);
-    set_topic(client, data, &topic, Some(t)).await;
-
-    let _ = client.publish(topic.clone().replacen("N", "R", 1), 
-                            rumqttc::QoS::AtLeastOnce, false, "").await;
-
-    /* We wait up to five second */
-    let mut res: Option<Topic> = None;
-    for _ in 0..=500 {
-        sleep(Duration::from_millis(10)).await;
-        res = get_topic(data, &topic).await;
-        if res.is_some() {
-            if res.clone().unwrap().payload != "" {
-                break;
-            }
-        }
-    }
-
-    if res.is_none() {
-        return None;
-    }
-
-    let topic_data = res.clone().unwrap();
-    if topic_data.payload == "" {
-        debug!("{topic} No payload found, so returning default ");
-        return None;
-    }
-
-    return Some(victron_value_to_string(&topic_data.payload, ""));
+    read_topic_payload(client, data, topic, json_key, None).await
+        .map(|payload| victron_value_to_string(&payload, ""))
 }
 
 pub async fn read_topic_value(client: &AsyncClient, data: &Arc<Mutex<VictronData>>, topic: &String, json_key: String) -> Option<Value> {
-
-    let t = Topic::new_with_key("".to_string(), json_key);
-    set_topic(client, data, &topic, Some(t)).await;
-
-    let _ = client.publish(topic.clone().replacen("N", "R", 1),
-                            rumqttc::QoS::AtLeastOnce, false, "").await;
-
-    /* We wait up to five second */
-    let mut res: Option<Topic> = None;
-    for _ in 0..=500 {
-        sleep(Duration::from_millis(10)).await;
-        res = get_topic(data, &topic).await;
-        if res.is_some() {
-            if res.clone().unwrap().payload != "" {
-                break;
-            }
-        }
-    }
-
-    if res.is_none() {
-        return None;
-    }
-
-    let topic_data = res.clone().unwrap();
-    if topic_data.payload == "" {
-        debug!("{topic} No payload found, so returning default ");
-        return None;
-    }
-
-    return Some(victron_value_to_value(&topic_data.payload, Value::Null));
+    read_topic_payload(client, data, topic, json_key, None).await
+        .map(|payload| victron_value_to_value(&payload, Value::Null))
 }
 
 /// Read a u64 topic and register it with a cluster
@@ -203,32 +146,96 @@ pub async fn read_topic_u64_cluster(
     json_key: String,
     cluster: VictronCluster,
 ) -> Option<u64> {
-    let t = Topic::new_with_key("".to_string(), json_key.clone());
-    set_topic_with_cluster(client, data, &topic, Some(t), &json_key, cluster).await;
+    read_topic_payload(client, data, topic, json_key, Some(cluster)).await
+        .map(|payload| victron_value_to_u64(&payload, 0))
+}
 
-    let _ = client.publish(topic.clone().replacen("N", "R", 1),
+/// Enumerate the device instances of a Venus service (e.g. "pvinverter" or "solarcharger")
+///
+/// Subscribes to the DeviceInstance topic of every instance and asks the GX device for a
+/// full republish via keepalive. The eventloop records every instance it sees.
+pub async fn discover_service_instances(client: &AsyncClient, data: &Arc<Mutex<VictronData>>, service: &str) -> Vec<u64> {
+    let portal_id = get_portal(data).await;
+
+    let _ = client.subscribe(format!("N/{portal_id}/{service}/+/DeviceInstance"),
+                            rumqttc::QoS::AtLeastOnce).await;
+    let _ = client.publish(format!("R/{portal_id}/keepalive"),
                             rumqttc::QoS::AtLeastOnce, false, "").await;
 
-    let mut res: Option<Topic> = None;
+    /* We wait up to five second for the first instance and give the others one more second */
     for _ in 0..=500 {
         sleep(Duration::from_millis(10)).await;
-        res = get_topic(data, &topic).await;
-        if res.is_some() {
-            if res.clone().unwrap().payload != "" {
-                break;
-            }
+        if data.lock().await.service_instances.contains_key(service) {
+            sleep(Duration::from_secs(1)).await;
+            break;
         }
     }
 
-    if res.is_none() {
-        return None;
+    let mut instances = data.lock().await.service_instances.get(service).cloned().unwrap_or_default();
+    instances.sort();
+    instances
+}
+
+/// Translate a Home Assistant command payload into the value Victron expects
+///
+/// Switches send ON/OFF, numbers send their value as plain text.
+pub fn command_payload_to_value(payload: &str) -> Option<Value> {
+    let payload = payload.trim();
+    match payload.to_uppercase().as_str() {
+        "ON" | "TRUE" => return Some(Value::from(1)),
+        "OFF" | "FALSE" => return Some(Value::from(0)),
+        _ => {}
     }
 
-    let topic_data = res.clone().unwrap();
-    if topic_data.payload == "" {
-        debug!("{topic} No payload found, so returning default ");
-        return None;
+    if let Ok(v) = payload.parse::<i64>() {
+        return Some(Value::from(v));
     }
 
-    return Some(victron_value_to_u64(&topic_data.payload, 0));
+    match payload.parse::<f64>() {
+        Ok(v) if v.is_finite() => Some(Value::from(v)),
+        _ => None,
+    }
+}
+
+/// Publish a write request (W/...) for a Victron N/ topic
+pub async fn write_topic(client: &AsyncClient, topic: &String, payload: &str, log_prefix: String) {
+    let value = match command_payload_to_value(payload) {
+        Some(v) => v,
+        None => {
+            error!("{log_prefix} Invalid command payload for {topic}: {payload}");
+            return;
+        }
+    };
+
+    let write_topic = topic.replacen("N", "W", 1);
+    let body = serde_json::json!({ "value": value }).to_string();
+
+    info!("{log_prefix} Writing {body} to {write_topic}");
+    if let Err(e) = client.publish(write_topic, rumqttc::QoS::AtLeastOnce, false, body).await {
+        error!("{log_prefix} Failed to write {topic}: {:?}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_victron_values() {
+        let payload = |v: &str| format!("{{\"value\": {v}}}");
+        assert_eq!(victron_value_to_u64(&payload("3"), 0), 3);
+        assert_eq!(victron_value_to_u64(&payload("3.0"), 0), 3);
+        assert_eq!(victron_value_to_u64(&payload("-1"), 7), 7);
+        assert_eq!(victron_value_to_u64(&"garbage".to_string(), 7), 7);
+    }
+
+    #[test]
+    fn test_command_payload_to_value() {
+        assert_eq!(command_payload_to_value("ON"), Some(Value::from(1)));
+        assert_eq!(command_payload_to_value("off"), Some(Value::from(0)));
+        assert_eq!(command_payload_to_value("-1500"), Some(Value::from(-1500)));
+        assert_eq!(command_payload_to_value(" 250.5 "), Some(Value::from(250.5)));
+        assert_eq!(command_payload_to_value("garbage"), None);
+        assert_eq!(command_payload_to_value("NaN"), None);
+    }
 }
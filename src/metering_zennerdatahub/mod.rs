@@ -160,7 +160,9 @@ impl ZennerDatahubManager {
                         match eventloop.poll().await {
                             Ok(Event::Incoming(Packet::Publish(p))) => {
                                 let topic = p.topic;
-                                let payload = String::from_utf8(p.payload.to_vec()).unwrap();
+                                let Some(payload) = crate::mqtt::payload_to_string(&topic, &p.payload) else {
+                                    continue;
+                                };
 
                                 debug!("[{name_clone} {host}:{port}] Received {topic} -> {payload:?}");
 
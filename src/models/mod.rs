@@ -88,6 +88,7 @@ pub enum DeviceProtocol {
     Victron,
     KNX,
     ZennerDatahub,
+    MqttInput,
 }
 
 impl Display for DeviceProtocol {
@@ -106,6 +107,7 @@ impl Display for DeviceProtocol {
             DeviceProtocol::Victron => "Victron".to_string(),
             DeviceProtocol::KNX => "KNX".to_string(),
             DeviceProtocol::ZennerDatahub => "zridh".to_string(),
+            DeviceProtocol::MqttInput => "mqtt_input".to_string(),
         })
     }
 }
@@ -125,6 +127,7 @@ impl DeviceProtocol {
             "Victron" => Some(DeviceProtocol::Victron),
             "KNX" => Some(DeviceProtocol::KNX),
             "ZENNER Datahub" => Some(DeviceProtocol::ZennerDatahub),
+            "MQTT Input" => Some(DeviceProtocol::MqttInput),
             _ => Some(DeviceProtocol::Unknown),
         }
     }
@@ -144,6 +147,7 @@ impl DeviceProtocol {
             DeviceProtocol::Victron => "Victron".to_string(),
             DeviceProtocol::KNX => "KNX".to_string(),
             DeviceProtocol::ZennerDatahub => "ZENNER Datahub".to_string(),
+            DeviceProtocol::MqttInput => "MQTT Input".to_string(),
         }
     }
 }
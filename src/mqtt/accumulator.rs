@@ -0,0 +1,168 @@
+//! Ever increasing energy totals for counters that reset
+//!
+//! Some meters start their energy counters over after a power loss or firmware reset, which
+//! Home Assistant's total_increasing sensors record as a huge negative delta. For every
+//! configured field we keep the last raw value and an offset. A raw value below half of the
+//! last one is taken as reset and the total before the reset is added to the offset, smaller
+//! drops are treated as glitch and keep the last total. The state is stored in the device
+//! database so the totals survive restarts.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use log::{error, info};
+use serde_json::Value;
+
+use crate::device_manager::db::DeviceDb;
+use crate::MeteringData;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AccumulatorState {
+    pub last_raw: f64,
+    pub offset: f64,
+}
+
+impl AccumulatorState {
+    /// Total for a new raw value, true if the state changed
+    pub fn update(&mut self, raw: f64) -> (f64, bool) {
+        if raw >= self.last_raw {
+            let changed = raw != self.last_raw;
+            self.last_raw = raw;
+            return (raw + self.offset, changed);
+        }
+
+        if raw < self.last_raw / 2.0 {
+            self.offset += self.last_raw;
+            self.last_raw = raw;
+            return (raw + self.offset, true);
+        }
+
+        (self.last_raw + self.offset, false)
+    }
+}
+
+pub struct EnergyAccumulators {
+    fields: BTreeMap<String, Vec<String>>,
+    states: HashMap<(String, String), AccumulatorState>,
+    db: Option<Arc<DeviceDb>>,
+}
+
+impl EnergyAccumulators {
+    pub fn new(fields: BTreeMap<String, Vec<String>>) -> Self {
+        EnergyAccumulators { fields, states: HashMap::new(), db: None }
+    }
+
+    /// Database the state is kept in, without one the totals start over on restart
+    pub fn set_db(&mut self, db: Option<Arc<DeviceDb>>) {
+        self.db = db;
+    }
+
+    fn load_state(&self, meter_id: &str, field: &str, raw: f64) -> AccumulatorState {
+        let stored = match &self.db {
+            Some(db) => db.get_accumulator(meter_id, field).unwrap_or_else(|e| {
+                error!("Unable to load energy accumulator {meter_id}/{field}: {e}");
+                None
+            }),
+            None => None,
+        };
+
+        match stored {
+            Some((last_raw, offset)) => AccumulatorState { last_raw, offset },
+            None => AccumulatorState { last_raw: raw, offset: 0.0 },
+        }
+    }
+
+    /// Replace the configured fields of the meter by their accumulated totals
+    pub fn apply(&mut self, data: &mut MeteringData) {
+        let Some(fields) = self.fields.get(&data.id) else {
+            return;
+        };
+
+        for field in fields {
+            let Some(raw) = data.metered_values.get(field).and_then(Value::as_f64) else {
+                continue;
+            };
+
+            let key = (data.id.clone(), field.clone());
+            if !self.states.contains_key(&key) {
+                let state = self.load_state(&data.id, field, raw);
+                self.states.insert(key.clone(), state);
+            }
+
+            let state = self.states.get_mut(&key).unwrap();
+            let offset = state.offset;
+            let (total, changed) = state.update(raw);
+            if state.offset != offset {
+                info!("Counter {field} of {} was reset, continuing from {total}", data.id);
+            }
+
+            if changed {
+                if let Some(db) = &self.db {
+                    if let Err(e) = db.set_accumulator(&data.id, field, state.last_raw, state.offset) {
+                        error!("Unable to store energy accumulator {}/{field}: {e}", data.id);
+                    }
+                }
+            }
+
+            if let Some(total) = serde_json::Number::from_f64(total) {
+                data.metered_values.insert(field.clone(), Value::Number(total));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading(value: f64) -> MeteringData {
+        let mut data = MeteringData::new().unwrap();
+        data.id = "modbus-grid".to_string();
+        data.metered_values.insert("energy".to_string(), Value::from(value));
+        data.metered_values.insert("power".to_string(), Value::from(value));
+        data
+    }
+
+    #[test]
+    fn test_accumulator_state() {
+        let mut state = AccumulatorState { last_raw: 1000.0, offset: 0.0 };
+        assert_eq!(state.update(1010.0), (1010.0, true));
+        assert_eq!(state.update(1010.0), (1010.0, false));
+
+        /* A small drop is a glitch, the total stays */
+        assert_eq!(state.update(1005.0), (1010.0, false));
+
+        /* Reset of the counter */
+        assert_eq!(state.update(3.0), (1013.0, true));
+        assert_eq!(state.update(10.0), (1020.0, true));
+        assert_eq!(state, AccumulatorState { last_raw: 10.0, offset: 1010.0 });
+    }
+
+    #[test]
+    fn test_accumulators_survive_restart() {
+        let db = Arc::new(DeviceDb::open_in_memory().unwrap());
+        let mut fields = BTreeMap::new();
+        fields.insert("modbus-grid".to_string(), vec!["energy".to_string()]);
+
+        let mut accumulators = EnergyAccumulators::new(fields.clone());
+        accumulators.set_db(Some(db.clone()));
+        accumulators.apply(&mut reading(500.0));
+        let mut data = reading(2.0);
+        accumulators.apply(&mut data);
+        assert_eq!(data.metered_values["energy"], Value::from(502.0));
+        /* Fields not configured are untouched */
+        assert_eq!(data.metered_values["power"], Value::from(2.0));
+
+        let mut accumulators = EnergyAccumulators::new(fields);
+        accumulators.set_db(Some(db));
+        let mut data = reading(4.0);
+        accumulators.apply(&mut data);
+        assert_eq!(data.metered_values["energy"], Value::from(504.0));
+
+        /* Other meters are not touched */
+        let mut data = reading(1.0);
+        data.id = "modbus-pv".to_string();
+        accumulators.apply(&mut data);
+        assert_eq!(data.metered_values["energy"], Value::from(1.0));
+    }
+}
@@ -0,0 +1,115 @@
+//! Availability tracking per meter
+//!
+//! Every metering transmission marks its meter as seen. The interval between two
+//! transmissions is learned per meter and a meter is reported offline once it
+//! missed that interval by the configured factor.
+
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+
+use lazy_static::lazy_static;
+use serde::Serialize;
+#[cfg(feature = "api")]
+use utoipa::ToSchema;
+
+/// State of a single meter
+#[derive(Clone, Serialize, Debug, PartialEq)]
+#[cfg_attr(feature = "api", derive(ToSchema))]
+pub struct MeterAvailability {
+    pub protocol: String,
+    pub meter_name: String,
+    /// Unix timestamp of the last metering data
+    pub last_seen: u64,
+    /// Learned seconds between two transmissions, unknown until the second one
+    pub interval: Option<u64>,
+    pub online: bool,
+}
+
+lazy_static! {
+    /// Availability keyed by (protocol path, meter name)
+    pub static ref METER_AVAILABILITY: RwLock<BTreeMap<(String, String), MeterAvailability>> = RwLock::new(BTreeMap::new());
+}
+
+/// Record a transmission, returns true if the meter was not known as online before
+pub fn record_seen(protocol: &str, meter_name: &str, now: u64) -> bool {
+    let mut map = METER_AVAILABILITY.write().unwrap();
+    let key = (protocol.to_string(), meter_name.to_string());
+
+    match map.get_mut(&key) {
+        Some(entry) => {
+            let gap = now.saturating_sub(entry.last_seen);
+            if gap > 0 {
+                entry.interval = Some(gap);
+            }
+            entry.last_seen = now;
+
+            let came_back = !entry.online;
+            entry.online = true;
+            came_back
+        }
+        None => {
+            map.insert(key, MeterAvailability {
+                protocol: protocol.to_string(),
+                meter_name: meter_name.to_string(),
+                last_seen: now,
+                interval: None,
+                online: true,
+            });
+            true
+        }
+    }
+}
+
+/// Mark meters offline which missed their interval by `factor`, returns the newly offline ones
+pub fn check_timeouts(now: u64, factor: f64) -> Vec<(String, String)> {
+    let mut map = METER_AVAILABILITY.write().unwrap();
+    let mut offline = Vec::new();
+
+    for (key, entry) in map.iter_mut() {
+        let Some(interval) = entry.interval else {
+            continue;
+        };
+
+        let limit = (interval as f64 * factor).ceil() as u64;
+        if entry.online && now.saturating_sub(entry.last_seen) > limit {
+            entry.online = false;
+            offline.push(key.clone());
+        }
+    }
+
+    offline
+}
+
+/// Copy of the availability of all known meters
+pub fn get_availability() -> Vec<MeterAvailability> {
+    METER_AVAILABILITY.read().unwrap().values().cloned().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_meter_goes_offline_and_back() {
+        /* Unique names as the registry is shared between tests */
+        assert!(record_seen("test_avail", "meter", 100));
+        assert!(!record_seen("test_avail", "meter", 110));
+
+        /* Interval is 10s, factor 3 means offline after 30s */
+        assert!(check_timeouts(140, 3.0).is_empty());
+        assert_eq!(check_timeouts(141, 3.0), vec![("test_avail".to_string(), "meter".to_string())]);
+        /* Reported only once */
+        assert!(check_timeouts(200, 3.0).is_empty());
+
+        assert!(record_seen("test_avail", "meter", 250));
+        let entry = get_availability().into_iter().find(|a| a.protocol == "test_avail").unwrap();
+        assert!(entry.online);
+        assert_eq!(entry.interval, Some(140));
+    }
+
+    #[test]
+    fn test_unknown_interval_never_times_out() {
+        record_seen("test_avail_single", "meter", 100);
+        assert!(check_timeouts(100_000, 3.0).iter().all(|(p, _)| p != "test_avail_single"));
+    }
+}
@@ -0,0 +1,69 @@
+//! Reconnect policy of the MQTT event loops
+//!
+//! After a connection error the event loop waits before polling again, starting with
+//! mqtt.reconnect_delay and doubling the wait with every further error up to
+//! mqtt.reconnect_max_delay. A successful connection starts over with the initial delay.
+
+use std::time::Duration;
+
+use crate::CONFIG;
+
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    current: Duration,
+    attempts: u32,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        let initial = initial.max(Duration::from_millis(100));
+        Backoff { initial, max: max.max(initial), current: initial, attempts: 0 }
+    }
+
+    /// Policy from the mqtt section of the configuration
+    pub fn from_config() -> Self {
+        let config = &CONFIG.read().unwrap().config.mqtt;
+        Self::new(Duration::from_secs(config.reconnect_delay), Duration::from_secs(config.reconnect_max_delay))
+    }
+
+    /// Delay before the next attempt, every call doubles the following one
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = (self.current * 2).min(self.max);
+        self.attempts += 1;
+        delay
+    }
+
+    /// Failed attempts since the last successful connection
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// Connected again, the next error waits the initial delay
+    pub fn reset(&mut self) {
+        self.current = self.initial;
+        self.attempts = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(5));
+        let delays: Vec<u64> = (0..5).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 5, 5]);
+        assert_eq!(backoff.attempts(), 5);
+
+        backoff.reset();
+        assert_eq!(backoff.attempts(), 0);
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+
+        /* A zero delay would spin again */
+        let mut backoff = Backoff::new(Duration::ZERO, Duration::ZERO);
+        assert_eq!(backoff.next_delay(), Duration::from_millis(100));
+    }
+}
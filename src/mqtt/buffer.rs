@@ -0,0 +1,173 @@
+//! Offline buffer for metering publishes
+//!
+//! Keeps the newest messages while the broker is not reachable so they can be
+//! replayed in order after reconnecting. Retained messages are remembered per topic
+//! so additional brokers get discovery and states again after they reconnect.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use rumqttc::QoS;
+
+/// A message waiting to be published
+#[derive(Debug, Clone, PartialEq)]
+pub struct BufferedMessage {
+    pub topic: String,
+    pub payload: String,
+    pub qos: QoS,
+    pub retain: bool,
+}
+
+/// Bounded FIFO dropping the oldest message when full
+pub struct OfflineBuffer {
+    queue: VecDeque<BufferedMessage>,
+    capacity: usize,
+    dropped: u64,
+}
+
+impl OfflineBuffer {
+    /// Create a buffer holding up to `capacity` messages, 0 disables buffering
+    pub fn new(capacity: usize) -> Self {
+        OfflineBuffer {
+            queue: VecDeque::with_capacity(capacity),
+            capacity,
+            dropped: 0,
+        }
+    }
+
+    /// Store a message, returns false if it was not stored or an old one was dropped
+    pub fn push(&mut self, topic: String, payload: String, qos: QoS, retain: bool) -> bool {
+        if self.capacity == 0 {
+            self.dropped += 1;
+            return false;
+        }
+
+        let mut complete = true;
+        if self.queue.len() >= self.capacity {
+            self.queue.pop_front();
+            self.dropped += 1;
+            complete = false;
+        }
+
+        self.queue.push_back(BufferedMessage { topic, payload, qos, retain });
+        complete
+    }
+
+    /// Take the oldest message
+    pub fn pop(&mut self) -> Option<BufferedMessage> {
+        self.queue.pop_front()
+    }
+
+    /// Put a message back in front, e.g. if publishing it failed
+    pub fn push_front(&mut self, msg: BufferedMessage) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        if self.queue.len() >= self.capacity {
+            /* The message to retry is older than everything in the queue */
+            self.dropped += 1;
+            return;
+        }
+        self.queue.push_front(msg);
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Number of messages lost since startup because the buffer was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+/// Last retained payload of every topic, shared with the connections which replay it
+#[derive(Clone, Default)]
+pub struct RetainedMessages {
+    messages: Arc<Mutex<BTreeMap<String, (QoS, String)>>>,
+}
+
+impl RetainedMessages {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remember a retained publish, an empty payload removes the topic like it does on the broker
+    pub fn remember(&self, topic: &str, qos: QoS, payload: &str) {
+        let mut messages = self.messages.lock().unwrap();
+        if payload.is_empty() {
+            messages.remove(topic);
+        } else {
+            messages.insert(topic.to_string(), (qos, payload.to_string()));
+        }
+    }
+
+    /// All remembered messages ready to be published again
+    pub fn snapshot(&self) -> Vec<BufferedMessage> {
+        self.messages.lock().unwrap().iter()
+            .map(|(topic, (qos, payload))| BufferedMessage { topic: topic.clone(), payload: payload.clone(), qos: *qos, retain: true })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fifo_order() {
+        let mut buffer = OfflineBuffer::new(3);
+        assert!(buffer.push("a".to_string(), "1".to_string(), QoS::AtLeastOnce, false));
+        assert!(buffer.push("b".to_string(), "2".to_string(), QoS::AtLeastOnce, false));
+
+        assert!(buffer.push("c".to_string(), "3".to_string(), QoS::AtMostOnce, true));
+
+        assert_eq!(buffer.pop().unwrap().topic, "a");
+        assert_eq!(buffer.pop().unwrap().topic, "b");
+        /* Replayed with the options of the original publish */
+        let msg = buffer.pop().unwrap();
+        assert_eq!((msg.qos, msg.retain), (QoS::AtMostOnce, true));
+        assert!(buffer.pop().is_none());
+    }
+
+    #[test]
+    fn test_drop_oldest() {
+        let mut buffer = OfflineBuffer::new(2);
+        buffer.push("a".to_string(), "1".to_string(), QoS::AtLeastOnce, false);
+        buffer.push("b".to_string(), "2".to_string(), QoS::AtLeastOnce, false);
+        assert!(!buffer.push("c".to_string(), "3".to_string(), QoS::AtLeastOnce, false));
+
+        assert_eq!(buffer.len(), 2);
+        assert_eq!(buffer.dropped(), 1);
+        assert_eq!(buffer.pop().unwrap().topic, "b");
+        assert_eq!(buffer.pop().unwrap().topic, "c");
+    }
+
+    #[test]
+    fn test_disabled_buffer() {
+        let mut buffer = OfflineBuffer::new(0);
+        assert!(!buffer.push("a".to_string(), "1".to_string(), QoS::AtLeastOnce, false));
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.dropped(), 1);
+    }
+
+    #[test]
+    fn test_retained_messages() {
+        let retained = RetainedMessages::new();
+        retained.remember("homeassistant/sensor/a/config", QoS::AtLeastOnce, "{}");
+        retained.remember("energy2mqtt/devs/a", QoS::AtMostOnce, "1");
+        retained.remember("energy2mqtt/devs/a", QoS::AtMostOnce, "2");
+        retained.remember("homeassistant/sensor/b/config", QoS::AtLeastOnce, "{}");
+        /* Removing a discovery clears it */
+        retained.clone().remember("homeassistant/sensor/b/config", QoS::AtLeastOnce, "");
+
+        let messages = retained.snapshot();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].payload, "2");
+        assert!(messages.iter().all(|m| m.retain));
+    }
+}
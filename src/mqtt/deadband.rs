@@ -0,0 +1,131 @@
+//! Change-only publishing of metering data
+//!
+//! Meters polled every few seconds mostly repeat their values. For every configured meter the
+//! values published last are kept, a new reading is only published if a field moved beyond its
+//! deadband, any other value changed or max_interval seconds passed since the last publish.
+
+use std::collections::{BTreeMap, HashMap};
+
+use serde_json::{Map, Value};
+
+use crate::config::{MqttDeadband, MqttDeadbandConfig};
+use crate::MeteringData;
+
+/// True if the change from last to new is beyond the deadband, without limits every change is
+pub fn exceeded(deadband: &MqttDeadband, last: f64, new: f64) -> bool {
+    let diff = (new - last).abs();
+    if deadband.absolute.is_none() && deadband.percent.is_none() {
+        return diff > 0.0;
+    }
+
+    let absolute = deadband.absolute.is_some_and(|a| diff > a);
+    /* Relative to zero every change is beyond the deadband */
+    let percent = deadband.percent.is_some_and(|p| diff > 0.0 && (last == 0.0 || diff * 100.0 / last.abs() > p));
+    absolute || percent
+}
+
+/// True if the values have to be published compared to the ones published last
+fn values_changed(conf: &MqttDeadbandConfig, last: &Map<String, Value>, values: &Map<String, Value>) -> bool {
+    if last.len() != values.len() {
+        return true;
+    }
+
+    values.iter().any(|(key, value)| {
+        let Some(previous) = last.get(key) else {
+            return true;
+        };
+
+        match (conf.fields.get(key), previous.as_f64(), value.as_f64()) {
+            (Some(deadband), Some(previous), Some(value)) => exceeded(deadband, previous, value),
+            _ => previous != value,
+        }
+    })
+}
+
+pub struct DeadbandFilter {
+    /* Per meter id, "default" applies to all other meters */
+    config: BTreeMap<String, MqttDeadbandConfig>,
+    /* Time and values of the last publish per meter id */
+    published: HashMap<String, (u64, Map<String, Value>)>,
+}
+
+impl DeadbandFilter {
+    pub fn new(config: BTreeMap<String, MqttDeadbandConfig>) -> Self {
+        DeadbandFilter { config, published: HashMap::new() }
+    }
+
+    /// True if the reading has to be published, it is remembered as published then
+    pub fn should_publish(&mut self, data: &MeteringData, now: u64) -> bool {
+        let Some(conf) = self.config.get(&data.id).or_else(|| self.config.get("default")) else {
+            return true;
+        };
+
+        if let Some((time, last)) = self.published.get(&data.id) {
+            let heartbeat_due = conf.max_interval > 0 && now >= time + conf.max_interval;
+            if !heartbeat_due && !values_changed(conf, last, &data.metered_values) {
+                return false;
+            }
+        }
+
+        self.published.insert(data.id.clone(), (now, data.metered_values.clone()));
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading(power: f64, state: &str) -> MeteringData {
+        let mut data = MeteringData::new().unwrap();
+        data.id = "modbus-grid".to_string();
+        data.metered_values.insert("power".to_string(), Value::from(power));
+        data.metered_values.insert("state".to_string(), Value::from(state));
+        data
+    }
+
+    #[test]
+    fn test_deadband_exceeded() {
+        let absolute = MqttDeadband { absolute: Some(5.0), percent: None };
+        assert!(!exceeded(&absolute, 100.0, 105.0));
+        assert!(exceeded(&absolute, 100.0, 94.0));
+
+        let percent = MqttDeadband { absolute: None, percent: Some(2.0) };
+        assert!(!exceeded(&percent, 230.0, 233.0));
+        assert!(exceeded(&percent, 230.0, 235.0));
+        assert!(exceeded(&percent, 0.0, 0.1));
+        assert!(!exceeded(&percent, 0.0, 0.0));
+
+        let any = MqttDeadband { absolute: None, percent: None };
+        assert!(exceeded(&any, 1.0, 1.001));
+        assert!(!exceeded(&any, 1.0, 1.0));
+    }
+
+    #[test]
+    fn test_should_publish() {
+        let mut fields = BTreeMap::new();
+        fields.insert("power".to_string(), MqttDeadband { absolute: Some(10.0), percent: None });
+        let mut config = BTreeMap::new();
+        config.insert("modbus-grid".to_string(), MqttDeadbandConfig { fields, max_interval: 300 });
+        let mut filter = DeadbandFilter::new(config);
+
+        assert!(filter.should_publish(&reading(100.0, "ok"), 1000));
+        assert!(!filter.should_publish(&reading(105.0, "ok"), 1010));
+        /* Compared to the published value, not the one dropped before */
+        assert!(filter.should_publish(&reading(111.0, "ok"), 1020));
+        assert!(!filter.should_publish(&reading(111.0, "ok"), 1030));
+
+        /* Fields without deadband are published on every change */
+        assert!(filter.should_publish(&reading(111.0, "fault"), 1040));
+
+        /* Heartbeat */
+        assert!(!filter.should_publish(&reading(111.0, "fault"), 1339));
+        assert!(filter.should_publish(&reading(111.0, "fault"), 1340));
+
+        /* Meters without config are not filtered */
+        let mut other = reading(1.0, "ok");
+        other.id = "sml-main".to_string();
+        assert!(filter.should_publish(&other, 1341));
+        assert!(filter.should_publish(&other, 1342));
+    }
+}
@@ -65,7 +65,10 @@ pub struct HaComponent {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub payload_off: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub payload_press: Option<String>
+    pub payload_press: Option<String>,
+    /// "diagnostic" or "config", primary entities have none
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entity_category: Option<String>,
 }
 
 impl HaComponent {
@@ -99,6 +102,7 @@ impl HaComponent {
             payload_off: p_off,
             payload_press: p_press,
             via_device: "e2m_management".to_string(),
+            entity_category: None,
          }
     }
 
@@ -117,6 +121,7 @@ impl HaComponent {
             payload_off: None,
             payload_press: None,
             via_device: "e2m_management".to_string(),
+            entity_category: None,
          }
     }
 
@@ -135,6 +140,7 @@ impl HaComponent {
             payload_off: None,
             payload_press: None,
             via_device: "e2m_management".to_string(),
+            entity_category: None,
          }
     }
 
@@ -153,6 +159,7 @@ impl HaComponent {
             payload_off: None,
             payload_press: None,
             via_device: "e2m_management".to_string(),
+            entity_category: None,
          }
     }
 
@@ -171,6 +178,7 @@ impl HaComponent {
             payload_off: None,
             payload_press: None,
             via_device: "e2m_management".to_string(),
+            entity_category: None,
          }
     }
 
@@ -189,6 +197,7 @@ impl HaComponent {
             payload_off: None,
             payload_press: None,
             via_device: "e2m_management".to_string(),
+            entity_category: None,
          }
     }
 
@@ -207,6 +216,7 @@ impl HaComponent {
             payload_off: None,
             payload_press: None,
             via_device: "e2m_management".to_string(),
+            entity_category: None,
          }
     }
 
@@ -214,6 +224,16 @@ impl HaComponent {
         self.via_device = via;
     }
 
+    pub fn entity_category(mut self, cat: String) -> Self {
+        self.entity_category = Some(cat);
+        self
+    }
+
+    /// Show the entity with the diagnostics of the device, e.g. serial numbers and versions
+    pub fn cat_diagnostic(self) -> Self {
+        self.entity_category("diagnostic".to_string())
+    }
+
     pub fn new_full_sensor(name: String, device_class: String, unit: String, json_key: String, object_id: String, unique_id: String) -> Self {
         return HaComponent {
             p: "sensor".to_string(),
@@ -228,6 +248,7 @@ impl HaComponent {
             payload_off: None,
             payload_press: None,
             via_device: "e2m_management".to_string(),
+            entity_category: None,
          }
     }
 }
@@ -238,6 +259,9 @@ pub struct HaDiscover {
     pub o: HaOrigin,
     pub cmps: serde_json::Map<String, serde_json::Value>,
     pub state_topic: String,
+    pub availability_topic: String,
+    pub payload_available: String,
+    pub payload_not_available: String,
     pub qos: u32,
     #[serde(skip_serializing)]
     pub discover_topic: String,
@@ -246,7 +270,7 @@ pub struct HaDiscover {
 impl HaDiscover {
     pub fn new(name: String, manu: String, model: String, proto: String) -> Self {
         return HaDiscover {
-            discover_topic: format!("homeassistant/device/e2m_{}-{}/config", proto.clone(), name.clone()),
+            discover_topic: format!("{}/device/e2m_{}-{}/config", super::get_discovery_prefix(), proto.clone(), name.clone()),
             dev: HaDevice {
                 ids: format!("e2m_{}_{}", proto.clone(), name.clone()),
                 name: name.clone(),
@@ -260,13 +284,16 @@ impl HaDiscover {
                 support_url: "https://energy2mqtt.org".to_string(),
             },
             cmps: serde_json::Map::new(),
-            state_topic: format!("energy2mqtt/devs/{}/{}", proto, name),
+            state_topic: super::get_meter_topic(&proto, &name, "", ""),
+            availability_topic: super::get_availability_topic(),
+            payload_available: "online".to_string(),
+            payload_not_available: "offline".to_string(),
             qos: 2
         }
     }
     pub fn new_with_topic_from_name(name: String, manu: String, model: String, proto: String, topic: String) -> Self {
         return HaDiscover {
-            discover_topic: format!("homeassistant/device/e2m_{}-{}/config", proto.clone(), name.clone()),
+            discover_topic: format!("{}/device/e2m_{}-{}/config", super::get_discovery_prefix(), proto.clone(), name.clone()),
             dev: HaDevice {
                 ids: format!("e2m_{}_{}", proto.clone(), name.clone()),
                 name: name,
@@ -280,7 +307,10 @@ impl HaDiscover {
                 support_url: "https://energy2mqtt.org".to_string(),
             },
             cmps: serde_json::Map::new(),
-            state_topic: format!("energy2mqtt/devs/{}/{}", proto, topic),
+            state_topic: super::get_meter_topic(&proto, &topic, "", ""),
+            availability_topic: super::get_availability_topic(),
+            payload_available: "online".to_string(),
+            payload_not_available: "offline".to_string(),
             qos: 2
         }
     }
@@ -1,5 +1,8 @@
 use serde::Serialize;
 use serde_json::{Map, Value};
+use crate::obis_utils::{self, HaUnitInfo, QuantityKind};
+use crate::models::DeviceProtocol;
+use super::MeteringData;
 
 
 pub trait HaToJSON {
@@ -12,7 +15,7 @@ pub trait HaToJSON {
 /// - "power_l2" -> "power/l2"
 /// - "cache_misses" -> "cache_misses"
 /// - "energy_all" -> "energy/all"
-fn key_to_topic_path(key: &str) -> String {
+pub(crate) fn key_to_topic_path(key: &str) -> String {
     // Split on common suffixes that should become path segments
     // Phase identifiers: l1, l2, l3, all, avg, sum, total
     let suffixes = ["_l1", "_l2", "_l3", "_all", "_avg", "_sum", "_total"];
@@ -28,12 +31,12 @@ fn key_to_topic_path(key: &str) -> String {
     result
 }
 
-pub fn get_state_topic(proto: &String, device: &String) -> String {
-    format!("energy2mqtt/devs/{proto}/{device}")
+pub fn get_state_topic(proto: &str, device: &str) -> String {
+    super::get_meter_topic(proto, device, "", "")
 }
 
 pub fn get_command_topic(proto: &String, instance: &String, device: &String) -> String {
-    format!("energy2mqtt/cmds/{proto}/{instance}/{device}")
+    format!("{}/cmds/{proto}/{instance}/{device}", super::get_topic_prefix())
 }
 
 pub fn get_dev_cmd_proto_from_topic(topic: &String) -> (String, String, String) {
@@ -69,7 +72,7 @@ impl HaToJSON for HaOrigin2 {
 }
 
 /// Individual entity discovery message
-/// Sent to homeassistant/{platform}/{unique_id}/config
+/// Sent to {discovery_prefix}/{platform}/{unique_id}/config
 #[derive(Clone)]
 pub struct HaEntityDiscovery {
     pub topic: String,
@@ -84,6 +87,8 @@ pub struct HaDeviceInfo {
     pub manufacturer: String,
     pub model: String,
     pub via_device: String,
+    /// Tenant of the meter, HA groups the devices of a tenant by area
+    pub suggested_area: Option<String>,
 }
 
 impl HaDeviceInfo {
@@ -94,16 +99,27 @@ impl HaDeviceInfo {
         dev.insert("manufacturer".to_string(), Value::from(self.manufacturer.clone()));
         dev.insert("model".to_string(), Value::from(self.model.clone()));
         dev.insert("via_device".to_string(), Value::from(self.via_device.clone()));
+        if let Some(area) = &self.suggested_area {
+            dev.insert("suggested_area".to_string(), Value::from(area.clone()));
+        }
         Value::Object(dev)
     }
 }
 
+/// Device part of the entity discovery topics: homeassistant/{platform}/{device_id}/{key_path}/config
+pub fn discovery_device_id(proto: &str, device: &str) -> String {
+    format!("e2m_{}_{}", proto, device).to_lowercase()
+}
+
 pub struct HaSensor {
     proto: String,
     device: String,
     device_info: HaDeviceInfo,
     origin: HaOrigin2,
     state_topic: String,
+    /// Meter id given via meter_ids, empty for devices without readings
+    meter_id: String,
+    discovery_prefix: String,
     components: Vec<(String, HaComponent2)>,
 }
 
@@ -140,6 +156,7 @@ impl HaSensor {
             manufacturer: manu.unwrap_or_else(|| "Unknown".to_string()),
             model: model.unwrap_or_else(|| "Unknown".to_string()),
             via_device: "e2m_management".to_string(),
+            suggested_area: None,
         };
 
         let origin = HaOrigin2::new(
@@ -156,6 +173,8 @@ impl HaSensor {
             device_info,
             origin,
             state_topic,
+            meter_id: String::new(),
+            discovery_prefix: super::get_discovery_prefix(),
             components: Vec::new(),
         }
     }
@@ -173,6 +192,32 @@ impl HaSensor {
         self.state_topic.clone()
     }
 
+    /// Id grouping the entity discovery topics of this device
+    pub fn get_device_id(&self) -> String {
+        discovery_device_id(&self.proto, &self.device)
+    }
+
+    pub fn get_proto(&self) -> &str {
+        &self.proto
+    }
+
+    /// Id of the parent device this device is linked to
+    pub fn get_via(&self) -> &str {
+        &self.device_info.via_device
+    }
+
+    pub fn get_meter_id(&self) -> &str {
+        &self.meter_id
+    }
+
+    pub fn get_state_topic(&self) -> &str {
+        &self.state_topic
+    }
+
+    pub fn get_cmp(&self, key: &str) -> Option<&HaComponent2> {
+        self.components.iter().find(|(k, _)| k == key).map(|(_, cmp)| cmp)
+    }
+
     /// Generate individual discovery messages for each entity
     /// This is the new approach that avoids MQTT message size limits
     pub fn get_entity_discoveries(&self) -> Vec<HaEntityDiscovery> {
@@ -193,11 +238,11 @@ impl HaSensor {
             // Add origin info
             payload.insert("origin".to_string(), self.origin.to_json());
 
-            // Add state topic
-            payload.insert("state_topic".to_string(), Value::from(self.state_topic.clone()));
+            // Add state topic, unless the component has its own
+            payload.entry("state_topic").or_insert_with(|| Value::from(self.state_topic.clone()));
 
             // Add availability topic for online/offline status
-            payload.insert("availability_topic".to_string(), Value::from("energy2mqtt/status"));
+            payload.insert("availability_topic".to_string(), Value::from(super::get_availability_topic()));
             payload.insert("payload_available".to_string(), Value::from("online"));
             payload.insert("payload_not_available".to_string(), Value::from("offline"));
 
@@ -215,10 +260,10 @@ impl HaSensor {
             let key_path = key_to_topic_path(key);
 
             // Device ID for topic grouping
-            let device_id = format!("e2m_{}_{}", self.proto, self.device).to_lowercase();
+            let device_id = self.get_device_id();
 
-            // Topic format: homeassistant/{platform}/{device_id}/{key_path}/config
-            let topic = format!("homeassistant/{platform}/{device_id}/{key_path}/config");
+            // Topic format: {discovery_prefix}/{platform}/{device_id}/{key_path}/config
+            let topic = format!("{}/{platform}/{device_id}/{key_path}/config", self.discovery_prefix);
 
             discoveries.push(HaEntityDiscovery {
                 topic,
@@ -229,6 +274,16 @@ impl HaSensor {
         discoveries
     }
 
+    /* Set tenant and id of the meter, both are part of the state topic and the tenant groups the devices in HA */
+    pub fn meter_ids(mut self, tenant: String, id: String) -> Self {
+        self.state_topic = super::get_meter_topic(&self.proto, &self.device, &tenant, &id);
+        self.meter_id = id;
+        if !tenant.is_empty() && tenant != super::DEFAULT_TENANT {
+            self.device_info.suggested_area = Some(tenant);
+        }
+        self
+    }
+
     /* Set parent device */
     pub fn via(mut self, via: String) -> Self {
         self.device_info.via_device = via;
@@ -247,6 +302,139 @@ impl HaSensor {
     }
 }
 
+/// Abbreviations kept in capitals when a key is turned into a name
+const NAME_ACRONYMS: [&str; 6] = ["ac", "dc", "pv", "soc", "soh", "pf"];
+
+/// True for phase parts of a key like l1 or l1l2 (the voltage between two phases)
+fn is_phase_token(token: &str) -> bool {
+    let bytes = token.as_bytes();
+    !bytes.is_empty() && bytes.len().is_multiple_of(2)
+        && bytes.chunks(2).all(|c| c[0] == b'l' && (b'1'..=b'3').contains(&c[1]))
+}
+
+/// Name of the value of one phase, all protocols name them "<Name> L<phase>"
+pub fn phase_entity_name(name: &str, phase: u64) -> String {
+    format!("{name} L{phase}")
+}
+
+/// Friendly name for a snake_case key, the phases of a device then sort next to each other
+/// in HA, e.g. "voltage_l1" becomes "Voltage L1" and "ac_voltage_l1l2" becomes "AC Voltage L1-L2"
+pub fn entity_name(key: &str) -> String {
+    key.split('_')
+        .filter(|t| !t.is_empty())
+        .map(|token| {
+            let lower = token.to_lowercase();
+            if is_phase_token(&lower) {
+                lower.as_bytes().chunks(2)
+                    .map(|c| format!("L{}", c[1] as char))
+                    .collect::<Vec<_>>()
+                    .join("-")
+            } else if NAME_ACRONYMS.contains(&lower.as_str()) {
+                lower.to_uppercase()
+            } else {
+                let mut chars = token.chars();
+                chars.next().map(|c| c.to_uppercase().chain(chars).collect()).unwrap_or_default()
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// Serial numbers and versions describe the device rather than measure anything, HA shows
+/// them with the diagnostic entities instead of the primary ones. Only whole words of keys
+/// (serial_number) and names (Serial Number) count, conversion_factor is no version.
+pub fn is_diagnostic_key(key: &str) -> bool {
+    key.to_lowercase()
+        .split(|c: char| c == '_' || c.is_whitespace())
+        .any(|token| ["serial", "version", "firmware"].contains(&token))
+}
+
+/// Diagnostic sensor showing the last read error of a meter, "ok" once it reads again
+pub fn error_component(proto: &str, device: &str) -> HaComponent2 {
+    let topic = super::get_meter_error_topic(proto, device);
+    HaComponent2::new()
+        .name("Error".to_string())
+        .non_numeric()
+        .cat_diagnostic()
+        .add_information("state_topic", Value::from(topic.clone()))
+        .add_information("json_attributes_topic", Value::from(topic))
+        .add_information("value_template", Value::from("{{ value_json.error or 'ok' }}"))
+}
+
+/// Build a discovery for meters without a register definition (OMS, IEC 62056).
+/// Every metered value becomes a component classified by the unit registry using
+/// its key (OBIS code or VIF field name), a "<key>_unit" value sent by the meter
+/// takes precedence over the unit of the registry. IEC 62056 field names (e.g.
+/// gas_volume) are classified by their OBIS code.
+pub fn build_metering_discovery(data: &MeteringData, manu: Option<String>, model: Option<String>) -> HaSensor {
+    let mut proto = data.protocol.to_string();
+    if !data.state_topic_base.is_empty() {
+        proto = data.state_topic_base.clone();
+    }
+
+    let mut disc = HaSensor::new(proto.clone(), data.meter_name.clone(), manu, model)
+        .meter_ids(data.tenant.clone(), data.id.clone());
+    disc.add_cmp("read_error".to_string(), error_component(&proto, &data.meter_name));
+
+    for (key, value) in &data.metered_values {
+        /* Units are part of the component, protocol details and raw payloads are not for HA */
+        if key.ends_with("_unit") || key == "payload" || value.is_object() {
+            continue;
+        }
+
+        let unit = data.metered_values.get(&format!("{key}_unit"))
+            .and_then(|u| u.as_str())
+            .filter(|u| !u.is_empty());
+
+        let code = match data.protocol {
+            DeviceProtocol::IEC62056 => obis_utils::get_obis_code_for_field(key).unwrap_or(key),
+            _ => key,
+        };
+
+        /* Keys like 1-0:1.8.0 can't be used with the dot notation */
+        let name = obis_utils::get_obis_description(code).map(str::to_string).unwrap_or_else(|| entity_name(key));
+        let diagnostic = is_diagnostic_key(key) || is_diagnostic_key(&name);
+        let mut cmp = HaComponent2::new()
+            .name(name)
+            .add_information("value_template", Value::from(format!("{{{{ value_json['{key}'] }}}}")));
+        if diagnostic {
+            cmp = cmp.cat_diagnostic();
+        }
+
+        match obis_utils::get_ha_unit_info(code) {
+            Some(info) => {
+                cmp = cmp.unit_info(&info);
+                if let Some(u) = unit {
+                    cmp = cmp.unit_of_measurement(u.to_string());
+                }
+            }
+            None if value.is_number() => {
+                /* Unknown keys are still classified by the unit the meter sends */
+                if let Some(info) = unit.and_then(QuantityKind::from_unit).map(|k| k.ha_unit_info()) {
+                    cmp = cmp.unit_info(&info);
+                }
+                if let Some(u) = unit {
+                    cmp = cmp.unit_of_measurement(u.to_string());
+                }
+            }
+            None => {
+                cmp = cmp.non_numeric();
+            }
+        }
+
+        disc.add_cmp(component_key(key), cmp);
+    }
+
+    disc
+}
+
+/// Component key of a metered value usable in entity ids, e.g. 1_0_1_8_0 for 1-0:1.8.0
+pub fn component_key(key: &str) -> String {
+    key.chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
 #[derive(Clone)]
 pub struct HaComponent2 {
     defs: Map<String, Value>
@@ -352,6 +540,18 @@ impl HaComponent2 {
         self
     }
 
+    /* Set device class, unit and state class as found in the unit registry */
+    pub fn unit_info(mut self, info: &HaUnitInfo) -> Self {
+        if !info.device_class.is_empty() {
+            self.defs.insert("device_class".to_string(), Value::from(info.device_class));
+        }
+        if !info.unit.is_empty() {
+            self.defs.insert("unit_of_measurement".to_string(), Value::from(info.unit));
+        }
+        self.defs.insert("state_class".to_string(), Value::from(info.state_class));
+        self
+    }
+
     pub fn entity_category(mut self, cat: String) -> Self {
         self.defs.insert("entity_category".to_string(), Value::from(cat));
         self
@@ -371,6 +571,10 @@ impl HaComponent2 {
         self
     }
 
+    pub fn get_information(&self, key: &str) -> Option<&Value> {
+        self.defs.get(key)
+    }
+
     pub fn del_information(mut self, key: &str) -> Self {
         self.defs.remove(&key.to_string());
         self
@@ -412,4 +616,137 @@ mod tests {
         // Only last matching suffix is converted
         assert_eq!(key_to_topic_path("total_energy_all"), "total_energy/all");
     }
+
+    #[test]
+    fn test_entity_name() {
+        assert_eq!(entity_name("voltage_l1"), "Voltage L1");
+        assert_eq!(entity_name("ac_voltage_l1l2"), "AC Voltage L1-L2");
+        assert_eq!(entity_name("energy_positive_l3"), "Energy Positive L3");
+        assert_eq!(entity_name("soc"), "SOC");
+        assert_eq!(entity_name("cell__temp"), "Cell Temp");
+        /* Only l1 to l3 are phases */
+        assert_eq!(entity_name("level_l4"), "Level L4");
+        assert_eq!(phase_entity_name("Current", 2), "Current L2");
+
+        assert!(is_diagnostic_key("serial_number"));
+        assert!(is_diagnostic_key("bms_software_version"));
+        assert!(!is_diagnostic_key("voltage_l1"));
+        assert!(is_diagnostic_key("Firmware Version"));
+        assert!(!is_diagnostic_key("conversion_factor"));
+        assert!(!is_diagnostic_key("serialized_state"));
+    }
+
+    #[test]
+    fn test_entity_discovery_has_availability() {
+        let mut sensor = HaSensor::new("modbus".to_string(), "meter".to_string(), None, None);
+        sensor.add_cmp("power".to_string(), HaComponent2::new().name("Power".to_string()));
+
+        let discoveries = sensor.get_entity_discoveries();
+        assert_eq!(discoveries.len(), 1);
+        assert_eq!(discoveries[0].payload["availability_topic"], "energy2mqtt/status");
+        assert_eq!(discoveries[0].payload["payload_not_available"], "offline");
+    }
+
+    #[test]
+    fn test_entity_topics_are_grouped_by_device_id() {
+        let mut sensor = HaSensor::new("ModbusTCP".to_string(), "Grid".to_string(), None, None);
+        sensor.add_cmp("power".to_string(), HaComponent2::new().name("Power".to_string()));
+
+        /* Removing a device relies on finding its entities by the device id */
+        let device_id = discovery_device_id("ModbusTCP", "Grid");
+        assert_eq!(sensor.get_device_id(), device_id);
+        assert!(sensor.get_entity_discoveries()[0].topic.contains(&format!("/{device_id}/")));
+    }
+
+    #[test]
+    fn test_build_metering_discovery() {
+        let mut data = MeteringData::new().unwrap();
+        data.protocol = crate::models::DeviceProtocol::IEC62056;
+        data.meter_name = "meter".to_string();
+        data.metered_values.insert("1-0:1.8.1".to_string(), Value::from("000123.456"));
+        data.metered_values.insert("1-0:1.8.1_unit".to_string(), Value::from("kWh"));
+        data.metered_values.insert("0-0:96.1.0".to_string(), Value::from("12345678"));
+        data.metered_values.insert("proto".to_string(), serde_json::json!({"type": "iec62056"}));
+
+        let discoveries = build_metering_discovery(&data, None, None).get_entity_discoveries();
+        assert_eq!(discoveries.len(), 3);
+
+        let error = &discoveries[0];
+        assert_eq!(error.payload["state_topic"], "energy2mqtt/devs/IEC 62056-21/meter/error");
+        assert_eq!(error.payload["entity_category"], "diagnostic");
+
+        let energy = discoveries.iter()
+            .find(|d| d.payload["value_template"] == "{{ value_json['1-0:1.8.1'] }}")
+            .unwrap();
+        assert_eq!(energy.payload["device_class"], "energy");
+        assert_eq!(energy.payload["unit_of_measurement"], "kWh");
+        assert_eq!(energy.payload["state_class"], "total_increasing");
+        assert!(energy.topic.contains("/1_0_1_8_1/"));
+
+        let ident = discoveries.iter()
+            .find(|d| d.payload["value_template"] == "{{ value_json['0-0:96.1.0'] }}")
+            .unwrap();
+        assert!(ident.payload.get("state_class").is_none());
+
+        /* Field names of IEC 62056 meters are classified by their OBIS code */
+        data.metered_values.insert("gas_volume".to_string(), Value::from(123.456));
+        data.metered_values.insert("gas_volume_unit".to_string(), Value::from("m³"));
+        let discoveries = build_metering_discovery(&data, None, None).get_entity_discoveries();
+        let gas = discoveries.iter()
+            .find(|d| d.payload["value_template"] == "{{ value_json['gas_volume'] }}")
+            .unwrap();
+        assert_eq!(gas.payload["name"], "Gas volume (total)");
+        assert_eq!(gas.payload["device_class"], "gas");
+        assert_eq!(gas.payload["state_class"], "total_increasing");
+        assert!(gas.topic.contains("/gas_volume/"));
+    }
+
+    #[test]
+    fn test_metering_discovery_classifies_by_unit() {
+        let mut data = MeteringData::new().unwrap();
+        data.meter_name = "meter".to_string();
+        data.metered_values.insert("q_total".to_string(), Value::from(120.5));
+        data.metered_values.insert("q_total_unit".to_string(), Value::from("kvar"));
+        data.metered_values.insert("s_energy".to_string(), Value::from(42.0));
+        data.metered_values.insert("s_energy_unit".to_string(), Value::from("kVAh"));
+        data.metered_values.insert("firmware_version".to_string(), Value::from("1.2.3"));
+
+        let discoveries = build_metering_discovery(&data, None, None).get_entity_discoveries();
+        let find = |key: &str| discoveries.iter()
+            .find(|d| d.payload["value_template"] == format!("{{{{ value_json['{key}'] }}}}"))
+            .unwrap();
+
+        let reactive = find("q_total");
+        assert_eq!(reactive.payload["name"], "Q Total");
+        assert_eq!(reactive.payload["device_class"], "reactive_power");
+        assert_eq!(reactive.payload["unit_of_measurement"], "kvar");
+        assert!(reactive.payload.get("entity_category").is_none());
+
+        let apparent = find("s_energy");
+        assert!(apparent.payload.get("device_class").is_none());
+        assert_eq!(apparent.payload["state_class"], "total_increasing");
+
+        let firmware = find("firmware_version");
+        assert_eq!(firmware.payload["name"], "Firmware Version");
+        assert_eq!(firmware.payload["entity_category"], "diagnostic");
+    }
+
+    #[test]
+    fn test_meter_ids_tenant_area() {
+        let mut sensor = HaSensor::new("OMS".to_string(), "water".to_string(), None, None)
+            .meter_ids("flat1".to_string(), String::new());
+        sensor.add_cmp("volume".to_string(), HaComponent2::new().name("Volume".to_string()));
+
+        let discoveries = sensor.get_entity_discoveries();
+        assert_eq!(discoveries[0].payload["device"]["suggested_area"], "flat1");
+        assert_eq!(discoveries[0].payload["state_topic"], "energy2mqtt/devs/flat1/OMS/water");
+
+        let mut sensor = HaSensor::new("OMS".to_string(), "gas".to_string(), None, None)
+            .meter_ids(String::new(), String::new());
+        sensor.add_cmp("volume".to_string(), HaComponent2::new().name("Volume".to_string()));
+
+        let discoveries = sensor.get_entity_discoveries();
+        assert!(discoveries[0].payload["device"].get("suggested_area").is_none());
+        assert_eq!(discoveries[0].payload["state_topic"], "energy2mqtt/devs/OMS/gas");
+    }
 }
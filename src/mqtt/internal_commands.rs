@@ -1,8 +1,43 @@
 
-use log::info;
+use std::collections::HashMap;
+use std::sync::RwLock;
+use lazy_static::lazy_static;
+use log::{error, info};
 use tokio::sync::mpsc::Sender;
 use crate::mqtt::{PublishData, SubscribeData, Transmission};
 
+/// Topic prefix of the read requests handed to the tasks, followed by protocol, hub and meter name
+pub fn read_request_topic() -> String {
+    format!("{}/read/", super::get_topic_prefix())
+}
+
+lazy_static! {
+    /* Task reading a meter by "{protocol}/{hub}/{meter}", fed by "read <protocol> <hub>/<meter>" commands */
+    static ref READ_REQUESTS: RwLock<HashMap<String, Sender<(String, String)>>> = RwLock::new(HashMap::new());
+}
+
+/// Make a meter readable on demand, the sender gets read_request_topic(){protocol}/{hub}/{meter} with an empty payload
+pub fn register_read_request(protocol: &str, hub: &str, meter: &str, sender: Sender<(String, String)>) {
+    READ_REQUESTS.write().unwrap().insert(format!("{protocol}/{hub}/{meter}"), sender);
+}
+
+/// Ask the task of a meter to read it right away
+pub fn request_read(protocol: &str, hub: &str, meter: &str) -> Result<(), String> {
+    let key = format!("{protocol}/{hub}/{meter}");
+    let sender = READ_REQUESTS.read().unwrap().get(&key).cloned()
+        .ok_or_else(|| format!("{protocol} meter {hub}/{meter} can not be read on demand"))?;
+
+    sender.try_send((format!("{}{key}", read_request_topic()), String::new()))
+        .map_err(|e| format!("read request for {protocol} meter {hub}/{meter} failed: {e}"))
+}
+
+/// Split "read <protocol> <hub>/<meter>", the names may contain spaces
+fn parse_read_command(message: &str) -> Option<(&str, &str, &str)> {
+    let (protocol, target) = message.strip_prefix("read ")?.trim().split_once(' ')?;
+    let (hub, meter) = target.trim().split_once('/')?;
+    Some((protocol, hub, meter))
+}
+
 pub struct CommandHandler {
    sender: Sender<Transmission>,
 }
@@ -28,6 +63,7 @@ impl CommandHandler {
         let _ = self.sender.send(register).await;
 
         /* We are not using the HADiscover and HAComponent stuff here because we know the json  */
+        let prefix = super::get_topic_prefix();
         let json = format!(r###"{{
           "dev": {{
             "ids":"e2m_management",
@@ -48,7 +84,7 @@ impl CommandHandler {
               "object_id":"bridge_restart",
               "payload_press":"restart",
               "unique_id":"e2m_management_bridge_restart",
-              "command_topic": "energy2mqtt/mgt/command",
+              "command_topic": "{prefix}/mgt/command",
               "entity_category": "config"
             }},
             "uptime": {{
@@ -56,7 +92,7 @@ impl CommandHandler {
               "name":"uptime",
               "object_id":"uptime",
               "unique_id":"e2m_management_uptime",
-              "state_topic": "energy2mqtt/mgt/uptime",
+              "state_topic": "{prefix}/mgt/uptime",
               "state_class": "measurement",
               "unit_of_measurement": "s",
               "entity_category": "diagnostic"
@@ -65,13 +101,13 @@ impl CommandHandler {
         }}"###, crate::VERSION);
 
         let p = Transmission::Publish(PublishData {
-            topic: "homeassistant/device/e2m_bridge/config".to_string(),
+            topic: format!("{}/device/e2m_bridge/config", super::get_discovery_prefix()),
             payload: json,
             qos: 0,
             retain: true,
         });
 
-        /* Send our data */
+        /* Send our data, the MQTT manager drops it if Home Assistant is disabled */
         let _ = self.sender.send(p).await;
 
         info!("Start waiting for command messages");
@@ -83,8 +119,40 @@ impl CommandHandler {
                 info!("Request to shutdown received");
                 return;
             }
+
+            if let Some((protocol, hub, meter)) = parse_read_command(&message) {
+                if let Err(e) = request_read(protocol, hub, meter) {
+                    error!("{e}");
+                }
+            }
         }
   }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_read_command() {
+        assert_eq!(parse_read_command("read modbus hub/grid"), Some(("modbus", "hub", "grid")));
+        assert_eq!(parse_read_command("read modbus Main Hub/Grid Meter "), Some(("modbus", "Main Hub", "Grid Meter")));
+        assert_eq!(parse_read_command("read modbus grid"), None);
+        assert_eq!(parse_read_command("read modbus"), None);
+        assert_eq!(parse_read_command("restart"), None);
+    }
+
+    #[tokio::test]
+    async fn test_request_read() {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        register_read_request("modbus", "hub", "test_read_meter", tx);
+
+        request_read("modbus", "hub", "test_read_meter").unwrap();
+        assert_eq!(rx.recv().await, Some(("energy2mqtt/read/modbus/hub/test_read_meter".to_string(), String::new())));
+        /* Same meter name on another hub or protocol */
+        assert!(request_read("modbus", "other", "test_read_meter").is_err());
+        assert!(request_read("oms", "hub", "test_read_meter").is_err());
+    }
+}
+
 
@@ -0,0 +1,93 @@
+//! Read error tracking per meter
+//!
+//! Every error reported via Transmission::MeterError is counted per meter and the
+//! last one is kept with its time, so support requests show what failed recently
+//! without digging through the retained error topics.
+
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+
+use lazy_static::lazy_static;
+use serde::Serialize;
+#[cfg(feature = "api")]
+use utoipa::ToSchema;
+
+/// Errors of a single meter
+#[derive(Clone, Serialize, Debug, PartialEq)]
+#[cfg_attr(feature = "api", derive(ToSchema))]
+pub struct MeterErrorSummary {
+    pub protocol: String,
+    pub meter_name: String,
+    /// Errors since the start of energy2mqtt
+    pub error_count: u64,
+    pub last_error: Option<String>,
+    /// Unix timestamp of the last error
+    pub last_error_at: Option<u64>,
+    /// True until the meter reads fine again
+    pub failing: bool,
+}
+
+lazy_static! {
+    /// Errors keyed by (protocol path, meter name)
+    pub static ref METER_ERRORS: RwLock<BTreeMap<(String, String), MeterErrorSummary>> = RwLock::new(BTreeMap::new());
+}
+
+/// Record an error of a meter, None once it reads fine again
+pub fn record_error(protocol: &str, meter_name: &str, error: Option<&str>, now: u64) {
+    let mut map = METER_ERRORS.write().unwrap();
+    let key = (protocol.to_string(), meter_name.to_string());
+
+    match error {
+        Some(error) => {
+            let entry = map.entry(key).or_insert_with(|| MeterErrorSummary {
+                protocol: protocol.to_string(),
+                meter_name: meter_name.to_string(),
+                error_count: 0,
+                last_error: None,
+                last_error_at: None,
+                failing: false,
+            });
+            entry.error_count += 1;
+            entry.last_error = Some(error.to_string());
+            entry.last_error_at = Some(now);
+            entry.failing = true;
+        },
+        /* Meters without any error so far are not listed */
+        None => {
+            if let Some(entry) = map.get_mut(&key) {
+                entry.failing = false;
+            }
+        },
+    }
+}
+
+/// Copy of the error summaries of all meters which reported an error
+pub fn get_meter_errors() -> Vec<MeterErrorSummary> {
+    METER_ERRORS.read().unwrap().values().cloned().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_error() {
+        /* Unique names as the registry is shared between tests */
+        record_error("test_errors", "fine", None, 100);
+        record_error("test_errors", "meter", Some("Connection timeout after 10000ms"), 100);
+        record_error("test_errors", "meter", Some("Modbus exception"), 160);
+
+        let errors: Vec<MeterErrorSummary> = get_meter_errors().into_iter().filter(|e| e.protocol == "test_errors").collect();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].error_count, 2);
+        assert_eq!(errors[0].last_error.as_deref(), Some("Modbus exception"));
+        assert_eq!(errors[0].last_error_at, Some(160));
+        assert!(errors[0].failing);
+
+        /* The last error is kept once it reads again */
+        record_error("test_errors", "meter", None, 200);
+        let entry = get_meter_errors().into_iter().find(|e| e.protocol == "test_errors").unwrap();
+        assert!(!entry.failing);
+        assert_eq!(entry.last_error_at, Some(160));
+    }
+}
@@ -15,10 +15,14 @@ use crate::config::{MqttConfig, MQTT_DISCOVERY_VERSION_CURRENT};
 ///
 /// Old format (v1): homeassistant/sensor/e2m_knx_device_sensor/config
 /// New format (v2): homeassistant/sensor/e2m_knx_device/sensor/config
-fn is_old_format_topic(topic: &str) -> bool {
+fn is_old_format_topic(topic: &str, discovery_prefix: &str) -> bool {
+    let Some(topic) = topic.strip_prefix(discovery_prefix).and_then(|t| t.strip_prefix('/')) else {
+        return false;
+    };
+
     // Pattern 1: Old combined device discovery
     // homeassistant/device/e2m_*/config
-    if topic.starts_with("homeassistant/device/e2m_") && topic.ends_with("/config") {
+    if topic.starts_with("device/e2m_") && topic.ends_with("/config") {
         return true;
     }
 
@@ -27,12 +31,11 @@ fn is_old_format_topic(topic: &str) -> bool {
     // where {something} contains underscores but no path separators
     let parts: Vec<&str> = topic.split('/').collect();
 
-    // Expected old format: ["homeassistant", "{platform}", "e2m_*", "config"]
-    // Expected new format: ["homeassistant", "{platform}", "e2m_*", "{key}", "config"] or more segments
-    if parts.len() == 4
-        && parts[0] == "homeassistant"
-        && parts[2].starts_with("e2m_")
-        && parts[3] == "config"
+    // Expected old format: ["{platform}", "e2m_*", "config"] after the prefix
+    // Expected new format: ["{platform}", "e2m_*", "{key}", "config"] or more segments
+    if parts.len() == 3
+        && parts[1].starts_with("e2m_")
+        && parts[2] == "config"
     {
         // This is old format - flat structure with device_sensor all in one segment
         return true;
@@ -70,6 +73,7 @@ async fn run_cleanup(config: &MqttConfig) -> Result<bool, String> {
     let mut mqttoptions = MqttOptions::new(&client_name, &config.host, config.port);
     mqttoptions.set_keep_alive(Duration::from_secs(30));
     mqttoptions.set_credentials(&config.user, &config.pass);
+    super::tls::configure_tls(&mut mqttoptions, &config.tls)?;
     // Clean session to ensure we get all retained messages
     mqttoptions.set_clean_session(true);
 
@@ -100,11 +104,12 @@ async fn run_cleanup(config: &MqttConfig) -> Result<bool, String> {
         Err(_) => return Err("Connection timeout".to_string()),
     }
 
-    // Subscribe to all homeassistant topics to discover existing entries
-    client.subscribe("homeassistant/#", QoS::AtLeastOnce).await
+    // Subscribe to all discovery topics to find existing entries
+    let discovery_filter = format!("{}/#", config.discovery_prefix);
+    client.subscribe(discovery_filter.clone(), QoS::AtLeastOnce).await
         .map_err(|e| format!("Failed to subscribe for migration: {:?}", e))?;
 
-    info!("Subscribed to homeassistant/# - scanning for old discovery topics...");
+    info!("Subscribed to {discovery_filter} - scanning for old discovery topics...");
 
     // Collect topics that need cleanup
     let mut topics_to_delete: HashSet<String> = HashSet::new();
@@ -130,7 +135,7 @@ async fn run_cleanup(config: &MqttConfig) -> Result<bool, String> {
                     if p.retain && !p.payload.is_empty() && topic.contains("/e2m_") {
                         all_e2m_topics.insert(topic.clone());
 
-                        if is_old_format_topic(&topic) {
+                        if is_old_format_topic(&topic, &config.discovery_prefix) {
                             info!("Found old format topic to delete: {}", topic);
                             topics_to_delete.insert(topic);
                         } else {
@@ -241,23 +246,28 @@ mod tests {
     #[test]
     fn test_is_old_format_topic() {
         // Old device format
-        assert!(is_old_format_topic("homeassistant/device/e2m_knx_meter/config"));
-        assert!(is_old_format_topic("homeassistant/device/e2m_modbus_device/config"));
+        assert!(is_old_format_topic("homeassistant/device/e2m_knx_meter/config", "homeassistant"));
+        assert!(is_old_format_topic("homeassistant/device/e2m_modbus_device/config", "homeassistant"));
 
         // Old flat sensor format - these should be detected as OLD
-        assert!(is_old_format_topic("homeassistant/sensor/e2m_knx_meter_voltage/config"));
-        assert!(is_old_format_topic("homeassistant/sensor/e2m_knx_dg1_j_energy/config"));
-        assert!(is_old_format_topic("homeassistant/sensor/e2m_modbus_device_power_l1/config"));
-        assert!(is_old_format_topic("homeassistant/switch/e2m_knx_meter_switch/config"));
+        assert!(is_old_format_topic("homeassistant/sensor/e2m_knx_meter_voltage/config", "homeassistant"));
+        assert!(is_old_format_topic("homeassistant/sensor/e2m_knx_dg1_j_energy/config", "homeassistant"));
+        assert!(is_old_format_topic("homeassistant/sensor/e2m_modbus_device_power_l1/config", "homeassistant"));
+        assert!(is_old_format_topic("homeassistant/switch/e2m_knx_meter_switch/config", "homeassistant"));
 
         // New hierarchical format - should NOT be detected as old
-        assert!(!is_old_format_topic("homeassistant/sensor/e2m_knx_meter/voltage/config"));
-        assert!(!is_old_format_topic("homeassistant/sensor/e2m_knx_dg1_j/energy/config"));
-        assert!(!is_old_format_topic("homeassistant/sensor/e2m_modbus_device/power/l1/config"));
-        assert!(!is_old_format_topic("homeassistant/switch/e2m_knx_meter/switch/config"));
+        assert!(!is_old_format_topic("homeassistant/sensor/e2m_knx_meter/voltage/config", "homeassistant"));
+        assert!(!is_old_format_topic("homeassistant/sensor/e2m_knx_dg1_j/energy/config", "homeassistant"));
+        assert!(!is_old_format_topic("homeassistant/sensor/e2m_modbus_device/power/l1/config", "homeassistant"));
+        assert!(!is_old_format_topic("homeassistant/switch/e2m_knx_meter/switch/config", "homeassistant"));
 
         // Non-e2m topics should not match
-        assert!(!is_old_format_topic("homeassistant/sensor/other_device/config"));
-        assert!(!is_old_format_topic("homeassistant/sensor/something_else/config"));
+        assert!(!is_old_format_topic("homeassistant/sensor/other_device/config", "homeassistant"));
+        assert!(!is_old_format_topic("homeassistant/sensor/something_else/config", "homeassistant"));
+
+        // Only topics below the configured discovery prefix
+        assert!(is_old_format_topic("ha/discovery/sensor/e2m_knx_meter_voltage/config", "ha/discovery"));
+        assert!(!is_old_format_topic("ha/discovery/sensor/e2m_knx_meter/voltage/config", "ha/discovery"));
+        assert!(!is_old_format_topic("homeassistant/sensor/e2m_knx_meter_voltage/config", "ha/discovery"));
     }
 }
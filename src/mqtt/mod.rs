@@ -2,22 +2,33 @@ pub mod internal_commands;
 pub mod ha_interface;
 pub mod home_assistant;
 pub mod migration;
-
-use std::collections::HashMap;
+pub mod tls;
+pub mod buffer;
+pub mod availability;
+pub mod rate_limit;
+pub mod accumulator;
+pub mod deadband;
+pub mod rollup;
+pub mod backoff;
+pub mod probe;
+pub mod meter_errors;
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use lazy_static::lazy_static;
 use tokio::sync::RwLock;
 use std::io::Error;
+use crate::mqtt::buffer::{OfflineBuffer, RetainedMessages};
 use crate::mqtt::ha_interface::HaDiscover;
 use crate::mqtt::home_assistant::HaSensor;
 use crate::mqtt::migration::run_migration_if_needed;
-use crate::config::{ConfigBases, MQTT_DISCOVERY_VERSION_CURRENT};
+use crate::config::{ConfigBases, MqttConfig, MqttMessageFormat, MqttPublishConfig, MqttTlsConfig, MQTT_DISCOVERY_VERSION_CURRENT};
 use crate::models::DeviceProtocol;
 use crate::{CONFIG, get_config_or_panic, get_unix_ts};
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use tokio::sync::mpsc::{Receiver, Sender};
 use serde::{Serialize, Deserialize};
 use serde_json;
-use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+use rumqttc::{AsyncClient, ClientError, Event, EventLoop, MqttOptions, Packet, QoS};
 use std::time::{Duration, Instant};
 
 
@@ -35,6 +46,7 @@ pub struct MqttHealthStatus {
     pub last_connected: Option<Instant>,
     pub last_message_sent: Option<Instant>,
     pub last_message_received: Option<Instant>,
+    /// Successful and failed connects since the start
     pub connection_attempts: u64,
 }
 
@@ -42,6 +54,8 @@ pub struct MqttHealthStatus {
 pub struct AppStatus {
     pub start_time: Instant,
     pub mqtt_health: MqttHealthStatus,
+    /// Health of the additional brokers by name
+    pub brokers: BTreeMap<String, MqttHealthStatus>,
 }
 
 impl MqttHealthStatus {
@@ -61,6 +75,7 @@ impl AppStatus {
         Self {
             start_time: Instant::now(),
             mqtt_health: MqttHealthStatus::new(),
+            brokers: BTreeMap::new(),
         }
     }
 
@@ -127,6 +142,39 @@ pub struct SubscribeData {
     pub sender: tokio::sync::mpsc::Sender<(String, String)>
 }
 
+/// Device to be removed from Home Assistant, proto and device as used for its discovery.
+/// Devices linked to a removed device via `via_device` are removed as well.
+pub struct DiscoveryRemoveData {
+    pub proto: String,
+    pub device: String,
+    /// Only remove this component of the device
+    pub component: Option<String>,
+    /// Remove every device whose id starts with `device` followed by '_'
+    pub prefix: bool,
+}
+
+impl DiscoveryRemoveData {
+    pub fn device(proto: String, device: String) -> Self {
+        DiscoveryRemoveData { proto, device, component: None, prefix: false }
+    }
+
+    pub fn component(proto: String, device: String, key: String) -> Self {
+        DiscoveryRemoveData { proto, device, component: Some(key), prefix: false }
+    }
+
+    /* Used for devices named after their parent without being linked to it, like the homes of a Tibber account */
+    pub fn devices_with_prefix(proto: String, prefix: String) -> Self {
+        DiscoveryRemoveData { proto, device: prefix, component: None, prefix: true }
+    }
+}
+
+/// Failed reading of a meter, published retained until the next successful reading
+pub struct MeterErrorData {
+    pub protocol: String,
+    pub meter_name: String,
+    pub error: String,
+}
+
 pub struct TaskCrashData {
     pub manager: String,
     pub task_name: String,
@@ -139,16 +187,60 @@ pub enum Transmission {
     Metering(MeteringData),
     AutoDiscovery(HaDiscover),
     AutoDiscovery2(HaSensor),
+    AutoDiscoveryRemove(DiscoveryRemoveData),
     Command(CommandData),
     Subscribe(SubscribeData),
+    /// Subscribe to the topic as given, e.g. data other devices publish on the broker
+    SubscribeAbsolute(SubscribeData),
     Publish(PublishData),
     TaskCrash(TaskCrashData),
+    MeterError(MeterErrorData),
+}
+
+impl Transmission {
+    /// Home Assistant discovery, including plain publishes below the discovery prefix
+    pub fn is_discovery(&self, discovery_prefix: &str) -> bool {
+        match self {
+            Transmission::AutoDiscovery(_) | Transmission::AutoDiscovery2(_) | Transmission::AutoDiscoveryRemove(_) => true,
+            Transmission::Publish(p) => p.topic.strip_prefix(discovery_prefix).is_some_and(|t| t.starts_with('/')),
+            _ => false,
+        }
+    }
 }
 
 pub struct MqttManager {
     rx: Receiver<Transmission>,
     exit_thread: bool,
     client: AsyncClient,
+    mirrors: Vec<MirrorBroker>,
+    /// Retained messages replayed to additional brokers after they reconnect
+    retained: RetainedMessages,
+    topic_prefix: String,
+    topic_templates: BTreeMap<String, String>,
+    discovery_prefix: String,
+    ha_enabled: bool,
+    qos: QoS,
+    retain: bool,
+    publish_overrides: BTreeMap<String, MqttPublishConfig>,
+    message_formats: BTreeMap<String, MqttMessageFormat>,
+    buffer: OfflineBuffer,
+    availability_factor: f64,
+    accumulators: accumulator::EnergyAccumulators,
+    deadbands: deadband::DeadbandFilter,
+    rollups: rollup::PeriodRollups,
+    /* Meters by protocol path and name with the last error state published, true if it is an error */
+    meter_errors: HashMap<(String, String), bool>,
+    /* Entity discovery topics published per device, cleared when the device is removed */
+    discovery_topics: HashMap<String, BTreeSet<String>>,
+    /* Parent device id (lowercase) per device, devices linked to a removed device are removed with it */
+    discovery_via: HashMap<String, String>,
+}
+
+/// Connection to an additional broker every publish is mirrored to
+struct MirrorBroker {
+    name: String,
+    client: AsyncClient,
+    input: bool,
 }
 
 pub struct Callbacks {
@@ -266,6 +358,175 @@ impl LiveEvent {
     }
 }
 
+/// Payload of an incoming message as text, binary payloads are logged and skipped
+pub fn payload_to_string(topic: &str, payload: &[u8]) -> Option<String> {
+    match std::str::from_utf8(payload) {
+        Ok(s) => Some(s.to_string()),
+        Err(e) => {
+            warn!("Ignoring message on {topic}, payload is not UTF-8: {e}");
+            None
+        }
+    }
+}
+
+/// Map a configured QoS level to rumqttc, unknown levels fall back to at most once
+pub fn qos_from_u8(qos: u8) -> QoS {
+    match qos {
+        0 => QoS::AtMostOnce,
+        1 => QoS::AtLeastOnce,
+        2 => QoS::ExactlyOnce,
+        _ => QoS::AtMostOnce,
+    }
+}
+
+/// QoS and retain of the metering publishes of a protocol, falling back to the "default" entry
+/// and the global settings
+pub fn select_publish_options(overrides: &BTreeMap<String, MqttPublishConfig>, proto: &str, qos: QoS, retain: bool) -> (QoS, bool) {
+    let default = overrides.get("default");
+    let proto = overrides.get(proto);
+
+    let qos = proto.and_then(|o| o.qos)
+        .or_else(|| default.and_then(|o| o.qos))
+        .map(qos_from_u8)
+        .unwrap_or(qos);
+    let retain = proto.and_then(|o| o.retain)
+        .or_else(|| default.and_then(|o| o.retain))
+        .unwrap_or(retain);
+
+    (qos, retain)
+}
+
+/// Messages published for a meter, looked up by its id, then its protocol and then the "default" entry
+pub fn select_message_format(formats: &BTreeMap<String, MqttMessageFormat>, id: &str, proto: &str) -> MqttMessageFormat {
+    formats.get(id)
+        .or_else(|| formats.get(proto))
+        .or_else(|| formats.get("default"))
+        .copied()
+        .unwrap_or_default()
+}
+
+/// False if the readings of the discovered meter are not published to its state topic
+pub fn has_state_topic(formats: &BTreeMap<String, MqttMessageFormat>, disc: &HaSensor) -> bool {
+    disc.get_meter_id().is_empty() || select_message_format(formats, disc.get_meter_id(), disc.get_proto()).publishes_flat()
+}
+
+/// Take the known discovery topics addressed by a removal, including those of all devices linked to a removed device
+pub fn take_discovery_topics(topics: &mut HashMap<String, BTreeSet<String>>, via: &mut HashMap<String, String>,
+                             remove: &DiscoveryRemoveData) -> Vec<String> {
+    let device_id = home_assistant::discovery_device_id(&remove.proto, &remove.device);
+
+    if let Some(key) = &remove.component {
+        let suffix = format!("/{device_id}/{}/config", home_assistant::key_to_topic_path(key));
+        let Some(known) = topics.get_mut(&device_id) else {
+            return Vec::new();
+        };
+        let removed: Vec<String> = known.iter().filter(|t| t.ends_with(&suffix)).cloned().collect();
+        known.retain(|t| !t.ends_with(&suffix));
+        return removed;
+    }
+
+    let mut devices: BTreeSet<String> = if remove.prefix {
+        let prefix = format!("{device_id}_");
+        topics.keys().chain(via.keys()).filter(|id| id.starts_with(&prefix)).cloned().collect()
+    } else {
+        BTreeSet::from([device_id])
+    };
+
+    /* Children may have children of their own, collect until nothing new is linked */
+    loop {
+        let linked: Vec<String> = via.iter()
+            .filter(|(id, parent)| devices.contains(*parent) && !devices.contains(*id))
+            .map(|(id, _)| id.clone())
+            .collect();
+        if linked.is_empty() {
+            break;
+        }
+        devices.extend(linked);
+    }
+
+    devices.iter()
+        .flat_map(|id| {
+            via.remove(id);
+            topics.remove(id).unwrap_or_default()
+        })
+        .collect()
+}
+
+/// Prefix of the metering topics as configured
+pub fn get_topic_prefix() -> String {
+    match CONFIG.read().unwrap().get_copy("mqtt") {
+        Ok(ConfigBases::Mqtt(c)) => c.topic_prefix,
+        _ => "energy2mqtt".to_string(),
+    }
+}
+
+/// Home Assistant discovery prefix as configured
+pub fn get_discovery_prefix() -> String {
+    match CONFIG.read().unwrap().get_copy("mqtt") {
+        Ok(ConfigBases::Mqtt(c)) => c.discovery_prefix,
+        _ => "homeassistant".to_string(),
+    }
+}
+
+/// Meter topic used when no topic template is configured
+pub const DEFAULT_TOPIC_TEMPLATE: &str = "{prefix}/devs/{proto}/{name}";
+/// Built-in meter topic for meters assigned to a tenant
+pub const DEFAULT_TENANT_TOPIC_TEMPLATE: &str = "{prefix}/devs/{tenant}/{proto}/{name}";
+/// Tenant of meters without a configured tenant
+pub const DEFAULT_TENANT: &str = "default";
+
+/// Topic template of a protocol, falling back to the "default" entry and the built-in scheme
+pub fn select_topic_template<'a>(templates: &'a BTreeMap<String, String>, proto: &str, tenant: &str) -> &'a str {
+    let builtin = match tenant.is_empty() || tenant == DEFAULT_TENANT {
+        true => DEFAULT_TOPIC_TEMPLATE,
+        false => DEFAULT_TENANT_TOPIC_TEMPLATE,
+    };
+
+    templates.get(proto)
+        .or_else(|| templates.get("default"))
+        .map(|t| t.as_str())
+        .unwrap_or(builtin)
+}
+
+/// Fill the placeholders of a meter topic template, an empty tenant is "default"
+/// and an empty id falls back to the meter name
+pub fn render_topic_template(template: &str, prefix: &str, proto: &str, name: &str, tenant: &str, id: &str) -> String {
+    let tenant = if tenant.is_empty() { DEFAULT_TENANT } else { tenant };
+    let id = if id.is_empty() { name } else { id };
+
+    template.replace("{prefix}", prefix)
+        .replace("{proto}", proto)
+        .replace("{name}", name)
+        .replace("{tenant}", tenant)
+        .replace("{id}", id)
+}
+
+/// State topic of a meter as configured by the (protocol specific) topic template
+pub fn get_meter_topic(proto: &str, name: &str, tenant: &str, id: &str) -> String {
+    match CONFIG.read().unwrap().get_copy("mqtt") {
+        Ok(ConfigBases::Mqtt(c)) => {
+            render_topic_template(select_topic_template(&c.topic_templates, proto, tenant), &c.topic_prefix, proto, name, tenant, id)
+        },
+        _ => format!("energy2mqtt/devs/{proto}/{name}"),
+    }
+}
+
+/// Retained last error of a meter, see Transmission::MeterError. Not covered by the topic templates
+pub fn get_meter_error_topic(proto: &str, name: &str) -> String {
+    let prefix = CONFIG.read().unwrap().config.mqtt.topic_prefix.clone();
+    format!("{prefix}/devs/{proto}/{name}/error")
+}
+
+/// Retained bridge availability, "online" after connecting and "offline" via the last will
+pub fn get_availability_topic() -> String {
+    format!("{}/status", get_topic_prefix())
+}
+
+/// Requests queued for an additional broker, large enough for the discovery burst at startup
+const MIRROR_QUEUE_SIZE: usize = 1000;
+/// Longest wait for a slot in the queue of an additional broker before its message is dropped
+const MIRROR_PUBLISH_TIMEOUT: Duration = Duration::from_secs(1);
+
 lazy_static! {
     pub static ref CALLBACKS: RwLock<Callbacks> = RwLock::new(Callbacks::new());
     pub static ref APP_STATUS: RwLock<AppStatus> = RwLock::new(AppStatus::new());
@@ -275,177 +536,476 @@ lazy_static! {
     };
 }
 
-impl MqttManager {
-    pub fn new() -> Result<(Self, Sender<Transmission>), Error> {
-        let (mtx,mrx) = tokio::sync::mpsc::channel(100);
+/// Update the health of the main broker (None) or of an additional broker
+async fn update_health<F: FnOnce(&mut MqttHealthStatus)>(broker: &Option<String>, update: F) {
+    let mut app_status = APP_STATUS.write().await;
+    match broker {
+        None => update(&mut app_status.mqtt_health),
+        Some(name) => update(app_status.brokers.entry(name.clone()).or_insert_with(MqttHealthStatus::new)),
+    }
+}
 
-        info!("MQTT connection starting up");
-        let config = get_config_or_panic!("mqtt", ConfigBases::Mqtt);
+/// Connection options of a broker without TLS and last will
+pub fn client_options(client_name: &str, host: &str, port: u16, user: &str, pass: &str) -> MqttOptions {
+    let mut mqttoptions   = MqttOptions::new(client_name, host, port);
+    mqttoptions.set_keep_alive(Duration::from_secs(5));
+    mqttoptions.set_credentials(user, pass);
+    mqttoptions
+}
 
-        // Check if migration is needed (will be run async after connection)
-        let needs_migration = config.discovery_version < MQTT_DISCOVERY_VERSION_CURRENT;
-        if needs_migration {
-            info!("MQTT discovery migration pending: version {} -> {}",
-                  config.discovery_version, MQTT_DISCOVERY_VERSION_CURRENT);
-        }
-        let mut mqttoptions   = MqttOptions::new(config.client_name.clone(), config.host.clone(), config.port);
-        mqttoptions.set_keep_alive(Duration::from_secs(5));
-        mqttoptions.set_credentials(config.user.clone(), config.pass.clone());
+/// Create a broker connection, the last will marks the bridge offline on every broker.
+/// If TLS is enabled but can not be set up there is no event loop, we never fall back to
+/// plaintext. Publishes on the client fail right away in that case.
+fn create_client(client_name: &str, host: &str, port: u16, user: &str, pass: &str, tls: &MqttTlsConfig, queue_size: usize) -> (AsyncClient, Result<EventLoop, String>) {
+    let mut mqttoptions = client_options(client_name, host, port, user, pass);
+    let tls_result = tls::configure_tls(&mut mqttoptions, tls)
+        .map_err(|e| format!("TLS setup for {}:{} failed: {}", host, port, e));
+
+    info!("Connection setup to {}@{}:{}", user, host, port);
+
+    // Set last will message for availability - broker publishes "offline" if we disconnect unexpectedly
+    let last_will = rumqttc::LastWill::new(
+        get_availability_topic(),
+        "offline".as_bytes().to_vec(),
+        QoS::AtLeastOnce,
+        true  // retain = true so new subscribers see the status
+    );
+    mqttoptions.set_last_will(last_will);
+
+    let (client, eventloop) = AsyncClient::new(mqttoptions, queue_size);
+    (client, tls_result.map(|_| eventloop))
+}
 
-        info!("Connection setup to {}@{}:{}", config.user, config.host, config.port);
+/// Drive the connection of a broker or report why it is not connected
+fn start_broker(broker: Option<String>, client: &AsyncClient, eventloop: Result<EventLoop, String>, input: bool,
+                migration_config: Option<MqttConfig>, replay: Option<RetainedMessages>) {
+    match eventloop {
+        Ok(eventloop) => spawn_eventloop(broker, client.clone(), eventloop, input, migration_config, replay),
+        Err(e) => {
+            error!("Not connecting to {} broker: {}", broker.as_deref().unwrap_or("main"), e);
+            tokio::spawn(async move {
+                update_health(&broker, |health| health.status = MqttConnectionStatus::Error(e)).await;
+            });
+        }
+    }
+}
 
-        // Set last will message for availability - broker publishes "offline" if we disconnect unexpectedly
-        let last_will = rumqttc::LastWill::new(
-            "energy2mqtt/status",
-            "offline".as_bytes().to_vec(),
-            QoS::AtLeastOnce,
-            true  // retain = true so new subscribers see the status
-        );
-        mqttoptions.set_last_will(last_will);
-
-        let (client, mut eventloop) = AsyncClient::new(mqttoptions, 10);
-
-        // Spawn a new thread to handle the incomming commands
-        let reconnect_c = client.clone();
-        let migration_config = config.clone();
-        let migration_needed = needs_migration;
-        tokio::spawn( async move {
-            info!("MQTT Eventloop started");
-
-            // Backoff state for reconnection
-            let mut backoff_secs: u64 = 1;
-            const MAX_BACKOFF_SECS: u64 = 60;
-            const INITIAL_BACKOFF_SECS: u64 = 1;
-            let mut last_error_log = Instant::now();
-            let mut consecutive_errors: u32 = 0;
-            let mut migration_done = !migration_needed;
-
-            loop {
-                match eventloop.poll().await {
-                    Ok(Event::Incoming(Packet::Publish(p))) => {
-                        /* TODO: Handle incomming commands! */
-                        let topic = p.topic;
-                        let payload = String::from_utf8(p.payload.to_vec()).unwrap();
-                        debug!("Received MQTT command {payload:?}");
-
-                        // Broadcast incoming message to live view
-                        let payload_json = serde_json::from_str(&payload)
-                            .unwrap_or_else(|_| serde_json::Value::String(payload.clone()));
-                        let live_event = LiveEvent::incoming(topic.clone(), payload_json);
-                        let _ = LIVE_EVENTS.send(live_event);
+/// Drive the connection of a broker, only input brokers get subscriptions and forward incoming messages.
+/// The discovery migration is only run against the main broker, additional brokers get the
+/// retained messages in replay again after every connect.
+fn spawn_eventloop(broker: Option<String>, reconnect_c: AsyncClient, mut eventloop: EventLoop, input: bool,
+                   migration_config: Option<MqttConfig>, replay: Option<RetainedMessages>) {
+    let label = broker.clone().unwrap_or_else(|| "main".to_string());
+
+    // Spawn a new thread to handle the incomming commands
+    tokio::spawn( async move {
+        info!("MQTT Eventloop for {label} broker started");
+
+        let mut backoff = backoff::Backoff::from_config();
+        let mut last_error_log = Instant::now();
+        let mut migration_done = migration_config.is_none();
+        let (input_rate_limits, topic_prefix) = {
+            let config = CONFIG.read().unwrap();
+            (config.config.mqtt.input_rate_limits.clone(), config.config.mqtt.topic_prefix.clone())
+        };
+        let mut rate_limiter = rate_limit::RateLimiter::new(input_rate_limits, topic_prefix);
+
+        loop {
+            match eventloop.poll().await {
+                Ok(Event::Incoming(Packet::Publish(p))) => {
+                    update_health(&broker, |health| health.last_message_received = Some(Instant::now())).await;
+                    if !input {
+                        continue;
+                    }
 
-                        CALLBACKS.write().await.send(topic.clone(), payload.clone()).await;
-                    },
-                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
-                        info!("MQTT Connected, resubscribing everything");
+                    let topic = p.topic;
+                    if !rate_limiter.allow(&topic, Instant::now()) {
+                        rate_limit::record_dropped(rate_limiter.input_name(&topic));
+                        debug!("Input rate limit of {topic} exceeded, dropping message");
+                        continue;
+                    }
 
-                        // Reset backoff on successful connection
-                        backoff_secs = INITIAL_BACKOFF_SECS;
-                        consecutive_errors = 0;
+                    let Some(payload) = payload_to_string(&topic, &p.payload) else {
+                        continue;
+                    };
+                    debug!("Received MQTT command {payload:?}");
 
-                        // Update health status to connected
-                        {
-                            let mut app_status = APP_STATUS.write().await;
-                            app_status.mqtt_health.status = MqttConnectionStatus::Connected;
-                            app_status.mqtt_health.last_connected = Some(Instant::now());
-                            app_status.mqtt_health.connection_attempts += 1;
-                        }
+                    // Broadcast incoming message to live view
+                    let payload_json = serde_json::from_str(&payload)
+                        .unwrap_or_else(|_| serde_json::Value::String(payload.clone()));
+                    let live_event = LiveEvent::incoming(topic.clone(), payload_json);
+                    let _ = LIVE_EVENTS.send(live_event);
 
-                        // Run migration if needed (only once on first connect)
-                        if !migration_done {
-                            info!("Running MQTT discovery migration...");
-                            let mig_config = migration_config.clone();
-                            match run_migration_if_needed(&mig_config).await {
-                                Ok(true) => {
-                                    info!("Migration completed, updating config version");
-                                    // Update config with new version
-                                    if let Ok(mut cfg) = CONFIG.write() {
-                                        cfg.config.mqtt.discovery_version = MQTT_DISCOVERY_VERSION_CURRENT;
-                                        cfg.dirty = true;
-                                        cfg.save();
-                                        info!("Config updated to discovery version {}", MQTT_DISCOVERY_VERSION_CURRENT);
-                                    }
-                                }
-                                Ok(false) => {
-                                    debug!("No migration was needed");
-                                }
-                                Err(e) => {
-                                    error!("Migration failed: {}", e);
+                    CALLBACKS.write().await.send(topic.clone(), payload.clone()).await;
+                },
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    info!("MQTT Connected to {label} broker, resubscribing everything");
+
+                    // Reset backoff on successful connection
+                    backoff.reset();
+
+                    // Update health status to connected
+                    update_health(&broker, |health| {
+                        health.status = MqttConnectionStatus::Connected;
+                        health.last_connected = Some(Instant::now());
+                        health.connection_attempts += 1;
+                    }).await;
+
+                    // Run migration if needed (only once on first connect)
+                    if !migration_done {
+                        info!("Running MQTT discovery migration...");
+                        let mig_config = migration_config.clone().unwrap();
+                        match run_migration_if_needed(&mig_config).await {
+                            Ok(true) => {
+                                info!("Migration completed, updating config version");
+                                // Update config with new version
+                                if let Ok(mut cfg) = CONFIG.write() {
+                                    cfg.config.mqtt.discovery_version = MQTT_DISCOVERY_VERSION_CURRENT;
+                                    cfg.dirty = true;
+                                    cfg.save();
+                                    info!("Config updated to discovery version {}", MQTT_DISCOVERY_VERSION_CURRENT);
                                 }
                             }
-                            migration_done = true;
+                            Ok(false) => {
+                                debug!("No migration was needed");
+                            }
+                            Err(e) => {
+                                error!("Migration failed: {}", e);
+                            }
                         }
+                        migration_done = true;
+                    }
 
-                        // Publish "online" availability status (retained)
-                        let online_client = reconnect_c.clone();
+                    // Publish "online" availability status (retained)
+                    let online_client = reconnect_c.clone();
+                    let online_label = label.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = online_client.publish(
+                            get_availability_topic(),
+                            QoS::AtLeastOnce,
+                            true,  // retain
+                            "online"
+                        ).await {
+                            error!("Failed to publish online status to {online_label} broker: {:?}", e);
+                        } else {
+                            info!("Published online availability status to {online_label} broker");
+                        }
+                    });
+
+                    /* Discovery and states published while the broker was down */
+                    if let Some(replay) = &replay {
+                        let messages = replay.snapshot();
+                        let replay_client = reconnect_c.clone();
+                        let replay_label = label.clone();
                         tokio::spawn(async move {
-                            if let Err(e) = online_client.publish(
-                                "energy2mqtt/status",
-                                QoS::AtLeastOnce,
-                                true,  // retain
-                                "online"
-                            ).await {
-                                error!("Failed to publish online status: {:?}", e);
-                            } else {
-                                info!("Published online availability status");
+                            info!("Replaying {} retained messages to {replay_label} broker", messages.len());
+                            for msg in messages {
+                                if let Err(e) = replay_client.publish(msg.topic, msg.qos, true, msg.payload).await {
+                                    error!("Replaying retained messages to {replay_label} broker failed: {:?}", e);
+                                    break;
+                                }
                             }
                         });
+                    }
 
-                        /* We are connected resubstribe to everything */
-                        let callbacks = CALLBACKS.read().await.get_topics().await;
-                        for callback in callbacks {
-                            /* Move the resubscription to it's own thread */
-                            let client_clone = reconnect_c.clone();
-                            tokio::spawn(async move {
-                                let _ = client_clone.subscribe(callback, QoS::AtLeastOnce).await.unwrap();
-                            });
-                        }
-                    },
-                    Ok(Event::Incoming(Packet::Disconnect)) => {
-                        info!("MQTT Disconnected");
-                        let mut app_status = APP_STATUS.write().await;
-                        app_status.mqtt_health.status = MqttConnectionStatus::Disconnected;
-                    },
-                    Ok(_) => {},
-                    Err(e) => {
-                        consecutive_errors += 1;
-
-                        // Only log errors periodically to avoid flooding
-                        let now = Instant::now();
-                        if consecutive_errors == 1 || now.duration_since(last_error_log).as_secs() >= 30 {
-                            error!("MQTT connection error: to {:?} (attempt {}, next retry in {}s)",
-                                   e, consecutive_errors, backoff_secs);
-                            last_error_log = now;
-                        }
-
-                        // Update health status on error
-                        {
-                            let mut app_status = APP_STATUS.write().await;
-                            app_status.mqtt_health.status = MqttConnectionStatus::Reconnecting;
-                        }
-
-                        // Apply backoff delay before next reconnection attempt
-                        tokio::time::sleep(Duration::from_secs(backoff_secs)).await;
+                    if !input {
+                        continue;
+                    }
 
-                        // Exponential backoff with max limit
-                        backoff_secs = (backoff_secs * 2).min(MAX_BACKOFF_SECS);
+                    /* We are connected resubstribe to everything */
+                    let callbacks = CALLBACKS.read().await.get_topics().await;
+                    for callback in callbacks {
+                        /* Move the resubscription to it's own thread */
+                        let client_clone = reconnect_c.clone();
+                        let subscribe_label = label.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = client_clone.subscribe(callback.clone(), QoS::AtLeastOnce).await {
+                                error!("Resubscribing {} on {subscribe_label} broker failed: {:?}", callback, e);
+                            }
+                        });
                     }
+                },
+                Ok(Event::Incoming(Packet::Disconnect)) => {
+                    info!("MQTT Disconnected from {label} broker");
+                    update_health(&broker, |health| health.status = MqttConnectionStatus::Disconnected).await;
+                },
+                Ok(_) => {},
+                Err(e) => {
+                    let delay = backoff.next_delay();
+
+                    // Only log errors periodically to avoid flooding
+                    let now = Instant::now();
+                    if backoff.attempts() == 1 || now.duration_since(last_error_log).as_secs() >= 30 {
+                        error!("MQTT connection error: to {label} broker {:?} (attempt {}, next retry in {:?})",
+                               e, backoff.attempts(), delay);
+                        last_error_log = now;
+                    }
+
+                    // Reconnecting while we wait for the next attempt, rumqttc reconnects on the next poll
+                    update_health(&broker, |health| {
+                        health.status = MqttConnectionStatus::Reconnecting;
+                        health.connection_attempts += 1;
+                    }).await;
+                    tokio::time::sleep(delay).await;
                 }
             }
-        });
+        }
+    });
+}
+
+impl MqttManager {
+    pub fn new() -> Result<(Self, Sender<Transmission>), Error> {
+        let (mtx,mrx) = tokio::sync::mpsc::channel(100);
+
+        info!("MQTT connection starting up");
+        let config = get_config_or_panic!("mqtt", ConfigBases::Mqtt);
+
+        // Check if migration is needed (will be run async after connection)
+        let needs_migration = config.discovery_version < MQTT_DISCOVERY_VERSION_CURRENT;
+        if needs_migration {
+            info!("MQTT discovery migration pending: version {} -> {}",
+                  config.discovery_version, MQTT_DISCOVERY_VERSION_CURRENT);
+        }
+
+        let (client, eventloop) = create_client(&config.client_name, &config.host, config.port,
+                                                &config.user, &config.pass, &config.tls, 10);
+        start_broker(None, &client, eventloop, true, needs_migration.then(|| config.clone()), None);
+
+        let retained = RetainedMessages::new();
+
+        let mut mirrors = Vec::new();
+        for broker in &config.brokers {
+            info!("Mirroring MQTT data to broker {}", broker.name);
+            let (mirror_client, mirror_eventloop) = create_client(&broker.client_name, &broker.host, broker.port,
+                                                                  &broker.user, &broker.pass, &broker.tls, MIRROR_QUEUE_SIZE);
+            start_broker(Some(broker.name.clone()), &mirror_client, mirror_eventloop, broker.input, None, Some(retained.clone()));
+            mirrors.push(MirrorBroker { name: broker.name.clone(), client: mirror_client, input: broker.input });
+        }
 
         return Ok((MqttManager {
             client: client,
+            mirrors,
+            retained,
             rx: mrx,
             exit_thread: false,
+            topic_prefix: config.topic_prefix.clone(),
+            topic_templates: config.topic_templates.clone(),
+            discovery_prefix: config.discovery_prefix.clone(),
+            ha_enabled: config.ha_enabled,
+            qos: qos_from_u8(config.qos),
+            retain: config.retain,
+            publish_overrides: config.publish_overrides.clone(),
+            message_formats: config.message_formats.clone(),
+            buffer: OfflineBuffer::new(config.offline_buffer_size),
+            availability_factor: config.availability_factor,
+            accumulators: accumulator::EnergyAccumulators::new(config.energy_accumulators.clone()),
+            deadbands: deadband::DeadbandFilter::new(config.deadbands.clone()),
+            rollups: rollup::PeriodRollups::new(config.rollups.clone()),
+            meter_errors: HashMap::new(),
+            discovery_topics: HashMap::new(),
+            discovery_via: HashMap::new(),
         }, mtx));
     }
 
+    /// Database the energy accumulators and rollups keep their state in
+    pub fn set_device_db(&mut self, db: Option<std::sync::Arc<crate::device_manager::db::DeviceDb>>) {
+        self.accumulators.set_db(db.clone());
+        self.rollups.set_db(db);
+    }
+
+    /// Publish the hourly and daily totals of the configured counters below the device topic
+    /// and to {prefix}/raw, as the message format of the meter asks for
+    async fn publish_rollups(&mut self, data: &MeteringData, proto_path: &str) {
+        let rollups = self.rollups.apply(data);
+        if rollups.is_empty() {
+            return;
+        }
+
+        let format = select_message_format(&self.message_formats, &data.id, proto_path);
+        let template = select_topic_template(&self.topic_templates, proto_path, &data.tenant);
+        let dev_topic = render_topic_template(template, &self.topic_prefix, proto_path,
+                                              &data.meter_name, &data.tenant, &data.id);
+        let (qos, retain) = select_publish_options(&self.publish_overrides, proto_path, self.qos, self.retain);
+
+        for (period, rollup) in rollups {
+            if format.publishes_envelope() {
+                let raw_topic = format!("{}/raw", self.topic_prefix);
+                let live_event = LiveEvent::outgoing(LiveEventType::Metering, raw_topic.clone(),
+                                                     serde_json::to_value(&rollup).unwrap_or_default());
+                let _ = LIVE_EVENTS.send(live_event);
+
+                let _ = self.publish_metering(raw_topic, serde_json::to_string(&rollup).unwrap(), qos, false).await;
+            }
+
+            if format.publishes_flat() {
+                let topic = format!("{dev_topic}/{}", period.name());
+                let mut values = rollup.metered_values.clone();
+                values.insert("transmission_type".to_string(), serde_json::to_value(&rollup.transmission_type).unwrap_or_default());
+                let live_event = LiveEvent::outgoing(LiveEventType::Metering, topic.clone(),
+                                                     serde_json::Value::Object(values.clone()));
+                let _ = LIVE_EVENTS.send(live_event);
+
+                let payload = serde_json::to_string(&values).unwrap();
+                let _ = self.publish_metering(topic, payload, qos, retain).await;
+            }
+        }
+    }
+
+    /// Mirror a publish to all connected additional brokers, a broker which is down never blocks us.
+    /// Retained messages are remembered and replayed once a broker connects again.
+    async fn mirror(&self, topic: &str, qos: QoS, retain: bool, payload: &str) {
+        if self.mirrors.is_empty() {
+            return;
+        }
+
+        if retain {
+            self.retained.remember(topic, qos, payload);
+        }
+
+        let app_status = APP_STATUS.read().await;
+        for mirror in &self.mirrors {
+            let connected = app_status.brokers.get(&mirror.name)
+                .is_some_and(|h| matches!(h.status, MqttConnectionStatus::Connected));
+            if !connected {
+                continue;
+            }
+
+            match tokio::time::timeout(MIRROR_PUBLISH_TIMEOUT, mirror.client.publish(topic, qos, retain, payload)).await {
+                Ok(Ok(())) => {},
+                Ok(Err(e)) => warn!("Unable to mirror {} to broker {}: {}", topic, mirror.name, e),
+                Err(_) => warn!("Mirroring {} to broker {} timed out", topic, mirror.name),
+            }
+        }
+    }
+
+    /// Publish to the main broker and mirror it to all additional brokers
+    async fn publish(&self, topic: String, qos: QoS, retain: bool, payload: String) -> Result<(), ClientError> {
+        self.mirror(&topic, qos, retain, &payload).await;
+        self.client.publish(topic, qos, retain, payload).await
+    }
+
+    /// Subscribe on the main and all input brokers and route the messages to the callback
+    async fn subscribe(&self, topic: String, sender: tokio::sync::mpsc::Sender<(String, String)>) {
+        if self.client.subscribe(topic.clone(), QoS::AtLeastOnce).await.is_err() {
+            return;
+        }
+
+        /* Input brokers which are down subscribe once they are connected again */
+        for mirror in self.mirrors.iter().filter(|m| m.input) {
+            if let Err(e) = mirror.client.try_subscribe(topic.clone(), QoS::AtLeastOnce) {
+                debug!("Subscription of {} on broker {} postponed: {}", topic, mirror.name, e);
+            }
+        }
+
+        CALLBACKS.write().await.insert(topic.clone(), sender);
+        info!("Registered Callback {topic}");
+
+        // Broadcast subscribe to live view
+        let live_event = LiveEvent::outgoing(
+            LiveEventType::Subscribe,
+            topic,
+            serde_json::json!({"action": "subscribe"})
+        );
+        let _ = LIVE_EVENTS.send(live_event);
+    }
+
+    async fn is_connected() -> bool {
+        matches!(APP_STATUS.read().await.mqtt_health.status, MqttConnectionStatus::Connected)
+    }
+
+    /// Publish metering data or keep it in the offline buffer if the broker is not reachable
+    async fn publish_metering(&mut self, topic: String, payload: String, qos: QoS, retain: bool) -> bool {
+        if !Self::is_connected().await {
+            /* Additional brokers do not buffer, they get the data as long as they are up */
+            self.mirror(&topic, qos, retain, &payload).await;
+            if !self.buffer.push(topic, payload, qos, retain) {
+                warn!("MQTT offline buffer full, dropped metering data ({} lost so far)", self.buffer.dropped());
+            }
+            return false;
+        }
+
+        /* Keep the order, older data goes first */
+        self.flush_buffer().await;
+        self.mirror(&topic, qos, retain, &payload).await;
+
+        match self.client.publish(topic.clone(), qos, retain, payload.clone()).await {
+            Err(e) => {
+                error!("Error sending: {}", e);
+                self.buffer.push(topic, payload, qos, retain);
+                false
+            },
+            Ok(_) => true,
+        }
+    }
+
+    /// Replay everything buffered while we were disconnected
+    async fn flush_buffer(&mut self) {
+        if self.buffer.is_empty() || !Self::is_connected().await {
+            return;
+        }
+
+        info!("MQTT connected again, replaying {} buffered metering messages", self.buffer.len());
+        while let Some(msg) = self.buffer.pop() {
+            if let Err(e) = self.client.publish(msg.topic.clone(), msg.qos, msg.retain, msg.payload.clone()).await {
+                error!("Error replaying buffered data: {}", e);
+                self.buffer.push_front(msg);
+                break;
+            }
+        }
+    }
+
+    /// Publish the retained availability of a single meter, not covered by the topic templates
+    async fn publish_availability(&self, proto_path: &str, meter_name: &str, online: bool) {
+        let topic = format!("{}/devs/{}/{}/availability", self.topic_prefix, proto_path, meter_name);
+        let payload = if online { "online" } else { "offline" };
+
+        if let Err(e) = self.publish(topic, self.qos, true, payload.to_string()).await {
+            error!("Error sending availability of {}/{}: {}", proto_path, meter_name, e);
+        }
+    }
+
+    /// Publish the last error of a meter, None clears it after a successful reading
+    async fn publish_meter_error(&mut self, proto_path: &str, meter_name: &str, error: Option<&str>) {
+        meter_errors::record_error(proto_path, meter_name, error, get_unix_ts());
+
+        let key = (proto_path.to_string(), meter_name.to_string());
+        /* Clear once after the start in case an old error is still retained */
+        if error.is_none() && self.meter_errors.get(&key) == Some(&false) {
+            return;
+        }
+        self.meter_errors.insert(key, error.is_some());
+
+        let topic = format!("{}/devs/{}/{}/error", self.topic_prefix, proto_path, meter_name);
+        let payload = serde_json::json!({ "error": error, "timestamp": get_unix_ts() });
+        let _ = LIVE_EVENTS.send(LiveEvent::outgoing(LiveEventType::System, topic.clone(), payload.clone()).with_retain(true));
+
+        if let Err(e) = self.publish(topic, QoS::AtLeastOnce, true, payload.to_string()).await {
+            error!("Error sending error state of {}/{}: {}", proto_path, meter_name, e);
+        }
+    }
+
+    /// Report meters which stopped sending data as offline
+    async fn check_availability(&self) {
+        for (proto_path, meter_name) in availability::check_timeouts(crate::get_unix_ts(), self.availability_factor) {
+            warn!("Meter {}/{} missed its interval, marking it offline", proto_path, meter_name);
+            self.publish_availability(&proto_path, &meter_name, false).await;
+        }
+    }
+
     pub async fn start_thread(&mut self, broadcast: tokio::sync::broadcast::Sender<String>) {
-       
+        let mut flush_interval = tokio::time::interval(Duration::from_secs(1));
+
         // Handle all the incomming metering stuff
         while !self.exit_thread {
-            let option = self.rx.recv().await;
+            let option = tokio::select! {
+                option = self.rx.recv() => option,
+                /* Replay buffered data even if no new data arrives */
+                _ = flush_interval.tick() => {
+                    self.flush_buffer().await;
+                    self.check_availability().await;
+                    continue;
+                }
+            };
 
             if option.is_none() {
                 debug!("Reading returned none, we exit now");
@@ -454,50 +1014,84 @@ impl MqttManager {
             }
             
             match option.unwrap() {
-                Transmission::Metering(data) => {
+                Transmission::Metering(mut data) => {
                     info!("Metering data received: {}", data.id);
-                    let raw_topic = "energy2mqtt/raw".to_string();
-                    let raw_payload = serde_json::to_string(&data).unwrap();
+                    self.accumulators.apply(&mut data);
+                    crate::prometheus::update_latest_values(&data);
 
-                    // Broadcast to live view
-                    let live_event = LiveEvent::outgoing(
-                        LiveEventType::Metering,
-                        raw_topic.clone(),
-                        serde_json::to_value(&data).unwrap_or_default()
-                    );
-                    let _ = LIVE_EVENTS.send(live_event);
+                    let mut proto_path = data.protocol.to_string();
+                    if !data.state_topic_base.is_empty() {
+                        proto_path = data.state_topic_base.clone();
+                    }
 
-                    match self.client.publish(raw_topic, QoS::AtLeastOnce, false, raw_payload).await {
-                        Err(e) => { error!("Error sending: {}", e); },
-                        Ok(_) => {
-                            debug!("Send successfully");
-                            // Update health status
-                            tokio::spawn(async {
-                                let mut app_status = APP_STATUS.write().await;
-                                app_status.mqtt_health.last_message_sent = Some(Instant::now());
-                            });
+                    /* Any reading clears the last error, even one the deadbands hold back */
+                    self.publish_meter_error(&proto_path, &data.meter_name, None).await;
+
+                    /* Totals follow every reading, even those the deadbands hold back */
+                    self.publish_rollups(&data, &proto_path).await;
+
+                    if !self.deadbands.should_publish(&data, crate::get_unix_ts()) {
+                        debug!("Values of {} within their deadbands, not publishing", data.id);
+                        /* The meter is alive, only its values did not change enough */
+                        if availability::record_seen(&proto_path, &data.meter_name, crate::get_unix_ts()) {
+                            self.publish_availability(&proto_path, &data.meter_name, true).await;
                         }
+                        continue;
                     }
 
-                    let _ = broadcast.send(serde_json::to_string_pretty(&data).unwrap());
-                    let mut proto_path = data.protocol.to_string();
-                    if !data.state_topic_base.is_empty() {
-                        proto_path = data.state_topic_base.clone();
+                    let format = select_message_format(&self.message_formats, &data.id, &proto_path);
+                    let (qos, retain) = select_publish_options(&self.publish_overrides, &proto_path, self.qos, self.retain);
+                    let mut sent = false;
+
+                    if format.publishes_envelope() {
+                        let raw_topic = format!("{}/raw", self.topic_prefix);
+                        let raw_payload = serde_json::to_string(&data).unwrap();
+
+                        // Broadcast to live view
+                        let live_event = LiveEvent::outgoing(
+                            LiveEventType::Metering,
+                            raw_topic.clone(),
+                            serde_json::to_value(&data).unwrap_or_default()
+                        );
+                        let _ = LIVE_EVENTS.send(live_event);
+
+                        /* The raw topic is shared by all meters, retaining it makes no sense */
+                        sent |= self.publish_metering(raw_topic, raw_payload, qos, false).await;
                     }
 
-                    let dev_topic = format!("energy2mqtt/devs/{}/{}", proto_path, data.meter_name);
-                    let dev_payload = serde_json::to_string(&data.metered_values.clone()).unwrap();
+                    let _ = broadcast.send(serde_json::to_string_pretty(&data).unwrap());
 
-                    // Broadcast device topic to live view
-                    let live_event = LiveEvent::outgoing(
-                        LiveEventType::Metering,
-                        dev_topic.clone(),
-                        serde_json::Value::Object(data.metered_values.clone())
-                    );
-                    let _ = LIVE_EVENTS.send(live_event);
+                    if format.publishes_flat() {
+                        let template = select_topic_template(&self.topic_templates, &proto_path, &data.tenant);
+                        let dev_topic = render_topic_template(template, &self.topic_prefix, &proto_path,
+                                                              &data.meter_name, &data.tenant, &data.id);
+                        let dev_payload = serde_json::to_string(&data.metered_values.clone()).unwrap();
 
-                    let _ = self.client.publish(dev_topic, QoS::AtLeastOnce, false, dev_payload).await;
+                        // Broadcast device topic to live view
+                        let live_event = LiveEvent::outgoing(
+                            LiveEventType::Metering,
+                            dev_topic.clone(),
+                            serde_json::Value::Object(data.metered_values.clone())
+                        );
+                        let _ = LIVE_EVENTS.send(live_event);
 
+                        sent |= self.publish_metering(dev_topic, dev_payload, qos, retain).await;
+                    }
+
+                    if sent {
+                        debug!("Send successfully");
+                        // Update health status
+                        tokio::spawn(async {
+                            let mut app_status = APP_STATUS.write().await;
+                            app_status.mqtt_health.last_message_sent = Some(Instant::now());
+                        });
+                    } else {
+                        debug!("Metering data buffered, broker not reachable");
+                    }
+
+                    if availability::record_seen(&proto_path, &data.meter_name, crate::get_unix_ts()) {
+                        self.publish_availability(&proto_path, &data.meter_name, true).await;
+                    }
                 },
                 Transmission::Command(command) => {
                     let payload_json = serde_json::from_str(&command.value)
@@ -506,7 +1100,10 @@ impl MqttManager {
                         .with_retain(command.retain);
                     let _ = LIVE_EVENTS.send(live_event);
 
-                    let _ = self.client.publish(command.topic, QoS::AtLeastOnce, command.retain, command.value).await;
+                    let _ = self.publish(command.topic, QoS::AtLeastOnce, command.retain, command.value).await;
+                },
+                t if !self.ha_enabled && t.is_discovery(&self.discovery_prefix) => {
+                    debug!("Home Assistant is disabled, skipping discovery");
                 },
                 Transmission::AutoDiscovery(disc) => {
                     let topic = disc.discover_topic.clone();
@@ -515,11 +1112,21 @@ impl MqttManager {
                         .with_retain(true);
                     let _ = LIVE_EVENTS.send(live_event);
 
-                    let _ = self.client.publish(topic, QoS::AtLeastOnce, true, serde_json::to_string(&disc).unwrap()).await;
+                    let _ = self.publish(topic, QoS::AtLeastOnce, true, serde_json::to_string(&disc).unwrap()).await;
+                },
+                Transmission::AutoDiscovery2(disc) if !has_state_topic(&self.message_formats, &disc) => {
+                    /* The entities would point at the device topic, which this meter does not publish to */
+                    warn!("Skipping Home Assistant discovery of {}, its readings are only published to {}/raw",
+                          disc.get_device_id(), self.topic_prefix);
                 },
-                Transmission::AutoDiscovery2(disc) => {
+                Transmission::AutoDiscovery2(mut disc) => {
+                    self.rollups.add_discovery(&mut disc);
+
                     // Send individual discovery messages per entity to avoid MQTT size limits
                     let discoveries = disc.get_entity_discoveries();
+                    let known_topics = self.discovery_topics.entry(disc.get_device_id()).or_default();
+                    known_topics.extend(discoveries.iter().map(|d| d.topic.clone()));
+                    self.discovery_via.insert(disc.get_device_id(), disc.get_via().to_lowercase());
 
                     for entity_disc in discoveries {
                         let live_event = LiveEvent::outgoing(
@@ -529,7 +1136,7 @@ impl MqttManager {
                         ).with_retain(true);
                         let _ = LIVE_EVENTS.send(live_event);
 
-                        let _ = self.client.publish(
+                        let _ = self.publish(
                             entity_disc.topic,
                             QoS::AtLeastOnce,
                             true,
@@ -540,25 +1147,36 @@ impl MqttManager {
                         tokio::time::sleep(Duration::from_millis(50)).await;
                     }
                 },
-                Transmission::Subscribe(subscribe_data) =>  {
-                    let mut topic = subscribe_data.topic.clone();
-                    if !topic.starts_with("energy2mqtt/") && !topic.starts_with("homeassistant/") {
-                        topic = format!("energy2mqtt/{}", subscribe_data.topic);
+                Transmission::AutoDiscoveryRemove(remove) => {
+                    /* An empty retained config makes Home Assistant drop the entity or device */
+                    let mut topics = take_discovery_topics(&mut self.discovery_topics, &mut self.discovery_via, &remove);
+                    if remove.component.is_none() && !remove.prefix {
+                        topics.push(format!("{}/device/e2m_{}-{}/config", self.discovery_prefix, remove.proto, remove.device));
                     }
 
-                    if self.client.subscribe(topic.clone(), QoS::AtLeastOnce).await.is_ok() {
-                        CALLBACKS.write().await.insert(topic.clone(), subscribe_data.sender);
-                        info!("Registered Callback {topic}");
-
-                        // Broadcast subscribe to live view
-                        let live_event = LiveEvent::outgoing(
-                            LiveEventType::Subscribe,
-                            topic,
-                            serde_json::json!({"action": "subscribe"})
-                        );
+                    match &remove.component {
+                        Some(key) => info!("Removing {key} of {} from Home Assistant", remove.device),
+                        None => info!("Removing {} from Home Assistant", remove.device),
+                    }
+                    for topic in topics {
+                        let live_event = LiveEvent::outgoing(LiveEventType::AutoDiscovery, topic.clone(), serde_json::Value::Null)
+                            .with_retain(true);
                         let _ = LIVE_EVENTS.send(live_event);
+
+                        let _ = self.publish(topic, QoS::AtLeastOnce, true, String::new()).await;
                     }
                 },
+                Transmission::Subscribe(subscribe_data) =>  {
+                    let mut topic = subscribe_data.topic.clone();
+                    if !topic.starts_with(&format!("{}/", self.topic_prefix)) && !topic.starts_with(&format!("{}/", self.discovery_prefix)) {
+                        topic = format!("{}/{}", self.topic_prefix, subscribe_data.topic);
+                    }
+
+                    self.subscribe(topic, subscribe_data.sender).await;
+                },
+                Transmission::SubscribeAbsolute(subscribe_data) => {
+                    self.subscribe(subscribe_data.topic, subscribe_data.sender).await;
+                },
                 Transmission::Publish(publish_data) => {
                     let payload_json = serde_json::from_str(&publish_data.payload)
                         .unwrap_or_else(|_| serde_json::Value::String(publish_data.payload.clone()));
@@ -567,14 +1185,9 @@ impl MqttManager {
                         .with_qos(publish_data.qos);
                     let _ = LIVE_EVENTS.send(live_event);
 
-                    match self.client.publish(
+                    match self.publish(
                         publish_data.topic,
-                        match publish_data.qos {
-                            0 => QoS::AtMostOnce,
-                            1 => QoS::AtLeastOnce,
-                            2 => QoS::ExactlyOnce,
-                            _ => QoS::AtMostOnce,
-                        },
+                        qos_from_u8(publish_data.qos),
                         publish_data.retain,
                         publish_data.payload
                     ).await {
@@ -582,6 +1195,9 @@ impl MqttManager {
                         Ok(_) => { debug!("Published successfully"); }
                     }
                 },
+                Transmission::MeterError(data) => {
+                    self.publish_meter_error(&data.protocol, &data.meter_name, Some(&data.error)).await;
+                },
                 Transmission::TaskCrash(data) => {
                     error!("TASK CRASH NOTIFICATION: [{}] {} ({}) - {}", data.manager, data.task_name, data.task_type, data.message);
 
@@ -595,13 +1211,14 @@ impl MqttManager {
                         "timestamp": get_unix_ts(),
                     });
 
-                    let crash_topic = format!("energy2mqtt/mgt/task_crash/{}/{}", data.manager, data.task_name);
+                    let crash_topic = format!("{}/mgt/task_crash/{}/{}", self.topic_prefix, data.manager, data.task_name);
+                    let crashes_topic = format!("{}/mgt/crashes", self.topic_prefix);
 
                     // Broadcast to live view
                     let live_event = LiveEvent::outgoing(LiveEventType::System, crash_topic.clone(), crash_payload.clone());
                     let _ = LIVE_EVENTS.send(live_event);
 
-                    let _ = self.client.publish(
+                    let _ = self.publish(
                         crash_topic,
                         QoS::AtLeastOnce,
                         false,
@@ -611,13 +1228,13 @@ impl MqttManager {
                     // Also publish to a general crash topic for easy monitoring
                     let live_event = LiveEvent::outgoing(
                         LiveEventType::System,
-                        "energy2mqtt/mgt/crashes".to_string(),
+                        crashes_topic.clone(),
                         crash_payload.clone()
                     );
                     let _ = LIVE_EVENTS.send(live_event);
 
-                    let _ = self.client.publish(
-                        "energy2mqtt/mgt/crashes",
+                    let _ = self.publish(
+                        crashes_topic,
                         QoS::AtLeastOnce,
                         false,
                         crash_payload.to_string()
@@ -634,7 +1251,10 @@ impl MqttManager {
     }
 
     pub async fn register_device(&self, proto: String, name: String, disc: HaDiscover) {
-        let _ = self.client.publish(format!("homeassistant/device/e2m_{}-{}", proto, name),QoS::AtLeastOnce, true, serde_json::to_string(&disc).unwrap()).await;
+        if !self.ha_enabled {
+            return;
+        }
+        let _ = self.publish(format!("{}/device/e2m_{}-{}", self.discovery_prefix, proto, name),QoS::AtLeastOnce, true, serde_json::to_string(&disc).unwrap()).await;
     }
 }
 
@@ -667,7 +1287,7 @@ pub async fn publish_uptime(mqtt_sender: &Sender<Transmission>) {
     
     // Publish uptime only - protocol modules will publish their own counts
     let uptime_publish = PublishData {
-        topic: "energy2mqtt/mgt/uptime".to_string(),
+        topic: format!("{}/mgt/uptime", get_topic_prefix()),
         payload: app_status.uptime_seconds().to_string(),
         qos: 1,
         retain: true,
@@ -675,12 +1295,185 @@ pub async fn publish_uptime(mqtt_sender: &Sender<Transmission>) {
     let _ = mqtt_sender.send(Transmission::Publish(uptime_publish)).await;
 }
 
+/// Mark the service as offline, used on shutdown as the LWT only covers lost connections
+pub async fn publish_offline(mqtt_sender: &Sender<Transmission>) {
+    let offline_publish = PublishData {
+        topic: get_availability_topic(),
+        payload: "offline".to_string(),
+        qos: 1,
+        retain: true,
+    };
+    let _ = mqtt_sender.send(Transmission::Publish(offline_publish)).await;
+}
+
 pub async fn publish_protocol_count(mqtt_sender: &Sender<Transmission>, protocol: &str, count: u32) {
     let count_publish = PublishData {
-        topic: format!("energy2mqtt/mgt/{}/count", protocol),
+        topic: format!("{}/mgt/{}/count", get_topic_prefix(), protocol),
         payload: count.to_string(),
         qos: 1,
         retain: true,
     };
     let _ = mqtt_sender.send(Transmission::Publish(count_publish)).await;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select_topic_template() {
+        let mut templates = BTreeMap::new();
+        assert_eq!(select_topic_template(&templates, "SML", ""), DEFAULT_TOPIC_TEMPLATE);
+        assert_eq!(select_topic_template(&templates, "SML", "default"), DEFAULT_TOPIC_TEMPLATE);
+        assert_eq!(select_topic_template(&templates, "SML", "flat1"), DEFAULT_TENANT_TOPIC_TEMPLATE);
+
+        templates.insert("default".to_string(), "meters/{name}".to_string());
+        templates.insert("SML".to_string(), "home/energy/{name}/state".to_string());
+        assert_eq!(select_topic_template(&templates, "SML", "flat1"), "home/energy/{name}/state");
+        assert_eq!(select_topic_template(&templates, "OMS", ""), "meters/{name}");
+    }
+
+    #[test]
+    fn test_is_discovery() {
+        let publish = |topic: &str| Transmission::Publish(PublishData {
+            topic: topic.to_string(),
+            payload: String::new(),
+            qos: 0,
+            retain: true,
+        });
+
+        assert!(Transmission::AutoDiscoveryRemove(DiscoveryRemoveData::device("SML".to_string(), "meter".to_string()))
+            .is_discovery("homeassistant"));
+        assert!(publish("homeassistant/device/e2m_bridge/config").is_discovery("homeassistant"));
+        assert!(!publish("homeassistant_other/device/config").is_discovery("homeassistant"));
+        assert!(!publish("energy2mqtt/mgt/uptime").is_discovery("homeassistant"));
+        assert!(publish("ha/device/e2m_bridge/config").is_discovery("ha"));
+    }
+
+    #[test]
+    fn test_take_discovery_topics() {
+        let mut topics: HashMap<String, BTreeSet<String>> = HashMap::new();
+        let mut via = HashMap::new();
+        let mut add = |id: &str, parent: &str, topic_list: &[&str]| {
+            topics.insert(id.to_string(), topic_list.iter().map(|t| t.to_string()).collect());
+            via.insert(id.to_string(), parent.to_lowercase());
+        };
+        add("e2m_knx_hall", "e2m_management", &["ha/sensor/e2m_knx_hall/rx/config", "ha/switch/e2m_knx_hall/switch_light/config"]);
+        add("e2m_knx_meter", "e2m_KNX_hall", &["ha/sensor/e2m_knx_meter/energy/all/config"]);
+        add("e2m_knx_other", "e2m_management", &["ha/sensor/e2m_knx_other/energy/config"]);
+        add("e2m_tibber_home_flat", "e2m_management", &["ha/sensor/e2m_tibber_home_flat/price/config"]);
+        add("e2m_tibber_home_house", "e2m_management", &["ha/sensor/e2m_tibber_home_house/price/config"]);
+
+        /* A single component only removes its own topic */
+        let removed = take_discovery_topics(&mut topics, &mut via,
+            &DiscoveryRemoveData::component("KNX".to_string(), "hall".to_string(), "switch_light".to_string()));
+        assert_eq!(removed, vec!["ha/switch/e2m_knx_hall/switch_light/config"]);
+        assert_eq!(topics["e2m_knx_hall"].len(), 1);
+
+        /* Removing the adapter takes the linked meter with it */
+        let mut removed = take_discovery_topics(&mut topics, &mut via,
+            &DiscoveryRemoveData::device("KNX".to_string(), "hall".to_string()));
+        removed.sort();
+        assert_eq!(removed, vec!["ha/sensor/e2m_knx_hall/rx/config", "ha/sensor/e2m_knx_meter/energy/all/config"]);
+        assert!(topics.contains_key("e2m_knx_other"));
+        assert!(!via.contains_key("e2m_knx_meter"));
+
+        let removed = take_discovery_topics(&mut topics, &mut via,
+            &DiscoveryRemoveData::devices_with_prefix("Tibber".to_string(), "home".to_string()));
+        assert_eq!(removed.len(), 2);
+        assert_eq!(topics.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_callbacks_prune_closed_receivers() {
+        let mut callbacks = Callbacks::new();
+        let (alive, mut alive_rx) = tokio::sync::mpsc::channel(1);
+        let (dead, dead_rx) = tokio::sync::mpsc::channel(1);
+        callbacks.insert("energy2mqtt/alive".to_string(), alive);
+        callbacks.insert("energy2mqtt/dead".to_string(), dead);
+        drop(dead_rx);
+
+        callbacks.send("energy2mqtt/dead".to_string(), "1".to_string()).await;
+        assert_eq!(callbacks.get_topics().await, vec!["energy2mqtt/alive".to_string()]);
+        /* Sending to the pruned topic again is ignored */
+        callbacks.send("energy2mqtt/dead".to_string(), "2".to_string()).await;
+
+        callbacks.send("energy2mqtt/alive".to_string(), "3".to_string()).await;
+        assert_eq!(alive_rx.recv().await, Some(("energy2mqtt/alive".to_string(), "3".to_string())));
+    }
+
+    #[test]
+    fn test_payload_to_string() {
+        assert_eq!(payload_to_string("oms_input", b"{\"a\": 1}"), Some("{\"a\": 1}".to_string()));
+        assert_eq!(payload_to_string("oms_input", &[0x68, 0xff, 0xfe, 0x16]), None);
+    }
+
+    #[test]
+    fn test_select_publish_options() {
+        let mut overrides = BTreeMap::new();
+        assert_eq!(select_publish_options(&overrides, "SML", QoS::AtLeastOnce, false), (QoS::AtLeastOnce, false));
+
+        overrides.insert("default".to_string(), MqttPublishConfig { qos: None, retain: Some(true) });
+        overrides.insert("SML".to_string(), MqttPublishConfig { qos: Some(0), retain: None });
+        assert_eq!(select_publish_options(&overrides, "SML", QoS::AtLeastOnce, false), (QoS::AtMostOnce, true));
+        assert_eq!(select_publish_options(&overrides, "OMS", QoS::AtLeastOnce, false), (QoS::AtLeastOnce, true));
+    }
+
+    #[test]
+    fn test_select_message_format() {
+        let mut formats = BTreeMap::new();
+        assert_eq!(select_message_format(&formats, "sml-main", "SML"), MqttMessageFormat::Both);
+
+        formats.insert("default".to_string(), MqttMessageFormat::Envelope);
+        formats.insert("SML".to_string(), MqttMessageFormat::Flat);
+        formats.insert("sml-heat".to_string(), MqttMessageFormat::Both);
+        assert_eq!(select_message_format(&formats, "sml-main", "SML"), MqttMessageFormat::Flat);
+        assert_eq!(select_message_format(&formats, "sml-heat", "SML"), MqttMessageFormat::Both);
+        assert_eq!(select_message_format(&formats, "oms-water", "OMS"), MqttMessageFormat::Envelope);
+
+        let config: BTreeMap<String, MqttMessageFormat> = serde_yml::from_str("SML: flat\n\
+            default: envelope\n").unwrap();
+        assert_eq!(config["SML"], MqttMessageFormat::Flat);
+        assert!(!config["default"].publishes_flat());
+        assert!(MqttMessageFormat::Both.publishes_flat() && MqttMessageFormat::Both.publishes_envelope());
+    }
+
+    #[test]
+    fn test_envelope_meters_have_no_state_topic() {
+        let mut formats = BTreeMap::new();
+        formats.insert("SML".to_string(), MqttMessageFormat::Envelope);
+
+        let meter = HaSensor::new("SML".to_string(), "main".to_string(), None, None);
+        /* Devices without readings keep their discovery */
+        assert!(has_state_topic(&formats, &meter));
+
+        let meter = meter.meter_ids(String::new(), "sml-main".to_string());
+        assert!(!has_state_topic(&formats, &meter));
+        formats.insert("sml-main".to_string(), MqttMessageFormat::Both);
+        assert!(has_state_topic(&formats, &meter));
+    }
+
+    #[test]
+    fn test_render_topic_template() {
+        assert_eq!(render_topic_template("{prefix}/devs/{proto}/{name}", "energy2mqtt", "SML", "meter", "", ""),
+                   "energy2mqtt/devs/SML/meter");
+        assert_eq!(render_topic_template("home/energy/{name}/state", "energy2mqtt", "OMS", "water", "", ""),
+                   "home/energy/water/state");
+        assert_eq!(render_topic_template("{tenant}/{id}", "energy2mqtt", "OMS", "water", "", ""),
+                   "default/water");
+        assert_eq!(render_topic_template("{tenant}/{id}", "energy2mqtt", "OMS", "water", "flat1", "oms-1"),
+                   "flat1/oms-1");
+    }
+
+    #[tokio::test]
+    async fn test_update_health_of_additional_broker() {
+        let broker = Some("test_health_broker".to_string());
+        update_health(&broker, |health| health.last_message_received = Some(Instant::now())).await;
+        update_health(&broker, |health| health.connection_attempts += 1).await;
+
+        let app_status = APP_STATUS.read().await;
+        let health = &app_status.brokers["test_health_broker"];
+        assert!(health.last_message_received.is_some());
+        assert_eq!(health.connection_attempts, 1);
+    }
+}
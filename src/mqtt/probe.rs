@@ -0,0 +1,59 @@
+//! One-shot broker connections
+//!
+//! Used by the API to check broker settings before they are saved: connect once, wait for the
+//! broker's CONNACK and disconnect again. Nothing is subscribed or published.
+
+use std::time::Duration;
+
+use rumqttc::{AsyncClient, ConnectReturnCode, Event, MqttOptions, Packet};
+
+/// Time a probe waits for the broker to accept the connection
+pub const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connect with the options and wait for the broker's answer, returns the reason on failure
+pub async fn probe_broker(mut options: MqttOptions, timeout: Duration) -> Result<(), String> {
+    /* A probe never reconnects, so there is nothing to keep for the next session */
+    options.set_clean_session(true);
+    let (client, mut eventloop) = AsyncClient::new(options, 10);
+
+    let result = tokio::time::timeout(timeout, async {
+        loop {
+            match eventloop.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(ack))) if ack.code == ConnectReturnCode::Success => return Ok(()),
+                Ok(Event::Incoming(Packet::ConnAck(ack))) => return Err(format!("Connection rejected: {:?}", ack.code)),
+                Ok(_) => continue,
+                Err(e) => return Err(format!("Connection error: {e}")),
+            }
+        }
+    }).await.unwrap_or_else(|_| Err("Connection timeout".to_string()));
+
+    if result.is_ok() {
+        let _ = client.disconnect().await;
+        /* Send the DISCONNECT before the connection is dropped */
+        let _ = tokio::time::timeout(Duration::from_secs(1), eventloop.poll()).await;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_probe_failures() {
+        /* Nobody listens on the port of a dropped listener */
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+        let e = probe_broker(MqttOptions::new("e2m_test", "127.0.0.1", port), PROBE_TIMEOUT).await.unwrap_err();
+        assert!(e.starts_with("Connection error"), "{e}");
+
+        /* Accepted but never answered */
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let silent = tokio::spawn(async move { listener.accept().await });
+        let e = probe_broker(MqttOptions::new("e2m_test", "127.0.0.1", port), Duration::from_millis(200)).await.unwrap_err();
+        assert_eq!(e, "Connection timeout");
+        silent.abort();
+    }
+}
@@ -0,0 +1,109 @@
+//! Flood protection for the input topics
+//!
+//! A misbehaving gateway can send far more telegrams than the parsers keep up with.
+//! Every input with a configured limit (messages per second, keyed by the topic
+//! without the topic prefix, e.g. oms_input) gets a token bucket allowing
+//! bursts of one second worth of messages. Messages beyond are dropped and counted.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::RwLock;
+use std::time::Instant;
+
+use lazy_static::lazy_static;
+
+lazy_static! {
+    /// Dropped messages by input name since the start
+    static ref DROPPED_INPUTS: RwLock<BTreeMap<String, u64>> = RwLock::new(BTreeMap::new());
+}
+
+pub struct RateLimiter {
+    limits: BTreeMap<String, u32>,
+    topic_prefix: String,
+    /* Available tokens and the time they were calculated for */
+    buckets: HashMap<String, (f64, Instant)>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: BTreeMap<String, u32>, topic_prefix: String) -> Self {
+        RateLimiter { limits, topic_prefix, buckets: HashMap::new() }
+    }
+
+    /// Name of an input topic as used for the limits
+    pub fn input_name<'a>(&self, topic: &'a str) -> &'a str {
+        topic.strip_prefix(self.topic_prefix.as_str())
+            .and_then(|t| t.strip_prefix('/'))
+            .unwrap_or(topic)
+    }
+
+    /// Check if a message on the topic may pass, inputs without limit always do
+    pub fn allow(&mut self, topic: &str, now: Instant) -> bool {
+        let name = self.input_name(topic);
+        let rate = match self.limits.get(name) {
+            Some(rate) => *rate as f64,
+            None => return true,
+        };
+
+        let (tokens, last) = self.buckets.entry(name.to_string()).or_insert((rate, now));
+        *tokens = (*tokens + now.saturating_duration_since(*last).as_secs_f64() * rate).min(rate);
+        *last = now;
+
+        if *tokens < 1.0 {
+            return false;
+        }
+        *tokens -= 1.0;
+        true
+    }
+}
+
+/// Count a dropped message of an input, see RateLimiter::input_name
+pub fn record_dropped(input: &str) {
+    *DROPPED_INPUTS.write().unwrap().entry(input.to_string()).or_insert(0) += 1;
+}
+
+/// Copy of the dropped message counts by input name
+pub fn get_dropped() -> BTreeMap<String, u64> {
+    DROPPED_INPUTS.read().unwrap().clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut limits = BTreeMap::new();
+        limits.insert("oms_input".to_string(), 2);
+        let mut limiter = RateLimiter::new(limits, "site1/e2m".to_string());
+        assert_eq!(limiter.input_name("site1/e2m/oms_input"), "oms_input");
+        assert_eq!(limiter.input_name("energy2mqtt/oms_input"), "energy2mqtt/oms_input");
+
+        let start = Instant::now();
+        assert!(limiter.allow("site1/e2m/oms_input", start));
+        assert!(limiter.allow("site1/e2m/oms_input", start));
+        assert!(!limiter.allow("site1/e2m/oms_input", start));
+
+        /* Half a second brings back one message */
+        let later = start + Duration::from_millis(500);
+        assert!(limiter.allow("site1/e2m/oms_input", later));
+        assert!(!limiter.allow("site1/e2m/oms_input", later));
+
+        /* Idle time does not build up more than a second of burst */
+        let much_later = start + Duration::from_secs(60);
+        assert!(limiter.allow("site1/e2m/oms_input", much_later));
+        assert!(limiter.allow("site1/e2m/oms_input", much_later));
+        assert!(!limiter.allow("site1/e2m/oms_input", much_later));
+
+        /* Inputs without limit are not touched */
+        for _ in 0..100 {
+            assert!(limiter.allow("site1/e2m/sml_input", start));
+        }
+    }
+
+    #[test]
+    fn test_dropped_are_counted() {
+        record_dropped("test_rate_input");
+        record_dropped("test_rate_input");
+        assert_eq!(get_dropped().get("test_rate_input"), Some(&2));
+    }
+}
@@ -0,0 +1,312 @@
+//! Hourly and daily totals of energy counters
+//!
+//! For every configured field the counter value at the start of the current hour and day is
+//! kept, each reading yields the consumption since then. This gives "energy used today"
+//! without Home Assistant's utility meter helper. A period starts with the last value of the
+//! previous one so nothing is lost between two readings, a counter reset keeps what was consumed
+//! before it. The start and last values are stored in the device database so the totals survive
+//! restarts.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use chrono::{Local, TimeZone, Timelike};
+use log::error;
+use serde_json::Value;
+
+use crate::device_manager::db::DeviceDb;
+use crate::MeteringData;
+
+use super::home_assistant::{HaComponent2, HaSensor};
+use super::TranmissionValueType;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RollupPeriod {
+    Hourly,
+    Daily,
+}
+
+pub const ROLLUP_PERIODS: [RollupPeriod; 2] = [RollupPeriod::Hourly, RollupPeriod::Daily];
+
+impl RollupPeriod {
+    /// Last level of the topic and key in the database
+    pub fn name(&self) -> &'static str {
+        match self {
+            RollupPeriod::Hourly => "hourly",
+            RollupPeriod::Daily => "daily",
+        }
+    }
+
+    pub fn transmission_type(&self) -> TranmissionValueType {
+        match self {
+            RollupPeriod::Hourly => TranmissionValueType::Hourly,
+            RollupPeriod::Daily => TranmissionValueType::Daily,
+        }
+    }
+
+    /// Start of the period ts is part of, hours and days as seen in the time zone
+    pub fn period_start<Tz: TimeZone>(&self, tz: &Tz, ts: u64) -> u64 {
+        let Some(time) = tz.timestamp_opt(ts as i64, 0).single() else {
+            return ts;
+        };
+
+        let start = match self {
+            RollupPeriod::Hourly => time.with_minute(0).and_then(|t| t.with_second(0)),
+            /* Midnight may not exist on DST changes, the day starts with its first hour then */
+            RollupPeriod::Daily => time.date_naive().and_hms_opt(0, 0, 0)
+                .and_then(|midnight| tz.from_local_datetime(&midnight).earliest())
+                .or_else(|| time.with_minute(0).and_then(|t| t.with_second(0))),
+        };
+
+        start.map_or(ts, |s| s.timestamp() as u64)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct RollupState {
+    period_start: u64,
+    start_value: f64,
+    last_value: f64,
+}
+
+pub struct PeriodRollups {
+    fields: BTreeMap<String, Vec<String>>,
+    states: HashMap<(String, String, RollupPeriod), RollupState>,
+    db: Option<Arc<DeviceDb>>,
+}
+
+impl PeriodRollups {
+    pub fn new(fields: BTreeMap<String, Vec<String>>) -> Self {
+        PeriodRollups { fields, states: HashMap::new(), db: None }
+    }
+
+    /// Database the period starts are kept in, without one the totals start over on restart
+    pub fn set_db(&mut self, db: Option<Arc<DeviceDb>>) {
+        self.db = db;
+    }
+
+    fn load_state(&self, meter_id: &str, field: &str, period: RollupPeriod) -> Option<RollupState> {
+        let db = self.db.as_ref()?;
+        match db.get_rollup(meter_id, field, period.name()) {
+            Ok(stored) => stored.map(|(period_start, start_value, last_value)| RollupState { period_start, start_value, last_value }),
+            Err(e) => {
+                error!("Unable to load {} rollup {meter_id}/{field}: {e}", period.name());
+                None
+            }
+        }
+    }
+
+    fn store_state(&self, meter_id: &str, field: &str, period: RollupPeriod, state: &RollupState) {
+        if let Some(db) = &self.db {
+            if let Err(e) = db.set_rollup(meter_id, field, period.name(), state.period_start, state.start_value, state.last_value) {
+                error!("Unable to store {} rollup {meter_id}/{field}: {e}", period.name());
+            }
+        }
+    }
+
+    /// Add the entities of the totals to the discovery of their meter, published below its state topic
+    pub fn add_discovery(&self, disc: &mut HaSensor) {
+        let Some(fields) = self.fields.get(disc.get_meter_id()) else {
+            return;
+        };
+
+        for period in ROLLUP_PERIODS {
+            let state_topic = format!("{}/{}", disc.get_state_topic(), period.name());
+            for field in fields {
+                let counter = disc.get_cmp(field);
+                let name = counter.and_then(|c| c.get_information("name")).and_then(Value::as_str).unwrap_or(field);
+
+                /* The totals start over with every period, which total_increasing handles as a reset */
+                let mut cmp = HaComponent2::new()
+                    .name(format!("{name} {}", period.name()))
+                    .state_class("total_increasing".to_string())
+                    .add_information("state_topic", Value::from(state_topic.clone()))
+                    .add_information("value_template", Value::from(format!("{{{{ value_json.{field} }}}}")));
+                for info in ["device_class", "unit_of_measurement"] {
+                    if let Some(value) = counter.and_then(|c| c.get_information(info)) {
+                        cmp = cmp.add_information(info, value.clone());
+                    }
+                }
+
+                disc.add_cmp(format!("{field}_{}", period.name()), cmp);
+            }
+        }
+    }
+
+    /// Consumption of the configured fields in the current periods, one reading per period
+    pub fn apply(&mut self, data: &MeteringData) -> Vec<(RollupPeriod, MeteringData)> {
+        self.apply_at(data, &Local)
+    }
+
+    fn apply_at<Tz: TimeZone>(&mut self, data: &MeteringData, tz: &Tz) -> Vec<(RollupPeriod, MeteringData)> {
+        let Some(fields) = self.fields.get(&data.id).cloned() else {
+            return Vec::new();
+        };
+
+        let mut result = Vec::new();
+        for period in ROLLUP_PERIODS {
+            let period_start = period.period_start(tz, data.metered_time);
+            let mut values = serde_json::Map::new();
+
+            for field in fields.iter() {
+                let Some(value) = data.metered_values.get(field).and_then(Value::as_f64) else {
+                    continue;
+                };
+
+                let key = (data.id.clone(), field.clone(), period);
+                let known = self.states.get(&key).copied()
+                    .or_else(|| self.load_state(&data.id, field, period));
+
+                let mut state = match known {
+                    Some(state) if state.period_start == period_start => state,
+                    /* A new period continues where the last one ended */
+                    Some(state) if state.period_start < period_start => {
+                        RollupState { period_start, start_value: state.last_value, ..state }
+                    },
+                    _ => RollupState { period_start, start_value: value, last_value: value },
+                };
+                /* A counter reset keeps what was consumed before it, the counter starts over from zero */
+                if value < state.last_value {
+                    state.start_value -= state.last_value;
+                }
+                if known != Some(state) || state.last_value != value {
+                    state.last_value = value;
+                    self.store_state(&data.id, field, period, &state);
+                }
+                self.states.insert(key, state);
+
+                values.insert(field.clone(), Value::from(value - state.start_value));
+                if let Some(unit) = data.metered_values.get(&format!("{field}_unit")) {
+                    values.insert(format!("{field}_unit"), unit.clone());
+                }
+            }
+
+            if values.is_empty() {
+                continue;
+            }
+
+            let mut rollup = MeteringData::new().unwrap();
+            rollup.id = data.id.clone();
+            rollup.meter_name = data.meter_name.clone();
+            rollup.tenant = data.tenant.clone();
+            rollup.protocol = data.protocol.clone();
+            rollup.state_topic_base = data.state_topic_base.clone();
+            rollup.transmission_time = data.transmission_time;
+            rollup.transmission_type = period.transmission_type();
+            rollup.metered_time = period_start;
+            rollup.metered_values = values;
+            result.push((period, rollup));
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{FixedOffset, Utc};
+
+    fn reading(energy: f64, time: u64) -> MeteringData {
+        let mut data = MeteringData::new().unwrap();
+        data.id = "modbus-grid".to_string();
+        data.metered_time = time;
+        data.metered_values.insert("energy".to_string(), Value::from(energy));
+        data.metered_values.insert("energy_unit".to_string(), Value::from("kWh"));
+        data
+    }
+
+    fn rollups() -> PeriodRollups {
+        let mut fields = BTreeMap::new();
+        fields.insert("modbus-grid".to_string(), vec!["energy".to_string()]);
+        PeriodRollups::new(fields)
+    }
+
+    fn totals(result: &[(RollupPeriod, MeteringData)]) -> Vec<f64> {
+        result.iter().map(|(_, d)| d.metered_values["energy"].as_f64().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_period_start() {
+        /* 2025-03-01 12:07:42 UTC */
+        let ts = 1740830862;
+        assert_eq!(RollupPeriod::Hourly.period_start(&Utc, ts), 1740830400);
+        assert_eq!(RollupPeriod::Daily.period_start(&Utc, ts), 1740787200);
+
+        /* Days start at local midnight, 2025-03-01 00:00 +01:00 */
+        let cet = FixedOffset::east_opt(3600).unwrap();
+        assert_eq!(RollupPeriod::Daily.period_start(&cet, ts), 1740783600);
+        let ist = FixedOffset::east_opt(19800).unwrap();
+        assert_eq!(RollupPeriod::Hourly.period_start(&ist, ts), 1740830400 - 1800);
+    }
+
+    #[test]
+    fn test_rollup_totals() {
+        let mut rollups = rollups();
+        let start = 1740787200; // 2025-03-01 00:00 UTC
+
+        let result = rollups.apply_at(&reading(100.0, start + 60), &Utc);
+        assert_eq!(result.len(), 2);
+        assert_eq!(result[0].0, RollupPeriod::Hourly);
+        assert_eq!(result[1].1.metered_time, start);
+        assert_eq!(result[1].1.metered_values["energy_unit"], "kWh");
+        assert_eq!(totals(&result), vec![0.0, 0.0]);
+
+        assert_eq!(totals(&rollups.apply_at(&reading(101.5, start + 1800), &Utc)), vec![1.5, 1.5]);
+        /* Next hour starts with the last value of the previous one */
+        assert_eq!(totals(&rollups.apply_at(&reading(102.0, start + 3660), &Utc)), vec![0.5, 2.0]);
+        /* A counter reset keeps what was consumed before it */
+        assert_eq!(totals(&rollups.apply_at(&reading(1.0, start + 3700), &Utc)), vec![1.5, 3.0]);
+        assert_eq!(totals(&rollups.apply_at(&reading(1.5, start + 3800), &Utc)), vec![2.0, 3.5]);
+        /* Next day */
+        assert_eq!(totals(&rollups.apply_at(&reading(3.0, start + 86400 + 60), &Utc)), vec![1.5, 1.5]);
+
+        /* Meters and fields without config are ignored */
+        let mut other = reading(1.0, start);
+        other.id = "sml-main".to_string();
+        assert!(rollups.apply_at(&other, &Utc).is_empty());
+    }
+
+    #[test]
+    fn test_rollup_survives_restart() {
+        let db = Arc::new(DeviceDb::open_in_memory().unwrap());
+        let start = 1740787200;
+
+        let mut rollups = rollups();
+        rollups.set_db(Some(db.clone()));
+        rollups.apply_at(&reading(100.0, start + 60), &Utc);
+        rollups.apply_at(&reading(105.0, start + 7200), &Utc);
+
+        let mut restarted = self::rollups();
+        restarted.set_db(Some(db.clone()));
+        assert_eq!(totals(&restarted.apply_at(&reading(106.0, start + 7300), &Utc)), vec![6.0, 6.0]);
+
+        /* The last value survives as well, a reset right after the restart is detected */
+        let mut restarted = self::rollups();
+        restarted.set_db(Some(db));
+        assert_eq!(totals(&restarted.apply_at(&reading(2.0, start + 7400), &Utc)), vec![8.0, 8.0]);
+    }
+
+    #[test]
+    fn test_rollup_discovery() {
+        let mut disc = HaSensor::new("ModbusTCP".to_string(), "grid".to_string(), None, None)
+            .meter_ids(String::new(), "modbus-grid".to_string());
+        disc.add_cmp("energy".to_string(), HaComponent2::new().name("Energy".to_string())
+            .device_class("energy".to_string()).unit_of_measurement("kWh".to_string()));
+        rollups().add_discovery(&mut disc);
+
+        let daily = disc.get_cmp("energy_daily").unwrap().to_json_map();
+        assert_eq!(daily["name"], "Energy daily");
+        assert_eq!(daily["state_topic"], format!("{}/daily", disc.get_state_topic()));
+        assert_eq!(daily["value_template"], "{{ value_json.energy }}");
+        assert_eq!(daily["unit_of_measurement"], "kWh");
+        assert_eq!(daily["state_class"], "total_increasing");
+        assert!(disc.get_cmp("energy_hourly").is_some());
+
+        /* Meters without rollups stay as they are */
+        let mut other = HaSensor::new("SML".to_string(), "main".to_string(), None, None)
+            .meter_ids(String::new(), "sml-main".to_string());
+        rollups().add_discovery(&mut other);
+        assert!(other.get_entity_discoveries().is_empty());
+    }
+}
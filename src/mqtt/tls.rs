@@ -0,0 +1,173 @@
+//! TLS setup for broker connections
+//!
+//! Builds a rustls client config from the PEM files referenced in the config and
+//! hands it to rumqttc as transport.
+
+use std::sync::Arc;
+
+use rumqttc::tokio_rustls::rustls::{
+    self,
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use rumqttc::{MqttOptions, TlsConfiguration, Transport};
+
+use crate::config::MqttTlsConfig;
+
+/// Certificate verifier accepting everything, used for insecure_skip_verify
+#[derive(Debug)]
+struct NoVerifier(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, String> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .map_err(|e| format!("Unable to read certificates from {path}: {e}"))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Invalid certificate in {path}: {e}"))?;
+
+    if certs.is_empty() {
+        return Err(format!("No certificate found in {path}"));
+    }
+
+    Ok(certs)
+}
+
+fn load_root_store(tls: &MqttTlsConfig) -> Result<RootCertStore, String> {
+    let mut roots = RootCertStore::empty();
+
+    match &tls.ca_cert_path {
+        Some(path) => {
+            let (added, _) = roots.add_parsable_certificates(load_certs(path)?);
+            if added == 0 {
+                return Err(format!("No usable CA certificate found in {path}"));
+            }
+        }
+        None => {
+            /* No CA given, trust the same roots as the system does */
+            let native = rustls_native_certs::load_native_certs();
+            roots.add_parsable_certificates(native.certs);
+            if roots.is_empty() {
+                return Err("No system CA certificates found, please set ca_cert_path".to_string());
+            }
+        }
+    }
+
+    Ok(roots)
+}
+
+/// Build the rustls client config for the given TLS settings
+pub fn build_client_config(tls: &MqttTlsConfig) -> Result<ClientConfig, String> {
+    /* Other dependencies may enable a second crypto backend, so pick one explicitly */
+    let provider = CryptoProvider::get_default()
+        .cloned()
+        .unwrap_or_else(|| Arc::new(rustls::crypto::aws_lc_rs::default_provider()));
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| format!("Unable to setup TLS: {e}"))?;
+
+    let builder = if tls.insecure_skip_verify {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoVerifier(provider)))
+    } else {
+        builder.with_root_certificates(load_root_store(tls)?)
+    };
+
+    match (&tls.client_cert, &tls.client_key) {
+        (Some(cert), Some(key)) => {
+            let certs = load_certs(cert)?;
+            let key = PrivateKeyDer::from_pem_file(key)
+                .map_err(|e| format!("Unable to read client key from {key}: {e}"))?;
+
+            builder.with_client_auth_cert(certs, key)
+                .map_err(|e| format!("Invalid client certificate: {e}"))
+        }
+        (None, None) => Ok(builder.with_no_client_auth()),
+        _ => Err("client_cert and client_key need to be set together".to_string()),
+    }
+}
+
+/// Switch the transport of the given options to TLS if enabled
+pub fn configure_tls(options: &mut MqttOptions, tls: &MqttTlsConfig) -> Result<(), String> {
+    if !tls.tls_enabled {
+        return Ok(());
+    }
+
+    let config = build_client_config(tls)?;
+    options.set_transport(Transport::tls_with_config(TlsConfiguration::Rustls(Arc::new(config))));
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disabled_tls_keeps_tcp() {
+        let mut options = MqttOptions::new("test", "localhost", 1883);
+        let tls = MqttTlsConfig::default();
+
+        assert!(configure_tls(&mut options, &tls).is_ok());
+        assert!(matches!(options.transport(), Transport::Tcp));
+    }
+
+    #[test]
+    fn test_missing_ca_file() {
+        let tls = MqttTlsConfig {
+            tls_enabled: true,
+            ca_cert_path: Some("/nonexistent/ca.pem".to_string()),
+            ..Default::default()
+        };
+
+        assert!(build_client_config(&tls).is_err());
+    }
+
+    #[test]
+    fn test_client_cert_requires_key() {
+        let tls = MqttTlsConfig {
+            tls_enabled: true,
+            client_cert: Some("/nonexistent/client.pem".to_string()),
+            insecure_skip_verify: true,
+            ..Default::default()
+        };
+
+        assert!(build_client_config(&tls).is_err());
+    }
+}
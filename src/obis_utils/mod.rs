@@ -5,6 +5,8 @@ pub struct ObisData {
     pub code: String,
     pub value: String,
     pub unit: Option<String>,
+    /// Numeric part of the value, see parse_numeric_value
+    pub number: Option<f64>,
 }
 
 pub fn get_obis_description(obis_code: &str) -> Option<&'static str> {
@@ -28,19 +30,19 @@ pub fn get_standard_obis_descriptions() -> HashMap<&'static str, &'static str> {
     map.insert("1-0:1.7.0", "Active power + (total)");
     map.insert("1-0:2.7.0", "Active power - (total)");
     map.insert("1-0:15.7.0", "Absolute active instantaneous power");
-    map.insert("1-0:21.7.0", "Active power + (L1)");
-    map.insert("1-0:41.7.0", "Active power + (L2)");
-    map.insert("1-0:61.7.0", "Active power + (L3)");
+    map.insert("1-0:21.7.0", "Active power + L1");
+    map.insert("1-0:41.7.0", "Active power + L2");
+    map.insert("1-0:61.7.0", "Active power + L3");
     
     // Voltage values
-    map.insert("1-0:32.7.0", "Voltage (L1)");
-    map.insert("1-0:52.7.0", "Voltage (L2)");
-    map.insert("1-0:72.7.0", "Voltage (L3)");
+    map.insert("1-0:32.7.0", "Voltage L1");
+    map.insert("1-0:52.7.0", "Voltage L2");
+    map.insert("1-0:72.7.0", "Voltage L3");
     
     // Current values
-    map.insert("1-0:31.7.0", "Current (L1)");
-    map.insert("1-0:51.7.0", "Current (L2)");
-    map.insert("1-0:71.7.0", "Current (L3)");
+    map.insert("1-0:31.7.0", "Current L1");
+    map.insert("1-0:51.7.0", "Current L2");
+    map.insert("1-0:71.7.0", "Current L3");
     
     // Reactive energy
     map.insert("1-0:3.8.0", "Reactive energy + (total)");
@@ -62,18 +64,238 @@ pub fn get_standard_obis_descriptions() -> HashMap<&'static str, &'static str> {
     // EasyMeter specific OBIS codes
     map.insert("1-0:0.0.0", "Equipment identifier");
     map.insert("1-0:0.0.9", "Date and time");
-    map.insert("1-0:32.32.0", "Number of voltage sags (L1)");
-    map.insert("1-0:52.32.0", "Number of voltage sags (L2)");
-    map.insert("1-0:72.32.0", "Number of voltage sags (L3)");
+    map.insert("1-0:32.32.0", "Number of voltage sags L1");
+    map.insert("1-0:52.32.0", "Number of voltage sags L2");
+    map.insert("1-0:72.32.0", "Number of voltage sags L3");
     
     // EBZ specific OBIS codes  
     map.insert("1-0:16.7.0", "Sum active instantaneous power");
     map.insert("1-0:36.7.0", "Sum reactive instantaneous power");
     map.insert("1-0:13.7.0", "Power factor");
-    
+
+    // Heat meters (medium 6)
+    map.insert("6-0:1.0.0", "Heat energy (total)");
+    map.insert("6-0:2.0.0", "Heat volume (total)");
+    map.insert("6-0:8.0.0", "Heat power");
+    map.insert("6-0:9.0.0", "Heat volume flow");
+    map.insert("6-0:10.0.0", "Flow temperature");
+    map.insert("6-0:11.0.0", "Return temperature");
+    map.insert("6-0:12.0.0", "Temperature difference");
+
+    // Gas meters (medium 7)
+    map.insert("7-0:3.0.0", "Gas volume (total)");
+    map.insert("7-0:3.1.0", "Gas volume (converted)");
+    map.insert("7-0:41.0.0", "Gas temperature");
+    map.insert("7-0:42.0.0", "Gas pressure");
+    map.insert("7-0:43.0.0", "Gas volume flow");
+
+    // Cold water meters (medium 8)
+    map.insert("8-0:1.0.0", "Water volume (total)");
+    map.insert("8-0:2.0.0", "Water volume flow");
+
+    // Hot water meters (medium 9)
+    map.insert("9-0:1.0.0", "Hot water volume (total)");
+    map.insert("9-0:2.0.0", "Hot water volume flow");
+
+    map
+}
+
+/// Get the medium (value group A) of an OBIS code, e.g. 7 for gas
+pub fn get_obis_medium(code: &str) -> Option<u8> {
+    code.split('-').next()?.trim().parse().ok()
+}
+
+/// Field names for the common OBIS codes of a medium
+/// 1 = electricity, 6 = heat, 7 = gas, 8 = cold water, 9 = hot water
+pub fn get_obis_mapping_for_medium(medium: u8) -> HashMap<&'static str, &'static str> {
+    let mut map = HashMap::new();
+
+    match medium {
+        1 => {
+            map.insert("1-0:1.8.0", "total_energy_consumed");
+            map.insert("1-0:2.8.0", "total_energy_delivered");
+            map.insert("1-0:16.7.0", "current_power");
+            map.insert("1-0:32.7.0", "voltage_l1");
+            map.insert("1-0:52.7.0", "voltage_l2");
+            map.insert("1-0:72.7.0", "voltage_l3");
+            map.insert("1-0:31.7.0", "current_l1");
+            map.insert("1-0:51.7.0", "current_l2");
+            map.insert("1-0:71.7.0", "current_l3");
+        }
+        6 => {
+            map.insert("6-0:1.0.0", "heat_energy");
+            map.insert("6-0:2.0.0", "heat_volume");
+            map.insert("6-0:8.0.0", "heat_power");
+            map.insert("6-0:9.0.0", "heat_flow");
+            map.insert("6-0:10.0.0", "flow_temperature");
+            map.insert("6-0:11.0.0", "return_temperature");
+            map.insert("6-0:12.0.0", "temperature_difference");
+        }
+        7 => {
+            map.insert("7-0:3.0.0", "gas_volume");
+            map.insert("7-0:3.1.0", "gas_volume_converted");
+            map.insert("7-0:41.0.0", "gas_temperature");
+            map.insert("7-0:42.0.0", "gas_pressure");
+            map.insert("7-0:43.0.0", "gas_flow");
+        }
+        8 => {
+            map.insert("8-0:1.0.0", "water_volume");
+            map.insert("8-0:2.0.0", "water_flow");
+        }
+        9 => {
+            map.insert("9-0:1.0.0", "hot_water_volume");
+            map.insert("9-0:2.0.0", "hot_water_flow");
+        }
+        _ => {}
+    }
+
     map
 }
 
+/// OBIS code of a field name from get_obis_mapping_for_medium, e.g. 7-0:3.0.0 for gas_volume
+pub fn get_obis_code_for_field(field: &str) -> Option<&'static str> {
+    [1, 6, 7, 8, 9].into_iter().find_map(|medium| {
+        get_obis_mapping_for_medium(medium).into_iter()
+            .find(|(_, name)| *name == field)
+            .map(|(code, _)| code)
+    })
+}
+
+/// Home Assistant classification of a metered value
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HaUnitInfo {
+    pub device_class: &'static str,
+    pub unit: &'static str,
+    pub state_class: &'static str,
+}
+
+impl HaUnitInfo {
+    const fn new(device_class: &'static str, unit: &'static str, state_class: &'static str) -> Self {
+        HaUnitInfo { device_class, unit, state_class }
+    }
+}
+
+/// Electrical quantities meters report, independent of how a protocol names them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuantityKind {
+    ActiveEnergy,
+    ActivePower,
+    ReactiveEnergy,
+    ReactivePower,
+    ApparentEnergy,
+    ApparentPower,
+    PowerFactor,
+    Voltage,
+    Current,
+    Frequency,
+}
+
+impl QuantityKind {
+    /// HA classification in the unit the quantity is usually sent in
+    pub const fn ha_unit_info(&self) -> HaUnitInfo {
+        match self {
+            QuantityKind::ActiveEnergy => HaUnitInfo::new("energy", "kWh", "total_increasing"),
+            QuantityKind::ActivePower => HaUnitInfo::new("power", "W", "measurement"),
+            QuantityKind::ReactiveEnergy => HaUnitInfo::new("reactive_energy", "kvarh", "total_increasing"),
+            QuantityKind::ReactivePower => HaUnitInfo::new("reactive_power", "var", "measurement"),
+            /* HA has no device class for it, the state class still gives long term statistics */
+            QuantityKind::ApparentEnergy => HaUnitInfo::new("", "kVAh", "total_increasing"),
+            QuantityKind::ApparentPower => HaUnitInfo::new("apparent_power", "VA", "measurement"),
+            QuantityKind::PowerFactor => HaUnitInfo::new("power_factor", "", "measurement"),
+            QuantityKind::Voltage => HaUnitInfo::new("voltage", "V", "measurement"),
+            QuantityKind::Current => HaUnitInfo::new("current", "A", "measurement"),
+            QuantityKind::Frequency => HaUnitInfo::new("frequency", "Hz", "measurement"),
+        }
+    }
+
+    /// Kind of a value by the unit the meter sends along with it
+    pub fn from_unit(unit: &str) -> Option<Self> {
+        let kind = match unit {
+            "Wh" | "kWh" | "MWh" => QuantityKind::ActiveEnergy,
+            "W" | "kW" | "MW" => QuantityKind::ActivePower,
+            "varh" | "kvarh" | "Mvarh" => QuantityKind::ReactiveEnergy,
+            "var" | "kvar" | "Mvar" => QuantityKind::ReactivePower,
+            "VAh" | "kVAh" | "MVAh" => QuantityKind::ApparentEnergy,
+            "VA" | "kVA" | "MVA" => QuantityKind::ApparentPower,
+            "mV" | "V" | "kV" => QuantityKind::Voltage,
+            "mA" | "A" | "kA" => QuantityKind::Current,
+            "Hz" | "kHz" => QuantityKind::Frequency,
+            _ => return None,
+        };
+
+        Some(kind)
+    }
+}
+
+/// Look up the HA device_class, unit and state_class for an OBIS code
+/// (e.g. 1-0:1.8.0) or for a field name as produced by the OMS VIF parser
+/// (e.g. flow_temperature). Returns None for values HA can't classify.
+pub fn get_ha_unit_info(key: &str) -> Option<HaUnitInfo> {
+    if validate_obis_code(key) {
+        return get_obis_ha_unit_info(key);
+    }
+
+    let info = match key {
+        "energy" => HaUnitInfo::new("energy", "kWh", "total_increasing"),
+        "volume" => HaUnitInfo::new("volume", "m³", "total_increasing"),
+        "mass" => HaUnitInfo::new("weight", "kg", "total_increasing"),
+        "power" => HaUnitInfo::new("power", "W", "measurement"),
+        "reactive_energy" => QuantityKind::ReactiveEnergy.ha_unit_info(),
+        "reactive_power" => QuantityKind::ReactivePower.ha_unit_info(),
+        "apparent_energy" => QuantityKind::ApparentEnergy.ha_unit_info(),
+        "apparent_power" => QuantityKind::ApparentPower.ha_unit_info(),
+        "power_factor" => QuantityKind::PowerFactor.ha_unit_info(),
+        "volume_flow" | "volume_flow_ext" => HaUnitInfo::new("volume_flow_rate", "m³/h", "measurement"),
+        "flow_temperature" | "return_temperature" | "external_temperature" => HaUnitInfo::new("temperature", "°C", "measurement"),
+        "temperature_difference" => HaUnitInfo::new("temperature", "K", "measurement"),
+        "pressure" => HaUnitInfo::new("pressure", "bar", "measurement"),
+        "on_time" | "operation_time" => HaUnitInfo::new("duration", "s", "total_increasing"),
+        _ => return None,
+    };
+
+    Some(info)
+}
+
+fn get_obis_ha_unit_info(code: &str) -> Option<HaUnitInfo> {
+    let (ab, cde) = code.split_once(':')?;
+    let medium = get_obis_medium(ab)?;
+    let mut groups = cde.split(['.', '*', '&']);
+    let c: u8 = groups.next()?.parse().ok()?;
+    let d: u8 = groups.next()?.parse().ok()?;
+
+    let info = match (medium, c, d) {
+        /* Active energy import/export, total and per phase */
+        (1, 1 | 2 | 15 | 16 | 21 | 22 | 41 | 42 | 61 | 62, 8) => QuantityKind::ActiveEnergy.ha_unit_info(),
+        /* Active power, total and per phase */
+        (1, 1 | 2 | 15 | 16 | 21 | 22 | 41 | 42 | 61 | 62, 7) => QuantityKind::ActivePower.ha_unit_info(),
+        /* Reactive import/export and the four quadrants, total and per phase */
+        (1, 3..=8 | 23 | 24 | 43 | 44 | 63 | 64, 8) => QuantityKind::ReactiveEnergy.ha_unit_info(),
+        (1, 3..=8 | 23 | 24 | 43 | 44 | 63 | 64, 7) => QuantityKind::ReactivePower.ha_unit_info(),
+        /* Apparent import/export, total and per phase */
+        (1, 9 | 10 | 29 | 30 | 49 | 50 | 69 | 70, 8) => QuantityKind::ApparentEnergy.ha_unit_info(),
+        (1, 9 | 10 | 29 | 30 | 49 | 50 | 69 | 70, 7) => QuantityKind::ApparentPower.ha_unit_info(),
+        (1, 11 | 31 | 51 | 71, 7) => QuantityKind::Current.ha_unit_info(),
+        (1, 12 | 32 | 52 | 72, 7) => QuantityKind::Voltage.ha_unit_info(),
+        (1, 13 | 33 | 53 | 73, 7) => QuantityKind::PowerFactor.ha_unit_info(),
+        (1, 14, 7) => QuantityKind::Frequency.ha_unit_info(),
+        (6, 1, _) => HaUnitInfo::new("energy", "kWh", "total_increasing"),
+        (6, 2, _) => HaUnitInfo::new("volume", "m³", "total_increasing"),
+        (6, 8, _) => HaUnitInfo::new("power", "kW", "measurement"),
+        (6, 9, _) => HaUnitInfo::new("volume_flow_rate", "m³/h", "measurement"),
+        (6, 10 | 11, _) => HaUnitInfo::new("temperature", "°C", "measurement"),
+        (6, 12, _) => HaUnitInfo::new("temperature", "K", "measurement"),
+        (7, 3, _) => HaUnitInfo::new("gas", "m³", "total_increasing"),
+        (7, 41, _) => HaUnitInfo::new("temperature", "°C", "measurement"),
+        (7, 42, _) => HaUnitInfo::new("pressure", "bar", "measurement"),
+        (7, 43, _) => HaUnitInfo::new("volume_flow_rate", "m³/h", "measurement"),
+        (8 | 9, 1, _) => HaUnitInfo::new("water", "m³", "total_increasing"),
+        (8 | 9, 2, _) => HaUnitInfo::new("volume_flow_rate", "m³/h", "measurement"),
+        _ => return None,
+    };
+
+    Some(info)
+}
+
 pub fn get_easymeter_obis_mapping() -> HashMap<&'static str, &'static str> {
     let mut map = HashMap::new();
     
@@ -121,43 +343,47 @@ pub fn get_ebz_obis_mapping() -> HashMap<&'static str, &'static str> {
 pub fn validate_obis_code(code: &str) -> bool {
     // OBIS code format: A-B:C.D.E*F
     // A: Medium (0=abstract, 1=electricity, 6=heat, 7=gas, 8=water)
-    // B: Channel (0-15)
-    // C: Physical value (1-255) 
+    // B: Channel (0-255, 129 and up are manufacturer specific like 129-129:199.130.3)
+    // C: Physical value (0-255, IEC 62056-61 also allows the letters C, F, L and P)
     // D: Processing method (0-255)
     // E: Tariff/Time (0-255)
-    // F: Storage (optional, 0-255)
-    
+    // F: Storage (optional, 0-255, separated by '*' or '&')
+
+    fn is_group(part: &str) -> bool {
+        !part.is_empty() && part.parse::<u8>().is_ok()
+    }
+
     let parts: Vec<&str> = code.split(':').collect();
     if parts.len() != 2 {
         return false;
     }
-    
+
     // Check A-B part
     let ab_parts: Vec<&str> = parts[0].split('-').collect();
-    if ab_parts.len() != 2 {
+    if ab_parts.len() != 2 || !ab_parts.iter().all(|p| is_group(p)) {
         return false;
     }
-    
-    // Check C.D.E part (and optional *F)
-    let cde_part = parts[1];
-    let cde_parts: Vec<&str> = if cde_part.contains('*') {
-        cde_part.split('*').next().unwrap_or("").split('.').collect()
-    } else {
-        cde_part.split('.').collect()
+
+    // Split off the optional F group
+    let (cde_part, f_part) = match parts[1].split_once(['*', '&']) {
+        Some((cde, f)) => (cde, Some(f)),
+        None => (parts[1], None),
     };
-    
-    if cde_parts.len() != 3 {
-        return false;
-    }
-    
-    // Validate that all parts are numeric
-    for part in ab_parts.iter().chain(cde_parts.iter()) {
-        if part.parse::<u8>().is_err() {
+
+    if let Some(f) = f_part {
+        if !is_group(f) {
             return false;
         }
     }
-    
-    true
+
+    // Check C.D.E part
+    let cde_parts: Vec<&str> = cde_part.split('.').collect();
+    if cde_parts.len() != 3 {
+        return false;
+    }
+
+    let c_valid = is_group(cde_parts[0]) || matches!(cde_parts[0], "C" | "F" | "L" | "P");
+    c_valid && is_group(cde_parts[1]) && is_group(cde_parts[2])
 }
 
 pub fn normalize_obis_code(code: &str) -> String {
@@ -174,10 +400,57 @@ pub fn extract_unit(value_content: &str) -> Option<String> {
     None
 }
 
+/// Number of a value like "000123.456*kWh" or "-001.2*kW". Only values with a unit or a
+/// decimal point are numbers, serial numbers and the like keep their leading zeros as text.
+pub fn parse_numeric_value(value_content: &str) -> Option<f64> {
+    let (number, unit) = match value_content.rfind('*') {
+        Some(star_pos) => (&value_content[..star_pos], Some(&value_content[star_pos + 1..])),
+        None => (value_content, None),
+    };
+    let number = number.trim();
+
+    if unit.is_none_or(|u| u.is_empty()) && !number.contains('.') {
+        return None;
+    }
+
+    let digits = number.strip_prefix(['+', '-']).unwrap_or(number);
+    let mut parts = digits.splitn(2, '.');
+    let valid = parts.all(|p| !p.is_empty() && p.chars().all(|c| c.is_ascii_digit()));
+    if !valid {
+        return None;
+    }
+
+    number.parse().ok()
+}
+
+/// Decode a FLAG manufacturer code packed like the M-Bus M field, three letters of 5 bits each
+/// with an offset of 64 (see https://www.m-bus.de/man.html)
+pub fn decode_flag_manufacturer(m: u16) -> String {
+    [(m >> 10) & 0x1F, (m >> 5) & 0x1F, m & 0x1F]
+        .iter()
+        .map(|c| ((c + 64) as u8) as char)
+        .collect()
+}
+
+/// A FLAG code consists of three upper case letters
+pub fn is_valid_flag_code(code: &str) -> bool {
+    code.len() == 3 && code.chars().all(|c| c.is_ascii_uppercase())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_decode_flag_manufacturer() {
+        assert_eq!(decode_flag_manufacturer(0x1593), "ELS");
+        assert_eq!(decode_flag_manufacturer(0x15A8), "EMH");
+        assert_eq!(decode_flag_manufacturer(0x30FA), "LGZ");
+        assert!(is_valid_flag_code("ISK"));
+        assert!(!is_valid_flag_code("@@@"));
+        assert!(!is_valid_flag_code("EM"));
+    }
+
     #[test]
     fn test_validate_obis_code() {
         assert!(validate_obis_code("1-0:1.8.1"));
@@ -188,6 +461,22 @@ mod tests {
         assert!(!validate_obis_code("1-0:1.8"));
     }
 
+    #[test]
+    fn test_validate_obis_code_full_grammar() {
+        // EMH manufacturer code
+        assert!(validate_obis_code("129-129:199.130.3"));
+        // Storage group
+        assert!(validate_obis_code("1-0:1.8.0*255"));
+        assert!(validate_obis_code("1-0:1.8.0&01"));
+        assert!(validate_obis_code("0-0:C.1.0"));
+        assert!(!validate_obis_code("1-0:F.F.0"));
+        assert!(!validate_obis_code("1-0:1.8.0*"));
+        assert!(!validate_obis_code("1-0:1.8.0*256"));
+        assert!(!validate_obis_code("1-0:1.8.0*abc"));
+        assert!(!validate_obis_code("256-0:1.8.0"));
+        assert!(!validate_obis_code("1-0:1..0"));
+    }
+
     #[test]
     fn test_get_obis_description() {
         assert_eq!(get_obis_description("1-0:1.8.1"), Some("Active energy + (tariff 1)"));
@@ -195,6 +484,71 @@ mod tests {
         assert_eq!(get_obis_description("nonexistent"), None);
     }
 
+    #[test]
+    fn test_obis_mapping_for_medium() {
+        assert_eq!(get_obis_medium("7-0:3.0.0"), Some(7));
+        assert_eq!(get_obis_medium("invalid"), None);
+
+        assert_eq!(get_obis_mapping_for_medium(7).get("7-0:3.0.0"), Some(&"gas_volume"));
+        assert_eq!(get_obis_mapping_for_medium(6).get("6-0:1.0.0"), Some(&"heat_energy"));
+        assert_eq!(get_obis_mapping_for_medium(8).get("8-0:1.0.0"), Some(&"water_volume"));
+        assert!(get_obis_mapping_for_medium(42).is_empty());
+        assert_eq!(get_obis_description("7-0:3.0.0"), Some("Gas volume (total)"));
+
+        assert_eq!(get_obis_code_for_field("gas_volume"), Some("7-0:3.0.0"));
+        assert_eq!(get_obis_code_for_field("total_energy_consumed"), Some("1-0:1.8.0"));
+        assert_eq!(get_obis_code_for_field("volume"), None);
+    }
+
+    #[test]
+    fn test_get_ha_unit_info() {
+        let energy = get_ha_unit_info("1-0:1.8.0").unwrap();
+        assert_eq!((energy.device_class, energy.unit, energy.state_class), ("energy", "kWh", "total_increasing"));
+        assert_eq!(get_ha_unit_info("1-0:2.8.1*255").unwrap().device_class, "energy");
+        assert_eq!(get_ha_unit_info("1-0:41.7.0").unwrap().device_class, "power");
+        assert_eq!(get_ha_unit_info("1-0:52.7.0").unwrap().unit, "V");
+        assert_eq!(get_ha_unit_info("7-0:3.0.0").unwrap().device_class, "gas");
+        assert_eq!(get_ha_unit_info("8-0:1.0.0").unwrap().device_class, "water");
+        assert_eq!(get_ha_unit_info("0-0:1.0.0"), None);
+
+        /* Reactive and apparent quantities */
+        assert_eq!(get_ha_unit_info("1-0:3.8.0").unwrap().device_class, "reactive_energy");
+        assert_eq!(get_ha_unit_info("1-0:7.8.0").unwrap().unit, "kvarh");
+        assert_eq!(get_ha_unit_info("1-0:43.7.0").unwrap().device_class, "reactive_power");
+        assert_eq!(get_ha_unit_info("1-0:9.7.0").unwrap().device_class, "apparent_power");
+        let apparent_energy = get_ha_unit_info("1-0:10.8.0").unwrap();
+        assert_eq!((apparent_energy.device_class, apparent_energy.state_class), ("", "total_increasing"));
+
+        /* VIF field names of the OMS parser */
+        assert_eq!(get_ha_unit_info("flow_temperature").unwrap().unit, "°C");
+        assert_eq!(get_ha_unit_info("energy").unwrap().state_class, "total_increasing");
+        assert_eq!(get_ha_unit_info("error_flags"), None);
+        assert_eq!(get_ha_unit_info("reactive_power").unwrap().unit, "var");
+    }
+
+    #[test]
+    fn test_quantity_from_unit() {
+        assert_eq!(QuantityKind::from_unit("kvarh"), Some(QuantityKind::ReactiveEnergy));
+        assert_eq!(QuantityKind::from_unit("var"), Some(QuantityKind::ReactivePower));
+        assert_eq!(QuantityKind::from_unit("kVA"), Some(QuantityKind::ApparentPower));
+        assert_eq!(QuantityKind::from_unit("Wh"), Some(QuantityKind::ActiveEnergy));
+        assert_eq!(QuantityKind::from_unit("m³"), None);
+        assert_eq!(QuantityKind::ApparentPower.ha_unit_info().unit, "VA");
+    }
+
+    #[test]
+    fn test_parse_numeric_value() {
+        assert_eq!(parse_numeric_value("000123.456*kWh"), Some(123.456));
+        assert_eq!(parse_numeric_value("-001.234*kW"), Some(-1.234));
+        assert_eq!(parse_numeric_value("+0230*V"), Some(230.0));
+        assert_eq!(parse_numeric_value("0.998"), Some(0.998));
+        assert_eq!(parse_numeric_value("00012345"), None);
+        assert_eq!(parse_numeric_value("210101120000W"), None);
+        assert_eq!(parse_numeric_value("1ESY1160123456*"), None);
+        assert_eq!(parse_numeric_value("12.*kWh"), None);
+        assert_eq!(parse_numeric_value("1e5*kWh"), None);
+    }
+
     #[test]
     fn test_extract_unit() {
         assert_eq!(extract_unit("123.456*kWh"), Some("kWh".to_string()));
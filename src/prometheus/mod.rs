@@ -0,0 +1,230 @@
+//! Prometheus exposition of the latest metering values and the service health
+//!
+//! Every metering transmission handled by the MQTT manager updates a shared map
+//! holding the newest values per meter, the API renders it in text format.
+//! The generic metrics describe the service itself, all of them use the e2m_ prefix.
+
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+
+use lazy_static::lazy_static;
+use serde_json::Value;
+
+use crate::mqtt::{AppStatus, MqttConnectionStatus, MqttHealthStatus};
+use crate::MeteringData;
+
+/// Newest values of one meter
+#[derive(Clone)]
+pub struct LatestValues {
+    pub protocol: String,
+    pub meter_name: String,
+    pub metered_time: u64,
+    pub values: serde_json::Map<String, Value>,
+}
+
+lazy_static! {
+    /// Latest values keyed by (protocol, meter name)
+    pub static ref LATEST_VALUES: RwLock<BTreeMap<(String, String), LatestValues>> = RwLock::new(BTreeMap::new());
+}
+
+/// Remember the values of a metering transmission, values not sent again are kept
+pub fn update_latest_values(data: &MeteringData) {
+    let protocol = data.protocol.to_string();
+    let mut latest = LATEST_VALUES.write().unwrap();
+
+    let entry = latest.entry((protocol.clone(), data.meter_name.clone()))
+        .or_insert_with(|| LatestValues {
+            protocol,
+            meter_name: data.meter_name.clone(),
+            metered_time: 0,
+            values: serde_json::Map::new(),
+        });
+
+    entry.metered_time = data.metered_time;
+    for (key, value) in data.metered_values.iter() {
+        entry.values.insert(key.clone(), value.clone());
+    }
+}
+
+/// Replace everything not allowed in a metric name by an underscore
+fn sanitize_metric_name(name: &str) -> String {
+    let mut result: String = name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' { c.to_ascii_lowercase() } else { '_' })
+        .collect();
+
+    if result.starts_with(|c: char| c.is_ascii_digit()) {
+        result.insert(0, '_');
+    }
+
+    result
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn value_to_gauge(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(n) => n.as_f64(),
+        Value::Bool(b) => Some(if *b { 1.0 } else { 0.0 }),
+        _ => None,
+    }
+}
+
+/// Render the given meters in Prometheus text exposition format
+pub fn render_metering(latest: &BTreeMap<(String, String), LatestValues>) -> String {
+    let mut out = String::new();
+
+    for meter in latest.values() {
+        let labels = format!("meter=\"{}\",protocol=\"{}\"",
+                                escape_label(&meter.meter_name), escape_label(&meter.protocol));
+
+        for (field, value) in meter.values.iter() {
+            let gauge = match value_to_gauge(value) {
+                Some(v) => v,
+                None => continue, /* text values can not be exported */
+            };
+
+            let name = sanitize_metric_name(&format!("e2m_{}_{}_{}", meter.protocol, meter.meter_name, field));
+            out.push_str(&format!("# TYPE {name} gauge\n"));
+            out.push_str(&format!("{name}{{{labels}}} {gauge}\n"));
+        }
+
+        let name = sanitize_metric_name(&format!("e2m_{}_{}_metered_time", meter.protocol, meter.meter_name));
+        out.push_str(&format!("# TYPE {name} gauge\n"));
+        out.push_str(&format!("{name}{{{labels}}} {}\n", meter.metered_time));
+    }
+
+    out
+}
+
+/// Render all known meters
+pub fn render_latest_metering() -> String {
+    let latest = LATEST_VALUES.read().unwrap();
+    render_metering(&latest)
+}
+
+/// Meter counts of one config section for the generic metrics
+pub struct ProtocolMeters {
+    pub configured: usize,
+    pub reporting: usize,
+    pub oldest_last_seen_age: Option<u64>,
+}
+
+/// Append a metric with its HELP and TYPE lines, samples are (labels, value)
+fn push_metric(out: &mut String, name: &str, kind: &str, help: &str, samples: &[(String, f64)]) {
+    if samples.is_empty() {
+        return;
+    }
+
+    out.push_str(&format!("# HELP {name} {help}\n"));
+    out.push_str(&format!("# TYPE {name} {kind}\n"));
+    for (labels, value) in samples {
+        match labels.is_empty() {
+            true => out.push_str(&format!("{name} {value}\n")),
+            false => out.push_str(&format!("{name}{{{labels}}} {value}\n")),
+        }
+    }
+}
+
+/// Render the service health, `now` is used for the age of the last publish
+pub fn render_generic(status: &AppStatus, protocols: &BTreeMap<String, ProtocolMeters>, dropped: &BTreeMap<String, u64>,
+                      now: std::time::Instant) -> String {
+    let mut out = String::new();
+
+    let brokers: Vec<(String, &MqttHealthStatus)> = std::iter::once(("main".to_string(), &status.mqtt_health))
+        .chain(status.brokers.iter().map(|(name, health)| (name.clone(), health)))
+        .map(|(name, health)| (format!("broker=\"{}\"", escape_label(&name)), health))
+        .collect();
+    let by_broker = |value: &dyn Fn(&MqttHealthStatus) -> Option<f64>| -> Vec<(String, f64)> {
+        brokers.iter().filter_map(|(labels, health)| Some((labels.clone(), value(health)?))).collect()
+    };
+    let by_protocol = |value: &dyn Fn(&ProtocolMeters) -> Option<f64>| -> Vec<(String, f64)> {
+        protocols.iter()
+            .filter_map(|(name, meters)| Some((format!("protocol=\"{}\"", escape_label(name)), value(meters)?)))
+            .collect()
+    };
+
+    push_metric(&mut out, "e2m_uptime_seconds", "gauge", "Seconds since energy2mqtt started",
+                &[(String::new(), status.uptime_seconds() as f64)]);
+    push_metric(&mut out, "e2m_mqtt_connected", "gauge", "Whether the MQTT broker is connected",
+                &by_broker(&|h| Some(if matches!(h.status, MqttConnectionStatus::Connected) { 1.0 } else { 0.0 })));
+    push_metric(&mut out, "e2m_mqtt_connection_attempts_total", "counter", "Connection attempts to the MQTT broker",
+                &by_broker(&|h| Some(h.connection_attempts as f64)));
+    push_metric(&mut out, "e2m_mqtt_last_publish_age_seconds", "gauge", "Seconds since the last message was published",
+                &by_broker(&|h| h.last_message_sent.map(|t| now.saturating_duration_since(t).as_secs() as f64)));
+    push_metric(&mut out, "e2m_meters_configured", "gauge", "Meters in the configuration",
+                &by_protocol(&|p| Some(p.configured as f64)));
+    push_metric(&mut out, "e2m_meters_reporting", "gauge", "Meters currently online",
+                &by_protocol(&|p| Some(p.reporting as f64)));
+    push_metric(&mut out, "e2m_meters_oldest_last_seen_age_seconds", "gauge", "Seconds since the meter which reported longest ago sent data",
+                &by_protocol(&|p| p.oldest_last_seen_age.map(|a| a as f64)));
+    push_metric(&mut out, "e2m_input_dropped_total", "counter", "Input messages dropped by the rate limit",
+                &dropped.iter().map(|(input, count)| (format!("input=\"{}\"", escape_label(input)), *count as f64)).collect::<Vec<_>>());
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sanitize_metric_name() {
+        assert_eq!(sanitize_metric_name("e2m_ModbusTCP_meter-1_power"), "e2m_modbustcp_meter_1_power");
+        assert_eq!(sanitize_metric_name("1abc"), "_1abc");
+    }
+
+    #[test]
+    fn test_render_metering() {
+        let mut values = serde_json::Map::new();
+        values.insert("power".to_string(), Value::from(12.5));
+        values.insert("state".to_string(), Value::from("charging"));
+        values.insert("relay".to_string(), Value::from(true));
+
+        let mut latest = BTreeMap::new();
+        latest.insert(("SML".to_string(), "main".to_string()), LatestValues {
+            protocol: "SML".to_string(),
+            meter_name: "main".to_string(),
+            metered_time: 1700000000,
+            values,
+        });
+
+        let out = render_metering(&latest);
+        assert!(out.contains("# TYPE e2m_sml_main_power gauge\n"));
+        assert!(out.contains("e2m_sml_main_power{meter=\"main\",protocol=\"SML\"} 12.5\n"));
+        assert!(out.contains("e2m_sml_main_relay{meter=\"main\",protocol=\"SML\"} 1\n"));
+        assert!(out.contains("e2m_sml_main_metered_time{meter=\"main\",protocol=\"SML\"} 1700000000\n"));
+        assert!(!out.contains("state"));
+    }
+
+    #[test]
+    fn test_render_generic() {
+        let now = std::time::Instant::now();
+        let mut status = AppStatus::new();
+        status.mqtt_health.status = MqttConnectionStatus::Connected;
+        status.mqtt_health.connection_attempts = 2;
+        status.mqtt_health.last_message_sent = now.checked_sub(std::time::Duration::from_secs(5));
+        status.brokers.insert("backup".to_string(), MqttHealthStatus::new());
+
+        let mut protocols = BTreeMap::new();
+        protocols.insert("modbus".to_string(), ProtocolMeters { configured: 3, reporting: 2, oldest_last_seen_age: Some(42) });
+        protocols.insert("oms".to_string(), ProtocolMeters { configured: 1, reporting: 0, oldest_last_seen_age: None });
+
+        let mut dropped = BTreeMap::new();
+        dropped.insert("oms_input".to_string(), 7);
+
+        let out = render_generic(&status, &protocols, &dropped, now);
+        assert!(out.contains("# HELP e2m_uptime_seconds Seconds since energy2mqtt started\n# TYPE e2m_uptime_seconds gauge\ne2m_uptime_seconds "));
+        assert!(out.contains("e2m_mqtt_connected{broker=\"main\"} 1\n"));
+        assert!(out.contains("e2m_mqtt_connected{broker=\"backup\"} 0\n"));
+        assert!(out.contains("# TYPE e2m_mqtt_connection_attempts_total counter\ne2m_mqtt_connection_attempts_total{broker=\"main\"} 2\n"));
+        assert!(out.contains("e2m_mqtt_last_publish_age_seconds{broker=\"main\"} 5\n"));
+        assert!(!out.contains("e2m_mqtt_last_publish_age_seconds{broker=\"backup\"}"));
+        assert!(out.contains("e2m_meters_configured{protocol=\"modbus\"} 3\n"));
+        assert!(out.contains("e2m_meters_reporting{protocol=\"oms\"} 0\n"));
+        assert!(out.contains("e2m_meters_oldest_last_seen_age_seconds{protocol=\"modbus\"} 42\n"));
+        assert!(!out.contains("e2m_meters_oldest_last_seen_age_seconds{protocol=\"oms\"}"));
+        assert!(out.contains("# TYPE e2m_input_dropped_total counter\ne2m_input_dropped_total{input=\"oms_input\"} 7\n"));
+    }
+}
@@ -0,0 +1,104 @@
+//! Cron based read schedules, used by Modbus devices
+//!
+//! Expressions use the classic five fields (minute hour day month weekday) or six/seven
+//! fields with leading seconds and an optional year as understood by the cron crate.
+//! They are evaluated in local time, so "*/15 * * * *" fires exactly on the quarter hours.
+//! Weekdays of five field expressions are numbered like classic cron (0 and 7 are Sunday),
+//! six/seven field expressions keep the numbering of the cron crate (1 is Sunday, 7 Saturday).
+//! Names like Mon-Fri mean the same in both.
+
+use std::str::FromStr;
+use std::time::Duration;
+use chrono::{DateTime, Local};
+pub use cron::Schedule;
+
+/// Weekday field of classic cron (0-7, Sunday is 0 and 7) in the numbering of the cron crate
+/// (1-7, Sunday is 1). Numeric days, ranges and steps are spelled out, names are kept.
+fn classic_weekdays(field: &str) -> String {
+    field.split(',').map(|part| {
+        let (base, step) = part.split_once('/').map_or((part, None), |(b, s)| (b, Some(s)));
+        /* A single day with a step runs until Saturday like in classic cron */
+        let (from, to) = base.split_once('-').unwrap_or((base, if step.is_some() { "6" } else { base }));
+        match (from.parse::<usize>(), to.parse::<usize>(), step.map_or(Ok(1), str::parse::<usize>)) {
+            (Ok(from), Ok(to), Ok(step)) if to <= 7 && step > 0 => (from..=to).step_by(step)
+                .map(|day| (day % 7 + 1).to_string())
+                .collect::<Vec<String>>()
+                .join(","),
+            _ => part.to_string(),
+        }
+    }).collect::<Vec<String>>().join(",")
+}
+
+/// Parse a cron expression, five field expressions fire at second 0
+pub fn parse_cron(expr: &str) -> Result<Schedule, String> {
+    let expr = expr.trim();
+    let fields: Vec<&str> = expr.split_whitespace().collect();
+    let full = match fields.len() {
+        5 => format!("0 {} {}", fields[..4].join(" "), classic_weekdays(fields[4])),
+        _ => expr.to_string(),
+    };
+
+    Schedule::from_str(&full).map_err(|e| format!("invalid cron expression \"{expr}\": {e}"))
+}
+
+/// Time from `now` until the schedule fires next, None if it never fires again
+pub fn until_next_after(schedule: &Schedule, now: DateTime<Local>) -> Option<Duration> {
+    let next = schedule.after(&now).next()?;
+    Some((next - now).to_std().unwrap_or(Duration::ZERO))
+}
+
+/// Time until the schedule fires next, None if it never fires again
+pub fn until_next(schedule: &Schedule) -> Option<Duration> {
+    until_next_after(schedule, Local::now())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_parse_cron() {
+        assert!(parse_cron("* * * * *").is_ok());
+        assert!(parse_cron("30 */5 * * * *").is_ok());
+        assert!(parse_cron("0 0 6-22 * * Mon-Fri 2030").is_ok());
+        assert!(parse_cron("every minute").is_err());
+        assert!(parse_cron("").is_err());
+    }
+
+    #[test]
+    fn test_classic_weekdays() {
+        assert_eq!(classic_weekdays("0"), "1");
+        assert_eq!(classic_weekdays("7"), "1");
+        assert_eq!(classic_weekdays("1-5"), "2,3,4,5,6");
+        assert_eq!(classic_weekdays("5-7"), "6,7,1");
+        assert_eq!(classic_weekdays("0,6"), "1,7");
+        assert_eq!(classic_weekdays("1/2"), "2,4,6");
+        assert_eq!(classic_weekdays("*"), "*");
+        assert_eq!(classic_weekdays("*/2"), "*/2");
+        assert_eq!(classic_weekdays("Mon-Fri"), "Mon-Fri");
+
+        /* 2025-03-01 is a Saturday */
+        let now = Local.with_ymd_and_hms(2025, 3, 1, 12, 0, 0).unwrap();
+        let sunday = parse_cron("0 0 * * 0").unwrap();
+        assert_eq!(until_next_after(&sunday, now), Some(Duration::from_secs(12 * 3600)));
+        let workdays = parse_cron("0 0 * * 1-5").unwrap();
+        assert_eq!(until_next_after(&workdays, now), Some(Duration::from_secs(36 * 3600)));
+        let named = parse_cron("0 0 * * Mon-Fri").unwrap();
+        assert_eq!(until_next_after(&named, now), Some(Duration::from_secs(36 * 3600)));
+    }
+
+    #[test]
+    fn test_until_next_is_aligned() {
+        let now = Local.with_ymd_and_hms(2025, 3, 1, 12, 7, 42).unwrap();
+
+        let every_minute = parse_cron("* * * * *").unwrap();
+        assert_eq!(until_next_after(&every_minute, now), Some(Duration::from_secs(18)));
+
+        let quarter_hours = parse_cron("*/15 * * * *").unwrap();
+        assert_eq!(until_next_after(&quarter_hours, now), Some(Duration::from_secs(7 * 60 + 18)));
+
+        let past = parse_cron("0 0 0 1 1 * 2020").unwrap();
+        assert_eq!(until_next_after(&past, now), None);
+    }
+}
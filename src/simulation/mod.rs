@@ -0,0 +1,121 @@
+//! Replay of captured telegrams
+//!
+//! A capture file contains one frame per line as `<protocol> <hex>`, e.g. `oms 2E4493...`.
+//! Supported protocols are `oms` (a leading `!` marks frames without CRC), `sml` and
+//! `iec62056` whose ASCII telegram is hex encoded as well. Empty lines and lines starting
+//! with `#` are ignored. The frames are handed to the input callbacks as if they were
+//! received via MQTT, so parsing and publishing work exactly like for live data.
+
+use std::time::Duration;
+use log::{error, info, warn};
+
+use crate::mqtt::{LiveEvent, CALLBACKS, LIVE_EVENTS};
+
+#[derive(Debug, PartialEq)]
+pub struct SimulatedFrame {
+    pub topic: String,
+    pub payload: String,
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn parse_line(line: &str) -> Result<SimulatedFrame, String> {
+    let (proto, data) = line.split_once(char::is_whitespace)
+        .ok_or_else(|| "expected \"<protocol> <hex>\"".to_string())?;
+    let data = data.trim();
+
+    let (input, payload) = match proto.to_lowercase().as_str() {
+        "oms" => {
+            decode_hex(data.trim_start_matches('!')).ok_or("invalid hex data")?;
+            ("oms_input", data.to_string())
+        },
+        "sml" => {
+            decode_hex(data).ok_or("invalid hex data")?;
+            ("sml_input", data.to_string())
+        },
+        "iec62056" => {
+            let bytes = decode_hex(data).ok_or("invalid hex data")?;
+            let telegram = String::from_utf8(bytes).map_err(|_| "IEC 62056-21 telegram is not ASCII")?;
+            ("iec62056_input", telegram)
+        },
+        p => return Err(format!("unknown protocol {p}")),
+    };
+
+    Ok(SimulatedFrame { topic: format!("{}/{input}", crate::mqtt::get_topic_prefix()), payload })
+}
+
+/// Parse a capture file, errors name the offending line
+pub fn parse_capture(content: &str) -> Result<Vec<SimulatedFrame>, String> {
+    content.lines()
+        .enumerate()
+        .map(|(no, line)| (no + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(no, line)| parse_line(line).map_err(|e| format!("line {no}: {e}")))
+        .collect()
+}
+
+/// Feed all frames of a capture file to the protocol managers, one frame per interval
+pub async fn replay(path: &str, interval: Duration) {
+    let frames = match std::fs::read_to_string(path).map_err(|e| e.to_string()).and_then(|c| parse_capture(&c)) {
+        Ok(f) => f,
+        Err(e) => {
+            error!("Unable to load simulation file {path}: {e}");
+            return;
+        }
+    };
+
+    info!("Simulating {} captured frames from {path}", frames.len());
+    for frame in frames {
+        /* The managers register their input topics during startup */
+        let mut waited = 0;
+        while !CALLBACKS.read().await.get_topics().await.contains(&frame.topic) {
+            if waited == 10 {
+                warn!("Nobody listens on {}, is the protocol enabled?", frame.topic);
+                break;
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            waited += 1;
+        }
+
+        let payload_json = serde_json::Value::String(frame.payload.clone());
+        let _ = LIVE_EVENTS.send(LiveEvent::incoming(frame.topic.clone(), payload_json));
+        CALLBACKS.write().await.send(frame.topic, frame.payload).await;
+
+        tokio::time::sleep(interval).await;
+    }
+
+    info!("Simulation of {path} finished");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_capture() {
+        let capture = "# captured at the test bench\n\
+                       oms !2E449315\n\
+                       \n\
+                       SML 1b1b1b1b\n\
+                       iec62056 2f49534b350d0a\n";
+
+        let frames = parse_capture(capture).unwrap();
+        assert_eq!(frames, vec![
+            SimulatedFrame { topic: "energy2mqtt/oms_input".to_string(), payload: "!2E449315".to_string() },
+            SimulatedFrame { topic: "energy2mqtt/sml_input".to_string(), payload: "1b1b1b1b".to_string() },
+            SimulatedFrame { topic: "energy2mqtt/iec62056_input".to_string(), payload: "/ISK5\r\n".to_string() },
+        ]);
+    }
+
+    #[test]
+    fn test_parse_capture_errors() {
+        assert_eq!(parse_capture("oms 2E4\n").unwrap_err(), "line 1: invalid hex data");
+        assert_eq!(parse_capture("\nknx 0102\n").unwrap_err(), "line 2: unknown protocol knx");
+        assert_eq!(parse_capture("sml\n").unwrap_err(), "line 1: expected \"<protocol> <hex>\"");
+    }
+}
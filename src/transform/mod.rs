@@ -0,0 +1,79 @@
+//! Expressions applied to decoded meter values
+//!
+//! SML and OMS meters can carry transforms, a map of field name to evalexpr expression
+//! (e.g. energy_kwh: "energy_wh / 1000"). Every numeric value of the reading, including
+//! strings starting with a number like "1234.5 Wh", is available under its field name with
+//! everything but letters, digits and '_' replaced by '_' (1-0:1.8.0 becomes 1_0_1_8_0).
+//! The expressions only see the decoded values, the results are stored as plain numbers
+//! under their field name, replacing a decoded value of the same name.
+
+use std::collections::BTreeMap;
+use evalexpr::{ContextWithMutableVariables, DefaultNumericTypes, HashMapContext};
+use log::error;
+use serde_json::{Map, Value};
+
+/// Name a field is visible to the expressions under
+pub fn variable_name(field: &str) -> String {
+    field.chars()
+        .map(|c| if c.is_alphanumeric() || c == '_' { c } else { '_' })
+        .collect()
+}
+
+fn numeric_value(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => s.split_whitespace().next()?.parse().ok(),
+        _ => None,
+    }
+}
+
+/// Evaluate the transforms of a meter on its values, failing expressions are logged and skipped
+pub fn apply_transforms(meter: &str, values: &mut Map<String, Value>, transforms: &BTreeMap<String, String>) {
+    if transforms.is_empty() {
+        return;
+    }
+
+    let mut context = HashMapContext::<DefaultNumericTypes>::new();
+    for (field, value) in values.iter() {
+        if let Some(v) = numeric_value(value) {
+            let _ = context.set_value(variable_name(field), evalexpr::Value::Float(v));
+        }
+    }
+
+    for (field, expression) in transforms {
+        match evalexpr::eval_number_with_context(expression, &context) {
+            Ok(result) => match serde_json::Number::from_f64(result) {
+                Some(n) => { values.insert(field.clone(), Value::Number(n)); },
+                None => error!("Meter {meter}: transform {field} evaluated to {result}"),
+            },
+            Err(e) => error!("Meter {meter}: failed to evaluate transform {field}: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_transforms() {
+        let mut values = Map::new();
+        values.insert("1-0:1.8.0".to_string(), Value::from("12345.5 Wh"));
+        values.insert("1-0:2.8.0".to_string(), Value::from("345.5 Wh"));
+        values.insert("volume".to_string(), Value::from(12.5));
+        values.insert("volume_unit".to_string(), Value::from("m³"));
+
+        let mut transforms = BTreeMap::new();
+        transforms.insert("energy_half".to_string(), "1_0_1_8_0 / 2".to_string());
+        transforms.insert("net_wh".to_string(), "1_0_1_8_0 - 1_0_2_8_0".to_string());
+        transforms.insert("volume".to_string(), "volume * 1000".to_string());
+        transforms.insert("broken".to_string(), "unknown_field * 2".to_string());
+
+        apply_transforms("meter", &mut values, &transforms);
+        assert_eq!(values["energy_half"], Value::from(6172.75));
+        assert_eq!(values["net_wh"], Value::from(12000.0));
+        assert_eq!(values["volume"], Value::from(12500.0));
+        assert!(!values.contains_key("broken"));
+        assert_eq!(values["1-0:1.8.0"], Value::from("12345.5 Wh"));
+    }
+}
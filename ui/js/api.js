@@ -13,6 +13,59 @@ class Energy2MqttApi {
         this.configChangeCallbacks = [];
     }
 
+    // =========================================================================
+    // Authentication
+    // =========================================================================
+
+    getToken() {
+        return localStorage.getItem('e2m_auth_token') || '';
+    }
+
+    /**
+     * Ask for the API token after the server rejected a request
+     * Returns true if a token was entered and the request should be retried
+     */
+    askForToken() {
+        const token = window.prompt('This energy2mqtt instance requires an API token:');
+        if (!token) {
+            return false;
+        }
+
+        localStorage.setItem('e2m_auth_token', token.trim());
+        return true;
+    }
+
+    /**
+     * Websockets can not send headers, the token is passed as query parameter
+     */
+    withToken(url) {
+        const token = this.getToken();
+        return token ? `${url}?access_token=${encodeURIComponent(token)}` : url;
+    }
+
+    /**
+     * fetch() with the API token, asks for the token once the server requires one
+     */
+    async fetch(url, options = {}) {
+        const send = (token) => {
+            const headers = { ...(options.headers || {}) };
+            if (token) {
+                headers['Authorization'] = `Bearer ${token}`;
+            }
+            return fetch(url, { ...options, headers });
+        };
+
+        const token = this.getToken();
+        const response = await send(token);
+
+        // Parallel requests may have been rejected before the token was entered, only ask once
+        if (response.status === 401 && (this.getToken() !== token || this.askForToken())) {
+            return send(this.getToken());
+        }
+
+        return response;
+    }
+
     // =========================================================================
     // HTTP Methods
     // =========================================================================
@@ -31,7 +84,7 @@ class Energy2MqttApi {
         }
 
         try {
-            const response = await fetch(url, options);
+            const response = await this.fetch(url, options);
 
             if (!response.ok) {
                 const errorText = await response.text();
@@ -98,8 +151,8 @@ class Energy2MqttApi {
         return this.post('/api/v1/ha/config/save');
     }
 
-    async reloadConfig() {
-        return this.post('/api/v1/ha/config/reload');
+    async reloadConfig(discard = false) {
+        return this.post(`/api/v1/ha/config/reload${discard ? '?discard=true' : ''}`);
     }
 
     async restart() {
@@ -285,7 +338,7 @@ class Energy2MqttApi {
         const wsUrl = `${wsProtocol}//${window.location.host}/api/v1/ws/configChanges`;
 
         try {
-            this.wsConnection = new WebSocket(wsUrl);
+            this.wsConnection = new WebSocket(this.withToken(wsUrl));
 
             this.wsConnection.onopen = () => {
                 console.log('WebSocket connected');
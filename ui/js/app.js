@@ -1046,7 +1046,11 @@ class Energy2MqttApp {
         // Reload config
         document.getElementById('reloadConfig')?.addEventListener('click', async () => {
             try {
-                await window.api.reloadConfig();
+                const status = await window.api.getConfigStatus();
+                if (status?.dirty && !confirm('There are unsaved changes, reloading will discard them. Continue?')) {
+                    return;
+                }
+                await window.api.reloadConfig(status?.dirty);
                 Toast.success('Configuration reloaded');
                 await this.loadInitialData();
             } catch (error) {
@@ -66,7 +66,7 @@ class LiveView {
         const wsUrl = `${wsProtocol}//${window.location.host}/api/v1/ws/live`;
 
         try {
-            this.wsConnection = new WebSocket(wsUrl);
+            this.wsConnection = new WebSocket(window.api.withToken(wsUrl));
 
             this.wsConnection.onopen = () => {
                 console.log('Live view WebSocket connected');
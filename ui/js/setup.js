@@ -13,7 +13,7 @@ class SetupWizard {
 
     async checkIfNeeded() {
         try {
-            const response = await fetch('/api/v1/setup/status');
+            const response = await window.api.fetch('/api/v1/setup/status');
             const data = await response.json();
 
             if (data.needs_setup) {
@@ -119,7 +119,7 @@ class SetupWizard {
 
         try {
             const data = this.getFormData();
-            const response = await fetch('/api/v1/setup/mqtt/test', {
+            const response = await window.api.fetch('/api/v1/setup/mqtt/test', {
                 method: 'POST',
                 headers: { 'Content-Type': 'application/json' },
                 body: JSON.stringify(data)
@@ -155,7 +155,7 @@ class SetupWizard {
 
         try {
             const data = this.getFormData();
-            const response = await fetch('/api/v1/setup/mqtt/save', {
+            const response = await window.api.fetch('/api/v1/setup/mqtt/save', {
                 method: 'POST',
                 headers: { 'Content-Type': 'application/json' },
                 body: JSON.stringify(data)